@@ -73,11 +73,13 @@ impl App {
         let torrent_id = self.engine.create_torrent(TorrentParams {
             metainfo: metainfo.clone(),
             listen_addr: args.listen,
+            storage: None,
             mode: args.mode,
             conf: Some(TorrentConf {
                 alerts: TorrentAlertConf {
                     completed_pieces: true,
                     peers: true,
+                    files: true,
                 },
                 ..Default::default()
             }),
@@ -111,38 +113,10 @@ impl App {
         stats: TorrentStats,
     ) {
         if let Some(torrent) = self.torrents.get_mut(&torrent_id) {
-            // update file completion by checking which pieces were downloaded
-            // this round
-            // TODO: clean this up or possibly move into cratetorrent as
-            // a utility function
-            // TODO: consider letting tradetorrent send file completion progress
-            // since if a client is not listening continuously for completed
-            // pieces they won't be able to reconsruct this
-            if let Some(pieces) = &stats.pieces.latest_completed {
-                // for each piece, check which
-                for piece in pieces.iter().cloned() {
-                    let piece_len = torrent.storage.piece_len(piece);
-                    let mut torrent_piece_offset =
-                        torrent.storage.torrent_piece_offset(piece);
-                    let mut consumed = 0;
-
-                    let file_range =
-                        torrent.storage.files_intersecting_piece(piece);
-                    let files = &mut torrent.files[file_range];
-                    for file in files.iter_mut() {
-                        let remaining_piece_len = piece_len as u64 - consumed;
-                        let file_slice = file.info.get_slice(
-                            torrent_piece_offset,
-                            remaining_piece_len,
-                        );
-                        torrent_piece_offset += file_slice.len;
-                        consumed += file_slice.len;
-                        file.complete += file_slice.len;
-                        debug_assert!(
-                            file.complete <= file.info.len,
-                            "cannot have downloaded more than file length"
-                        );
-                    }
+            // update file completion from the engine's per file progress
+            if let Some(files) = &stats.files {
+                for (file, complete) in torrent.files.iter_mut().zip(files) {
+                    file.complete = *complete;
                 }
             }
 
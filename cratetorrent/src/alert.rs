@@ -15,13 +15,19 @@
 //! engine, but an effort is made to make more expensive operations optional.
 //!
 //! Such alerts include the [latest downloaded
-//! pieces](crate::conf::TorrentAlertConf::completed_pieces) or aggregate
-//! statistics about a torrent's [peers](crate::conf::TorrentAlertConf::peers).
+//! pieces](crate::conf::TorrentAlertConf::completed_pieces), aggregate
+//! statistics about a torrent's [peers](crate::conf::TorrentAlertConf::peers),
+//! or the download progress of its [files](crate::conf::TorrentAlertConf::files).
 //! More will be added later.
 
+use std::path::PathBuf;
+
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+    conf::SeedGoal, error::Error, torrent::stats::TorrentStats, PieceIndex,
+    Sha1Hash, TorrentId,
+};
 
 pub(crate) type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received. See [`Alert`]
@@ -34,12 +40,84 @@ pub type AlertReceiver = UnboundedReceiver<Alert>;
 pub enum Alert {
     /// Posted when the torrent has finished downloading.
     TorrentComplete(TorrentId),
+    /// Posted when the engine restarts a torrent from the [session
+    /// file](crate::session). Restored torrents are assigned new ids, which
+    /// can be matched with the torrents of the previous run by their info
+    /// hashes.
+    TorrentRestored { id: TorrentId, info_hash: Sha1Hash },
+    /// Posted when all pieces overlapping with a file have been downloaded
+    /// and verified. The file can be used from this point on, even if the rest
+    /// of the torrent is still downloading.
+    ///
+    /// The index refers to the file's position in
+    /// [`Metainfo::files`](crate::metainfo::Metainfo::files).
+    FileCompleted { id: TorrentId, index: usize },
     /// Each running torrent sends an update of its latest statistics every
     /// second via this alert.
     TorrentStats {
         id: TorrentId,
         stats: Box<TorrentStats>,
     },
+    /// Posted when a seeding torrent reached one of its [seeding
+    /// goals](crate::conf::SeedGoalConf) and was stopped. The torrent is
+    /// removed from the engine, so it's no longer saved in the session.
+    SeedGoalReached { id: TorrentId, goal: SeedGoal },
+    /// Posted when a torrent's configuration was changed at runtime. If the
+    /// change was rejected, an [`Error::InvalidConf`] is posted instead.
+    TorrentConfUpdated(TorrentId),
+    /// Posted as a torrent's storage is being moved, with the number of bytes
+    /// moved so far out of the torrent's total length.
+    StorageMoveProgress { id: TorrentId, moved: u64, total: u64 },
+    /// Posted when a torrent's storage was moved to the new download
+    /// directory. If the move failed, an [`Error::MoveStorage`] is posted
+    /// instead.
+    StorageMoved { id: TorrentId, download_dir: PathBuf },
+    /// Posted when a torrent's own directory was renamed. If the rename
+    /// failed, an [`Error::MoveStorage`] is posted instead.
+    TorrentRenamed { id: TorrentId, name: String },
+    /// Posted when a torrent's file was renamed. If the rename failed, an
+    /// [`Error::RenameFile`] is posted instead.
+    FileRenamed {
+        id: TorrentId,
+        /// The index of the file, as in
+        /// [`Metainfo::files`](crate::metainfo::Metainfo::files).
+        index: usize,
+        /// The file's new path, relative to the torrent's directory.
+        path: PathBuf,
+    },
+    /// Posted when writing the torrent's data failed even after retrying the
+    /// write several times, e.g. because the disk is full.
+    ///
+    /// The torrent is paused: its peers are disconnected, and the pieces that
+    /// could not be written are kept in memory. Once the cause of the error
+    /// is fixed, the torrent can be
+    /// [resumed](crate::engine::EngineHandle::resume_torrent), which writes
+    /// the pieces and reconnects the peers.
+    StorageError {
+        id: TorrentId,
+        /// The file that could not be written.
+        path: PathBuf,
+        /// The error of the write, as returned by the OS.
+        error: std::io::Error,
+    },
+    /// Posted when some of the torrent's data was found to be changed or
+    /// removed on disk by another program, e.g. when a file was deleted
+    /// while the torrent was seeding.
+    ///
+    /// The torrent no longer has the pieces in the changed parts of the
+    /// files, and tells its peers so. The pieces are checked again: those
+    /// that are still intact are regained, and the rest are downloaded again.
+    StorageChanged {
+        id: TorrentId,
+        /// The indices of the changed files, as in
+        /// [`Metainfo::files`](crate::metainfo::Metainfo::files).
+        files: Vec<usize>,
+        /// The pieces that are being checked again.
+        pieces: Vec<PieceIndex>,
+    },
+    /// Posted when the engine's configuration was changed at runtime. If the
+    /// change was rejected, an [`Error::InvalidConf`] is posted instead.
+    EngineConfUpdated,
     /// An error from somewhere inside the engine.
     Error(Error),
 }
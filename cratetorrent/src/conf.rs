@@ -1,6 +1,6 @@
 //! Global and per‐torrent configuration, with optional mod flags.
 
-use std::{path::PathBuf, time::Duration};
+use std::{fmt, path::PathBuf, thread, time::Duration};
 
 // Keep the first import conditional
 #[cfg(feature = "spoofing")]
@@ -35,6 +35,13 @@ impl Conf {
                 #[cfg(not(feature = "spoofing"))]
                 client_id: Default::default(),
                 download_dir: download_dir.into(),
+                session_path: None,
+                session_save_interval: Duration::from_secs(60),
+                write_buf_budget: 64 * 1024 * 1024,
+                max_open_files: 256,
+                disk_cache_budget: 64 * 1024 * 1024,
+                hasher_thread_count: thread::available_parallelism()
+                    .map_or(1, |count| count.get().min(4)),
             },
             torrent: TorrentConf::default(),
             #[cfg(any(feature = "ghostleech", feature = "ratio"))]
@@ -50,6 +57,29 @@ pub struct EngineConf {
     pub client_id: PeerId,
     /// Directory for downloads and seeds.
     pub download_dir: PathBuf,
+    /// If set, the engine restores the torrents saved in this file when it's
+    /// spawned, and saves its torrents to it periodically and on shutdown.
+    pub session_path: Option<PathBuf>,
+    /// How often the session file is saved. If zero, it's not saved
+    /// periodically, but still on shutdown.
+    pub session_save_interval: Duration,
+    /// The maximum number of bytes the disk write buffers of all torrents may
+    /// hold before peers stop starting new pieces, until the buffered blocks
+    /// are written to disk. The pieces in progress are still completed.
+    pub write_buf_budget: u64,
+    /// The maximum number of files the torrents may have open at a time.
+    /// Files are opened as they are read or written, and when this is
+    /// reached, the least recently used one is closed.
+    pub max_open_files: usize,
+    /// The maximum number of bytes of the torrents' pieces kept in memory, to
+    /// serve peers' requests without reading from disk. Both pieces read from
+    /// disk and pieces just written to disk are cached, and when this is
+    /// exceeded, the least recently used ones are evicted.
+    pub disk_cache_budget: u64,
+    /// The number of threads that hash the pieces of all torrents, both those
+    /// downloaded and those read from disk to be sent to peers. Defaults to
+    /// the number of CPU cores, but at most 4.
+    pub hasher_thread_count: usize,
 }
 
 /// Per‐torrent settings.
@@ -81,6 +111,41 @@ pub struct TorrentConf {
     pub upload_multiplier: Option<f64>,
 
     pub alerts: TorrentAlertConf,
+
+    /// The goals after which a seeding torrent is stopped.
+    pub seed_goals: SeedGoalConf,
+
+    /// How the torrent's files are allocated when it's started.
+    pub allocation: Allocation,
+
+    /// If set, the blocks that peers request are read straight out of the
+    /// torrent's files mapped into memory, instead of copying their pieces
+    /// into the disk cache. The kernel's page cache then serves repeated
+    /// reads, which saves memory and syscalls when seeding heavily.
+    ///
+    /// Only applies to the default file system storage, and only while the
+    /// torrent is seeding. Each file is checked before it's read from, and a
+    /// file changed by another program is unmapped and its pieces rechecked.
+    pub mmap_reads: bool,
+
+    /// When the data written to the torrent's files is flushed to disk.
+    pub flush_policy: FlushPolicy,
+
+    /// If set, the torrent is downloaded into this directory instead of the
+    /// engine's download directory.
+    pub incomplete_dir: Option<PathBuf>,
+    /// If set, the torrent's storage is moved to this directory once it's
+    /// complete, before [`Alert::TorrentComplete`] is posted, and the torrent
+    /// continues seeding from there. Torrents that are started as seeds are
+    /// expected to be in this directory.
+    ///
+    /// The torrent's file, or its directory if it's an archive, appears there
+    /// only once all of it was moved, even if it's copied from another file
+    /// system. The move fails if any of the torrent's files already exist
+    /// there, in which case the torrent stays where it was.
+    ///
+    /// [`Alert::TorrentComplete`]: crate::alert::Alert::TorrentComplete
+    pub completed_dir: Option<PathBuf>,
 }
 
 
@@ -102,16 +167,242 @@ impl Default for TorrentConf {
             #[cfg(feature = "upload_multiplier")]
             upload_multiplier: None,
             alerts: Default::default(),
+            seed_goals: Default::default(),
+            allocation: Default::default(),
+            mmap_reads: false,
+            flush_policy: Default::default(),
+            incomplete_dir: None,
+            completed_dir: None,
         }
     }
 }
 
 
+impl TorrentConf {
+    /// Checks that the configuration values are consistent with each other.
+    pub fn validate(&self) -> Result<(), ConfError> {
+        if self.max_connected_peer_count == 0 {
+            return Err(ConfError::ZeroMaxConnectedPeerCount);
+        }
+        if self.min_requested_peer_count > self.max_connected_peer_count {
+            return Err(ConfError::PeerCountLimits);
+        }
+        if self.announce_interval == Duration::default() {
+            return Err(ConfError::ZeroAnnounceInterval);
+        }
+        if matches!(self.seed_goals.ratio, Some(r) if r.is_nan() || r < 0.0) {
+            return Err(ConfError::InvalidSeedRatio);
+        }
+        Ok(())
+    }
+}
+
+/// A partial update of a [`TorrentConf`], applied to a running torrent via
+/// [`set_torrent_conf`](crate::engine::EngineHandle::set_torrent_conf).
+///
+/// Only the fields that are set are changed, the rest of the torrent's
+/// configuration is left as is.
+#[derive(Clone, Debug, Default)]
+pub struct TorrentConfPatch {
+    pub min_requested_peer_count: Option<usize>,
+    /// If lowered below the number of currently connected peers, the excess
+    /// peers with the lowest transfer rates are disconnected.
+    pub max_connected_peer_count: Option<usize>,
+    pub announce_interval: Option<Duration>,
+    pub tracker_error_threshold: Option<usize>,
+    pub alerts: Option<TorrentAlertConf>,
+    pub seed_goals: Option<SeedGoalConf>,
+}
+
+impl TorrentConfPatch {
+    /// Returns a copy of the configuration with this patch applied, or an
+    /// error if the result would be invalid.
+    pub fn apply(&self, conf: &TorrentConf) -> Result<TorrentConf, ConfError> {
+        let mut conf = conf.clone();
+        if let Some(n) = self.min_requested_peer_count {
+            conf.min_requested_peer_count = n;
+        }
+        if let Some(n) = self.max_connected_peer_count {
+            conf.max_connected_peer_count = n;
+        }
+        if let Some(interval) = self.announce_interval {
+            conf.announce_interval = interval;
+        }
+        if let Some(n) = self.tracker_error_threshold {
+            conf.tracker_error_threshold = n;
+        }
+        if let Some(alerts) = &self.alerts {
+            conf.alerts = alerts.clone();
+        }
+        if let Some(seed_goals) = &self.seed_goals {
+            conf.seed_goals = seed_goals.clone();
+        }
+        conf.validate()?;
+        Ok(conf)
+    }
+}
+
+/// A partial update of the engine configuration, applied via
+/// [`set_engine_conf`](crate::engine::EngineHandle::set_engine_conf).
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfPatch {
+    /// The download directory of torrents created after this change. Existing
+    /// torrents are not moved.
+    pub download_dir: Option<PathBuf>,
+    /// Changes to the default torrent configuration.
+    ///
+    /// These are applied to the defaults used for new torrents, as well as to
+    /// all running torrents that were not created with their own
+    /// configuration.
+    pub torrent: TorrentConfPatch,
+}
+
+/// The reason a configuration was rejected.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ConfError {
+    /// The maximum connected peer count must be positive.
+    ZeroMaxConnectedPeerCount,
+    /// The minimum requested peer count exceeds the maximum connected peer
+    /// count.
+    PeerCountLimits,
+    /// The announce interval must be positive.
+    ZeroAnnounceInterval,
+    /// The seed ratio goal must be a non-negative number.
+    InvalidSeedRatio,
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use ConfError::*;
+        match self {
+            ZeroMaxConnectedPeerCount => {
+                write!(fmt, "max connected peer count must be positive")
+            }
+            PeerCountLimits => write!(
+                fmt,
+                "min requested peer count exceeds max connected peer count"
+            ),
+            ZeroAnnounceInterval => {
+                write!(fmt, "announce interval must be positive")
+            }
+            InvalidSeedRatio => write!(fmt, "invalid seed ratio goal"),
+        }
+    }
+}
+
+impl std::error::Error for ConfError {}
+
 /// Optional engine alerts per torrent.
 #[derive(Clone, Debug, Default)]
 pub struct TorrentAlertConf {
     pub completed_pieces: bool,
     pub peers: bool,
+    /// Include the download progress of each file in the torrent's stats.
+    pub files: bool,
+}
+
+/// How the files of a torrent are allocated on disk.
+///
+/// Regardless of the mode, the torrent fails to start if there isn't enough
+/// free space for its missing data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// Files are created empty and grow as pieces are written to them, in
+    /// whatever order they arrive. This is the default.
+    #[default]
+    None,
+    /// Files are set to their full length up front, but the space is not
+    /// reserved and is only used as pieces are written.
+    Sparse,
+    /// The space of the files is reserved up front (with `fallocate` where
+    /// supported), which avoids fragmentation and running out of space
+    /// halfway through the download, at the cost of a slower start.
+    Full,
+}
+
+/// When the data written to a torrent's files is flushed to disk, i.e. synced
+/// so that it survives a crash or power loss.
+///
+/// Written pieces are served to peers right away, but they are only saved as
+/// downloaded in the session, and the torrent is only complete, once they're
+/// flushed. Pieces that were not flushed before a crash are downloaded again.
+/// All pending pieces are flushed when the engine shuts down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// The data is never flushed explicitly, and writing it back is left to
+    /// the OS. Pieces are considered durable as soon as they're written, so
+    /// after a crash the session may claim pieces that never reached disk.
+    Never,
+    /// The files of each piece are flushed after the piece is written.
+    PerPiece,
+    /// The files written to are flushed at this interval, and when the
+    /// torrent's last piece is written.
+    Periodic(Duration),
+    /// The files are flushed once the torrent's last piece is written.
+    OnCompletion,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::Periodic(Duration::from_secs(30))
+    }
+}
+
+/// Seeding goals of a torrent.
+///
+/// Once the torrent has all its pieces and _any_ of the set goals is reached,
+/// the torrent is stopped: it disconnects its peers, announces its exit to
+/// trackers, is removed from the engine and its session, and posts an
+/// [`Alert::SeedGoalReached`](crate::alert::Alert::SeedGoalReached) alert.
+/// By default no goal is set and the torrent seeds indefinitely.
+#[derive(Clone, Debug, Default)]
+pub struct SeedGoalConf {
+    /// Stop once the share ratio (uploaded / downloaded payload bytes) reaches
+    /// this value.
+    ///
+    /// The totals include the torrent's earlier runs, restored from the
+    /// session. If less than the torrent's total length was downloaded (e.g.
+    /// because the torrent was started as a seed), the torrent's length is
+    /// used as the denominator, as otherwise the ratio would be infinite.
+    pub ratio: Option<f64>,
+    /// Stop after seeding for this long.
+    pub seed_time: Option<Duration>,
+    /// Stop after seeding for this long without uploading anything.
+    pub idle_time: Option<Duration>,
+}
+
+impl SeedGoalConf {
+    /// Returns the first goal that has been reached, if any.
+    pub(crate) fn reached(
+        &self,
+        ratio: f64,
+        seed_duration: Duration,
+        idle_duration: Duration,
+    ) -> Option<SeedGoal> {
+        if matches!(self.ratio, Some(goal) if ratio >= goal) {
+            Some(SeedGoal::Ratio)
+        } else if matches!(self.seed_time, Some(goal) if seed_duration >= goal)
+        {
+            Some(SeedGoal::SeedTime)
+        } else if matches!(self.idle_time, Some(goal) if idle_duration >= goal)
+        {
+            Some(SeedGoal::IdleTime)
+        } else {
+            None
+        }
+    }
+}
+
+/// The seeding goal that caused a torrent to stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeedGoal {
+    /// [`SeedGoalConf::ratio`] was reached.
+    Ratio,
+    /// [`SeedGoalConf::seed_time`] was reached.
+    SeedTime,
+    /// [`SeedGoalConf::idle_time`] was reached.
+    IdleTime,
 }
 
 /// "Extreme mod" flags: ghost‐leech and upload‐ratio guard.
@@ -136,3 +427,97 @@ impl Default for ExtremeModConf {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seed_goals() {
+        let secs = Duration::from_secs;
+
+        // no goals are set by default
+        let goals = SeedGoalConf::default();
+        assert_eq!(goals.reached(100.0, secs(1000), secs(1000)), None);
+
+        let goals = SeedGoalConf {
+            ratio: Some(2.0),
+            seed_time: Some(secs(7 * 24 * 60 * 60)),
+            idle_time: None,
+        };
+        assert_eq!(goals.reached(1.9, secs(60), secs(60)), None);
+        assert_eq!(
+            goals.reached(2.0, secs(60), secs(60)),
+            Some(SeedGoal::Ratio)
+        );
+        assert_eq!(
+            goals.reached(0.5, secs(7 * 24 * 60 * 60), secs(0)),
+            Some(SeedGoal::SeedTime)
+        );
+
+        let goals = SeedGoalConf {
+            idle_time: Some(secs(30)),
+            ..Default::default()
+        };
+        assert_eq!(goals.reached(0.0, secs(100), secs(29)), None);
+        assert_eq!(
+            goals.reached(0.0, secs(100), secs(30)),
+            Some(SeedGoal::IdleTime)
+        );
+    }
+
+    #[test]
+    fn test_torrent_conf_patch() {
+        let conf = TorrentConf::default();
+
+        // an empty patch leaves the conf as is
+        let patched = TorrentConfPatch::default().apply(&conf).unwrap();
+        assert_eq!(
+            patched.max_connected_peer_count,
+            conf.max_connected_peer_count
+        );
+
+        let patch = TorrentConfPatch {
+            max_connected_peer_count: Some(20),
+            announce_interval: Some(Duration::from_secs(30)),
+            alerts: Some(TorrentAlertConf {
+                completed_pieces: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let patched = patch.apply(&conf).unwrap();
+        assert_eq!(patched.max_connected_peer_count, 20);
+        assert_eq!(
+            patched.min_requested_peer_count,
+            conf.min_requested_peer_count
+        );
+        assert_eq!(patched.announce_interval, Duration::from_secs(30));
+        assert!(patched.alerts.completed_pieces);
+
+        // the min requested peer count may not exceed the max
+        let patch = TorrentConfPatch {
+            max_connected_peer_count: Some(conf.min_requested_peer_count - 1),
+            ..Default::default()
+        };
+        assert!(matches!(patch.apply(&conf), Err(ConfError::PeerCountLimits)));
+
+        let patch = TorrentConfPatch {
+            announce_interval: Some(Duration::default()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            patch.apply(&conf),
+            Err(ConfError::ZeroAnnounceInterval)
+        ));
+
+        let patch = TorrentConfPatch {
+            seed_goals: Some(SeedGoalConf {
+                ratio: Some(-1.0),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(patch.apply(&conf), Err(ConfError::InvalidSeedRatio)));
+    }
+}
@@ -1,31 +1,56 @@
 //! This module defines the entity responsible for disk IO and various utility
 //! types and functions.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 
+use bytes::Bytes;
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        watch, RwLock,
     },
     task,
 };
 
 use crate::{
-    engine, error::Error, peer, storage_info::StorageInfo, torrent, BlockInfo,
-    TorrentId,
+    engine,
+    error::Error,
+    peer,
+    storage::{MoveConflict, Storage},
+    storage_info::StorageInfo,
+    torrent, BlockInfo, FileIndex, PieceIndex, TorrentId,
 };
+use cache::DiskCache;
 use error::*;
-use io::torrent::Torrent;
+use hasher::HasherPool;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use io::uring::Ring;
+use io::torrent::{FailedWrite, Torrent};
 
+pub(crate) mod buf_pool;
+pub(crate) mod cache;
 pub(crate) mod error;
-mod io;
+pub(crate) mod hasher;
+pub(crate) mod io;
 
 /// Spawns a disk IO task and returns a tuple with the task join handle and the
 /// disk handle used for sending commands.
-pub(crate) fn spawn(engine_tx: engine::Sender) -> Result<(JoinHandle, Sender)> {
+pub(crate) fn spawn(
+    engine_tx: engine::Sender,
+    write_buf: Arc<WriteBuffer>,
+    cache: Arc<DiskCache>,
+    hasher: Arc<HasherPool>,
+) -> Result<(JoinHandle, Sender)> {
     log::info!("Spawning disk IO task");
-    let (mut disk, disk_tx) = Disk::new(engine_tx)?;
+    let (mut disk, disk_tx) =
+        Disk::new(engine_tx, write_buf, cache, hasher)?;
     // spawn disk event loop on a new task
     let join_handle = task::spawn(async move { disk.start().await });
     log::info!("Spawned disk IO task");
@@ -40,6 +65,90 @@ pub(crate) type Sender = UnboundedSender<Command>;
 /// The channel the disk task uses to listen for commands.
 type Receiver = UnboundedReceiver<Command>;
 
+/// The memory budget of the write buffers of all torrents.
+///
+/// Downloaded blocks are kept in their torrent's write buffer until their
+/// piece is complete, hashed and written to disk. If the disk is slower than
+/// the network, the buffered blocks keep piling up, so once the budget is
+/// exceeded, the disk signals all peer sessions to stop starting new pieces.
+/// The buffers only drain as pieces are completed, so the missing blocks of
+/// the pieces in progress are still requested, and the budget may be exceeded
+/// by those. New pieces are picked again once the buffers have drained to
+/// three quarters of the budget.
+pub(crate) struct WriteBuffer {
+    /// The maximum number of bytes buffered before backpressure is applied.
+    budget: u64,
+    /// The number of bytes currently buffered.
+    len: AtomicU64,
+    /// Whether the budget was exceeded and the buffers haven't drained yet.
+    ///
+    /// Peer sessions subscribe to this to know when they may resume picking
+    /// new pieces.
+    is_full: watch::Sender<bool>,
+}
+
+impl WriteBuffer {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            len: AtomicU64::new(0),
+            is_full: watch::Sender::new(false),
+        }
+    }
+
+    /// Returns the number of bytes currently buffered.
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of bytes to buffer.
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// Returns whether new block requests should be held back.
+    pub fn is_full(&self) -> bool {
+        *self.is_full.borrow()
+    }
+
+    /// Returns a receiver that is notified when the buffer becomes full or
+    /// when it has drained.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.is_full.subscribe()
+    }
+
+    /// Records that the bytes were placed in a write buffer.
+    fn add(&self, bytes: u64) {
+        let len = self.len.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if len > self.budget {
+            self.is_full.send_if_modified(|is_full| {
+                if *is_full {
+                    return false;
+                }
+                log::warn!("Write buffer full ({} b), pausing requests", len);
+                *is_full = true;
+                true
+            });
+        }
+    }
+
+    /// Records that the bytes were removed from a write buffer, after having
+    /// been written to disk or discarded.
+    fn remove(&self, bytes: u64) {
+        let len = self.len.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        if len <= self.budget / 4 * 3 {
+            self.is_full.send_if_modified(|is_full| {
+                if !*is_full {
+                    return false;
+                }
+                log::info!("Write buffer drained ({} b), resuming requests", len);
+                *is_full = false;
+                true
+            });
+        }
+    }
+}
+
 /// The type of commands that the disk can execute.
 #[derive(Debug)]
 pub(crate) enum Command {
@@ -47,6 +156,8 @@ pub(crate) enum Command {
     NewTorrent {
         id: TorrentId,
         storage_info: StorageInfo,
+        /// The backend in which the torrent's data is stored.
+        storage: Box<dyn Storage>,
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
     },
@@ -54,7 +165,9 @@ pub(crate) enum Command {
     WriteBlock {
         id: TorrentId,
         block_info: BlockInfo,
-        data: Vec<u8>,
+        /// The block's data, which is shared with the buffer it was received
+        /// into instead of being copied.
+        data: Bytes,
     },
     /// Request to eventually read a block from disk and return it via the
     /// sender.
@@ -63,6 +176,71 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         result_tx: peer::Sender,
     },
+    /// Move the torrent's data to a new directory, reporting the progress and
+    /// the result to the engine.
+    MoveStorage {
+        id: TorrentId,
+        /// The torrent's new download directory, which is only passed back to
+        /// the engine with the result.
+        download_dir: PathBuf,
+        /// Where the data is moved, which for archives includes the torrent's
+        /// own directory.
+        dir: PathBuf,
+        conflict: MoveConflict,
+    },
+    /// Rename one of the torrent's files, reporting the result to the engine.
+    RenameFile {
+        id: TorrentId,
+        index: FileIndex,
+        /// The file's new path, relative to the torrent's download directory.
+        path: PathBuf,
+    },
+    /// Sent by an IO worker thread when writing a verified piece failed, to
+    /// keep the piece in memory until the write is retried.
+    WriteFailed {
+        id: TorrentId,
+        piece_index: PieceIndex,
+        write: FailedWrite,
+        error: std::io::Error,
+    },
+    /// Retry writing the piece whose write failed, once its backoff delay has
+    /// passed.
+    RetryWrite {
+        id: TorrentId,
+        piece_index: PieceIndex,
+    },
+    /// Retry writing all of the torrent's pieces whose writes failed right
+    /// away, after the torrent was resumed.
+    ResumeWrites(TorrentId),
+    /// Flush the torrent's pieces written since the last flush to disk, and
+    /// report them to the torrent as durable.
+    Flush(TorrentId),
+    /// Report the current state of the torrent's files to the torrent, so
+    /// that it can notice data that was changed or removed by another
+    /// program.
+    CheckFiles(TorrentId),
+    /// Read the pieces from disk and verify them again, reporting to the
+    /// torrent which of them are still intact.
+    Recheck {
+        id: TorrentId,
+        pieces: Vec<PieceIndex>,
+    },
+    /// Update the paths of the torrent's files, which its storage already
+    /// uses, after they were moved or renamed, so that they're reported
+    /// along with errors.
+    SetStorageInfo {
+        id: TorrentId,
+        storage_info: StorageInfo,
+    },
+    /// Tell the disk task whether the torrent has all of its pieces, since
+    /// its files are only memory mapped while it's seeding.
+    SetSeed {
+        id: TorrentId,
+        is_seed: bool,
+    },
+    /// Drop the torrent's storage, closing its files, and its cached pieces,
+    /// once the torrent was removed from the engine.
+    RemoveTorrent(TorrentId),
     /// Eventually shut down the disk task.
     Shutdown,
 }
@@ -76,20 +254,61 @@ struct Disk {
     torrents: HashMap<TorrentId, RwLock<Torrent>>,
     /// Port on which disk IO commands are received.
     cmd_rx: Receiver,
+    /// A copy of the disk task's own command channel, given to torrents.
+    tx: Sender,
     /// Channel on which `Disk` sends alerts to the torrent engine.
     engine_tx: engine::Sender,
+    /// The resources shared with the torrents.
+    shared: Shared,
+}
+
+/// The engine-wide resources of the disk task, which it shares with all its
+/// torrents.
+#[derive(Clone)]
+pub(crate) struct Shared {
+    /// The usage of all torrents' write buffers.
+    pub write_buf: Arc<WriteBuffer>,
+    /// The cache of all torrents' pieces.
+    pub cache: Arc<DiskCache>,
+    /// The threads that hash all torrents' pieces.
+    pub hasher: Arc<HasherPool>,
+    /// The io_uring through which the torrents' files are read and written,
+    /// or `None` if the kernel doesn't support it, in which case the files
+    /// are accessed with blocking IO on a thread pool.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    pub ring: Option<Arc<Ring>>,
 }
 
 impl Disk {
     /// Creates a new `Disk` instance and returns a command sender and an alert
     /// receiver.
-    fn new(engine_tx: engine::Sender) -> Result<(Self, Sender)> {
+    fn new(
+        engine_tx: engine::Sender,
+        write_buf: Arc<WriteBuffer>,
+        cache: Arc<DiskCache>,
+        hasher: Arc<HasherPool>,
+    ) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        let ring = Ring::new()
+            .map_err(|e| {
+                log::warn!("Cannot set up io_uring, using blocking IO: {}", e);
+                e
+            })
+            .ok();
         Ok((
             Self {
                 torrents: HashMap::new(),
                 cmd_rx,
+                tx: cmd_tx.clone(),
                 engine_tx,
+                shared: Shared {
+                    write_buf,
+                    cache,
+                    hasher,
+                    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+                    ring,
+                },
             },
             cmd_tx,
         ))
@@ -104,6 +323,7 @@ impl Disk {
                 Command::NewTorrent {
                     id,
                     storage_info,
+                    storage,
                     piece_hashes,
                     torrent_tx,
                 } => {
@@ -126,8 +346,15 @@ impl Disk {
                     // NOTE: Do _NOT_ return on failure, we don't want to kill
                     // the disk task due to potential disk IO errors: we just
                     // want to log it and notify engine of it.
-                    let torrent_res =
-                        Torrent::new(storage_info, piece_hashes, torrent_tx);
+                    let torrent_res = Torrent::new(
+                        id,
+                        storage_info,
+                        storage,
+                        piece_hashes,
+                        torrent_tx,
+                        self.tx.clone(),
+                        self.shared.clone(),
+                    );
                     match torrent_res {
                         Ok(torrent) => {
                             log::info!("Torrent {} successfully allocated", id);
@@ -170,6 +397,72 @@ impl Disk {
                 } => {
                     self.read_block(id, block_info, result_tx).await?;
                 }
+                Command::MoveStorage {
+                    id,
+                    download_dir,
+                    dir,
+                    conflict,
+                } => {
+                    self.move_storage(id, download_dir, dir, conflict).await?;
+                }
+                Command::RenameFile { id, index, path } => {
+                    self.rename_file(id, index, path).await?;
+                }
+                Command::WriteFailed {
+                    id,
+                    piece_index,
+                    write,
+                    error,
+                } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.handle_write_failure(
+                            piece_index,
+                            write,
+                            error,
+                        );
+                    }
+                }
+                Command::RetryWrite { id, piece_index } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.retry_write(piece_index);
+                    }
+                }
+                Command::ResumeWrites(id) => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.resume_writes();
+                    }
+                }
+                Command::Flush(id) => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.read().await.flush();
+                    }
+                }
+                Command::CheckFiles(id) => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.check_files();
+                    }
+                }
+                Command::Recheck { id, pieces } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.read().await.recheck(pieces);
+                    }
+                }
+                Command::SetStorageInfo { id, storage_info } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.write().await.set_info(storage_info);
+                    }
+                }
+                Command::SetSeed { id, is_seed } => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.read().await.set_seed(is_seed);
+                    }
+                }
+                Command::RemoveTorrent(id) => {
+                    if self.torrents.remove(&id).is_some() {
+                        log::info!("Torrent {} removed", id);
+                        self.shared.cache.remove_torrent(id);
+                    }
+                }
                 Command::Shutdown => {
                     log::info!("Shutting down disk event loop");
                     break;
@@ -189,7 +482,7 @@ impl Disk {
         &self,
         id: TorrentId,
         block_info: BlockInfo,
-        data: Vec<u8>,
+        data: Bytes,
     ) -> Result<()> {
         log::trace!("Saving torrent {} block {} to disk", id, block_info);
 
@@ -231,63 +524,109 @@ impl Disk {
         })?;
         torrent.read().await.read_block(block_info, tx)
     }
+
+    /// Starts moving the torrent's storage. Unlike with reads and writes, an
+    /// invalid torrent id is reported to the engine, as the torrent may not
+    /// have been allocated.
+    async fn move_storage(
+        &self,
+        id: TorrentId,
+        download_dir: PathBuf,
+        dir: PathBuf,
+        conflict: MoveConflict,
+    ) -> Result<()> {
+        log::trace!("Moving torrent {} storage to {:?}", id, dir);
+        match self.torrents.get(&id) {
+            Some(torrent) => torrent.read().await.move_storage(
+                id,
+                download_dir,
+                dir,
+                conflict,
+                self.engine_tx.clone(),
+            ),
+            None => {
+                log::error!("Torrent {} not found", id);
+                self.engine_tx.send(engine::Command::StorageMoved {
+                    id,
+                    download_dir,
+                    result: Err(std::io::ErrorKind::NotFound.into()),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts renaming one of the torrent's files. Like with storage moves,
+    /// an invalid torrent id is reported to the engine.
+    async fn rename_file(
+        &self,
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+    ) -> Result<()> {
+        log::trace!("Renaming torrent {} file {} to {:?}", id, index, path);
+        match self.torrents.get(&id) {
+            Some(torrent) => torrent.read().await.rename_file(
+                id,
+                index,
+                path,
+                self.engine_tx.clone(),
+            ),
+            None => {
+                log::error!("Torrent {} not found", id);
+                self.engine_tx.send(engine::Command::FileRenamed {
+                    id,
+                    index,
+                    path,
+                    result: Err(std::io::ErrorKind::NotFound.into()),
+                })?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        fs,
+        fs, io,
         path::{Path, PathBuf},
+        sync::atomic::AtomicUsize,
     };
 
     use sha1::{Digest, Sha1};
     use tokio::sync::mpsc;
 
     use super::*;
-    use crate::{block_count, FileInfo, BLOCK_LEN};
+    use crate::{
+        block_count,
+        conf::Allocation,
+        disk::io::torrent::MAX_WRITE_ATTEMPT_COUNT,
+        storage::{FilePool, FsStorage},
+        torrent::stats::HashPriority,
+        FileInfo, BLOCK_LEN,
+    };
 
     /// Tests the allocation of a torrent, and then the allocation of the same
     /// torrent returning an error.
     #[tokio::test]
     async fn should_allocate_new_torrent() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
-
-        let Env {
-            id,
-            piece_hashes,
-            info,
-            torrent_tx,
-            ..
-        } = Env::new("allocate_new_torrent");
-
-        // allocate torrent via channel
-        disk_tx
-            .send(Command::NewTorrent {
-                id,
-                storage_info: info.clone(),
-                piece_hashes: piece_hashes.clone(),
-                torrent_tx: torrent_tx.clone(),
-            })
-            .unwrap();
-        // wait for result on alert port
-        let alert = rx.recv().await.unwrap();
-        assert!(matches!(
-            alert,
-            engine::Command::TorrentAllocation { result: Ok(()), .. }
-        ));
+        let env = Env::new("allocate_new_torrent");
+        let storage = Env::storage(&env.info);
+        let (mut rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
 
         // check that file was created on disk
-        let file = info.files.first().unwrap();
-        assert!(info.download_dir.join(&file.path).is_file());
+        assert!(env.path().is_file());
 
         // try to allocate the same torrent a second time
         disk_tx
             .send(Command::NewTorrent {
-                id,
-                storage_info: info,
-                piece_hashes,
-                torrent_tx: torrent_tx.clone(),
+                id: env.id,
+                storage: Env::storage(&env.info),
+                storage_info: env.info.clone(),
+                piece_hashes: env.piece_hashes.clone(),
+                torrent_tx: env.torrent_tx.clone(),
             })
             .unwrap();
 
@@ -302,53 +641,156 @@ mod tests {
         ));
     }
 
+    /// Tests that the disk cache counts hits and misses, and that it evicts the
+    /// least recently used pieces once its budget is exceeded.
+    #[test]
+    fn should_evict_least_recently_used_pieces() {
+        let cache = DiskCache::new(3 * BLOCK_LEN as u64);
+        let piece = |len| vec![Bytes::from(vec![0; len])];
+        let id = TorrentId::new();
+
+        cache.insert(id, 0, piece(BLOCK_LEN as usize));
+        cache.insert(id, 1, piece(BLOCK_LEN as usize));
+        assert!(cache.get_block(id, 0, 0).unwrap().is_some());
+        assert!(cache.get_block(id, 0, 1).unwrap().is_none());
+        assert!(cache.get_block(id, 2, 0).is_none());
+
+        // piece 1 is the least recently used, so it's evicted
+        cache.insert(id, 2, piece(2 * BLOCK_LEN as usize));
+        assert!(cache.contains(id, 0));
+        assert!(!cache.contains(id, 1));
+        assert!(cache.contains(id, 2));
+        // pieces that don't fit in the cache at all are not cached
+        cache.insert(id, 3, piece(4 * BLOCK_LEN as usize));
+        assert!(!cache.contains(id, 3));
+
+        let stats = cache.stats();
+        assert_eq!(stats.len, 3 * BLOCK_LEN as u64);
+        assert_eq!(stats.budget, 3 * BLOCK_LEN as u64);
+        assert_eq!(stats.hit_count, 2);
+        assert_eq!(stats.miss_count, 1);
+        assert_eq!(stats.eviction_count, 1);
+
+        cache.remove_torrent(id);
+        assert_eq!(cache.stats().len, 0);
+    }
+
+    /// Tests that the buffers of the blocks evicted from the disk cache are
+    /// reused, unless the blocks are still in use.
+    #[test]
+    fn should_recycle_evicted_blocks() {
+        let cache = DiskCache::new(BLOCK_LEN as u64);
+        let block = || cache.pool().get(BLOCK_LEN as usize).freeze();
+        let id = TorrentId::new();
+
+        // the evicted block is still being sent to a peer
+        cache.insert(id, 0, vec![block()]);
+        let in_use = cache.get_block(id, 0, 0).unwrap().unwrap();
+        let reused = block();
+        let reused_ptr = reused.as_ptr();
+        cache.insert(id, 1, vec![reused]);
+        assert!(!cache.contains(id, 0));
+        assert_eq!(cache.pool().len(), 0);
+        drop(in_use);
+
+        cache.remove_torrent(id);
+        assert_eq!(cache.pool().len(), 1);
+        let buf = cache.pool().get(100);
+        assert_eq!(buf.as_ptr(), reused_ptr);
+        assert_eq!(&buf[..], &[0; 100][..]);
+        assert_eq!(cache.pool().len(), 0);
+    }
+
+    /// Tests that the hasher pool runs higher priority jobs first, and the
+    /// jobs of the same piece in the order they were submitted.
+    #[test]
+    fn should_run_hash_jobs_by_priority() {
+        let hasher = HasherPool::new(1).unwrap();
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let job = |name| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+
+        // keep the only thread busy until all jobs are queued
+        let (gate_tx, gate_rx) = std::sync::mpsc::channel::<()>();
+        hasher.submit(HashPriority::Download, None, 0, move || {
+            gate_rx.recv().ok();
+        });
+        let key = Some((TorrentId::new(), 0));
+        hasher.submit(HashPriority::Recheck, None, 1, job("recheck"));
+        hasher.submit(HashPriority::Seed, None, 1, job("seed"));
+        hasher.submit(HashPriority::Download, key, 1, job("download 1"));
+        hasher.submit(HashPriority::Download, key, 1, job("download 2"));
+        let stats = hasher.stats();
+        assert_eq!(stats.thread_count, 1);
+        assert_eq!(stats.queue_len(HashPriority::Recheck), 1);
+        assert_eq!(stats.queue_len(HashPriority::Seed), 1);
+
+        gate_tx.send(()).unwrap();
+        // dropping the pool only waits for the running job, so wait for the
+        // queued ones first
+        while order.lock().unwrap().len() < 4 {
+            std::thread::yield_now();
+        }
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["download 1", "download 2", "seed", "recheck"]
+        );
+        assert_eq!(hasher.stats().hashed_len, 4);
+    }
+
+    /// Tests that the hasher pool can be dropped by one of its own jobs.
+    #[test]
+    fn should_drop_hasher_pool_from_job() {
+        let hasher = Arc::new(HasherPool::new(1).unwrap());
+        let (gate_tx, gate_rx) = std::sync::mpsc::channel::<()>();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let pool = Arc::clone(&hasher);
+        hasher.submit(HashPriority::Download, None, 0, move || {
+            gate_rx.recv().ok();
+            // this is the last reference to the pool
+            drop(pool);
+            done_tx.send(()).ok();
+        });
+
+        drop(hasher);
+        gate_tx.send(()).unwrap();
+        done_rx.recv().expect("hasher thread panicked");
+    }
+
+    /// Tests that a hash job that panics neither stops its thread nor holds
+    /// back the next jobs of its piece.
+    #[test]
+    fn should_run_hash_jobs_after_panic() {
+        let hasher = HasherPool::new(1).unwrap();
+        let key = Some((TorrentId::new(), 0));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        hasher.submit(HashPriority::Download, key, 0, || panic!("job failed"));
+        hasher.submit(HashPriority::Download, key, 0, move || {
+            done_tx.send(()).ok();
+        });
+        done_rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("job after panic not run");
+    }
+
     /// Tests writing of a complete valid torrent's pieces and verifying that an
     /// alert of each disk write is returned by the disk task.
     #[tokio::test]
     async fn should_write_all_pieces() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
-
-        let Env {
-            id,
-            pieces,
-            piece_hashes,
-            info,
-            torrent_tx,
-            mut torrent_rx,
-        } = Env::new("write_all_pieces");
-
-        // allocate torrent via channel
-        disk_tx
-            .send(Command::NewTorrent {
-                id,
-                storage_info: info.clone(),
-                piece_hashes: piece_hashes.clone(),
-                torrent_tx: torrent_tx.clone(),
-            })
-            .unwrap();
-        // wait for result on alert port
-        rx.recv().await.expect("cannot allocate torrent");
+        let mut env = Env::new("write_all_pieces");
+        let storage = Env::storage(&env.info);
+        let (_rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
 
         // write all pieces to disk
-        for (index, piece) in pieces.iter().enumerate() {
-            for_each_block(index, piece.len() as u32, |block| {
-                let block_end = block.offset + block.len;
-                let data = &piece[block.offset as usize..block_end as usize];
-                debug_assert_eq!(data.len(), block.len as usize);
-                println!("Writing piece {} block {}", index, block);
-                disk_tx
-                    .send(Command::WriteBlock {
-                        id,
-                        block_info: block,
-                        data: data.to_vec(),
-                    })
-                    .unwrap();
-            });
+        for index in 0..env.pieces.len() {
+            env.write_piece(&disk_tx, index);
 
             // wait for disk write result
             if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
-                torrent_rx.recv().await
+                env.torrent_rx.recv().await
             {
                 // piece is complete so it should be hashed and valid
                 assert_eq!(piece.index, index);
@@ -358,157 +800,486 @@ mod tests {
             }
         }
 
+        // the written pieces are flushed once, and reported as durable
+        for expected in [(0..env.pieces.len()).collect(), Vec::new()] {
+            disk_tx.send(Command::Flush(env.id)).unwrap();
+            match env.torrent_rx.recv().await {
+                Some(torrent::Command::Flushed {
+                    mut pieces,
+                    result: Ok(()),
+                }) => {
+                    pieces.sort_unstable();
+                    assert_eq!(pieces, expected);
+                }
+                _ => panic!("pieces could not be flushed"),
+            }
+        }
+
         // clean up test env
-        let file = info.files.first().unwrap();
-        fs::remove_file(info.download_dir.join(&file.path))
-            .expect("cannot clean up disk test torrent file");
+        env.remove_file();
     }
 
     /// Tests writing of an invalid piece and verifying that an alert of it
     /// is returned by the disk task.
     #[tokio::test]
     async fn should_reject_writing_invalid_piece() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
-
-        let Env {
-            id,
-            pieces,
-            piece_hashes,
-            info,
-            torrent_tx,
-            mut torrent_rx,
-        } = Env::new("write_invalid_piece");
-
-        // allocate torrent via channel
-        disk_tx
-            .send(Command::NewTorrent {
-                id,
-                storage_info: info.clone(),
-                piece_hashes: piece_hashes.clone(),
-                torrent_tx: torrent_tx.clone(),
-            })
-            .unwrap();
-        // wait for result on alert port
-        rx.recv().await.expect("cannot allocate torrent");
+        let mut env = Env::new("write_invalid_piece");
+        let storage = Env::storage(&env.info);
+        let (_rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
 
         // write an invalid piece to disk
         let index = 0;
         let invalid_piece: Vec<_> =
-            pieces[index].iter().map(|b| b.saturating_add(5)).collect();
-        for_each_block(index, invalid_piece.len() as u32, |block| {
-            let block_end = block.offset + block.len;
-            let data =
-                &invalid_piece[block.offset as usize..block_end as usize];
-            debug_assert_eq!(data.len(), block.len as usize);
-            println!("Writing invalid piece {} block {}", index, block);
-            disk_tx
-                .send(Command::WriteBlock {
-                    id,
-                    block_info: block,
-                    data: data.to_vec(),
-                })
-                .unwrap();
-        });
+            env.pieces[index].iter().map(|b| b.saturating_add(5)).collect();
+        env.write_blocks(&disk_tx, index, &invalid_piece);
 
         // wait for disk write result
         if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
-            torrent_rx.recv().await
+            env.torrent_rx.recv().await
         {
             assert_eq!(piece.index, index);
             assert!(!piece.is_valid);
         } else {
             panic!("piece could not be written to disk");
         }
+
+        // clean up test env
+        env.remove_file();
+    }
+
+    /// Tests that a piece whose write keeps failing is kept in the write buffer
+    /// and retried, that the torrent is notified of the error once the
+    /// retries are used up, and that the piece is written once writes are
+    /// resumed.
+    #[tokio::test]
+    async fn should_retry_failed_piece_write() {
+        let mut env = Env::new("retry_failed_piece_write");
+        let write_buf = Env::write_buf();
+        // allocate torrent with a storage that fails all attempts to write the
+        // first piece
+        let storage = Box::new(FailingStorage {
+            inner: Env::storage(&env.info),
+            failure_count: AtomicUsize::new(MAX_WRITE_ATTEMPT_COUNT),
+        });
+        let (_rx, disk_tx) = env
+            .allocate(Arc::clone(&write_buf), Env::cache(), storage)
+            .await;
+
+        let index = 0;
+        env.write_piece(&disk_tx, index);
+
+        // the storage doesn't report the file, so the piece's file is named
+        match env.torrent_rx.recv().await {
+            Some(torrent::Command::PieceCompletion(Err(WriteError::Io {
+                path,
+                error,
+            }))) => {
+                assert_eq!(path, env.path());
+                assert_eq!(error.raw_os_error(), Some(ENOSPC));
+            }
+            _ => panic!("piece write should have failed"),
+        }
+        // the piece is kept in memory
+        assert_eq!(write_buf.len(), env.pieces[index].len() as u64);
+
+        disk_tx.send(Command::ResumeWrites(env.id)).unwrap();
+        if let Some(torrent::Command::PieceCompletion(Ok(completion))) =
+            env.torrent_rx.recv().await
+        {
+            assert_eq!(completion.index, index);
+            assert!(completion.is_valid);
+        } else {
+            panic!("piece could not be written to disk");
+        }
+        assert_eq!(write_buf.len(), 0);
+
+        env.remove_file();
+    }
+
+    /// The OS error of a full disk on Linux.
+    const ENOSPC: i32 = 28;
+
+    /// A storage whose writes fail with a full disk error a number of times
+    /// before they are passed on to the wrapped storage.
+    struct FailingStorage {
+        inner: Box<dyn Storage>,
+        failure_count: AtomicUsize,
+    }
+
+    impl Storage for FailingStorage {
+        fn open(&mut self) -> Result<(), NewTorrentError> {
+            self.inner.open()
+        }
+
+        fn write(&self, offset: u64, bufs: &[&[u8]]) -> io::Result<()> {
+            let failed = self.failure_count.fetch_update(
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+                |count| count.checked_sub(1),
+            );
+            if failed.is_ok() {
+                return Err(io::Error::from_raw_os_error(ENOSPC));
+            }
+            self.inner.write(offset, bufs)
+        }
+
+        fn read(&self, offset: u64, bufs: &mut [&mut [u8]]) -> io::Result<()> {
+            self.inner.read(offset, bufs)
+        }
+
+        fn move_to(
+            &mut self,
+            dir: &Path,
+            conflict: MoveConflict,
+            progress: &mut dyn FnMut(u64),
+        ) -> io::Result<()> {
+            self.inner.move_to(dir, conflict, progress)
+        }
+
+        fn delete(&mut self) -> io::Result<()> {
+            self.inner.delete()
+        }
     }
 
     /// Tests reading of a torrent piece's block and verifying that it is
     /// returned via the provided sender.
     #[tokio::test]
     async fn should_read_piece_blocks() {
-        let (tx, mut rx) = mpsc::unbounded_channel();
-        let (_, disk_tx) = spawn(tx).unwrap();
-
-        let Env {
-            id,
-            pieces,
-            piece_hashes,
-            info,
-            torrent_tx,
-            mut torrent_rx,
-        } = Env::new("read_piece_blocks");
-
-        // allocate torrent via channel
-        disk_tx
-            .send(Command::NewTorrent {
-                id,
-                storage_info: info.clone(),
-                piece_hashes: piece_hashes.clone(),
-                torrent_tx: torrent_tx.clone(),
-            })
-            .unwrap();
-        // wait for result on alert port
-        rx.recv().await.expect("cannot allocate torrent");
+        let mut env = Env::new("read_piece_blocks");
+        let storage = Env::storage(&env.info);
+        let (_rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
 
         // write piece to disk
         let index = 1;
-        let piece = &pieces[index];
-        for_each_block(index, piece.len() as u32, |block| {
-            let block_end = block.offset + block.len;
-            let data = &piece[block.offset as usize..block_end as usize];
-            debug_assert_eq!(data.len(), block.len as usize);
-            println!("Writing piece {} block {}", index, block);
-            disk_tx
-                .send(Command::WriteBlock {
-                    id,
-                    block_info: block,
-                    data: data.to_vec(),
-                })
-                .unwrap();
-        });
+        env.write_piece(&disk_tx, index);
 
         // wait for disk write result
-        assert!(torrent_rx.recv().await.is_some());
-
-        // set up channels to communicate disk read results
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(env.torrent_rx.recv().await.is_some());
 
         // read each block in piece
-        let block_count = block_count(piece.len() as u32) as u32;
+        let piece_len = env.pieces[index].len() as u32;
+        let block_count = block_count(piece_len) as u32;
         let mut block_offset = 0u32;
         for _ in 0..block_count {
             // when calculating the block length we need to consider that the
             // last block may be smaller than the rest
-            let block_len = (piece.len() as u32 - block_offset).min(BLOCK_LEN);
+            let block_len = (piece_len - block_offset).min(BLOCK_LEN);
             let block_info = BlockInfo {
                 piece_index: index,
                 offset: block_offset,
                 len: block_len,
             };
-            // read block
+            if let Some(peer::Command::Block(block)) =
+                env.read_block(&disk_tx, block_info).await
+            {
+                assert_eq!(block.info(), block_info);
+            } else {
+                panic!("block could not be read from disk");
+            }
+
+            // increment offset for next piece
+            block_offset += block_len;
+        }
+
+        // clean up test env
+        env.remove_file();
+    }
+
+    /// Tests that blocks of seeding torrents are read from memory mapped files
+    /// without going through the cache, and that corrupt pieces are not
+    /// served.
+    #[tokio::test]
+    async fn should_read_mapped_piece_blocks() {
+        let mut env = Env::new("read_mapped_piece_blocks");
+
+        // the torrent is seeded from a file written beforehand, in which the
+        // third piece is corrupt
+        let mut data = env.pieces.concat();
+        let corrupt_offset = env.info.torrent_piece_offset(2) as usize;
+        data[corrupt_offset] ^= 0xff;
+        fs::write(env.path(), &data).unwrap();
+
+        let cache = Env::cache();
+        let pool = Arc::new(FilePool::new(16));
+        let storage = FsStorage::new(env.info.clone(), Allocation::None, pool)
+            .with_mmap_reads();
+        let (_rx, disk_tx) = env
+            .allocate(Env::write_buf(), Arc::clone(&cache), Box::new(storage))
+            .await;
+
+        // the files are not mapped until the torrent is seeding
+        let block_info = BlockInfo {
+            piece_index: 0,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        assert!(env.read_block(&disk_tx, block_info).await.is_some());
+        assert!(cache.contains(env.id, 0));
+        disk_tx
+            .send(Command::SetSeed {
+                id: env.id,
+                is_seed: true,
+            })
+            .unwrap();
+
+        // the piece is verified on the first read, and the rest of its blocks
+        // are served right away
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let index = 1;
+        let piece = &env.pieces[index];
+        for_each_block(index, piece.len() as u32, |block_info| {
             disk_tx
                 .send(Command::ReadBlock {
-                    id,
+                    id: env.id,
                     block_info,
                     result_tx: tx.clone(),
                 })
                 .unwrap();
-
-            // wait for result
-            if let Some(peer::Command::Block(block)) = rx.recv().await {
-                assert_eq!(block.info(), block_info);
-            } else {
+        });
+        // the blocks may arrive in any order
+        let mut offset = 0;
+        while offset < piece.len() {
+            let Some(peer::Command::Block(block)) = rx.recv().await else {
                 panic!("block could not be read from disk");
-            }
+            };
+            let start = block.offset as usize;
+            assert_eq!(&*block.data, &piece[start..start + block.data.len()]);
+            offset += block.data.len();
+        }
+        assert!(!cache.contains(env.id, index));
 
-            // increment offset for next piece
-            block_offset += block_len;
+        // the corrupt piece is not sent to the peer
+        let block_info = BlockInfo {
+            piece_index: 2,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        assert!(env.read_block(&disk_tx, block_info).await.is_none());
+        match env.torrent_rx.recv().await {
+            Some(torrent::Command::ReadError {
+                block_info: info,
+                error: ReadError::HashMismatch,
+            }) => assert_eq!(info, block_info),
+            _ => panic!("corrupt piece was read"),
+        }
+
+        env.remove_file();
+    }
+
+    /// Tests that the state of the torrent's files is reported, both when
+    /// asked for and after a read failed, and that changed pieces are
+    /// rechecked.
+    #[tokio::test]
+    async fn should_recheck_changed_files() {
+        let mut env = Env::new("recheck_changed_files");
+
+        // the file is missing its last piece, and its second piece is corrupt
+        let mut data = env.pieces[..3].concat();
+        data[env.info.torrent_piece_offset(1) as usize] ^= 0xff;
+        fs::write(env.path(), &data).unwrap();
+
+        let storage = Env::storage(&env.info);
+        let (_rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
+
+        disk_tx.send(Command::CheckFiles(env.id)).unwrap();
+        let Some(torrent::Command::FileStates(states)) =
+            env.torrent_rx.recv().await
+        else {
+            panic!("file states not reported");
+        };
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].len, data.len() as u64);
+        assert!(states[0].mtime.is_some());
+
+        disk_tx
+            .send(Command::Recheck {
+                id: env.id,
+                pieces: vec![0, 1, 3],
+            })
+            .unwrap();
+        for (index, is_valid) in [(0, true), (1, false), (3, false)] {
+            let Some(torrent::Command::Rechecked(piece)) =
+                env.torrent_rx.recv().await
+            else {
+                panic!("piece {} not rechecked", index);
+            };
+            assert_eq!(piece.index, index);
+            assert_eq!(piece.is_valid, is_valid);
+        }
+
+        // the file is truncated while the torrent is seeding, which is noticed
+        // when reading from it fails
+        fs::OpenOptions::new()
+            .write(true)
+            .open(env.path())
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        let block_info = BlockInfo {
+            piece_index: 2,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        assert!(env.read_block(&disk_tx, block_info).await.is_none());
+        assert!(matches!(
+            env.torrent_rx.recv().await,
+            Some(torrent::Command::ReadError {
+                error: ReadError::MissingData,
+                ..
+            })
+        ));
+        let Some(torrent::Command::FileStates(states)) =
+            env.torrent_rx.recv().await
+        else {
+            panic!("file states not reported after read error");
+        };
+        assert_eq!(states[0].len, 0);
+
+        env.remove_file();
+    }
+
+    /// Tests that the torrent's storage is moved, reporting its progress, and
+    /// that the data is read from the new location afterwards.
+    #[tokio::test]
+    async fn should_move_storage() {
+        let mut env = Env::new("move_storage");
+        let new_dir = Path::new("/tmp/torrent_disk_test_move_storage_dir");
+        fs::remove_dir_all(new_dir).ok();
+
+        let storage = Env::storage(&env.info);
+        let (mut rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
+
+        // write piece to disk
+        let index = 0;
+        env.write_piece(&disk_tx, index);
+        assert!(env.torrent_rx.recv().await.is_some());
+
+        disk_tx
+            .send(Command::MoveStorage {
+                id: env.id,
+                download_dir: new_dir.to_path_buf(),
+                dir: new_dir.to_path_buf(),
+                conflict: MoveConflict::Fail,
+            })
+            .unwrap();
+        let total = env.info.download_len;
+        assert!(matches!(
+            rx.recv().await,
+            Some(engine::Command::StorageMoveProgress { moved, .. })
+                if moved == total
+        ));
+        assert!(matches!(
+            rx.recv().await,
+            Some(engine::Command::StorageMoved { result: Ok(()), .. })
+        ));
+
+        let path = &env.info.files[0].path;
+        assert!(!env.path().exists());
+        assert!(new_dir.join(path).is_file());
+
+        // the piece is read from the new location
+        let block_info = BlockInfo {
+            piece_index: index,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        if let Some(peer::Command::Block(block)) =
+            env.read_block(&disk_tx, block_info).await
+        {
+            let piece = &env.pieces[index];
+            assert_eq!(&*block.data, &piece[..BLOCK_LEN as usize]);
+        } else {
+            panic!("block could not be read from disk");
+        }
+
+        // clean up test env
+        fs::remove_dir_all(new_dir).expect("cannot clean up moved torrent");
+    }
+
+    /// Tests that the torrent's file is renamed into a new subdirectory, and
+    /// that its data is then read from there.
+    #[tokio::test]
+    async fn should_rename_file() {
+        let mut env = Env::new("rename_file");
+        let new_dir =
+            env.info.download_dir.join("torrent_disk_test_renamed_dir");
+        let new_path = new_dir.join("renamed_file");
+        fs::remove_dir_all(&new_dir).ok();
+
+        let storage = Env::storage(&env.info);
+        let (mut rx, disk_tx) =
+            env.allocate(Env::write_buf(), Env::cache(), storage).await;
+
+        // write piece to disk
+        let index = 0;
+        env.write_piece(&disk_tx, index);
+        assert!(env.torrent_rx.recv().await.is_some());
+
+        let path = new_path.strip_prefix(&env.info.download_dir).unwrap();
+        disk_tx
+            .send(Command::RenameFile {
+                id: env.id,
+                index: 0,
+                path: path.to_path_buf(),
+            })
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(engine::Command::FileRenamed { result: Ok(()), .. })
+        ));
+        assert!(!env.path().exists());
+        assert!(new_path.is_file());
+
+        // the piece is read from the new path
+        let block_info = BlockInfo {
+            piece_index: index,
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        if let Some(peer::Command::Block(block)) =
+            env.read_block(&disk_tx, block_info).await
+        {
+            let piece = &env.pieces[index];
+            assert_eq!(&*block.data, &piece[..BLOCK_LEN as usize]);
+        } else {
+            panic!("block could not be read from disk");
         }
 
         // clean up test env
-        let file = info.files.first().unwrap();
-        fs::remove_file(info.download_dir.join(&file.path))
-            .expect("cannot clean up disk test torrent file");
+        fs::remove_dir_all(new_dir).expect("cannot clean up renamed file");
+    }
+
+    /// Tests that exceeding the write buffer budget signals backpressure until
+    /// the buffer drains below its low watermark.
+    #[tokio::test]
+    async fn should_signal_write_buffer_backpressure() {
+        let write_buf = WriteBuffer::new(4 * BLOCK_LEN as u64);
+        let mut is_full_rx = write_buf.subscribe();
+
+        for _ in 0..4 {
+            write_buf.add(BLOCK_LEN as u64);
+        }
+        assert_eq!(write_buf.len(), 4 * BLOCK_LEN as u64);
+        assert!(!write_buf.is_full());
+
+        write_buf.add(BLOCK_LEN as u64);
+        assert!(write_buf.is_full());
+        is_full_rx.changed().await.unwrap();
+        assert!(*is_full_rx.borrow_and_update());
+
+        // draining to the budget is not enough
+        write_buf.remove(BLOCK_LEN as u64);
+        assert!(write_buf.is_full());
+
+        write_buf.remove(BLOCK_LEN as u64);
+        assert_eq!(write_buf.len(), 3 * BLOCK_LEN as u64);
+        assert!(!write_buf.is_full());
+        is_full_rx.changed().await.unwrap();
+        assert!(!*is_full_rx.borrow_and_update());
     }
 
     /// Calls the provided function for each block in piece, passing it the
@@ -552,6 +1323,27 @@ mod tests {
     }
 
     impl Env {
+        /// Returns a write buffer that never applies backpressure.
+        fn write_buf() -> Arc<WriteBuffer> {
+            Arc::new(WriteBuffer::new(u64::MAX))
+        }
+
+        /// Returns a disk cache that never evicts pieces.
+        fn cache() -> Arc<DiskCache> {
+            Arc::new(DiskCache::new(u64::MAX))
+        }
+
+        /// Returns a small hasher pool.
+        fn hasher() -> Arc<HasherPool> {
+            Arc::new(HasherPool::new(2).unwrap())
+        }
+
+        /// Returns a file system storage that doesn't preallocate files.
+        fn storage(info: &StorageInfo) -> Box<dyn Storage> {
+            let pool = Arc::new(FilePool::new(16));
+            Box::new(FsStorage::new(info.clone(), Allocation::None, pool))
+        }
+
         /// Creates a new test environment.
         ///
         /// Tests are run in parallel so multiple environments must not clash,
@@ -617,6 +1409,7 @@ mod tests {
                     path: download_rel_path,
                     torrent_offset: 0,
                     len: download_len,
+                    ..Default::default()
                 }],
             };
 
@@ -631,5 +1424,98 @@ mod tests {
                 torrent_rx,
             }
         }
+
+        /// Spawns a disk task with the given write buffer and cache, and
+        /// allocates the torrent in it with the given storage.
+        ///
+        /// Returns the engine's receiver and the disk task's sender.
+        async fn allocate(
+            &self,
+            write_buf: Arc<WriteBuffer>,
+            cache: Arc<DiskCache>,
+            storage: Box<dyn Storage>,
+        ) -> (UnboundedReceiver<engine::Command>, Sender) {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let (_, disk_tx) =
+                spawn(tx, write_buf, cache, Env::hasher()).unwrap();
+
+            disk_tx
+                .send(Command::NewTorrent {
+                    id: self.id,
+                    storage,
+                    storage_info: self.info.clone(),
+                    piece_hashes: self.piece_hashes.clone(),
+                    torrent_tx: self.torrent_tx.clone(),
+                })
+                .unwrap();
+            // wait for result on alert port
+            assert!(
+                matches!(
+                    rx.recv().await,
+                    Some(engine::Command::TorrentAllocation {
+                        result: Ok(()),
+                        ..
+                    })
+                ),
+                "cannot allocate torrent"
+            );
+
+            (rx, disk_tx)
+        }
+
+        /// Sends the blocks of the piece to the disk task to be written.
+        fn write_piece(&self, disk_tx: &Sender, index: PieceIndex) {
+            self.write_blocks(disk_tx, index, &self.pieces[index]);
+        }
+
+        /// Sends the blocks of the given piece data to the disk task to be
+        /// written.
+        fn write_blocks(
+            &self,
+            disk_tx: &Sender,
+            index: PieceIndex,
+            piece: &[u8],
+        ) {
+            for_each_block(index, piece.len() as u32, |block| {
+                let block_end = block.offset + block.len;
+                let data = &piece[block.offset as usize..block_end as usize];
+                disk_tx
+                    .send(Command::WriteBlock {
+                        id: self.id,
+                        block_info: block,
+                        data: Bytes::copy_from_slice(data),
+                    })
+                    .unwrap();
+            });
+        }
+
+        /// Reads a block from the disk task, returning what is sent to the
+        /// peer.
+        async fn read_block(
+            &self,
+            disk_tx: &Sender,
+            block_info: BlockInfo,
+        ) -> Option<peer::Command> {
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            disk_tx
+                .send(Command::ReadBlock {
+                    id: self.id,
+                    block_info,
+                    result_tx: tx,
+                })
+                .unwrap();
+            rx.recv().await
+        }
+
+        /// Returns the absolute path of the torrent's file.
+        fn path(&self) -> PathBuf {
+            self.info.download_dir.join(&self.info.files[0].path)
+        }
+
+        /// Removes the torrent's file.
+        fn remove_file(&self) {
+            fs::remove_file(self.path())
+                .expect("cannot clean up disk test torrent file");
+        }
     }
 }
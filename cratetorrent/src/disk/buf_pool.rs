@@ -0,0 +1,60 @@
+use std::sync::Mutex;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::BLOCK_LEN;
+
+/// The maximum number of buffers kept for reuse, i.e. 4 MiB of blocks.
+const MAX_POOLED_BUF_COUNT: usize = 256;
+
+/// A pool of block sized buffers, which the blocks read from disk are read
+/// into.
+///
+/// Blocks are reference counted and shared with the peers they're sent to,
+/// so a block's buffer can only be reused once no one else refers to it. This
+/// is checked when blocks are evicted from the disk cache, which is when most
+/// blocks are done with, and the buffers of those that are no longer used
+/// are returned to the pool instead of being freed.
+#[derive(Default)]
+pub(crate) struct BufPool {
+    bufs: Mutex<Vec<BytesMut>>,
+}
+
+impl BufPool {
+    /// Returns a zeroed buffer of the given length, which is reused from the
+    /// pool if possible.
+    pub fn get(&self, len: usize) -> BytesMut {
+        let buf = if len <= BLOCK_LEN as usize {
+            self.bufs.lock().unwrap().pop()
+        } else {
+            None
+        };
+        let mut buf = buf.unwrap_or_else(|| BytesMut::with_capacity(len));
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Returns the block's buffer to the pool, if it's not referred to by
+    /// anything else, and the pool is not full.
+    pub fn put(&self, block: Bytes) {
+        let Ok(mut buf) = block.try_into_mut() else {
+            return;
+        };
+        // blocks split off of larger buffers may be smaller than a block,
+        // which can't be reused for any block
+        if buf.capacity() < BLOCK_LEN as usize {
+            return;
+        }
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < MAX_POOLED_BUF_COUNT {
+            buf.clear();
+            bufs.push(buf);
+        }
+    }
+
+    /// Returns the number of buffers in the pool.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.bufs.lock().unwrap().len()
+    }
+}
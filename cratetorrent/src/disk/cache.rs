@@ -0,0 +1,185 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use lru::LruCache;
+
+use crate::{
+    disk::buf_pool::BufPool, torrent::stats::CacheStats, CachedBlock,
+    PieceIndex, TorrentId,
+};
+
+/// The engine-wide cache of the pieces of all torrents, in memory.
+///
+/// Pieces enter the cache in two ways: when they are read from disk to serve
+/// a peer's request (along with the pieces that are read ahead), and when
+/// downloaded pieces are written to disk, at which point they leave the write
+/// buffer for the cache. Either way, subsequent requests for the piece's
+/// blocks are served from memory.
+///
+/// The cache holds at most its budget of bytes, and when that is exceeded,
+/// the least recently used pieces are evicted. The buffers of evicted blocks
+/// are reused for the pieces read later, see [`BufPool`].
+pub(crate) struct DiskCache {
+    /// The maximum number of bytes cached.
+    budget: u64,
+    /// The cached pieces' blocks, in order.
+    ///
+    /// # Sync mutex
+    ///
+    /// The cache is behind a synchronous mutex, instead of using tokio's async
+    /// locks. This is because it is being accessed by an async and a blocking
+    /// task (where an async mutex cannot be used). However, it is safe to do so
+    /// as the lock guards are never held across suspension points. In fact, the
+    /// tokio redis example recommends using sync mutexes if holding them across
+    /// await points is not needed:
+    /// https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs#L29.
+    /// https://docs.rs/tokio/0.2.24/tokio/sync/struct.Mutex.html#which-kind-of-mutex-should-you-use
+    ///
+    /// TODO: An improvement might be to use a concurrent LRU cache or to
+    /// not update the cache state on reads but to periodically do so via a
+    /// timer or similar.
+    pieces: Mutex<LruCache<(TorrentId, PieceIndex), Vec<CachedBlock>>>,
+    /// The number of bytes currently cached.
+    len: AtomicU64,
+    /// The number of block reads served from the cache.
+    hit_count: AtomicU64,
+    /// The number of block reads that had to go to disk.
+    miss_count: AtomicU64,
+    /// The number of pieces evicted to make room for others.
+    eviction_count: AtomicU64,
+    /// The buffers of evicted blocks, into which pieces are read.
+    pool: BufPool,
+}
+
+impl DiskCache {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            pieces: Mutex::new(LruCache::unbounded()),
+            len: AtomicU64::new(0),
+            hit_count: AtomicU64::new(0),
+            miss_count: AtomicU64::new(0),
+            eviction_count: AtomicU64::new(0),
+            pool: BufPool::default(),
+        }
+    }
+
+    /// Returns the pool of buffers that blocks are read into.
+    pub fn pool(&self) -> &BufPool {
+        &self.pool
+    }
+
+    /// Returns the statistics of the cache.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            len: self.len.load(Ordering::Relaxed),
+            budget: self.budget,
+            hit_count: self.hit_count.load(Ordering::Relaxed),
+            miss_count: self.miss_count.load(Ordering::Relaxed),
+            eviction_count: self.eviction_count.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the block of the piece if the piece is cached, and records the
+    /// hit or miss.
+    ///
+    /// The outer option is `None` on a miss, and the inner one is `None` if
+    /// the block index is out of the piece's bounds.
+    pub fn get_block(
+        &self,
+        id: TorrentId,
+        piece_index: PieceIndex,
+        block_index: usize,
+    ) -> Option<Option<CachedBlock>> {
+        let mut pieces = self.pieces.lock().unwrap();
+        match pieces.get(&(id, piece_index)) {
+            Some(blocks) => {
+                self.hit_count.fetch_add(1, Ordering::Relaxed);
+                Some(blocks.get(block_index).cloned())
+            }
+            None => {
+                self.miss_count.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Returns whether the piece is cached, without counting as a use of it.
+    pub fn contains(&self, id: TorrentId, piece_index: PieceIndex) -> bool {
+        self.pieces.lock().unwrap().contains(&(id, piece_index))
+    }
+
+    /// Places the piece's blocks in the cache, evicting the least recently
+    /// used pieces if they don't fit otherwise.
+    ///
+    /// Pieces larger than the whole budget are not cached.
+    pub fn insert(
+        &self,
+        id: TorrentId,
+        piece_index: PieceIndex,
+        blocks: Vec<CachedBlock>,
+    ) {
+        let piece_len = blocks_len(&blocks);
+        if piece_len > self.budget {
+            return;
+        }
+
+        let mut pieces = self.pieces.lock().unwrap();
+        // another read of the same piece may have cached it already
+        if let Some(old) = pieces.put((id, piece_index), blocks) {
+            self.len.fetch_sub(blocks_len(&old), Ordering::Relaxed);
+        }
+        let mut len =
+            self.len.fetch_add(piece_len, Ordering::Relaxed) + piece_len;
+        while len > self.budget {
+            let Some((key, evicted)) = pieces.pop_lru() else {
+                break;
+            };
+            log::trace!("Evicting piece {:?} from disk cache", key);
+            let evicted_len = blocks_len(&evicted);
+            len = self.len.fetch_sub(evicted_len, Ordering::Relaxed)
+                - evicted_len;
+            self.eviction_count.fetch_add(1, Ordering::Relaxed);
+            self.recycle(evicted);
+        }
+    }
+
+    /// Removes the piece from the cache, if it's cached.
+    pub fn remove(&self, id: TorrentId, piece_index: PieceIndex) {
+        let mut pieces = self.pieces.lock().unwrap();
+        if let Some(blocks) = pieces.pop(&(id, piece_index)) {
+            self.len.fetch_sub(blocks_len(&blocks), Ordering::Relaxed);
+            self.recycle(blocks);
+        }
+    }
+
+    /// Removes all pieces of the torrent from the cache.
+    pub fn remove_torrent(&self, id: TorrentId) {
+        let mut pieces = self.pieces.lock().unwrap();
+        let keys: Vec<_> = pieces
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(torrent_id, _)| *torrent_id == id)
+            .collect();
+        for key in keys {
+            if let Some(blocks) = pieces.pop(&key) {
+                self.len.fetch_sub(blocks_len(&blocks), Ordering::Relaxed);
+                self.recycle(blocks);
+            }
+        }
+    }
+
+    /// Returns the buffers of the blocks that are no longer used to the pool.
+    fn recycle(&self, blocks: Vec<CachedBlock>) {
+        for block in blocks {
+            self.pool.put(block);
+        }
+    }
+}
+
+/// Returns the number of bytes in the blocks.
+fn blocks_len(blocks: &[CachedBlock]) -> u64 {
+    blocks.iter().map(|block| block.len() as u64).sum()
+}
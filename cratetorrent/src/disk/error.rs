@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{fmt, path::PathBuf};
 
 use crate::error::Error;
 
@@ -7,12 +7,20 @@ pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
 
 /// Error type returned on failed torrent allocations.
 ///
-/// This error is non-fatal so it should not be grouped with the global `Error`
-/// type as it may be recovered from.
+/// This error is non-fatal: it only stops the torrent that could not be
+/// allocated.
 #[derive(Debug)]
-pub(crate) enum NewTorrentError {
+#[non_exhaustive]
+pub enum NewTorrentError {
     /// The torrent entry already exists in `Disk`'s hashmap of torrents.
     AlreadyExists,
+    /// There is not enough free space for the torrent's missing data.
+    InsufficientSpace {
+        /// The number of bytes the torrent still needs.
+        required: u64,
+        /// The number of bytes available in the download directory.
+        available: u64,
+    },
     /// IO error while allocating torrent.
     Io(std::io::Error),
 }
@@ -29,25 +37,46 @@ impl fmt::Display for NewTorrentError {
             Self::AlreadyExists => {
                 write!(fmt, "disk torrent entry already exists")
             }
+            Self::InsufficientSpace {
+                required,
+                available,
+            } => write!(
+                fmt,
+                "insufficient disk space: {} b required, {} b available",
+                required, available
+            ),
             Self::Io(e) => e.fmt(fmt),
         }
     }
 }
 
-/// Error type returned on failed block writes.
+impl std::error::Error for NewTorrentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Error type returned on failed piece writes.
 ///
 /// This error is non-fatal so it should not be grouped with the global `Error`
-/// type as it may be recovered from.
+/// type as it may be recovered from: the piece is kept in memory and written
+/// once the torrent is resumed.
 #[derive(Debug)]
 pub(crate) enum WriteError {
-    /// An IO error ocurred.
-    Io(std::io::Error),
+    /// An IO error ocurred writing the file, even after retrying the write.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
 }
 
 impl fmt::Display for WriteError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Self::Io(e) => write!(fmt, "{}", e),
+            Self::Io { path, error } => write!(fmt, "{:?}: {}", path, error),
         }
     }
 }
@@ -63,6 +92,9 @@ pub(crate) enum ReadError {
     /// The block is valid within torrent but its data has not been downloaded
     /// yet or has been deleted.
     MissingData,
+    /// The piece's data in the storage doesn't match its hash, e.g. because it
+    /// was modified by another program.
+    HashMismatch,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
@@ -72,6 +104,7 @@ impl fmt::Display for ReadError {
         match self {
             Self::InvalidBlockOffset => write!(fmt, "invalid block offset"),
             Self::MissingData => write!(fmt, "torrent data missing"),
+            Self::HashMismatch => write!(fmt, "torrent data corrupt"),
             Self::Io(e) => write!(fmt, "{}", e),
         }
     }
@@ -0,0 +1,196 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    torrent::stats::{HashPriority, HasherStats},
+    PieceIndex, TorrentId,
+};
+
+/// Identifies the piece of a job, whose jobs are run one at a time.
+type Key = (TorrentId, PieceIndex);
+
+/// The threads that hash pieces, shared by all torrents.
+///
+/// Hashing is CPU bound, so it's kept off the reactor and off tokio's blocking
+/// thread pool, where it would hold back disk IO. Jobs are queued by their
+/// [priority](HashPriority), so that e.g. a large recheck doesn't hold back
+/// the verification of downloaded pieces.
+///
+/// Jobs of the same piece are run in the order they were submitted, one at a
+/// time, which allows hashing a piece block by block on any of the threads.
+pub(crate) struct HasherPool {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    /// Signaled when a job is queued, when a piece's job is done, and on
+    /// shutdown.
+    cond: Condvar,
+    /// The total number of bytes hashed.
+    hashed_len: AtomicU64,
+    /// The total number of nanoseconds the threads spent running jobs.
+    busy_nanos: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queues {
+    /// The jobs waiting for a thread, indexed by their priority.
+    jobs: [VecDeque<Job>; HashPriority::COUNT],
+    /// The pieces one of whose jobs is being run.
+    running: HashSet<Key>,
+    is_shutdown: bool,
+}
+
+struct Job {
+    key: Option<Key>,
+    /// The number of bytes the job hashes.
+    len: u64,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+impl HasherPool {
+    /// Starts the hasher threads (but at least one).
+    pub fn new(thread_count: usize) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            queues: Mutex::default(),
+            cond: Condvar::new(),
+            hashed_len: AtomicU64::new(0),
+            busy_nanos: AtomicU64::new(0),
+        });
+        let threads = (0..thread_count.max(1))
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("hasher-{}", i))
+                    .spawn(move || shared.run())
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { shared, threads })
+    }
+
+    /// Returns the statistics of the pool.
+    pub fn stats(&self) -> HasherStats {
+        let queues = self.shared.queues.lock().unwrap();
+        let hashed_len = self.shared.hashed_len.load(Ordering::Relaxed);
+        let busy_nanos = self.shared.busy_nanos.load(Ordering::Relaxed);
+        HasherStats {
+            thread_count: self.threads.len(),
+            queue_lens: queues.jobs.each_ref().map(VecDeque::len),
+            hashed_len,
+            hash_rate: (hashed_len as u128 * 1_000_000_000)
+                .checked_div(busy_nanos as u128)
+                .unwrap_or_default() as u64,
+        }
+    }
+
+    /// Queues the job, which hashes `len` bytes.
+    ///
+    /// If the job belongs to a piece, it's only run once the piece's previously
+    /// submitted jobs are done.
+    pub fn submit(
+        &self,
+        priority: HashPriority,
+        key: Option<Key>,
+        len: u64,
+        job: impl FnOnce() + Send + 'static,
+    ) {
+        let mut queues = self.shared.queues.lock().unwrap();
+        queues.jobs[priority as usize].push_back(Job {
+            key,
+            len,
+            run: Box::new(job),
+        });
+        drop(queues);
+        self.shared.cond.notify_one();
+    }
+}
+
+impl Drop for HasherPool {
+    /// Stops the threads once they finish their current jobs. Queued jobs are
+    /// dropped.
+    ///
+    /// Jobs may hold the last reference to the pool, in which case it's
+    /// dropped on one of its own threads, which can't be joined.
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().is_shutdown = true;
+        self.shared.cond.notify_all();
+        let current = thread::current().id();
+        for thread in self.threads.drain(..) {
+            if thread.thread().id() != current {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+impl Shared {
+    /// Runs jobs until the pool is shut down.
+    ///
+    /// A job that panics is logged and otherwise treated as done, so that
+    /// neither the thread nor the piece's later jobs are lost with it.
+    fn run(&self) {
+        while let Some(job) = self.next_job() {
+            let start = Instant::now();
+            if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job.run)) {
+                let msg = e
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown error");
+                log::error!(
+                    "Hasher job of piece {:?} panicked: {}",
+                    job.key,
+                    msg
+                );
+            }
+            self.busy_nanos.fetch_add(
+                start.elapsed().as_nanos() as u64,
+                Ordering::Relaxed,
+            );
+            self.hashed_len.fetch_add(job.len, Ordering::Relaxed);
+
+            if let Some(key) = job.key {
+                self.queues.lock().unwrap().running.remove(&key);
+                // the piece's next job may be waiting for this one
+                self.cond.notify_all();
+            }
+        }
+    }
+
+    /// Waits for the highest priority job that may be run, or returns `None`
+    /// on shutdown.
+    fn next_job(&self) -> Option<Job> {
+        let mut queues = self.queues.lock().unwrap();
+        loop {
+            if queues.is_shutdown {
+                return None;
+            }
+            let Queues { jobs, running, .. } = &mut *queues;
+            for queue in jobs.iter_mut() {
+                // a piece's jobs are queued in order, so its first queued job
+                // is skipped only while its previous job is being run
+                let pos = queue.iter().position(|job| {
+                    job.key.is_none_or(|key| !running.contains(&key))
+                });
+                if let Some(job) = pos.and_then(|pos| queue.remove(pos)) {
+                    if let Some(key) = job.key {
+                        running.insert(key);
+                    }
+                    return Some(job);
+                }
+            }
+            queues = self.cond.wait(queues).unwrap();
+        }
+    }
+}
@@ -1,22 +1,24 @@
 pub(crate) mod file;
 pub(crate) mod piece;
 pub(crate) mod torrent;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub(crate) mod uring;
 
 #[cfg(test)]
 mod tests {
     use std::{
-        collections::BTreeMap,
         fs,
         io::Read,
-        ops::Range,
         path::{Path, PathBuf},
-        sync,
+        sync::Arc,
     };
 
     use sha1::{Digest, Sha1};
 
     use crate::{
+        conf::Allocation,
         disk::{
+            buf_pool::BufPool,
             error::*,
             io::{
                 file::TorrentFile,
@@ -24,17 +26,19 @@ mod tests {
             },
         },
         iovecs::IoVec,
-        storage_info::FileInfo,
-        FileIndex, BLOCK_LEN,
+        storage::{FilePool, FsStorage, Storage},
+        storage_info::{FileInfo, StorageInfo},
+        BLOCK_LEN,
     };
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    use bytes::BytesMut;
 
     const DOWNLOAD_DIR: &str = "/tmp";
 
     /// Tests that writing blocks to a single file using `TorrentFile` works.
     #[test]
     fn should_write_blocks_to_torrent_file() {
-        let file_range = 0..1;
-        let piece = make_piece(file_range);
+        let piece = make_piece();
 
         let download_dir = Path::new(DOWNLOAD_DIR);
         let mut file = TorrentFile::new(
@@ -43,7 +47,9 @@ mod tests {
                 path: PathBuf::from("TorrentFile_write_block.test"),
                 torrent_offset: 0,
                 len: 2 * piece.len as u64,
+                ..Default::default()
             },
+            Allocation::None,
         )
         .expect("cannot create test file");
 
@@ -66,7 +72,7 @@ mod tests {
             .expect("cannot read test file");
         assert_eq!(
             file_content,
-            piece.blocks.values().cloned().flatten().collect::<Vec<_>>(),
+            piece_data(&piece),
             "file content does not equal piece"
         );
 
@@ -78,255 +84,383 @@ mod tests {
     /// Tests that writing piece to a single file works.
     #[test]
     fn should_write_piece_to_single_file() {
-        let file_range = 0..1;
-        let piece = make_piece(file_range);
-        let download_dir = Path::new(DOWNLOAD_DIR);
-        let file = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_single_file.test"),
-                torrent_offset: 0,
-                len: 2 * piece.len as u64,
-            },
-        )
-        .expect("cannot create test file");
-        let files = &[sync::RwLock::new(file)];
+        let piece = make_piece();
+        let (info, storage) = make_storage(&[(
+            "Piece_write_single_file.test",
+            2 * piece.len as u64,
+        )]);
 
         // piece starts at the beginning of files
         let torrent_piece_offset = 0;
         piece
-            .write(torrent_piece_offset, files)
+            .write(torrent_piece_offset, &storage)
             .expect("cannot write piece to file");
 
         // compare file content to piece
-        let mut file = files[0].write().unwrap();
-        let mut file_content = Vec::new();
-        file.handle
-            .read_to_end(&mut file_content)
+        let file = &info.files[0];
+        let file_content = fs::read(info.download_dir.join(&file.path))
             .expect("cannot read test file");
         assert_eq!(
             file_content,
-            piece.blocks.values().cloned().flatten().collect::<Vec<_>>(),
+            piece_data(&piece),
             "file {:?} content does not equal piece",
-            file.info
+            file
         );
 
         // clean up env
-        fs::remove_file(download_dir.join(&file.info.path))
-            .expect("cannot remove test file");
+        clean_up(&info);
     }
 
     #[test]
     fn should_not_read_piece_from_empty_file() {
-        let file_range = 0..1;
-        let piece = make_piece(file_range.clone());
-        let download_dir = Path::new(DOWNLOAD_DIR);
-        let file = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_read_empty_single_file_error.test"),
-                torrent_offset: 0,
-                len: 2 * piece.len as u64,
-            },
-        )
-        .expect("cannot create test file");
-        let files = &[sync::RwLock::new(file)];
+        let piece = make_piece();
+        let (info, storage) = make_storage(&[(
+            "Piece_read_empty_single_file_error.test",
+            2 * piece.len as u64,
+        )]);
 
         // reading piece from empty file should result in error
         let torrent_piece_offset = 0;
+        let pool = BufPool::default();
         let result =
-            piece::read(torrent_piece_offset, file_range, files, piece.len);
+            piece::read(torrent_piece_offset, &storage, piece.len, &pool);
         assert!(matches!(result, Err(ReadError::MissingData)));
 
         // clean up env
-        fs::remove_file(download_dir.join(&files[0].read().unwrap().info.path))
-            .expect("cannot remove test file");
+        clean_up(&info);
     }
 
     #[test]
     fn should_read_piece_from_single_file() {
-        let file_range = 0..1;
-        let piece = make_piece(file_range.clone());
-        let download_dir = Path::new(DOWNLOAD_DIR);
-        let file = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_read_single_file.test"),
-                torrent_offset: 0,
-                len: 2 * piece.len as u64,
-            },
-        )
-        .expect("cannot create test file");
-        let files = &[sync::RwLock::new(file)];
+        let piece = make_piece();
+        let (info, storage) = make_storage(&[(
+            "Piece_read_single_file.test",
+            2 * piece.len as u64,
+        )]);
 
         let torrent_piece_offset = 0;
         piece
-            .write(torrent_piece_offset, files)
+            .write(torrent_piece_offset, &storage)
             .expect("cannot write piece to file");
 
         // read piece as list of blocks
+        let pool = BufPool::default();
         let blocks =
-            piece::read(torrent_piece_offset, file_range, files, piece.len)
+            piece::read(torrent_piece_offset, &storage, piece.len, &pool)
                 .expect("cannot read piece from file");
 
         // compare contents
-        // map Vec<Arc<Vec<u8>>> to Vec<Vec<u8>>
-        let actual: Vec<_> = blocks
-            .iter()
-            .map(AsRef::as_ref)
-            .cloned()
-            .flatten()
-            .collect();
-        let expected: Vec<_> =
-            piece.blocks.values().flatten().copied().collect();
-        assert_eq!(actual, expected);
+        assert_eq!(blocks.concat(), piece_data(&piece));
 
         // clean up env
-        fs::remove_file(download_dir.join(&files[0].read().unwrap().info.path))
-            .expect("cannot remove test file");
+        clean_up(&info);
     }
 
     /// Tests that writing piece to multiple files works.
     #[test]
     fn should_write_piece_to_multiple_files() {
         // piece spans 3 files
-        let file_range = 0..3;
-        let piece = make_piece(file_range);
-        let download_dir = Path::new(DOWNLOAD_DIR);
-        let file1 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files1.test"),
-                torrent_offset: 0,
-                len: BLOCK_LEN as u64 + 3,
-            },
-        )
-        .expect("cannot create test file 1");
-        let file2 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files2.test"),
-                torrent_offset: file1.info.len,
-                len: BLOCK_LEN as u64 - 1500,
-            },
-        )
-        .expect("cannot create test file 2");
-        let file3 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files3.test"),
-                torrent_offset: file2.info.torrent_offset + file2.info.len,
-                len: piece.len as u64 - (file1.info.len + file2.info.len),
-            },
-        )
-        .expect("cannot create test file 3");
-        let files = &[
-            sync::RwLock::new(file1),
-            sync::RwLock::new(file2),
-            sync::RwLock::new(file3),
-        ];
+        let piece = make_piece();
+        let (info, storage) =
+            make_multi_file_storage("Piece_write_files", &piece);
 
         // piece starts at the beginning of files
         let torrent_piece_offset = 0;
         piece
-            .write(torrent_piece_offset, files)
+            .write(torrent_piece_offset, &storage)
             .expect("cannot write piece to file");
 
         // compare contents of files to piece
-        for file in files.iter() {
-            let mut file = file.write().unwrap();
-            let mut file_content = Vec::new();
-            file.handle
-                .read_to_end(&mut file_content)
+        for file in info.files.iter() {
+            let file_content = fs::read(info.download_dir.join(&file.path))
                 .expect("cannot read test file");
             // compare the content of file to the portion that corresponds to
             // piece
             assert_eq!(
                 file_content,
-                piece
-                    .blocks
-                    .values()
-                    .cloned()
-                    .flatten()
-                    .skip(file.info.torrent_offset as usize)
-                    .take(file.info.len as usize)
-                    .collect::<Vec<_>>(),
+                piece_data(&piece)[file.torrent_offset as usize..]
+                    [..file.len as usize],
                 "file {:?} content does not equal piece",
-                file.info
+                file
             );
         }
 
         // clean up env
-        for file in files.iter() {
-            let path = download_dir.join(&file.read().unwrap().info.path);
-            fs::remove_file(path).expect("cannot remove test file");
-        }
+        clean_up(&info);
     }
 
     #[test]
     fn should_read_piece_from_multiple_files() {
-        let file_range = 0..3;
-        let piece = make_piece(file_range.clone());
-        let download_dir = Path::new(DOWNLOAD_DIR);
-        let file1 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files1.test"),
-                torrent_offset: 0,
-                len: BLOCK_LEN as u64 + 3,
-            },
-        )
-        .expect("cannot create test file 1");
-        let file2 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files2.test"),
-                torrent_offset: file1.info.len,
-                len: BLOCK_LEN as u64 - 1500,
-            },
-        )
-        .expect("cannot create test file 2");
-        let file3 = TorrentFile::new(
-            download_dir,
-            FileInfo {
-                path: PathBuf::from("Piece_write_files3.test"),
-                torrent_offset: file2.info.torrent_offset + file2.info.len,
-                len: piece.len as u64 - (file1.info.len + file2.info.len),
-            },
-        )
-        .expect("cannot create test file 3");
-        let files = &[
-            sync::RwLock::new(file1),
-            sync::RwLock::new(file2),
-            sync::RwLock::new(file3),
-        ];
+        let piece = make_piece();
+        let (info, storage) =
+            make_multi_file_storage("Piece_read_files", &piece);
 
         // piece starts at the beginning of files
         let torrent_piece_offset = 0;
         piece
-            .write(torrent_piece_offset, files)
+            .write(torrent_piece_offset, &storage)
             .expect("cannot write piece to file");
 
         // read piece as list of blocks
+        let pool = BufPool::default();
         let blocks =
-            piece::read(torrent_piece_offset, file_range, files, piece.len)
-                .expect("cannot read piece from files");
+            piece::read(torrent_piece_offset, &storage, piece.len, &pool)
+            .expect("cannot read piece from files");
 
         // compare contents
-        // map Vec<Arc<Vec<u8>>> to Vec<Vec<u8>>
-        let actual: Vec<_> = blocks
+        assert_eq!(blocks.concat(), piece_data(&piece));
+
+        // clean up env
+        clean_up(&info);
+    }
+
+    /// Tests that writing blocks to and reading them from a `TorrentFile`
+    /// through io_uring works like the blocking IO.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    #[tokio::test]
+    #[ignore = "needs a kernel with io_uring enabled"]
+    async fn should_write_and_read_torrent_file_through_ring() {
+        let ring = make_ring();
+        let piece = make_piece();
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let file = Arc::new(
+            TorrentFile::new(
+                download_dir,
+                FileInfo {
+                    path: PathBuf::from("TorrentFile_uring.test"),
+                    torrent_offset: 0,
+                    len: 2 * piece.len as u64,
+                    ..Default::default()
+                },
+                Allocation::None,
+            )
+            .expect("cannot create test file"),
+        );
+
+        // the file doesn't have the data yet
+        let file_slice = file.info.get_slice(0, piece.len as u64);
+        let bufs = vec![BytesMut::zeroed(BLOCK_LEN as usize); 4];
+        let err = ring.read(&file, file_slice, bufs, 0).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // write all but the first 100 bytes of the blocks
+        let blocks: Vec<_> = piece.blocks.values().cloned().collect();
+        let file_slice = file.info.get_slice(0, piece.len as u64 - 100);
+        ring.write(&file, file_slice, blocks, 100)
+            .await
+            .expect("cannot write blocks to file");
+        ring.fsync(&file).await.expect("cannot sync file");
+        let file_content = fs::read(download_dir.join(&file.info.path))
+            .expect("cannot read test file");
+        assert_eq!(file_content, piece_data(&piece)[100..]);
+
+        // read the data back into buffers that don't line up with the blocks
+        let bufs = vec![BytesMut::zeroed(3000); 22];
+        let bufs = ring
+            .read(&file, file_slice, bufs, 500)
+            .await
+            .expect("cannot read blocks from file");
+        let data: Vec<_> = bufs.concat();
+        let end = 500 + file_content.len();
+        assert!(data[..500].iter().all(|b| *b == 0));
+        assert_eq!(data[500..end], file_content);
+        assert!(data[end..].iter().all(|b| *b == 0));
+
+        fs::remove_file(download_dir.join(&file.info.path))
+            .expect("cannot remove test file");
+    }
+
+    /// Tests that an operation whose submission fails after it was queued is
+    /// kept until the kernel completes it, along with its buffers.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    #[tokio::test]
+    #[ignore = "needs a kernel with io_uring enabled"]
+    async fn should_complete_ring_operation_after_failed_submit() {
+        let ring = make_ring();
+        let piece = make_piece();
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let file = Arc::new(
+            TorrentFile::new(
+                download_dir,
+                FileInfo {
+                    path: PathBuf::from("TorrentFile_uring_submit.test"),
+                    torrent_offset: 0,
+                    len: piece.len as u64,
+                    ..Default::default()
+                },
+                Allocation::None,
+            )
+            .expect("cannot create test file"),
+        );
+
+        // the write is queued but not submitted
+        ring.fail_next_submit();
+        let blocks: Vec<_> = piece.blocks.values().cloned().collect();
+        let file_slice = file.info.get_slice(0, piece.len as u64);
+        let write = tokio::spawn({
+            let ring = Arc::clone(&ring);
+            let file = Arc::clone(&file);
+            async move { ring.write(&file, file_slice, blocks, 0).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!write.is_finished());
+
+        // the next submission submits the queued write too
+        ring.fsync(&file).await.expect("cannot sync file");
+        write.await.unwrap().expect("cannot write blocks to file");
+        let file_content = fs::read(download_dir.join(&file.info.path))
+            .expect("cannot read test file");
+        assert_eq!(file_content, piece_data(&piece));
+
+        fs::remove_file(download_dir.join(&file.info.path))
+            .expect("cannot remove test file");
+    }
+
+    /// Tests that writing a piece to and reading it from multiple files
+    /// through io_uring works like the blocking IO.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    #[tokio::test]
+    #[ignore = "needs a kernel with io_uring enabled"]
+    async fn should_write_and_read_piece_files_through_ring() {
+        let ring = make_ring();
+        let piece = make_piece();
+        let (info, storage) =
+            make_multi_file_storage("Piece_uring_files", &piece);
+
+        let regions = storage.file_regions(0, piece.len as u64, true).unwrap();
+        let blocks = piece.blocks.values().cloned().collect();
+        piece::write_regions(&ring, regions.unwrap(), blocks)
+            .await
+            .expect("cannot write piece to files");
+        let blocks = piece::read(0, &storage, piece.len, &BufPool::default())
+            .expect("cannot read piece from files");
+        assert_eq!(blocks.concat(), piece_data(&piece));
+
+        let regions = storage.file_regions(0, piece.len as u64, false).unwrap();
+        let pool = BufPool::default();
+        let blocks =
+            piece::read_regions(&ring, regions.unwrap(), piece.len, &pool)
+                .await
+                .expect("cannot read piece from files");
+        assert_eq!(blocks.concat(), piece_data(&piece));
+
+        clean_up(&info);
+    }
+
+    /// Sets up an io_uring.
+    ///
+    /// The io_uring tests are ignored by default, as not all kernels allow
+    /// it, and are run with `--ignored` where it's supported.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn make_ring() -> Arc<crate::disk::io::uring::Ring> {
+        crate::disk::io::uring::Ring::new()
+            .expect("io_uring is not supported by the kernel")
+    }
+
+    /// Creates and opens a file system storage with the given files (their
+    /// names and lengths) in the download directory.
+    fn make_storage(files: &[(&str, u64)]) -> (StorageInfo, FsStorage) {
+        let mut torrent_offset = 0;
+        let files: Vec<_> = files
             .iter()
-            .map(AsRef::as_ref)
-            .cloned()
-            .flatten()
+            .map(|(path, len)| {
+                let file = FileInfo {
+                    path: PathBuf::from(path),
+                    torrent_offset,
+                    len: *len,
+                    ..Default::default()
+                };
+                torrent_offset += len;
+                file
+            })
             .collect();
-        let expected: Vec<_> =
-            piece.blocks.values().flatten().copied().collect();
-        assert_eq!(actual, expected);
+        let download_len = torrent_offset;
+        let info = StorageInfo {
+            piece_count: 1,
+            piece_len: download_len as u32,
+            last_piece_len: download_len as u32,
+            download_len,
+            // multi-file torrents are downloaded into their own directory
+            download_dir: if files.len() > 1 {
+                Path::new(DOWNLOAD_DIR).join(files[0].path.with_extension(""))
+            } else {
+                PathBuf::from(DOWNLOAD_DIR)
+            },
+            files,
+        };
+        let pool = Arc::new(FilePool::new(16));
+        let mut storage = FsStorage::new(info.clone(), Allocation::None, pool);
+        storage.open().expect("cannot create test files");
+        (info, storage)
+    }
+
+    /// Creates a storage of 3 files that the piece spans.
+    fn make_multi_file_storage(
+        name: &str,
+        piece: &Piece,
+    ) -> (StorageInfo, FsStorage) {
+        let len1 = BLOCK_LEN as u64 + 3;
+        let len2 = BLOCK_LEN as u64 - 1500;
+        let len3 = piece.len as u64 - (len1 + len2);
+        make_storage(&[
+            (&format!("{}1.test", name), len1),
+            (&format!("{}2.test", name), len2),
+            (&format!("{}3.test", name), len3),
+        ])
+    }
+
+    /// Removes the test files and their directory, if it was created for them.
+    fn clean_up(info: &StorageInfo) {
+        for file in info.files.iter() {
+            fs::remove_file(info.download_dir.join(&file.path))
+                .expect("cannot remove test file");
+        }
+        if info.files.len() > 1 {
+            fs::remove_dir(&info.download_dir).expect("cannot remove test dir");
+        }
+    }
+
+    /// Tests that the piece's hash matches regardless of the order in which
+    /// its blocks arrive, and that it doesn't if a block is corrupt.
+    #[test]
+    fn should_hash_blocks_out_of_order() {
+        let piece = make_piece();
+        let mut blocks: Vec<_> = piece
+            .blocks
+            .iter()
+            .map(|(offset, block)| (*offset, block.to_vec()))
+            .collect();
+        assert!(piece.matches_hash());
+
+        // the blocks after the gap are buffered until it's filled
+        blocks.swap(0, 2);
+        let mut out_of_order = Piece::new(piece.expected_hash, piece.len);
+        for (i, (offset, block)) in blocks.iter().cloned().enumerate() {
+            assert!(out_of_order.enqueue_block(offset, block.into()));
+            let job = out_of_order.hash_job();
+            // the first two blocks follow the gap before the third one
+            assert_eq!(job.is_some(), i >= 2);
+            if let Some((_, job)) = job {
+                job();
+            }
+        }
+        assert!(out_of_order.is_complete());
+        assert!(out_of_order.matches_hash());
+
+        let mut corrupt = Piece::new(piece.expected_hash, piece.len);
+        blocks[1].1[0] ^= 1;
+        for (offset, block) in blocks {
+            assert!(corrupt.enqueue_block(offset, block.into()));
+            if let Some((_, job)) = corrupt.hash_job() {
+                job();
+            }
+        }
+        assert!(corrupt.is_complete());
+        assert!(!corrupt.matches_hash());
     }
 
     /// Creates a piece for testing that has 4 blocks of length `BLOCK_LEN`.
-    fn make_piece(files: Range<FileIndex>) -> Piece {
+    fn make_piece() -> Piece {
         let blocks = vec![
             (0..BLOCK_LEN)
                 .map(|b| b % u8::MAX as u32)
@@ -353,21 +487,17 @@ mod tests {
             hasher.finalize().into()
         };
         let len = blocks.len() as u32 * BLOCK_LEN;
-        // convert blocks to a b-tree map
-        let (blocks, _) = blocks.into_iter().fold(
-            (BTreeMap::new(), 0u32),
-            |(mut map, mut offset), block| {
-                let block_len = block.len();
-                map.insert(offset, block);
-                offset += block_len as u32;
-                (map, offset)
-            },
-        );
-        Piece {
-            expected_hash,
-            len,
-            blocks,
-            file_range: files,
+        let mut piece = Piece::new(expected_hash, len);
+        for (i, block) in blocks.into_iter().enumerate() {
+            assert!(piece.enqueue_block(i as u32 * BLOCK_LEN, block.into()));
+            let (_, job) = piece.hash_job().expect("block not hashed");
+            job();
         }
+        piece
+    }
+
+    /// Returns the concatenation of the piece's blocks.
+    fn piece_data(piece: &Piece) -> Vec<u8> {
+        piece.blocks.values().flat_map(|b| b.iter().copied()).collect()
     }
 }
@@ -1,36 +1,49 @@
 use std::{
-    fs::OpenOptions,
-    io::{IoSlice, IoSliceMut},
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, IoSliceMut},
     path::Path,
 };
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::fcntl::fallocate;
 use nix::sys::uio::{preadv, pwritev};
 
 use crate::{
-    disk::error::*,
-    iovecs,
-    iovecs::IoVec,
-    storage_info::FileSlice,
-    FileInfo,
+    conf::Allocation, iovecs, iovecs::IoVec, storage_info::FileSlice, FileInfo,
 };
 
 pub(crate) struct TorrentFile {
     pub info: FileInfo,
-    pub handle: std::fs::File,
+    pub handle: File,
 }
 
 impl TorrentFile {
+    /// Opens the file in the download directory, creating it if it doesn't
+    /// exist, and allocates it according to the mode.
     pub fn new(
         download_dir: &Path,
         info: FileInfo,
-    ) -> Result<Self, NewTorrentError> {
+        allocation: Allocation,
+    ) -> io::Result<Self> {
         let path = download_dir.join(&info.path);
         let handle = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
-            .open(&path)
-            .map_err(NewTorrentError::Io)?;
+            .truncate(false)
+            .open(&path)?;
+        // files that are already at least as long are left alone, so that
+        // existing data is not truncated
+        if allocation != Allocation::None
+            && handle.metadata()?.len() < info.len
+        {
+            log::debug!("Allocating {:?} ({:?})", path, allocation);
+            if allocation == Allocation::Full {
+                allocate(&handle, info.len)?;
+            } else {
+                handle.set_len(info.len)?;
+            }
+        }
         Ok(Self { info, handle })
     }
 
@@ -38,7 +51,7 @@ impl TorrentFile {
         &self,
         file_slice: FileSlice,
         blocks: &'a mut [IoVec<&'a [u8]>],
-    ) -> Result<&'a mut [IoVec<&'a [u8]>], WriteError> {
+    ) -> io::Result<&'a mut [IoVec<&'a [u8]>]> {
         let mut iovecs = iovecs::IoVecs::bounded(blocks, file_slice.len as usize);
 
         let mut total_written = 0;
@@ -49,8 +62,11 @@ impl TorrentFile {
                 .map(|iov| IoSlice::new(iov.as_slice()))
                 .collect();
 
-            let n = pwritev(&self.handle, &ios, file_slice.offset as i64)
-                .map_err(|e| WriteError::Io(e.into()))?;
+            let n = pwritev(
+                &self.handle,
+                &ios,
+                file_slice.offset as i64 + total_written as i64,
+            )?;
 
             total_written += n;
             if total_written as u64 == file_slice.len {
@@ -67,7 +83,7 @@ impl TorrentFile {
         &self,
         file_slice: FileSlice,
         io_vecs: &'a mut [IoVec<&'a mut [u8]>],
-    ) -> Result<&'a mut [IoVec<&'a mut [u8]>], ReadError> {
+    ) -> io::Result<&'a mut [IoVec<&'a mut [u8]>]> {
         let mut bufs = io_vecs;
         let mut total_read = 0;
 
@@ -82,11 +98,12 @@ impl TorrentFile {
                 &self.handle,
                 &mut ios,
                 file_slice.offset as i64 + total_read as i64,
-            )
-                .map_err(|e| ReadError::Io(e.into()))?;
+            )?;
 
+            // the file is shorter than expected, i.e. the data has not been
+            // written yet
             if n == 0 {
-                return Err(ReadError::MissingData);
+                return Err(io::ErrorKind::UnexpectedEof.into());
             }
 
             total_read += n;
@@ -96,3 +113,21 @@ impl TorrentFile {
         Ok(bufs)
     }
 }
+
+/// Reserves the space of the file on disk, which also sets its length.
+///
+/// If the platform or the file system doesn't support it, the file is only
+/// set to its full length.
+fn allocate(handle: &File, len: u64) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        use nix::{errno::Errno, fcntl::FallocateFlags};
+        match fallocate(handle, FallocateFlags::empty(), 0, len as i64) {
+            Err(Errno::EOPNOTSUPP) => {
+                log::warn!("File system does not support fallocate");
+            }
+            res => return res.map_err(io::Error::from),
+        }
+    }
+    handle.set_len(len)
+}
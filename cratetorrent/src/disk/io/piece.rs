@@ -1,16 +1,22 @@
 use std::{
     collections::BTreeMap,
-    ops::Range,
-    sync::{self, Arc},
+    io,
+    sync::{Arc, Mutex},
 };
 
+use bytes::{Bytes, BytesMut};
 use sha1::{Digest, Sha1};
 
 use crate::{
     block_count, block_len,
-    disk::{error::*, io::file::TorrentFile},
-    iovecs::IoVec,
-    CachedBlock, FileIndex, Sha1Hash,
+    disk::{buf_pool::BufPool, error::*},
+    storage::Storage,
+    CachedBlock, Sha1Hash,
+};
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use crate::{
+    disk::io::uring::Ring,
+    storage::{FileError, FileRegion},
 };
 
 /// An in-progress piece download that keeps in memory the so far downloaded
@@ -31,53 +37,99 @@ pub(crate) struct Piece {
     // TODO: consider whether using a preallocated Vec of Options would be more
     // performant due to cache locality (we would have to count the missing
     // blocks though, or keep a separate counter)
-    pub blocks: BTreeMap<u32, Vec<u8>>,
-    /// The files that this piece overlaps with.
+    pub blocks: BTreeMap<u32, CachedBlock>,
+    /// The SHA-1 state of the blocks hashed so far.
     ///
-    /// This is a left-inclusive range of all all file indices, that can be used
-    /// to index the `Torrent::files` vector to get the file handles.
-    pub file_range: Range<FileIndex>,
+    /// Blocks are hashed on the hasher threads as they arrive, as long as all
+    /// blocks before them have arrived too. Blocks that arrive out of order
+    /// are only hashed once the gap before them is filled. This way the whole
+    /// piece doesn't have to be hashed in one go once it's complete.
+    hasher: Arc<Mutex<Sha1>>,
+    /// The number of bytes from the start of the piece that have been handed
+    /// to the hasher, which is also the offset of the next block to hash.
+    hashed_len: u32,
 }
 
 impl Piece {
-    /// Places block into piece's write buffer if it doesn't exist. TODO: should
-    /// we return an error if it does?
-    pub fn enqueue_block(&mut self, offset: u32, data: Vec<u8>) {
+    /// Creates an in-progress piece without any blocks.
+    pub fn new(expected_hash: Sha1Hash, len: u32) -> Self {
+        Self {
+            expected_hash,
+            len,
+            blocks: BTreeMap::new(),
+            hasher: Arc::new(Mutex::new(Sha1::new())),
+            hashed_len: 0,
+        }
+    }
+
+    /// Places block into piece's write buffer if it doesn't exist, and returns
+    /// whether it was placed. TODO: should we return an error if it does?
+    pub fn enqueue_block(&mut self, offset: u32, data: Bytes) -> bool {
         use std::collections::btree_map::Entry;
         let entry = self.blocks.entry(offset);
         if matches!(entry, Entry::Occupied(_)) {
             log::warn!("Duplicate piece block at offset {}", offset);
+            false
         } else {
             entry.or_insert(data);
+            true
         }
     }
 
+    /// Returns the job that hashes the blocks which follow the blocks already
+    /// handed to the hasher without a gap, along with the number of bytes it
+    /// hashes, if there are any such blocks.
+    ///
+    /// The piece's jobs must be run in the order they were returned.
+    pub fn hash_job(
+        &mut self,
+    ) -> Option<(u64, impl FnOnce() + Send + 'static)> {
+        let mut blocks = Vec::new();
+        while let Some(block) = self.blocks.get(&self.hashed_len)
+            && !block.is_empty()
+        {
+            self.hashed_len += block.len() as u32;
+            blocks.push(block.clone());
+        }
+        if blocks.is_empty() {
+            return None;
+        }
+
+        let len = blocks.iter().map(|block| block.len() as u64).sum();
+        let hasher = Arc::clone(&self.hasher);
+        Some((len, move || {
+            let mut hasher = hasher.lock().unwrap();
+            for block in blocks.iter() {
+                hasher.update(block);
+            }
+        }))
+    }
+
     /// Returns true if the piece has all its blocks in its write buffer.
     pub fn is_complete(&self) -> bool {
         self.blocks.len() == block_count(self.len)
     }
 
-    /// Calculates the piece's hash using all its blocks and returns if it
-    /// matches the expected hash.
+    /// Returns whether the piece's hash matches the expected hash.
     ///
-    /// # Important
-    ///
-    /// This is potentially a computationally expensive function and should be
-    /// executed on a thread pool and not the executor.
+    /// The blocks have already been hashed as they arrived, so this only
+    /// finalizes the hash, which must be done after all the piece's
+    /// [hash jobs](Self::hash_job) were run. If the blocks don't line up, e.g.
+    /// because a peer sent a block of the wrong length, not all of the piece
+    /// is hashed, and so the hash doesn't match.
     pub fn matches_hash(&self) -> bool {
         // sanity check that we only call this method if we have all blocks in
         // piece
         debug_assert_eq!(self.blocks.len(), block_count(self.len));
-        let mut hasher = Sha1::new();
-        for block in self.blocks.values() {
-            hasher.update(&block);
+        if self.hashed_len != self.len {
+            return false;
         }
-        let hash = hasher.finalize();
+        let hash = self.hasher.lock().unwrap().clone().finalize();
         log::debug!("Piece hash: {:x}", hash);
         hash.as_slice() == self.expected_hash
     }
 
-    /// Writes the piece's blocks to the files the piece overlaps with.
+    /// Writes the piece's blocks to the storage.
     ///
     /// # Important
     ///
@@ -86,134 +138,167 @@ impl Piece {
     pub fn write(
         &self,
         torrent_piece_offset: u64,
-        files: &[sync::RwLock<TorrentFile>],
-    ) -> Result<(), WriteError> {
-        // convert the blocks to IO slices that the underlying
-        // systemcall can deal with
-        let mut blocks: Vec<_> =
-            self.blocks.values().map(|b| IoVec::from_slice(b)).collect();
-        // the actual slice of blocks being worked on
-        let mut bufs = blocks.as_mut_slice();
-
-        // loop through all files piece overlaps with and write that part of
-        // piece to file
-        let files = &files[self.file_range.clone()];
-        debug_assert!(!files.is_empty());
-        // the offset at which we need to write in torrent, which is updated
-        // with each write
-        let mut torrent_write_offset = torrent_piece_offset;
-        let mut total_write_count = 0;
-
-        for file in files.iter() {
-            let file = file.write().unwrap();
-
-            // determine which part of the file we need to write to
-            debug_assert!(self.len as u64 > total_write_count);
-            let remaining_piece_len = self.len as u64 - total_write_count;
-            let file_slice = file
-                .info
-                .get_slice(torrent_write_offset, remaining_piece_len);
-            // an empty file slice shouldn't occur as it would mean that piece
-            // was thought to span fewer files than it actually does
-            debug_assert!(file_slice.len > 0);
-            // the write buffer should still contain bytes to write
-            debug_assert!(!bufs.is_empty());
-            debug_assert!(!bufs[0].as_slice().is_empty());
-
-            // write to file
-            let tail = file.write(file_slice, bufs)?;
-
-            // `write_vectored_at` only writes at most `slice.len` bytes of
-            // `bufs` to disk and returns the portion that wasn't
-            // written, which we can use to set the write buffer for the next
-            // round
-            bufs = tail;
-
-            torrent_write_offset += file_slice.len as u64;
-            total_write_count += file_slice.len;
-        }
-
-        // we should have used up all write buffers (i.e. written all blocks to
-        // disk)
-        debug_assert!(bufs.is_empty());
-
-        Ok(())
+        storage: &dyn Storage,
+    ) -> io::Result<()> {
+        let blocks: Vec<&[u8]> =
+            self.blocks.values().map(|block| block.as_ref()).collect();
+        storage.write(torrent_piece_offset, &blocks)
     }
 }
 
-/// Reads a piece's blocks from the specified portion of the file from disk.
+/// Reads a piece's blocks from the storage.
 ///
 /// # Arguments
 ///
 /// * `torrent_piece_offset` - The absolute offset of the piece's first byte in
-///     the whole torrent. From this value the relative offset of piece within
-///     file is calculated.
-/// * `file_range` - The files that contain data of the piece.
-/// * `files` - A slice of all files in torrent.
+///     the whole torrent.
+/// * `storage` - The storage of the torrent's data.
 /// * `len` - The length of the piece to read in.  While this function is
 ///     currently used to read the whole piece, it could also be used to read
 ///     only a portion of the piece or several pieces with this argument.
+/// * `pool` - The pool of buffers the blocks are read into.
 pub(super) fn read(
     torrent_piece_offset: u64,
-    file_range: Range<FileIndex>,
-    files: &[sync::RwLock<TorrentFile>],
+    storage: &dyn Storage,
     len: u32,
+    pool: &BufPool,
 ) -> Result<Vec<CachedBlock>, ReadError> {
     // reserve a read buffer for all blocks in piece
-    let block_count = block_count(len);
-    let mut blocks = Vec::with_capacity(block_count);
-    for i in 0..block_count {
-        let block_len = block_len(len, i);
-        let mut buf = Vec::new();
-        buf.resize(block_len as usize, 0u8);
-        blocks.push(Arc::new(buf))
+    let mut blocks = block_bufs(len, pool);
+    let mut bufs: Vec<&mut [u8]> =
+        blocks.iter_mut().map(|b| b.as_mut()).collect();
+    storage
+        .read(torrent_piece_offset, &mut bufs)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ReadError::MissingData,
+            _ => ReadError::Io(e),
+        })?;
+
+    Ok(blocks.into_iter().map(BytesMut::freeze).collect())
+}
+
+/// Returns the zeroed buffers of the blocks of a piece of the given length.
+fn block_bufs(len: u32, pool: &BufPool) -> Vec<BytesMut> {
+    (0..block_count(len))
+        .map(|i| pool.get(block_len(len, i) as usize))
+        .collect()
+}
+
+/// Returns the range of the torrent's bytes as the storage's buffers that
+/// refer to the data in place, without copying it.
+///
+/// This must only be called with storages that support mapped reads, see
+/// [`Storage::read_mapped`].
+pub(super) fn read_mapped(
+    offset: u64,
+    storage: &dyn Storage,
+    len: u32,
+) -> Result<Vec<Bytes>, ReadError> {
+    match storage.read_mapped(offset, len as u64) {
+        Ok(Some(bufs)) => Ok(bufs),
+        Ok(None) => Err(ReadError::Io(io::Error::other(
+            "storage does not support mapped reads",
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(ReadError::MissingData)
+        }
+        Err(e) => Err(ReadError::Io(e)),
     }
+}
 
-    // convert the blocks to IO slices that the underlying
-    // systemcall can deal with
-    let mut iovecs: Vec<IoVec<&mut [u8]>> =
-        blocks
-            .iter_mut()
-            .map(|b| {
-                IoVec::from_mut_slice(Arc::get_mut(b).expect(
-                    "cannot get mut ref to buffer only used by this thread",
-                ).as_mut_slice())
-            })
-            .collect();
-    let mut bufs = iovecs.as_mut_slice();
-
-    // loop through all files piece overlaps with and read that part of
-    // file
-    let files = &files[file_range];
-    debug_assert!(!files.is_empty());
-    let len = len as u64;
-    // the offset at which we need to read from torrent, which is updated
-    // with each read
-    let mut torrent_read_offset = torrent_piece_offset;
-    let mut total_read_count = 0;
-
-    for file in files.iter() {
-        let file = file.read().unwrap();
-
-        // determine which part of the file we need to read from
-        debug_assert!(len > total_read_count);
-        let remaining_piece_len = len - total_read_count;
-        let file_slice = file
-            .info
-            .get_slice(torrent_read_offset, remaining_piece_len);
-        // an empty file slice shouldn't occur as it would mean that piece
-        // was thought to span fewer files than it actually does
-        debug_assert!(file_slice.len > 0);
-
-        // read data
-        bufs = file.read(file_slice, bufs)?;
-
-        torrent_read_offset += file_slice.len as u64;
-        total_read_count += file_slice.len;
+/// Returns `len` bytes of the buffers, starting at `offset`.
+///
+/// The block is only copied if it spans several buffers, i.e. files, which
+/// is rare, as blocks are much smaller than most files.
+pub(super) fn mapped_block(bufs: &[Bytes], offset: usize, len: usize) -> Bytes {
+    // find the buffer the block starts in
+    let mut start = offset;
+    let mut index = 0;
+    while index < bufs.len() && start >= bufs[index].len() {
+        start -= bufs[index].len();
+        index += 1;
+    }
+    let Some(first) = bufs.get(index) else {
+        return Bytes::new();
+    };
+    if start + len <= first.len() {
+        return first.slice(start..start + len);
     }
 
-    // we should have read in the whole piece
-    debug_assert_eq!(total_read_count, len);
+    let mut block = Vec::with_capacity(len);
+    block.extend_from_slice(&first[start..]);
+    for buf in &bufs[index + 1..] {
+        let n = (len - block.len()).min(buf.len());
+        block.extend_from_slice(&buf[..n]);
+        if block.len() == len {
+            break;
+        }
+    }
+    Bytes::from(block)
+}
 
-    Ok(blocks)
+/// Writes the piece's blocks to the file regions that hold the piece, which
+/// are given in order, through io_uring.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub(super) async fn write_regions(
+    ring: &Ring,
+    regions: Vec<FileRegion<'_>>,
+    blocks: Vec<CachedBlock>,
+) -> io::Result<()> {
+    let mut skip = 0;
+    for region in regions {
+        // the padding's zeros are discarded
+        if let Some(file) = &region.file {
+            ring.write(file, region.slice, blocks.clone(), skip)
+                .await
+                .map_err(|e| FileError::wrap(region.path, e))?;
+        }
+        skip += region.slice.len as usize;
+    }
+    Ok(())
+}
+
+/// Reads a piece's blocks of the given length from the file regions that hold
+/// the piece, which are given in order, through io_uring.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub(super) async fn read_regions(
+    ring: &Ring,
+    regions: Vec<FileRegion<'_>>,
+    len: u32,
+    pool: &BufPool,
+) -> Result<Vec<CachedBlock>, ReadError> {
+    // padding is always present and all zeros, so the buffers are zeroed
+    let mut blocks = block_bufs(len, pool);
+    let mut skip = 0;
+    for region in regions {
+        if let Some(file) = &region.file {
+            blocks = ring
+                .read(file, region.slice, blocks, skip)
+                .await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => ReadError::MissingData,
+                    _ => ReadError::Io(e),
+                })?;
+        }
+        skip += region.slice.len as usize;
+    }
+    Ok(blocks.into_iter().map(BytesMut::freeze).collect())
+}
+
+/// Returns whether the hash of the piece's blocks read from the storage matches
+/// the expected hash.
+///
+/// # Important
+///
+/// This is potentially a computationally expensive function and should be
+/// executed on the hasher threads, and not the async executor.
+pub(super) fn verify<'a>(
+    bufs: impl IntoIterator<Item = &'a [u8]>,
+    expected_hash: &Sha1Hash,
+) -> bool {
+    let mut hasher = Sha1::new();
+    for buf in bufs {
+        hasher.update(buf);
+    }
+    hasher.finalize().as_slice() == expected_hash
 }
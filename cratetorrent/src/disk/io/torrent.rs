@@ -1,31 +1,35 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    fs,
-    // Add NonZeroUsize to imports if preferred, or use full path std::num::NonZeroUsize
-    num::NonZeroUsize,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, io,
+    path::PathBuf,
     sync::{
-        self,
-        atomic::{AtomicU64, AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::Duration,
+    vec,
 };
 
-use lru::LruCache;
-use tokio::task;
+use bytes::Bytes;
+use tokio::{runtime, sync::RwLock, task, time};
 
 use crate::{
     disk::{
+        self,
+        cache::DiskCache,
         error::*,
-        io::{
-            file::TorrentFile,
-            piece::{self, Piece},
-        },
+        hasher::HasherPool,
+        WriteBuffer,
+        io::piece::{self, Piece},
     },
-    peer,
+    engine, peer,
+    storage::{FileError, FileState, MoveConflict, Storage},
     storage_info::StorageInfo,
-    torrent::{self, PieceCompletion},
-    Block, BlockInfo, CachedBlock, PieceIndex,
+    torrent::{self, stats::HashPriority, PieceCompletion},
+    Block, BlockInfo, CachedBlock, FileIndex, PieceIndex, Sha1Hash, TorrentId,
 };
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use crate::{disk::io::uring::Ring, storage::FileRegion};
 
 /// Torrent information related to disk IO.
 ///
@@ -37,10 +41,18 @@ pub(crate) struct Torrent {
 
     /// The in-progress piece downloads and disk writes. This is the torrent's
     /// disk write buffer. Each piece is mapped to its index for faster lookups.
-    // TODO(https://github.com/mandreyel/cratetorrent/issues/22): Currently
-    // there is no upper bound on this.
+    ///
+    /// The size of all torrents' write buffers is tracked in
+    /// [`ThreadContext::write_buf_usage`], which limits it indirectly, by
+    /// holding back new block requests when it's exceeded.
     write_buf: HashMap<PieceIndex, Piece>,
 
+    /// The verified pieces whose write to disk failed, waiting to be retried.
+    ///
+    /// These are still counted in [`ThreadContext::write_buf_usage`], until
+    /// they are written.
+    failed_writes: HashMap<PieceIndex, FailedWrite>,
+
     /// Contains the fields that may be accessed by other threads.
     ///
     /// This is an optimization to avoid having to call
@@ -50,6 +62,28 @@ pub(crate) struct Torrent {
 
     /// The concatenation of all expected piece hashes.
     piece_hashes: Vec<u8>,
+
+    /// The state of the storage's files before it was opened, which is
+    /// reported the first time the torrent checks its files, as opening the
+    /// storage may recreate the files that are missing.
+    initial_file_states: Option<Vec<FileState>>,
+}
+
+/// A verified piece that is being written to disk, or whose write failed.
+pub(crate) struct FailedWrite {
+    piece: Piece,
+    /// The number of times writing the piece failed since the torrent was
+    /// last resumed.
+    attempt_count: usize,
+}
+
+impl fmt::Debug for FailedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FailedWrite")
+            .field("len", &self.piece.len)
+            .field("attempt_count", &self.attempt_count)
+            .finish()
+    }
 }
 
 /// Contains fields that are commonly accessed by torrent's IO threads.
@@ -62,63 +96,87 @@ pub(crate) struct Torrent {
 /// struct needs to be in an arc and thus only a single atomic increment has to
 /// be made when sending the contained fields across threads.
 struct ThreadContext {
+    /// The id of the torrent, which its pieces are cached under.
+    id: TorrentId,
+
     /// The channel used to alert a torrent that a block has been written to
     /// disk and/or a piece was completed.
     tx: torrent::Sender,
 
-    /// The read cache that caches entire pieces.
+    /// The disk task's own channel, on which pieces whose write failed are
+    /// passed back to it to be retried.
+    disk_tx: disk::Sender,
+
+    /// The engine-wide cache of entire pieces, shared by all torrents.
     ///
     /// The piece is stored as a list of 16 KiB blocks since that is what peers
     /// are going to request, so this avoids extra copies. Blocks are ordered.
     ///
     /// Every time a block read is issued it is checked if it's already cached
     /// here. If not, the whole pieces is read from disk and placed in the cache.
+    /// Pieces that are written to disk are also placed here.
+    cache: Arc<DiskCache>,
+
+    /// The engine-wide threads that hash pieces, shared by all torrents.
     ///
-    /// # Sync mutex
-    ///
-    /// The cache is behind a synchronous mutex, instead of using tokio's async
-    /// locks. This is because it is being accessed by an async and a blocking
-    /// task (where an async mutex cannot be used). However, it is safe to do so
-    /// as the lock guards are never held across suspension points. In fact, the
-    /// tokio redis example recommends using sync mutexes if holding them across
-    /// await points is not needed:
-    /// https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs#L29.
-    /// https://docs.rs/tokio/0.2.24/tokio/sync/struct.Mutex.html#which-kind-of-mutex-should-you-use
-    ///
-    /// Locking occurs in two places:
-    /// - once when reading the cache on the async task,
-    /// - and once when inserting a new entry in the blocking task.
+    /// Downloaded pieces are hashed block by block as the blocks arrive, and
+    /// pieces read from disk are hashed before they're sent to peers.
+    hasher: Arc<HasherPool>,
+
+    /// The engine-wide io_uring, through which the files of the storage are
+    /// read and written if it supports that.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    ring: Option<Arc<Ring>>,
+
+    /// The tokio runtime of the disk task, on which the hasher threads spawn
+    /// the writes of the pieces they verified.
+    runtime: runtime::Handle,
+
+    /// The storage of the torrent's data, opened during torrent creation.
     ///
-    /// Both of these are very short lived and shouldn't bog down the reactor by
-    /// too much.
+    /// Pieces are written and read on IO worker threads, which may do so
+    /// concurrently, so the storage itself synchronizes access to the
+    /// underlying files. The lock is only taken exclusively while the storage
+    /// is moved, which holds back all reads and writes until it's done.
     ///
-    /// TODO: An improvement might be to use a concurrent LRU cache or to
-    /// not update the cache state on reads but to periodically do so via a
-    /// timer or similar.
-    read_cache: sync::Mutex<LruCache<PieceIndex, Vec<CachedBlock>>>,
+    /// This is an async lock so that it can also be held while the storage's
+    /// files are accessed through io_uring, but IO worker threads take it
+    /// blocking.
+    storage: RwLock<Box<dyn Storage>>,
 
-    /// Handles of all files in torrent, opened in advance during torrent
-    /// creation.
+    /// Whether blocks are read straight from the storage's memory mapped
+    /// files, bypassing the disk cache, see [`Storage::read_mapped`].
+    is_mapped: bool,
+
+    /// Whether the torrent has all of its pieces.
     ///
-    /// Each writer thread will get exclusive access to the file handle it
-    /// needs, referring to it directly in the vector (hence the arc).
-    /// Multiple readers may read from the same file, but not while there is
-    /// a pending write.
+    /// The files are only mapped while the torrent is seeding, as otherwise
+    /// they're still being written to.
+    is_seed: AtomicBool,
+
+    /// The pieces whose data in the mapped files was verified, which are
+    /// served without hashing them again.
     ///
-    /// Later we will need to make file access more granular, as multiple
-    /// concurrent writes to the same file that don't overlap are safe to do.
-    // TODO: consider improving concurreny by allowing concurrent reads and
-    // writes on different parts of the file using byte-range locking
-    // TODO: Is there a way to avoid copying `FileInfo`s here from
-    // `self.info.structure`? We could just pass the file info on demand, but
-    // that woudl require reallocating this vector every time (to pass a new
-    // vector of pairs of `TorrentFile` and `FileInfo`).
-    files: Vec<sync::RwLock<TorrentFile>>,
+    /// Pieces are verified when they're first read, or are already verified
+    /// if they're written in this session.
+    verified_pieces: Mutex<HashSet<PieceIndex>>,
+
+    /// The pieces written since the storage was last flushed, which may not
+    /// have reached the disk yet.
+    unflushed_pieces: Mutex<Vec<PieceIndex>>,
+
+    /// Whether the storage's files are being checked after a read failed, so
+    /// that a burst of failed reads only checks them once.
+    is_checking_files: AtomicBool,
 
     /// Various disk IO related statistics.
     ///
     /// Stas are atomically updated by the IO worker threads themselves.
     stats: Stats,
+
+    /// The engine-wide write buffer usage, which pieces are removed from once
+    /// they're written to disk (or discarded).
+    write_buf_usage: Arc<WriteBuffer>,
 }
 
 #[derive(Default)]
@@ -131,103 +189,72 @@ struct Stats {
     read_count: AtomicU64,
     /// The number of times we failed to read from disk.
     read_failure_count: AtomicUsize,
+    /// The number of block reads served from the disk cache.
+    cache_hit_count: AtomicU64,
+    /// The number of block reads that were not in the disk cache.
+    cache_miss_count: AtomicU64,
 }
 
 impl Torrent {
+    /// Opens the torrent's storage, which for the default file system storage
+    /// creates the file system structure of the torrent and opens the file
     /// handles.
-    /// Creates the file system structure of the torrent and opens the file
-    ///
-    /// For a single file, there is a path validity check and then the file is
-    /// opened. For multi-file torrents, if there are any subdirectories in the
-    /// torrent archive, they are created and all files are opened.
     pub fn new(
+        id: TorrentId,
         info: StorageInfo,
+        mut storage: Box<dyn Storage>,
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
+        disk_tx: disk::Sender,
+        shared: disk::Shared,
     ) -> Result<Self, NewTorrentError> {
+        // opening the storage may recreate the files that are missing, so
+        // their state is taken before it
+        let initial_file_states = storage.file_states().unwrap_or_else(|e| {
+            log::warn!("Cannot get the state of the torrent's files: {}", e);
+            None
+        });
         // TODO: since this is done as part of a tokio::task, should we use
         // tokio_fs here?
-        if !info.download_dir.is_dir() {
-            log::warn!(
-                "Creating missing download directory {:?}",
-                info.download_dir
-            );
-            fs::create_dir_all(&info.download_dir)?;
-            log::info!("Download directory {:?} created", info.download_dir);
-        }
-
-        // TODO: return error instead
-        debug_assert_ne!(info.files.len(), 0, "torrent must have files");
-        let files = if info.files.len() == 1 {
-            let file = &info.files[0];
-            log::debug!(
-                "Torrent is single {} bytes long file {:?}",
-                file.len,
-                file.path
-            );
-            vec![sync::RwLock::new(TorrentFile::new(
-                &info.download_dir,
-                file.clone(),
-            )?)]
-        } else {
-            debug_assert!(!info.files.is_empty());
-            log::debug!("Torrent is multi file: {:?}", info.files);
-            log::debug!("Setting up directory structure");
-
-            let mut torrent_files = Vec::with_capacity(info.files.len());
-            for file in info.files.iter() {
-                let path = info.download_dir.join(&file.path);
-                // file or subdirectory in download root must not exist if
-                // download root does not exists
-                debug_assert!(!path.exists());
-                debug_assert!(path.is_absolute());
-
-                // get the parent of the file path: if there is one (i.e.
-                // this is not a file in the torrent root), and doesn't
-                // exist, create it
-                if let Some(subdir) = path.parent() {
-                    if !subdir.exists() {
-                        log::info!("Creating torrent subdir {:?}", subdir);
-                        fs::create_dir_all(&subdir).map_err(|e| {
-                            log::error!("Failed to create subdir {:?}", subdir);
-                            NewTorrentError::Io(e)
-                        })?;
-                    }
-                }
-
-                // open the file and get a handle to it
-                //
-                // TODO: is there a clean way of avoiding creating the path
-                // buffer twice?
-                torrent_files.push(sync::RwLock::new(TorrentFile::new(
-                    &info.download_dir,
-                    file.clone(),
-                )?));
-            }
-            torrent_files
-        };
+        storage.open()?;
+        // io_uring can only be used if the storage's files can be accessed
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        let ring = shared.ring.filter(|_| {
+            matches!(storage.file_regions(0, 0, false), Ok(Some(_)))
+        });
+        let is_mapped = matches!(storage.read_mapped(0, 0), Ok(Some(_)));
 
         Ok(Self {
             info,
             write_buf: HashMap::new(),
+            failed_writes: HashMap::new(),
             thread_ctx: Arc::new(ThreadContext {
+                id,
                 tx: torrent_tx,
-                read_cache: sync::Mutex::new(LruCache::new(
-                    // Convert usize to NonZeroUsize
-                    NonZeroUsize::new(READ_CACHE_UPPER_BOUND)
-                        .expect("READ_CACHE_UPPER_BOUND must be non-zero"),
-                )),
-                files,
+                disk_tx,
+                cache: shared.cache,
+                hasher: shared.hasher,
+                #[cfg(all(feature = "io_uring", target_os = "linux"))]
+                ring,
+                runtime: runtime::Handle::current(),
+                storage: RwLock::new(storage),
+                is_mapped,
+                is_seed: AtomicBool::new(false),
+                verified_pieces: Mutex::default(),
+                unflushed_pieces: Mutex::default(),
+                is_checking_files: AtomicBool::new(false),
                 stats: Stats::default(),
+                write_buf_usage: shared.write_buf,
             }),
             piece_hashes,
+            initial_file_states,
         })
     }
 
     pub fn write_block(
         &mut self,
         info: BlockInfo,
-        data: Vec<u8>,
+        data: Bytes,
     ) -> Result<()> {
         log::trace!("Saving block {} to disk", info);
 
@@ -240,13 +267,21 @@ impl Torrent {
             .get_mut(&piece_index)
             .expect("Newly inserted piece not present");
 
-        piece.enqueue_block(info.offset, data);
+        let len = data.len() as u64;
+        let ctx = &self.thread_ctx;
+        if piece.enqueue_block(info.offset, data) {
+            ctx.write_buf_usage.add(len);
+        }
 
-        // if the piece has all its blocks, it means we can hash it and save it
-        // to disk and clear its write buffer
+        // hash the blocks that are now in order on the hasher threads
+        let key = Some((ctx.id, piece_index));
+        if let Some((len, job)) = piece.hash_job() {
+            ctx.hasher.submit(HashPriority::Download, key, len, job);
+        }
+
+        // if the piece has all its blocks, it means we can verify it and save
+        // it to disk and clear its write buffer
         if piece.is_complete() {
-            // TODO: remove from in memory store only if the disk write
-            // succeeded (otherwise we need to retry later)
             let piece = self.write_buf.remove(&piece_index).unwrap();
 
             log::debug!(
@@ -256,80 +291,111 @@ impl Torrent {
                 piece.blocks.len()
             );
 
-            // don't block the reactor with the potentially expensive hashing
-            // and sync file writing
+            // the piece's jobs are run in order, so by the time this one runs
+            // all its blocks have been hashed
             let torrent_piece_offset =
                 self.info.torrent_piece_offset(piece_index);
-            let ctx = Arc::clone(&self.thread_ctx);
-            task::spawn_blocking(move || {
-                let is_piece_valid = piece.matches_hash();
-
-                // save piece to disk if it's valid
-                if is_piece_valid {
-                    log::debug!(
-                        "Piece {} is valid, writing to disk",
-                        piece_index
-                    );
+            let ctx = Arc::clone(ctx);
+            self.thread_ctx.hasher.submit(
+                HashPriority::Download,
+                key,
+                0,
+                move || {
+                    ctx.verify_piece(piece_index, torrent_piece_offset, piece)
+                },
+            );
+        }
 
-                    if let Err(e) =
-                        piece.write(torrent_piece_offset, &*ctx.files)
-                    {
-                        log::error!(
-                            "Error writing piece {} to disk: {}",
-                            piece_index,
-                            e
-                        );
-                        // TODO(https://github.com/mandreyel/cratetorrent/issues/23):
-                        // also place back piece write buffer in torrent and
-                        // retry later
-                        ctx.stats
-                            .write_failure_count
-                            .fetch_add(1, Ordering::Relaxed);
-                        // alert torrent of block write failure
-                        ctx.tx
-                            .send(torrent::Command::PieceCompletion(Err(e)))
-                            .map_err(|e| {
-                                log::error!(
-                                    "Error sending piece result: {}",
-                                    e
-                                );
-                                e
-                            })
-                            .ok();
-                        return;
-                    }
+        Ok(())
+    }
 
-                    log::debug!("Wrote piece {} to disk", piece_index);
-                    ctx.stats
-                        .write_count
-                        .fetch_add(piece.len as u64, Ordering::Relaxed);
-                } else {
-                    log::warn!("Piece {} is not valid", info.piece_index);
-                }
+    /// Keeps the piece whose write failed in memory and schedules a retry of
+    /// the write, with an exponential backoff.
+    ///
+    /// If the write has already been attempted [`MAX_WRITE_ATTEMPT_COUNT`]
+    /// times, the torrent is notified of the error, which pauses it until the
+    /// user resumes it, at which point the write is retried again.
+    pub fn handle_write_failure(
+        &mut self,
+        piece_index: PieceIndex,
+        write: FailedWrite,
+        error: io::Error,
+    ) {
+        let attempt_count = write.attempt_count;
+        self.failed_writes.insert(piece_index, write);
 
-                // alert torrent of piece completion and hash result
-                ctx.tx
-                    .send(torrent::Command::PieceCompletion(Ok(
-                        PieceCompletion {
-                            index: piece_index,
-                            is_valid: is_piece_valid,
-                        },
-                    )))
-                    .map_err(|e| {
-                        log::error!("Error sending piece result: {}", e);
-                        e
-                    })
+        let ctx = &self.thread_ctx;
+        if attempt_count < MAX_WRITE_ATTEMPT_COUNT {
+            let delay = WRITE_RETRY_DELAY * 2u32.pow(attempt_count as u32 - 1);
+            log::warn!(
+                "Retrying write of piece {} in {} ms",
+                piece_index,
+                delay.as_millis()
+            );
+            let id = ctx.id;
+            let disk_tx = ctx.disk_tx.clone();
+            task::spawn(async move {
+                time::sleep(delay).await;
+                disk_tx
+                    .send(disk::Command::RetryWrite { id, piece_index })
                     .ok();
             });
+            return;
         }
 
-        Ok(())
+        log::error!(
+            "Writing piece {} failed {} times: {}",
+            piece_index,
+            attempt_count,
+            error
+        );
+        let (path, error) = FileError::unwrap(error);
+        // if the storage doesn't report the file, the piece's first file is
+        // named
+        let path = path.unwrap_or_else(|| {
+            let offset = self.info.torrent_piece_offset(piece_index);
+            let files = self.info.files_intersecting_bytes(offset..offset + 1);
+            self.info.download_dir.join(&self.info.files[files.start].path)
+        });
+        ctx.send_piece_completion(Err(WriteError::Io { path, error }));
+    }
+
+    /// Retries writing the piece whose write failed, if it's still waiting to
+    /// be retried.
+    pub fn retry_write(&mut self, piece_index: PieceIndex) {
+        // the write may have been retried already, when the torrent was
+        // resumed
+        if let Some(write) = self.failed_writes.remove(&piece_index) {
+            log::info!("Retrying write of piece {}", piece_index);
+            self.spawn_write(piece_index, write);
+        }
+    }
+
+    /// Retries writing all pieces whose writes failed right away, after the
+    /// torrent was resumed.
+    pub fn resume_writes(&mut self) {
+        log::info!("Retrying {} failed write(s)", self.failed_writes.len());
+        let writes: Vec<_> = self.failed_writes.drain().collect();
+        for (piece_index, mut write) in writes {
+            // the write gets as many attempts as it did originally
+            write.attempt_count = 0;
+            self.spawn_write(piece_index, write);
+        }
+    }
+
+    /// Writes the verified piece on an IO worker thread.
+    fn spawn_write(&self, piece_index: PieceIndex, write: FailedWrite) {
+        let torrent_piece_offset = self.info.torrent_piece_offset(piece_index);
+        Arc::clone(&self.thread_ctx).spawn_write(
+            piece_index,
+            torrent_piece_offset,
+            write,
+        );
     }
 
     /// Starts a new in-progress piece, creating metadata for it in self.
     ///
-    /// This involves getting the expected hash of the piece, its length, and
-    /// calculating the files that it intersects.
+    /// This involves getting the expected hash of the piece and its length.
     fn start_new_piece(&mut self, piece_index: PieceIndex) {
         log::trace!("Creating piece {} write buffer", piece_index);
 
@@ -338,14 +404,7 @@ impl Torrent {
             "piece index is invalid"
         );
 
-        // get the position of the piece in the concatenated hash string
-        let hash_pos = piece_index * 20;
-        // the above assert should take care of this, but just in case
-        debug_assert!(hash_pos + 20 <= self.piece_hashes.len());
-
-        let hash_slice = &self.piece_hashes[hash_pos..hash_pos + 20];
-        let mut expected_hash = [0; 20];
-        expected_hash.copy_from_slice(hash_slice);
+        let expected_hash = self.expected_hash(piece_index);
         log::debug!(
             "Piece {} expected hash {}",
             piece_index,
@@ -355,19 +414,24 @@ impl Torrent {
         let len = self.info.piece_len(piece_index);
         log::debug!("Piece {} is {} bytes long", piece_index, len);
 
-        let file_range = self.info.files_intersecting_piece(piece_index);
-        log::debug!("Piece {} intersects files: {:?}", piece_index, file_range);
+        self.write_buf
+            .insert(piece_index, Piece::new(expected_hash, len));
+    }
 
-        let piece = Piece {
-            expected_hash,
-            len,
-            blocks: BTreeMap::new(),
-            file_range,
-        };
-        self.write_buf.insert(piece_index, piece);
+    /// Returns the expected hash of the piece.
+    fn expected_hash(&self, piece_index: PieceIndex) -> Sha1Hash {
+        // get the position of the piece in the concatenated hash string
+        let hash_pos = piece_index * 20;
+        // the above assert should take care of this, but just in case
+        debug_assert!(hash_pos + 20 <= self.piece_hashes.len());
+
+        let hash_slice = &self.piece_hashes[hash_pos..hash_pos + 20];
+        let mut expected_hash = [0; 20];
+        expected_hash.copy_from_slice(hash_slice);
+        expected_hash
     }
 
-    /// Returns the specified block via the sender, either from the read cache
+    /// Returns the specified block via the sender, either from the disk cache
     /// or from the disk.
     ///
     /// If the block info refers to an invalid piece, an error is returned.
@@ -381,9 +445,14 @@ impl Torrent {
     /// to prepare for it. This is referred to as a "read cache line", much like
     /// how the CPU pulls in the next 64 bytes of the program into its L1 cache
     /// when hitting a cache miss.
-    /// For now, this is simplified in that we don't pull in blocks from the
-    /// next piece. Later, we will make the read cache line size configurable
-    /// and it will be applied across piece boundaries.
+    ///
+    /// If the previous piece is also in the cache, the pieces are likely being
+    /// requested sequentially, so the next [`READ_AHEAD_PIECE_COUNT`] pieces
+    /// are read in as well.
+    ///
+    /// If the storage's files are memory mapped and the torrent is seeding,
+    /// the cache is bypassed and the block is [read from the mapped
+    /// files](Self::read_mapped).
     pub fn read_block(
         &self,
         block_info: BlockInfo,
@@ -393,115 +462,980 @@ impl Torrent {
 
         let piece_index = block_info.piece_index;
         let block_index = block_info.index_in_piece();
+        let ctx = &self.thread_ctx;
 
-        // check if piece is in the read cache
-        if let Some(blocks) =
-            self.thread_ctx.read_cache.lock().unwrap().get(&piece_index)
-        {
-            log::debug!("Piece {} is in the read cache", piece_index);
+        if ctx.is_mapped && ctx.is_seed.load(Ordering::Relaxed) {
+            self.read_mapped(block_info, result_tx)?;
+            return Ok(());
+        }
+
+        // check if piece is in the cache
+        match ctx.cache.get_block(ctx.id, piece_index, block_index) {
+            Some(Some(block)) => {
+                log::debug!("Piece {} is in the disk cache", piece_index);
+                ctx.stats.cache_hit_count.fetch_add(1, Ordering::Relaxed);
+                // return block via sender
+                result_tx.send(peer::Command::Block(Block::new(
+                    block_info, block,
+                )))?;
+            }
             // the block's index in piece may be invalid
-            if block_index >= blocks.len() {
+            Some(None) => {
                 log::debug!(
                     "Piece {} block offset {} is invalid",
                     piece_index,
                     block_info.offset
                 );
-                self.thread_ctx.tx.send(torrent::Command::ReadError {
+                ctx.tx.send(torrent::Command::ReadError {
                     block_info,
                     error: ReadError::InvalidBlockOffset,
                 })?;
                 // the disk task itself mustn't be aborted due to invalid input
-                return Ok(());
             }
+            None => {
+                // otherwise read in the piece from disk
+                log::debug!(
+                    "Piece {} not in the disk cache, reading from disk",
+                    piece_index
+                );
+                ctx.stats.cache_miss_count.fetch_add(1, Ordering::Relaxed);
+                self.read_piece(block_info, result_tx);
+            }
+        }
 
-            // return block via sender
-            let block = Arc::clone(&blocks[block_index]);
-            result_tx
-                .send(peer::Command::Block(Block::new(block_info, block)))?;
+        Ok(())
+    }
+
+    /// Reads the block's piece from disk on an IO worker thread, along with
+    /// the pieces that are read ahead, and returns the block via the sender.
+    ///
+    /// The pieces are verified on the hasher threads before they're cached,
+    /// so that data corrupted on disk is not sent to peers.
+    fn read_piece(&self, block_info: BlockInfo, result_tx: peer::Sender) {
+        let ctx = Arc::clone(&self.thread_ctx);
+        let piece_index = block_info.piece_index;
+
+        let is_sequential =
+            piece_index > 0 && ctx.cache.contains(ctx.id, piece_index - 1);
+        let read_ahead_end = if is_sequential {
+            let end = piece_index + 1 + READ_AHEAD_PIECE_COUNT;
+            end.min(self.info.piece_count)
         } else {
-            // otherwise read in the piece from disk
+            piece_index + 1
+        };
+        // the pieces to read, starting with the block's, along with their
+        // offsets and lengths
+        let pieces: Vec<_> = (piece_index..read_ahead_end)
+            .filter(|&index| {
+                index == piece_index || !ctx.cache.contains(ctx.id, index)
+            })
+            .map(|index| {
+                let offset = self.info.torrent_piece_offset(index);
+                let len = self.info.piece_len(index);
+                (index, offset, len, self.expected_hash(index))
+            })
+            .collect();
+
+        // Checking if the piece has been downloaded yet is done
+        // implicitly as part of the read operation below: if we can't
+        // read all bytes, the data likely does not exist.
+
+        let mut result_tx = Some(result_tx);
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = ctx.ring.clone() {
+            task::spawn(async move {
+                for (index, offset, len, expected_hash) in pieces {
+                    let res = ctx.read_regions(&ring, offset, len).await;
+                    let read = PieceRead {
+                        index,
+                        len,
+                        expected_hash,
+                    };
+                    if !ctx.handle_read(read, res, block_info, &mut result_tx) {
+                        return;
+                    }
+                }
+            });
+            return;
+        }
+
+        // don't block the reactor with blocking disk IO
+        task::spawn_blocking(move || {
+            for (index, offset, len, expected_hash) in pieces {
+                let storage = ctx.storage.blocking_read();
+                let res =
+                    piece::read(offset, &**storage, len, ctx.cache.pool());
+                drop(storage);
+                let read = PieceRead {
+                    index,
+                    len,
+                    expected_hash,
+                };
+                if !ctx.handle_read(read, res, block_info, &mut result_tx) {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Returns the block out of the storage's memory mapped files via the
+    /// sender, on an IO worker thread, as faulting in the mapped pages may
+    /// block.
+    ///
+    /// The first time a piece is read, it's copied out of the storage and
+    /// verified on the hasher threads before the block is sent from the copy,
+    /// so that data corrupted on disk is not sent to peers. Only blocks of
+    /// verified pieces are sent from the mapped files: pieces wait in the
+    /// hasher's queue for a while, and if the files were changed by then,
+    /// reading the mapped pages could crash the process.
+    fn read_mapped(
+        &self,
+        block_info: BlockInfo,
+        result_tx: peer::Sender,
+    ) -> Result<()> {
+        let piece_index = block_info.piece_index;
+        let piece_len = self.info.piece_len(piece_index);
+        if block_info.offset as u64 + block_info.len as u64 > piece_len as u64
+        {
             log::debug!(
-                "Piece {} not in the read cache, reading from disk",
-                piece_index
+                "Piece {} block offset {} is invalid",
+                piece_index,
+                block_info.offset
             );
+            self.thread_ctx.tx.send(torrent::Command::ReadError {
+                block_info,
+                error: ReadError::InvalidBlockOffset,
+            })?;
+            return Ok(());
+        }
 
-            let file_range = self.info.files_intersecting_piece(piece_index);
+        let ctx = Arc::clone(&self.thread_ctx);
+        let piece_offset = self.info.torrent_piece_offset(piece_index);
+        let expected_hash = self.expected_hash(piece_index);
+        task::spawn_blocking(move || {
+            let is_verified =
+                ctx.verified_pieces.lock().unwrap().contains(&piece_index);
+            let storage = ctx.storage.blocking_read();
+            let res = if is_verified {
+                let offset = piece_offset + block_info.offset as u64;
+                piece::read_mapped(offset, &**storage, block_info.len)
+            } else {
+                let pool = ctx.cache.pool();
+                piece::read(piece_offset, &**storage, piece_len, pool)
+            };
+            drop(storage);
 
-            // Checking if the file pointed to by info has been downloaded yet
-            // is done implicitly as part of the read operation below: if we
-            // can't read any bytes, the file likely does not exist.
+            let bufs = match res {
+                Ok(bufs) => bufs,
+                Err(e) => {
+                    log::error!(
+                        "Error reading piece {} from disk: {}",
+                        piece_index,
+                        e
+                    );
+                    ctx.stats
+                        .read_failure_count
+                        .fetch_add(1, Ordering::Relaxed);
+                    // the files may have been changed, in which case the
+                    // pieces are verified again once they're mapped anew
+                    ctx.verified_pieces.lock().unwrap().clear();
+                    ctx.send_read_error(block_info, e);
+                    return;
+                }
+            };
+            ctx.stats
+                .read_count
+                .fetch_add(block_info.len as u64, Ordering::Relaxed);
 
-            // don't block the reactor with blocking disk IO
-            let torrent_piece_offset =
-                self.info.torrent_piece_offset(piece_index);
-            let piece_len = self.info.piece_len(piece_index);
-            let ctx = Arc::clone(&self.thread_ctx);
-            task::spawn_blocking(move || {
-                match piece::read(
-                    torrent_piece_offset,
-                    file_range,
-                    &ctx.files[..],
-                    piece_len,
-                ) {
-                    Ok(blocks) => {
-                        log::debug!("Read piece {}", piece_index);
-                        // pick requested block
-                        let block = Arc::clone(&blocks[block_index]);
-
-                        // Place piece in read cache. Another concurrent read
-                        // could already have read the piece just before this
-                        // thread, but replacing it shouldn't be an issue since
-                        // we're reading the same data.
-                        ctx.read_cache.lock().unwrap().put(piece_index, blocks);
-                        ctx.stats
-                            .read_count
-                            .fetch_add(piece_len as u64, Ordering::Relaxed);
-
-                        // send block to peer
-                        result_tx
-                            .send(peer::Command::Block(Block::new(
-                                block_info, block,
-                            )))
-                            .map_err(|e| {
-                                log::error!(
-                                    "Error sending block to peer: {}",
-                                    e
-                                );
-                                e
-                            })
-                            .ok();
-                    }
-                    Err(e) => {
+            if is_verified {
+                let block =
+                    piece::mapped_block(&bufs, 0, block_info.len as usize);
+                ctx.send_block(block_info, block, &result_tx);
+                return;
+            }
+
+            log::debug!("Verifying piece {} read for mapping", piece_index);
+            let hasher_ctx = Arc::clone(&ctx);
+            ctx.hasher.submit(
+                HashPriority::Seed,
+                None,
+                piece_len as u64,
+                move || {
+                    let ctx = hasher_ctx;
+                    let slices = bufs.iter().map(|b| b.as_ref());
+                    if !piece::verify(slices, &expected_hash) {
                         log::error!(
-                            "Error reading piece {} from disk: {}",
-                            piece_index,
-                            e
+                            "Piece {} read from disk is corrupt",
+                            piece_index
                         );
-
                         ctx.stats
                             .read_failure_count
                             .fetch_add(1, Ordering::Relaxed);
-                        ctx.tx
-                            .send(torrent::Command::ReadError {
-                                block_info,
-                                error: e,
-                            })
-                            .map_err(|e| {
-                                log::error!("Error sending read error: {}", e);
-                                e
-                            })
-                            .ok();
+                        ctx.send_read_error(
+                            block_info,
+                            ReadError::HashMismatch,
+                        );
+                        return;
                     }
-                }
+                    ctx.verified_pieces.lock().unwrap().insert(piece_index);
+                    // the block is sent from the verified copy, as the
+                    // mapped files may have changed since
+                    let block = piece::mapped_block(
+                        &bufs,
+                        block_info.offset as usize,
+                        block_info.len as usize,
+                    );
+                    ctx.send_block(block_info, block, &result_tx);
+                },
+            );
+        });
+        Ok(())
+    }
+
+    /// Flushes the files of the pieces written since the last flush to disk,
+    /// on an IO worker thread or through io_uring, and reports the flushed
+    /// pieces to the torrent, which considers them durable from then on.
+    ///
+    /// Pieces whose writes complete while the files are being flushed are
+    /// left for the next flush.
+    pub fn flush(&self) {
+        let ctx = Arc::clone(&self.thread_ctx);
+        let pieces = std::mem::take(&mut *ctx.unflushed_pieces.lock().unwrap());
+        // the torrent is answered even if there is nothing to flush
+        if pieces.is_empty() {
+            ctx.send_flush_result(pieces, Ok(()));
+            return;
+        }
+
+        // each of the pieces' files is flushed once
+        let files: BTreeSet<_> = pieces
+            .iter()
+            .flat_map(|&piece| self.info.piece_file_slices(piece))
+            .map(|(index, _)| index)
+            .collect();
+        let ranges: Vec<_> = files
+            .into_iter()
+            .map(|index| {
+                let file = &self.info.files[index];
+                (file.torrent_offset, file.len)
+            })
+            .collect();
+        log::debug!(
+            "Flushing {} piece(s) in {} file(s)",
+            pieces.len(),
+            ranges.len()
+        );
+        // if the storage doesn't report the file it failed to flush, the
+        // download directory is named
+        let dir = self.info.download_dir.clone();
+
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = ctx.ring.clone() {
+            task::spawn(async move {
+                let res = ctx.sync_regions(&ring, &ranges).await;
+                ctx.finish_flush(pieces, res, dir);
+            });
+            return;
+        }
+
+        task::spawn_blocking(move || {
+            let storage = ctx.storage.blocking_read();
+            let res = ranges
+                .iter()
+                .try_for_each(|&(offset, len)| storage.sync(offset, len));
+            drop(storage);
+            ctx.finish_flush(pieces, res, dir);
+        });
+    }
+
+    /// Reports the state of the storage's files to the torrent, which are
+    /// checked on an IO worker thread.
+    ///
+    /// The first report is of the files as they were before the storage was
+    /// opened.
+    pub fn check_files(&mut self) {
+        match self.initial_file_states.take() {
+            Some(states) => self.thread_ctx.send_file_states(states),
+            None => Arc::clone(&self.thread_ctx).check_files(),
+        }
+    }
+
+    /// Reads the pieces from disk and verifies them again on the hasher
+    /// threads, reporting to the torrent which of them are still intact.
+    ///
+    /// This is done when the pieces' data may have been changed by another
+    /// program, so any copies of the pieces in memory are discarded first,
+    /// along with what the storage keeps of their files.
+    pub fn recheck(&self, pieces: Vec<PieceIndex>) {
+        log::info!("Rechecking {} piece(s)", pieces.len());
+        let ctx = Arc::clone(&self.thread_ctx);
+        let mut verified_pieces = ctx.verified_pieces.lock().unwrap();
+        for &index in pieces.iter() {
+            ctx.cache.remove(ctx.id, index);
+            verified_pieces.remove(&index);
+        }
+        drop(verified_pieces);
+
+        let files: Vec<_> = pieces
+            .iter()
+            .flat_map(|&piece| self.info.piece_file_slices(piece))
+            .map(|(index, _)| index)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let pieces: Vec<_> = pieces
+            .into_iter()
+            .map(|index| {
+                let offset = self.info.torrent_piece_offset(index);
+                let read = PieceRead {
+                    index,
+                    len: self.info.piece_len(index),
+                    expected_hash: self.expected_hash(index),
+                };
+                (offset, read)
+            })
+            .collect();
+        let runtime = ctx.runtime.clone();
+        runtime.spawn_blocking(move || {
+            ctx.storage.blocking_read().invalidate_files(&files);
+            ctx.recheck_next(pieces.into_iter());
+        });
+    }
+
+    /// Updates the paths of the torrent's files after they were moved or
+    /// renamed. The layout of the files doesn't change.
+    pub fn set_info(&mut self, info: StorageInfo) {
+        debug_assert_eq!(info.files.len(), self.info.files.len());
+        self.info = info;
+    }
+
+    /// Sets whether the torrent has all of its pieces, which it tells the disk
+    /// task when it completes, or when it loses some of its pieces.
+    pub fn set_seed(&self, is_seed: bool) {
+        self.thread_ctx.is_seed.store(is_seed, Ordering::Relaxed);
+    }
+
+    /// Moves the torrent's storage to the directory on an IO worker thread,
+    /// reporting the progress and the result to the engine.
+    ///
+    /// The reads and writes issued in the meantime wait for the move to
+    /// finish, after which they use the new location.
+    pub fn move_storage(
+        &self,
+        id: TorrentId,
+        download_dir: PathBuf,
+        dir: PathBuf,
+        conflict: MoveConflict,
+        engine_tx: engine::Sender,
+    ) {
+        let total = self.info.download_len;
+        let ctx = Arc::clone(&self.thread_ctx);
+        task::spawn_blocking(move || {
+            let mut storage = ctx.storage.blocking_write();
+            let result = storage.move_to(&dir, conflict, &mut |moved| {
+                engine_tx
+                    .send(engine::Command::StorageMoveProgress {
+                        id,
+                        moved,
+                        total,
+                    })
+                    .ok();
+            });
+            drop(storage);
+            // files kept at the destination may have replaced the cached data
+            ctx.cache.remove_torrent(id);
+            ctx.verified_pieces.lock().unwrap().clear();
+            engine_tx
+                .send(engine::Command::StorageMoved {
+                    id,
+                    download_dir,
+                    result,
+                })
+                .map_err(|e| {
+                    log::error!("Error sending storage move result: {}", e);
+                    e
+                })
+                .ok();
+        });
+    }
+
+    /// Renames one of the torrent's files on a blocking thread, reporting the
+    /// result to the engine.
+    ///
+    /// As with moves, the reads and writes issued in the meantime wait for
+    /// the rename to finish.
+    pub fn rename_file(
+        &self,
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+        engine_tx: engine::Sender,
+    ) {
+        let ctx = Arc::clone(&self.thread_ctx);
+        task::spawn_blocking(move || {
+            let result = ctx.storage.blocking_write().rename_file(index, &path);
+            engine_tx
+                .send(engine::Command::FileRenamed {
+                    id,
+                    index,
+                    path,
+                    result,
+                })
+                .map_err(|e| {
+                    log::error!("Error sending file rename result: {}", e);
+                    e
+                })
+                .ok();
+        });
+    }
+}
+
+impl ThreadContext {
+    /// Checks the hash of the piece whose blocks have all been hashed, and
+    /// writes it to disk if it's valid. Run on a hasher thread.
+    fn verify_piece(
+        self: Arc<Self>,
+        piece_index: PieceIndex,
+        torrent_piece_offset: u64,
+        piece: Piece,
+    ) {
+        if !piece.matches_hash() {
+            log::warn!("Piece {} is not valid", piece_index);
+            // the piece is no longer needed in memory
+            self.write_buf_usage.remove(piece.len as u64);
+            self.send_piece_completion(Ok(PieceCompletion {
+                index: piece_index,
+                is_valid: false,
+            }));
+            return;
+        }
+
+        log::debug!("Piece {} is valid, writing to disk", piece_index);
+        let write = FailedWrite {
+            piece,
+            attempt_count: 0,
+        };
+        self.spawn_write(piece_index, torrent_piece_offset, write);
+    }
+
+    /// Writes the verified piece on an IO worker thread, so as not to block
+    /// the reactor or the hasher threads with the sync file writing, or
+    /// through io_uring if it's available.
+    fn spawn_write(
+        self: Arc<Self>,
+        piece_index: PieceIndex,
+        torrent_piece_offset: u64,
+        write: FailedWrite,
+    ) {
+        let runtime = self.runtime.clone();
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = self.ring.clone() {
+            runtime.spawn(async move {
+                let res = self
+                    .write_regions(&ring, torrent_piece_offset, &write.piece)
+                    .await;
+                self.finish_write(piece_index, write, res);
             });
+            return;
         }
+        runtime.spawn_blocking(move || {
+            self.write_piece(piece_index, torrent_piece_offset, write);
+        });
+    }
 
+    /// Writes the piece to the storage's files through io_uring.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    async fn write_regions(
+        &self,
+        ring: &Ring,
+        torrent_piece_offset: u64,
+        piece: &Piece,
+    ) -> io::Result<()> {
+        // the storage is not moved while the piece is being written
+        let storage = self.storage.read().await;
+        let len = piece.len as u64;
+        let regions =
+            file_regions(&**storage, torrent_piece_offset, len, true).await?;
+        let blocks = piece.blocks.values().cloned().collect();
+        piece::write_regions(ring, regions, blocks).await
+    }
+
+    /// Flushes the storage's files that hold the ranges of the torrent's bytes
+    /// through io_uring.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    async fn sync_regions(
+        &self,
+        ring: &Ring,
+        ranges: &[(u64, u64)],
+    ) -> io::Result<()> {
+        let storage = self.storage.read().await;
+        for &(offset, len) in ranges {
+            for region in file_regions(&**storage, offset, len, false).await? {
+                if let Some(file) = &region.file {
+                    ring.fsync(file)
+                        .await
+                        .map_err(|e| FileError::wrap(region.path, e))?;
+                }
+            }
+        }
         Ok(())
     }
-    
-    
+
+    /// Reads the piece from the storage's files through io_uring.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    async fn read_regions(
+        &self,
+        ring: &Ring,
+        torrent_piece_offset: u64,
+        len: u32,
+    ) -> Result<Vec<CachedBlock>, ReadError> {
+        let storage = self.storage.read().await;
+        let regions =
+            file_regions(&**storage, torrent_piece_offset, len as u64, false)
+                .await
+                .map_err(ReadError::Io)?;
+        piece::read_regions(ring, regions, len, self.cache.pool()).await
+    }
+
+    /// Verifies the piece that was read on the hasher threads, or reports the
+    /// error reading it, and returns whether the pieces after it should be
+    /// read.
+    ///
+    /// The block is sent to the peer along with the piece it's in, which
+    /// takes the sender.
+    fn handle_read(
+        self: &Arc<Self>,
+        read: PieceRead,
+        res: Result<Vec<CachedBlock>, ReadError>,
+        block_info: BlockInfo,
+        result_tx: &mut Option<peer::Sender>,
+    ) -> bool {
+        let PieceRead {
+            index,
+            len,
+            expected_hash,
+        } = read;
+        let piece_index = block_info.piece_index;
+        match res {
+            Ok(blocks) => {
+                log::debug!("Read piece {}", index);
+                self.stats.read_count.fetch_add(len as u64, Ordering::Relaxed);
+                let result_tx = if index == piece_index {
+                    result_tx.take()
+                } else {
+                    None
+                };
+                let ctx = Arc::clone(self);
+                self.hasher.submit(
+                    HashPriority::Seed,
+                    None,
+                    len as u64,
+                    move || {
+                        ctx.serve_piece(
+                            index,
+                            blocks,
+                            &expected_hash,
+                            block_info,
+                            result_tx,
+                        )
+                    },
+                );
+                true
+            }
+            // the pieces read ahead may not have been downloaded
+            Err(e) if index != piece_index => {
+                log::debug!("Cannot read ahead piece {}: {}", index, e);
+                false
+            }
+            Err(e) => {
+                log::error!(
+                    "Error reading piece {} from disk: {}",
+                    piece_index,
+                    e
+                );
+                self.stats.read_failure_count.fetch_add(1, Ordering::Relaxed);
+                self.send_read_error(block_info, e);
+                false
+            }
+        }
+    }
+
+    /// Reads the next of the pieces being rechecked and verifies it on the
+    /// hasher threads, after which the piece after it is read, so that only
+    /// one of them is in memory at a time.
+    fn recheck_next(
+        self: Arc<Self>,
+        mut pieces: vec::IntoIter<(u64, PieceRead)>,
+    ) {
+        let Some((offset, read)) = pieces.next() else {
+            return;
+        };
+        let runtime = self.runtime.clone();
+        #[cfg(all(feature = "io_uring", target_os = "linux"))]
+        if let Some(ring) = self.ring.clone() {
+            runtime.spawn(async move {
+                let res = self.read_regions(&ring, offset, read.len).await;
+                self.verify_recheck(read, res, pieces);
+            });
+            return;
+        }
+        runtime.spawn_blocking(move || {
+            let storage = self.storage.blocking_read();
+            let res =
+                piece::read(offset, &**storage, read.len, self.cache.pool());
+            drop(storage);
+            self.verify_recheck(read, res, pieces);
+        });
+    }
+
+    /// Verifies the piece read back from disk on the hasher threads, reports
+    /// whether it's intact to the torrent, and then rechecks the rest of the
+    /// pieces.
+    ///
+    /// Pieces that can't be read are not intact.
+    fn verify_recheck(
+        self: Arc<Self>,
+        read: PieceRead,
+        res: Result<Vec<CachedBlock>, ReadError>,
+        pieces: vec::IntoIter<(u64, PieceRead)>,
+    ) {
+        let PieceRead {
+            index,
+            len,
+            expected_hash,
+        } = read;
+        let blocks = match res {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                log::warn!("Cannot read piece {} to recheck it: {}", index, e);
+                self.send_recheck_result(index, false);
+                self.recheck_next(pieces);
+                return;
+            }
+        };
+        self.stats.read_count.fetch_add(len as u64, Ordering::Relaxed);
+
+        let hasher = Arc::clone(&self.hasher);
+        hasher.submit(HashPriority::Recheck, None, len as u64, move || {
+            let bufs = blocks.iter().map(|b| b.as_ref());
+            let is_valid = piece::verify(bufs, &expected_hash);
+            log::debug!("Rechecked piece {}, valid: {}", index, is_valid);
+            if is_valid && self.is_mapped {
+                self.verified_pieces.lock().unwrap().insert(index);
+            }
+            self.send_recheck_result(index, is_valid);
+            self.recheck_next(pieces);
+        });
+    }
+
+    /// Gets the state of the storage's files on an IO worker thread, and
+    /// reports it to the torrent.
+    fn check_files(self: Arc<Self>) {
+        let runtime = self.runtime.clone();
+        runtime.spawn_blocking(move || {
+            let storage = self.storage.blocking_read();
+            let res = storage.file_states();
+            drop(storage);
+            self.is_checking_files.store(false, Ordering::Relaxed);
+            match res {
+                Ok(Some(states)) => self.send_file_states(states),
+                Ok(None) => (),
+                Err(e) => log::warn!("Cannot check the torrent's files: {}", e),
+            }
+        });
+    }
+
+    /// Checks the hash of the piece read from disk, and if it's valid, caches
+    /// it and sends the requested block to the peer, if any. Run on a hasher
+    /// thread.
+    fn serve_piece(
+        self: &Arc<Self>,
+        piece_index: PieceIndex,
+        blocks: Vec<CachedBlock>,
+        expected_hash: &Sha1Hash,
+        block_info: BlockInfo,
+        result_tx: Option<peer::Sender>,
+    ) {
+        let bufs = blocks.iter().map(|b| b.as_ref());
+        if !piece::verify(bufs, expected_hash) {
+            log::error!("Piece {} read from disk is corrupt", piece_index);
+            self.stats.read_failure_count.fetch_add(1, Ordering::Relaxed);
+            if result_tx.is_some() {
+                self.send_read_error(block_info, ReadError::HashMismatch);
+            }
+            return;
+        }
+
+        let block = blocks.get(block_info.index_in_piece()).cloned();
+        // Place piece in the cache. Another concurrent read could already have
+        // read the piece just before this thread, but replacing it shouldn't
+        // be an issue since we're reading the same data.
+        self.cache.insert(self.id, piece_index, blocks);
+
+        // send block to peer, if this is the requested piece
+        let Some(result_tx) = result_tx else {
+            return;
+        };
+        let Some(block) = block else {
+            log::debug!(
+                "Piece {} block offset {} is invalid",
+                piece_index,
+                block_info.offset
+            );
+            self.send_read_error(block_info, ReadError::InvalidBlockOffset);
+            return;
+        };
+        result_tx
+            .send(peer::Command::Block(Block::new(block_info, block)))
+            .map_err(|e| {
+                log::error!("Error sending block to peer: {}", e);
+                e
+            })
+            .ok();
+    }
+
+    /// Sends the block of a [mapped read](Torrent::read_mapped) to the peer.
+    fn send_block(
+        &self,
+        block_info: BlockInfo,
+        block: Bytes,
+        result_tx: &peer::Sender,
+    ) {
+        result_tx
+            .send(peer::Command::Block(Block::new(block_info, block)))
+            .map_err(|e| {
+                log::error!("Error sending block to peer: {}", e);
+                e
+            })
+            .ok();
+    }
+
+    /// Alerts the torrent that the block could not be read.
+    ///
+    /// Unless the block was invalid, the likely cause is that the torrent's
+    /// files were changed or removed, so they're checked as well.
+    fn send_read_error(
+        self: &Arc<Self>,
+        block_info: BlockInfo,
+        error: ReadError,
+    ) {
+        let is_invalid = matches!(error, ReadError::InvalidBlockOffset);
+        self.tx
+            .send(torrent::Command::ReadError { block_info, error })
+            .map_err(|e| {
+                log::error!("Error sending read error: {}", e);
+                e
+            })
+            .ok();
+        if !is_invalid && !self.is_checking_files.swap(true, Ordering::Relaxed)
+        {
+            Arc::clone(self).check_files();
+        }
+    }
+
+    /// Sends the state of the storage's files to the torrent.
+    fn send_file_states(&self, states: Vec<FileState>) {
+        self.tx
+            .send(torrent::Command::FileStates(states))
+            .map_err(|e| {
+                log::error!("Error sending file states: {}", e);
+                e
+            })
+            .ok();
+    }
+
+    /// Tells the torrent whether the rechecked piece is intact.
+    fn send_recheck_result(&self, index: PieceIndex, is_valid: bool) {
+        self.tx
+            .send(torrent::Command::Rechecked(PieceCompletion {
+                index,
+                is_valid,
+            }))
+            .map_err(|e| {
+                log::error!("Error sending recheck result: {}", e);
+                e
+            })
+            .ok();
+    }
+
+    /// Writes the verified piece to disk, and then
+    /// [finishes the write](Self::finish_write).
+    ///
+    /// # Important
+    ///
+    /// This performs sync IO and must be called on an IO worker thread.
+    fn write_piece(
+        &self,
+        piece_index: PieceIndex,
+        torrent_piece_offset: u64,
+        write: FailedWrite,
+    ) {
+        let storage = self.storage.blocking_read();
+        let res = write.piece.write(torrent_piece_offset, &**storage);
+        drop(storage);
+        self.finish_write(piece_index, write, res);
+    }
+
+    /// Moves the piece from the write buffer to the cache and notifies the
+    /// torrent if it was written, or passes it back to the disk task to be
+    /// retried if the write failed.
+    fn finish_write(
+        &self,
+        piece_index: PieceIndex,
+        write: FailedWrite,
+        res: io::Result<()>,
+    ) {
+        if let Err(error) = res {
+            log::warn!(
+                "Error writing piece {} to disk: {}",
+                piece_index,
+                error
+            );
+            self.stats.write_failure_count.fetch_add(1, Ordering::Relaxed);
+            self.disk_tx
+                .send(disk::Command::WriteFailed {
+                    id: self.id,
+                    piece_index,
+                    write: FailedWrite {
+                        piece: write.piece,
+                        attempt_count: write.attempt_count + 1,
+                    },
+                    error,
+                })
+                .map_err(|e| {
+                    log::error!("Error sending write failure: {}", e);
+                    e
+                })
+                .ok();
+            return;
+        }
+
+        let piece = write.piece;
+        log::debug!("Wrote piece {} to disk", piece_index);
+        // the torrent may ask for the piece to be flushed once it learns of
+        // it, so it's registered first
+        self.unflushed_pieces.lock().unwrap().push(piece_index);
+        self.stats
+            .write_count
+            .fetch_add(piece.len as u64, Ordering::Relaxed);
+        // the piece is no longer in the write buffer, but it's kept in the
+        // cache, as peers are likely to request the pieces we just downloaded
+        self.write_buf_usage.remove(piece.len as u64);
+        if self.is_mapped {
+            // the mapped files take the place of the cache, and the data in
+            // them was just verified
+            self.verified_pieces.lock().unwrap().insert(piece_index);
+        } else {
+            let blocks = piece.blocks.into_values().collect();
+            self.cache.insert(self.id, piece_index, blocks);
+        }
+
+        self.send_piece_completion(Ok(PieceCompletion {
+            index: piece_index,
+            is_valid: true,
+        }));
+    }
+
+    /// Reports the pieces that were flushed to the torrent, or the error
+    /// flushing them.
+    ///
+    /// Pieces that failed to flush are not retried, as the failure may have
+    /// discarded their data, so they're never reported as durable.
+    fn finish_flush(
+        &self,
+        pieces: Vec<PieceIndex>,
+        res: io::Result<()>,
+        dir: PathBuf,
+    ) {
+        let result = res.map_err(|e| {
+            log::error!("Error flushing torrent files: {}", e);
+            let (path, error) = FileError::unwrap(e);
+            WriteError::Io {
+                path: path.unwrap_or(dir),
+                error,
+            }
+        });
+        self.send_flush_result(pieces, result);
+    }
+
+    /// Sends the result of the flush to the torrent.
+    fn send_flush_result(
+        &self,
+        pieces: Vec<PieceIndex>,
+        result: Result<(), WriteError>,
+    ) {
+        self.tx
+            .send(torrent::Command::Flushed { pieces, result })
+            .map_err(|e| {
+                log::error!("Error sending flush result: {}", e);
+                e
+            })
+            .ok();
+    }
+
+    /// Alerts the torrent of the piece's completion and hash result, or of
+    /// the error writing it.
+    fn send_piece_completion(
+        &self,
+        result: Result<PieceCompletion, WriteError>,
+    ) {
+        self.tx
+            .send(torrent::Command::PieceCompletion(result))
+            .map_err(|e| {
+                log::error!("Error sending piece result: {}", e);
+                e
+            })
+            .ok();
+    }
+}
+
+/// A piece being read from disk.
+struct PieceRead {
+    index: PieceIndex,
+    len: u32,
+    expected_hash: Sha1Hash,
 }
 
-// TODO(https://github.com/mandreyel/cratetorrent/issues/22):
-// make this configurable
-const READ_CACHE_UPPER_BOUND: usize = 1000;
\ No newline at end of file
+/// Returns the storage's file regions that hold the range of the torrent's
+/// bytes for reading, or writing if `is_write` is set, waiting for other
+/// accesses to the range to finish.
+///
+/// The disk task only uses io_uring for storages whose files it can access,
+/// so the storage is expected to have them.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+async fn file_regions(
+    storage: &dyn Storage,
+    offset: u64,
+    len: u64,
+    is_write: bool,
+) -> io::Result<Vec<FileRegion<'_>>> {
+    loop {
+        match storage.file_regions(offset, len, is_write) {
+            Ok(Some(regions)) => return Ok(regions),
+            Ok(None) => return Err(io::Error::other("storage has no files")),
+            // pieces are rarely accessed concurrently, and only briefly
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                time::sleep(RANGE_LOCK_RETRY_DELAY).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The delay before trying again to access a range of a file through
+/// io_uring, while another access holds it.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+const RANGE_LOCK_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// The number of pieces read in after the requested one when pieces are being
+/// requested sequentially.
+const READ_AHEAD_PIECE_COUNT: usize = 2;
+
+/// The number of times writing a piece is attempted before the torrent is
+/// paused.
+pub(crate) const MAX_WRITE_ATTEMPT_COUNT: usize = 4;
+
+/// The delay before the first retry of a failed piece write, which is doubled
+/// with each further retry.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(250);
\ No newline at end of file
@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+};
+
+use bytes::BytesMut;
+use io_uring::{opcode, squeue, types, IoUring};
+use nix::libc;
+use tokio::{
+    io::{unix::AsyncFd, Interest},
+    sync::oneshot,
+};
+
+use crate::{
+    disk::io::file::TorrentFile, storage_info::FileSlice, CachedBlock,
+};
+
+/// The number of operations that may be submitted at once.
+const RING_LEN: u32 = 256;
+
+/// The maximum number of buffers of a single vectored operation.
+const MAX_IOVEC_COUNT: usize = 1024;
+
+/// An io_uring instance through which the disk task reads and writes the
+/// torrents' files.
+///
+/// Operations are submitted from the disk task and complete asynchronously,
+/// without blocking a thread for each of them as the `pwritev`/`preadv`
+/// based IO of [`TorrentFile`] does. The completions are reaped by a task
+/// that waits for the ring's file descriptor to become readable.
+///
+/// The buffers, the file, and the IO vectors of an operation are owned by the
+/// ring until the kernel is done with them, so dropping the future of an
+/// operation doesn't free memory that the kernel still accesses.
+pub(crate) struct Ring {
+    /// The ring's file descriptor, which is readable when there are
+    /// completions to reap.
+    ///
+    /// This is declared before the ring so that it's deregistered before the
+    /// ring closes the file descriptor.
+    fd: AsyncFd<RawFd>,
+    ring: Mutex<IoUring>,
+    ops: Mutex<Ops>,
+    /// Makes the next submission fail after the entry is pushed, to test that
+    /// the operation still completes.
+    #[cfg(test)]
+    fail_next_submit: std::sync::atomic::AtomicBool,
+}
+
+#[derive(Default)]
+struct Ops {
+    /// The operations in flight, by their ids.
+    pending: HashMap<u64, Op>,
+    next_id: u64,
+}
+
+struct Op {
+    tx: oneshot::Sender<(i32, Bufs)>,
+    bufs: Bufs,
+    /// The IO vectors pointing into the buffers, which the kernel reads when
+    /// the operation is started.
+    _iovecs: IoVecs,
+    /// Keeps the file open until the operation completes.
+    _file: Arc<TorrentFile>,
+}
+
+/// The buffers of an operation, given back when it completes.
+enum Bufs {
+    Write(Vec<CachedBlock>),
+    Read(Vec<BytesMut>),
+    None,
+}
+
+/// The IO vectors of an operation.
+struct IoVecs(Vec<libc::iovec>);
+
+// The IO vectors only point into the buffers owned by the same operation.
+unsafe impl Send for IoVecs {}
+
+impl Ring {
+    /// Sets up the ring and spawns the task that reaps its completions on the
+    /// current tokio runtime.
+    pub fn new() -> io::Result<Arc<Self>> {
+        let ring = IoUring::new(RING_LEN)?;
+        // SAFETY: the file descriptor is deregistered before it's closed, see
+        // the `fd` field
+        let fd = unsafe {
+            AsyncFd::register_with_interest(
+                ring.as_raw_fd(),
+                Interest::READABLE,
+            )
+        }?;
+        let ring = Arc::new(Self {
+            ring: Mutex::new(ring),
+            fd,
+            ops: Mutex::default(),
+            #[cfg(test)]
+            fail_next_submit: Default::default(),
+        });
+        tokio::spawn(Arc::clone(&ring).reap());
+        Ok(ring)
+    }
+
+    /// Writes `file_slice.len` bytes of the blocks to the file, after skipping
+    /// the first `skip` bytes of the blocks.
+    pub async fn write(
+        &self,
+        file: &Arc<TorrentFile>,
+        file_slice: FileSlice,
+        mut blocks: Vec<CachedBlock>,
+        skip: usize,
+    ) -> io::Result<()> {
+        let mut total_written = 0;
+        while total_written < file_slice.len {
+            let len = (file_slice.len - total_written) as usize;
+            let iovecs = iovecs(
+                blocks.iter().map(|b| (b.as_ptr() as *mut u8, b.len())),
+                skip + total_written as usize,
+                len,
+            );
+            let entry = opcode::Writev::new(
+                types::Fd(file.handle.as_raw_fd()),
+                iovecs.0.as_ptr(),
+                iovecs.0.len() as u32,
+            )
+            .offset(file_slice.offset + total_written)
+            .build();
+            let (n, bufs) = self
+                .submit(entry, file, Bufs::Write(blocks), iovecs)
+                .await?;
+            let Bufs::Write(bufs) = bufs else {
+                unreachable!("write buffers not returned");
+            };
+            blocks = bufs;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            total_written += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Fills `file_slice.len` bytes of the buffers with the file's data,
+    /// after skipping the first `skip` bytes of the buffers, and returns the
+    /// buffers.
+    ///
+    /// If the file is shorter than expected, i.e. the data has not been
+    /// written yet, an error of kind [`io::ErrorKind::UnexpectedEof`] is
+    /// returned.
+    pub async fn read(
+        &self,
+        file: &Arc<TorrentFile>,
+        file_slice: FileSlice,
+        mut bufs: Vec<BytesMut>,
+        skip: usize,
+    ) -> io::Result<Vec<BytesMut>> {
+        let mut total_read = 0;
+        while total_read < file_slice.len {
+            let len = (file_slice.len - total_read) as usize;
+            let iovecs = iovecs(
+                bufs.iter_mut().map(|b| (b.as_mut_ptr(), b.len())),
+                skip + total_read as usize,
+                len,
+            );
+            let entry = opcode::Readv::new(
+                types::Fd(file.handle.as_raw_fd()),
+                iovecs.0.as_ptr(),
+                iovecs.0.len() as u32,
+            )
+            .offset(file_slice.offset + total_read)
+            .build();
+            let (n, read_bufs) =
+                self.submit(entry, file, Bufs::Read(bufs), iovecs).await?;
+            let Bufs::Read(read_bufs) = read_bufs else {
+                unreachable!("read buffers not returned");
+            };
+            bufs = read_bufs;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            total_read += n as u64;
+        }
+        Ok(bufs)
+    }
+
+    /// Flushes the file's data to the disk, like `fdatasync`.
+    pub async fn fsync(&self, file: &Arc<TorrentFile>) -> io::Result<()> {
+        let entry = opcode::Fsync::new(types::Fd(file.handle.as_raw_fd()))
+            .flags(types::FsyncFlags::DATASYNC)
+            .build();
+        self.submit(entry, file, Bufs::None, IoVecs(Vec::new()))
+            .await?;
+        Ok(())
+    }
+
+    /// Submits the operation and returns its result along with its buffers
+    /// once it completes.
+    async fn submit(
+        &self,
+        entry: squeue::Entry,
+        file: &Arc<TorrentFile>,
+        bufs: Bufs,
+        iovecs: IoVecs,
+    ) -> io::Result<(usize, Bufs)> {
+        let (tx, rx) = oneshot::channel();
+        let id = {
+            let mut ops = self.ops.lock().unwrap();
+            let id = ops.next_id;
+            ops.next_id += 1;
+            ops.pending.insert(
+                id,
+                Op {
+                    tx,
+                    bufs,
+                    _iovecs: iovecs,
+                    _file: Arc::clone(file),
+                },
+            );
+            id
+        };
+
+        let entry = entry.user_data(id);
+        {
+            let mut ring = self.ring.lock().unwrap();
+            // if the queue is full, its entries are submitted to make room
+            //
+            // SAFETY: the operation's buffers are kept alive in `ops` until
+            // it completes
+            while unsafe { ring.submission().push(&entry) }.is_err() {
+                if let Err(e) = self.submit_queued(&mut ring) {
+                    // the entry was never queued, so the kernel can't access
+                    // the operation's buffers
+                    drop(ring);
+                    self.ops.lock().unwrap().pending.remove(&id);
+                    return Err(e);
+                }
+            }
+            // Once queued, the entry is submitted by any later submission,
+            // so the operation must be kept until it completes even if this
+            // submission fails (e.g. while the completion queue overflows).
+            // The queue is submitted again once completions are reaped.
+            if let Err(e) = self.submit_queued(&mut ring) {
+                log::warn!("Error submitting io_uring operations: {}", e);
+            }
+        }
+
+        let (res, bufs) = rx.await.map_err(|_| {
+            io::Error::other("io_uring shut down before operation completed")
+        })?;
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        Ok((res as usize, bufs))
+    }
+
+    /// Makes the next submission fail after the operation's entry is queued.
+    #[cfg(test)]
+    pub fn fail_next_submit(&self) {
+        self.fail_next_submit
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Submits the entries in the submission queue to the kernel.
+    fn submit_queued(&self, ring: &mut IoUring) -> io::Result<usize> {
+        #[cfg(test)]
+        if self
+            .fail_next_submit
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        ring.submit()
+    }
+
+    /// Completes the operations whose results are in the completion queue,
+    /// each time the ring becomes readable.
+    async fn reap(self: Arc<Self>) {
+        loop {
+            let mut guard = match self.fd.readable().await {
+                Ok(guard) => guard,
+                Err(e) => {
+                    log::error!("Error polling io_uring: {}", e);
+                    return;
+                }
+            };
+            // readiness is cleared before draining the queue, so that
+            // completions arriving while it's drained wake the task again
+            guard.clear_ready();
+            self.complete();
+        }
+    }
+
+    /// Sends the results of the completed operations to their submitters.
+    fn complete(&self) {
+        let mut ring = self.ring.lock().unwrap();
+        let mut ops = self.ops.lock().unwrap();
+        for entry in ring.completion() {
+            if let Some(op) = ops.pending.remove(&entry.user_data()) {
+                // the submitter may no longer wait for the result
+                op.tx.send((entry.result(), op.bufs)).ok();
+            }
+        }
+        // retry the entries whose submission failed, now that there is room
+        // in the completion queue
+        if !ring.submission().is_empty()
+            && let Err(e) = self.submit_queued(&mut ring)
+        {
+            log::warn!("Error submitting io_uring operations: {}", e);
+        }
+    }
+}
+
+impl Drop for Ring {
+    /// Waits for the operations in flight, so that their buffers are not freed
+    /// while the kernel still accesses them.
+    fn drop(&mut self) {
+        let ring = self.ring.get_mut().unwrap();
+        let pending = &mut self.ops.get_mut().unwrap().pending;
+        while !pending.is_empty() {
+            if let Err(e) = ring.submit_and_wait(1) {
+                log::error!("Error waiting for io_uring operations: {}", e);
+                return;
+            }
+            for entry in ring.completion() {
+                pending.remove(&entry.user_data());
+            }
+        }
+    }
+}
+
+/// Returns the IO vectors of `len` bytes of the buffers, after skipping the
+/// first `skip` bytes of them.
+fn iovecs(
+    bufs: impl Iterator<Item = (*mut u8, usize)>,
+    mut skip: usize,
+    mut len: usize,
+) -> IoVecs {
+    let mut iovecs = Vec::new();
+    for (ptr, buf_len) in bufs {
+        if len == 0 || iovecs.len() == MAX_IOVEC_COUNT {
+            break;
+        }
+        if skip >= buf_len {
+            skip -= buf_len;
+            continue;
+        }
+        let iov_len = (buf_len - skip).min(len);
+        iovecs.push(libc::iovec {
+            // SAFETY: the offset is within the buffer
+            iov_base: unsafe { ptr.add(skip) } as *mut libc::c_void,
+            iov_len,
+        });
+        skip = 0;
+        len -= iov_len;
+    }
+    IoVecs(iovecs)
+}
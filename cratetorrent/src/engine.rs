@@ -8,27 +8,45 @@
 //!
 //! The engine is run until an unrecoverable error occurs, or until the user
 //! sends a shutdown command.
+//!
+//! If configured, the engine restores the torrents of its previous run from
+//! the [session file](crate::session) on start.
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    task,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task, time,
 };
 
 use crate::{
-    alert::{AlertReceiver, AlertSender},
-    conf::{Conf, TorrentConf},
-    disk::{self, error::NewTorrentError},
+    alert::{Alert, AlertReceiver, AlertSender},
+    conf::{Conf, EngineConfPatch, SeedGoal, TorrentConf, TorrentConfPatch},
+    disk::{
+        self, cache::DiskCache, error::NewTorrentError, hasher::HasherPool,
+        WriteBuffer,
+    },
     error::*,
-    metainfo::Metainfo,
+    metainfo::{self, Metainfo, MetainfoError},
+    session::{self, TorrentProgress, TorrentState},
+    storage::{FilePool, FsStorage, MoveConflict, StorageFactory},
     storage_info::StorageInfo,
-    torrent::{self, Torrent},
+    torrent::{
+        self,
+        stats::{FileStats, PeerSessionStats, TorrentStats},
+        Query, Torrent,
+    },
     tracker::Tracker,
-    Bitfield, TorrentId,
+    Bitfield, FileIndex, TorrentId,
 };
 
 /// Spawns the engine as a tokio task.
@@ -39,14 +57,31 @@ use crate::{
 /// The return value is a tuple of an [`EngineHandle`], which may be used to
 /// send the engine commands, and an [`crate::alert::AlertReceiver`], to which
 /// various components in the engine will send alerts of events.
+///
+/// If a [session file](crate::conf::EngineConf::session_path) is configured
+/// and exists, it's loaded here. The torrents saved in it are restarted once
+/// the engine starts, and [`Alert::TorrentRestored`] is posted for each. If
+/// it can't be loaded, it's moved aside to the same path with an `.invalid`
+/// extension, so that it may still be recovered, and the engine starts
+/// without torrents, after posting the error as an [`Error::Session`] alert.
 pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     log::info!("Spawning engine task");
 
     // create alert channels and return alert port to user
     let (alert_tx, alert_rx) = mpsc::unbounded_channel();
+
+    let restored = match &conf.engine.session_path {
+        Some(path) => session::load(path).unwrap_or_else(|e| {
+            log::error!("Cannot load session file {:?}: {}", path, e);
+            session::set_aside(path);
+            alert_tx.send(Alert::Error(Error::Session(e))).ok();
+            Vec::new()
+        }),
+        None => Vec::new(),
+    };
     let (mut engine, tx) = Engine::new(conf, alert_tx)?;
 
-    let join_handle = task::spawn(async move { engine.run().await });
+    let join_handle = task::spawn(async move { engine.run(restored).await });
     log::info!("Spawned engine task");
 
     Ok((
@@ -78,6 +113,175 @@ impl EngineHandle {
         Ok(id)
     }
 
+    /// Returns the ids of all torrents in engine.
+    pub async fn list_torrents(&self) -> Result<Vec<TorrentId>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(Command::ListTorrents(tx))?;
+        rx.await.map_err(|_| Error::Channel)
+    }
+
+    /// Returns the current statistics of the torrent.
+    ///
+    /// Only the number of peers is included, regardless of the torrent's
+    /// alert configuration. Use [`Self::peers`] for the full list.
+    pub async fn torrent_status(&self, id: TorrentId) -> Result<TorrentStats> {
+        self.query_torrent(id, Query::Status).await
+    }
+
+    /// Returns the statistics of each peer connected in the torrent.
+    pub async fn peers(&self, id: TorrentId) -> Result<Vec<PeerSessionStats>> {
+        self.query_torrent(id, Query::Peers).await
+    }
+
+    /// Returns the download progress of each file in the torrent.
+    pub async fn files(&self, id: TorrentId) -> Result<Vec<FileStats>> {
+        self.query_torrent(id, Query::Files).await
+    }
+
+    /// Returns the pieces the torrent has.
+    pub async fn piece_map(&self, id: TorrentId) -> Result<Bitfield> {
+        self.query_torrent(id, Query::PieceMap).await
+    }
+
+    /// Sends the query to the torrent and waits for its answer.
+    ///
+    /// If the torrent does not exist or is no longer running, the query is
+    /// dropped without an answer, which is reported as an invalid torrent id.
+    async fn query_torrent<T>(
+        &self,
+        id: TorrentId,
+        query: impl FnOnce(oneshot::Sender<T>) -> Query,
+    ) -> Result<T> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(Command::QueryTorrent {
+            id,
+            query: query(tx),
+        })?;
+        rx.await.map_err(|_| Error::InvalidTorrentId)
+    }
+
+    /// Changes the configuration of a running torrent.
+    ///
+    /// The change is applied asynchronously: the torrent posts
+    /// [`Alert::TorrentConfUpdated`] once it's in effect, or an
+    /// [`Error::InvalidConf`] alert if the resulting configuration would be
+    /// invalid, in which case the torrent's configuration is left unchanged.
+    pub fn set_torrent_conf(
+        &self,
+        id: TorrentId,
+        patch: TorrentConfPatch,
+    ) -> Result<()> {
+        log::trace!("Changing torrent {} conf", id);
+        self.tx.send(Command::SetTorrentConf { id, patch })?;
+        Ok(())
+    }
+
+    /// Changes the engine's configuration.
+    ///
+    /// The engine posts [`Alert::EngineConfUpdated`] once the change is in
+    /// effect, or an [`Error::InvalidConf`] alert if it's invalid. Running
+    /// torrents affected by the change post their own alerts.
+    pub fn set_engine_conf(&self, patch: EngineConfPatch) -> Result<()> {
+        log::trace!("Changing engine conf");
+        self.tx.send(Command::SetEngineConf(patch))?;
+        Ok(())
+    }
+
+    /// Moves the torrent's data to the new download directory while it keeps
+    /// running.
+    ///
+    /// Files are renamed if the directory is on the same file system, and are
+    /// copied and then deleted otherwise. Reads and writes of the torrent's
+    /// data are paused until the move is done, and since the data itself
+    /// doesn't change, the torrent continues from the new location without
+    /// rechecking it. If some of the files already exist there, the conflict
+    /// policy decides what happens.
+    ///
+    /// As the move progresses, [`Alert::StorageMoveProgress`] is posted, and
+    /// [`Alert::StorageMoved`] once it's done. If it fails, an
+    /// [`Error::MoveStorage`] alert is posted and the data is left in (or moved
+    /// back to) its previous location.
+    pub fn move_storage(
+        &self,
+        id: TorrentId,
+        download_dir: PathBuf,
+        conflict: MoveConflict,
+    ) -> Result<()> {
+        log::trace!("Moving torrent {} storage to {:?}", id, download_dir);
+        self.tx.send(Command::MoveStorage {
+            id,
+            download_dir,
+            conflict,
+        })?;
+        Ok(())
+    }
+
+    /// Renames the torrent's file at the index, as in [`Metainfo::files`],
+    /// while the torrent keeps running.
+    ///
+    /// The new path is relative to the torrent's directory, and may place the
+    /// file in a different subdirectory, which is created as needed. It must
+    /// not lead outside of the torrent's directory, in which case an
+    /// [`Error::InvalidPath`] alert is posted. The file's data is not
+    /// changed, so the torrent continues to seed it from its new path, which
+    /// is also kept in the session.
+    ///
+    /// [`Alert::FileRenamed`] is posted once the file is renamed, or if it
+    /// fails, an [`Error::RenameFile`] alert.
+    pub fn rename_file(
+        &self,
+        id: TorrentId,
+        file_index: usize,
+        new_path: PathBuf,
+    ) -> Result<()> {
+        log::trace!(
+            "Renaming torrent {} file {} to {:?}",
+            id,
+            file_index,
+            new_path
+        );
+        self.tx.send(Command::RenameFile {
+            id,
+            index: file_index,
+            path: new_path,
+        })?;
+        Ok(())
+    }
+
+    /// Renames the torrent's own directory in its download directory, while
+    /// the torrent keeps running.
+    ///
+    /// Only archives have their own directory: a single file torrent's file
+    /// is renamed instead, as with [`Self::rename_file`]. The name must be
+    /// a single valid file name, or an [`Error::InvalidPath`] alert is
+    /// posted.
+    ///
+    /// [`Alert::TorrentRenamed`] is posted once the directory or file is
+    /// renamed, or if it fails, an [`Error::MoveStorage`] alert, or an
+    /// [`Error::RenameFile`] alert for single file torrents.
+    pub fn rename_torrent_dir(
+        &self,
+        id: TorrentId,
+        name: String,
+    ) -> Result<()> {
+        log::trace!("Renaming torrent {} directory to {:?}", id, name);
+        self.tx.send(Command::RenameTorrentDir { id, name })?;
+        Ok(())
+    }
+
+    /// Clears the storage error of the torrent, which was reported in an
+    /// [`Alert::StorageError`], and resumes it.
+    ///
+    /// The pieces that could not be written are written again, and the
+    /// torrent reconnects its peers. If the write fails again, the torrent is
+    /// paused with a new alert. Resuming a torrent that is not paused has no
+    /// effect.
+    pub fn resume_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Resuming torrent {}", id);
+        self.tx.send(Command::ResumeTorrent(id))?;
+        Ok(())
+    }
+
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     ///
@@ -108,14 +312,20 @@ pub struct TorrentParams {
     pub conf: Option<TorrentConf>,
     /// Whether to download or seed the torrent.
     ///
-    /// This is expected to be removed as this will become automatic once
-    /// torrent resume data is supported.
+    /// This only applies when the torrent is first created: torrents restored
+    /// from the session file continue with the pieces they had.
     pub mode: Mode,
     /// The address on which the torrent should listen for new peers.
     ///
     /// This has to be unique for each torrent. If not set, or if already in
     /// use, a random port is assigned.
     pub listen_addr: Option<SocketAddr>,
+    /// Creates the backend in which the torrent's data is stored. If not set,
+    /// the torrent is stored in the file system, in the download directory.
+    ///
+    /// Custom backends are not persisted in the session file, so if the
+    /// torrent is restored after a restart, it's stored in the file system.
+    pub storage: Option<Arc<dyn StorageFactory>>,
 }
 
 /// The download mode.
@@ -125,6 +335,10 @@ pub enum Mode {
     Seed,
 }
 
+/// How long the engine waits for the torrents' progress when saving the
+/// session.
+const PROGRESS_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// The channel through which the user can send commands to the engine.
 pub(crate) type Sender = UnboundedSender<Command>;
 /// The channel on which the engine listens for commands from the user.
@@ -144,6 +358,53 @@ pub(crate) enum Command {
         id: TorrentId,
         result: Result<(), NewTorrentError>,
     },
+    /// Changes the configuration of a running torrent.
+    SetTorrentConf {
+        id: TorrentId,
+        patch: TorrentConfPatch,
+    },
+    /// Changes the engine configuration.
+    SetEngineConf(EngineConfPatch),
+    /// Sent by a torrent once it has downloaded all its pieces.
+    TorrentComplete(TorrentId),
+    /// Sent by a torrent once it has reached one of its seeding goals and
+    /// stopped.
+    SeedGoalReached { id: TorrentId, goal: SeedGoal },
+    /// Moves the torrent's data to the new download directory.
+    MoveStorage {
+        id: TorrentId,
+        download_dir: PathBuf,
+        conflict: MoveConflict,
+    },
+    /// The progress of a storage move, sent by the disk task.
+    StorageMoveProgress { id: TorrentId, moved: u64, total: u64 },
+    /// The result of a storage move, sent by the disk task.
+    StorageMoved {
+        id: TorrentId,
+        download_dir: PathBuf,
+        result: std::io::Result<()>,
+    },
+    /// Renames one of the torrent's files.
+    RenameFile {
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+    },
+    /// The result of a file rename, sent by the disk task.
+    FileRenamed {
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+        result: std::io::Result<()>,
+    },
+    /// Renames the torrent's own directory.
+    RenameTorrentDir { id: TorrentId, name: String },
+    /// Resumes the torrent paused due to a storage error.
+    ResumeTorrent(TorrentId),
+    /// Returns the ids of all torrents via the sender.
+    ListTorrents(oneshot::Sender<Vec<TorrentId>>),
+    /// Forwards the query to the torrent, which answers it directly.
+    QueryTorrent { id: TorrentId, query: Query },
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     Shutdown,
@@ -160,6 +421,17 @@ struct Engine {
     /// The disk channel.
     disk_tx: disk::Sender,
     disk_join_handle: Option<disk::JoinHandle>,
+    /// The usage of the torrents' disk write buffers, shared with the disk
+    /// task and the torrents.
+    write_buf: Arc<WriteBuffer>,
+    /// The pieces of the torrents cached in memory, shared with the disk task
+    /// and the torrents.
+    disk_cache: Arc<DiskCache>,
+    /// The threads hashing the pieces of the torrents, shared with the disk
+    /// task and the torrents.
+    hasher: Arc<HasherPool>,
+    /// The open files of the torrents stored in the file system.
+    file_pool: Arc<FilePool>,
 
     /// The channel on which tasks in the engine post alerts to user.
     alert_tx: AlertSender,
@@ -167,6 +439,15 @@ struct Engine {
     /// The global engine configuration that includes defaults for torrents
     /// whose config is not overridden.
     conf: Conf,
+
+    /// A copy of the engine's own command channel, given to torrents.
+    tx: Sender,
+    /// The torrents whose completion alert is held back until their storage
+    /// is moved to their completed directory.
+    completing: HashSet<TorrentId>,
+    /// The new names of the torrents whose directory is being renamed, which
+    /// is done by moving their storage.
+    renaming: HashMap<TorrentId, String>,
 }
 
 #[cfg(feature = "ratio")]
@@ -175,12 +456,21 @@ struct TorrentEntry {
     join_handle: Option<task::JoinHandle<torrent::error::Result<()>>>,
     info_hash: [u8; 20],
     trackers: Vec<Tracker>,
+    state: TorrentState,
 }
 
 #[cfg(not(feature = "ratio"))]
 struct TorrentEntry {
     tx: torrent::Sender,
     join_handle: Option<task::JoinHandle<torrent::error::Result<()>>>,
+    /// The state saved in the session file. The torrent's progress is updated
+    /// each time the session is saved, so that if the torrent stops, its last
+    /// known progress is kept.
+    ///
+    /// If the torrent was created with its own configuration, it's stored
+    /// here, in which case changes to the engine's default torrent
+    /// configuration don't apply to it.
+    state: TorrentState,
 }
 
 
@@ -188,7 +478,19 @@ impl Engine {
     /// Creates a new engine, spawning the disk task.
     fn new(conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
+        let write_buf =
+            Arc::new(WriteBuffer::new(conf.engine.write_buf_budget));
+        let disk_cache =
+            Arc::new(DiskCache::new(conf.engine.disk_cache_budget));
+        let hasher =
+            Arc::new(HasherPool::new(conf.engine.hasher_thread_count)?);
+        let (disk_join_handle, disk_tx) = disk::spawn(
+            cmd_tx.clone(),
+            Arc::clone(&write_buf),
+            Arc::clone(&disk_cache),
+            Arc::clone(&hasher),
+        )?;
+        let file_pool = Arc::new(FilePool::new(conf.engine.max_open_files));
 
         Ok((
             Self {
@@ -196,8 +498,15 @@ impl Engine {
                 cmd_rx,
                 disk_tx,
                 disk_join_handle: Some(disk_join_handle),
+                write_buf,
+                disk_cache,
+                hasher,
+                file_pool,
                 alert_tx,
                 conf,
+                tx: cmd_tx.clone(),
+                completing: HashSet::new(),
+                renaming: HashMap::new(),
             },
             cmd_tx,
         ))
@@ -205,10 +514,41 @@ impl Engine {
 
     /// Runs the engine until an unrecoverable error occurs, or until the user
     /// sends a shutdown command.
-    async fn run(&mut self) -> Result<()> {
+    ///
+    /// The torrents restored from the session file are started first.
+    async fn run(&mut self, restored: Vec<TorrentState>) -> Result<()> {
         log::info!("Starting engine");
 
-        while let Some(cmd) = self.cmd_rx.recv().await {
+        for state in restored {
+            let id = TorrentId::new();
+            let info_hash = state.metainfo.info_hash;
+            log::info!("Restoring torrent {} ({})", id, hex::encode(info_hash));
+            self.start_torrent(id, state)?;
+            self.alert_tx.send(Alert::TorrentRestored { id, info_hash })?;
+        }
+
+        // a zero interval turns off the periodic saves, which the timer
+        // doesn't support
+        let save_interval = self.conf.engine.session_save_interval;
+        let mut save_timer = (!save_interval.is_zero()).then(|| {
+            let start = time::Instant::now() + save_interval;
+            time::interval_at(start, save_interval)
+        });
+
+        loop {
+            let cmd = tokio::select! {
+                cmd = self.cmd_rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = async { save_timer.as_mut().unwrap().tick().await },
+                    if save_timer.is_some() =>
+                {
+                    self.save_session().await?;
+                    continue;
+                }
+            };
+
             match cmd {
                 Command::CreateTorrent { id, params } => {
                     self.create_torrent(id, params).await?;
@@ -217,14 +557,88 @@ impl Engine {
                     Ok(_) => {
                         log::info!("Torrent {} allocated on disk", id);
                     }
-                    Err(e) => {
+                    Err(error) => {
                         log::error!(
                             "Error allocating torrent {} on disk: {}",
                             id,
-                            e
+                            error
                         );
+                        // the torrent can't do anything without its storage
+                        if let Some(torrent) = self.torrents.remove(&id) {
+                            torrent.tx.send(torrent::Command::Shutdown).ok();
+                        }
+                        let error = Error::NewTorrent { id, error };
+                        self.alert_tx.send(Alert::Error(error))?;
                     }
                 },
+                Command::SetTorrentConf { id, patch } => {
+                    self.set_torrent_conf(id, patch)?;
+                }
+                Command::SetEngineConf(patch) => {
+                    self.set_engine_conf(patch)?;
+                }
+                Command::TorrentComplete(id) => {
+                    self.handle_torrent_complete(id)?;
+                }
+                Command::SeedGoalReached { id, goal } => {
+                    self.handle_seed_goal_reached(id, goal).await?;
+                }
+                Command::MoveStorage {
+                    id,
+                    download_dir,
+                    conflict,
+                } => {
+                    self.move_storage(id, download_dir, conflict)?;
+                }
+                Command::StorageMoveProgress { id, moved, total } => {
+                    self.alert_tx.send(Alert::StorageMoveProgress {
+                        id,
+                        moved,
+                        total,
+                    })?;
+                }
+                Command::StorageMoved {
+                    id,
+                    download_dir,
+                    result,
+                } => {
+                    self.handle_storage_moved(id, download_dir, result)?;
+                }
+                Command::RenameFile { id, index, path } => {
+                    self.rename_file(id, index, path)?;
+                }
+                Command::FileRenamed {
+                    id,
+                    index,
+                    path,
+                    result,
+                } => {
+                    self.handle_file_renamed(id, index, path, result)?;
+                }
+                Command::RenameTorrentDir { id, name } => {
+                    self.rename_torrent_dir(id, name)?;
+                }
+                Command::ResumeTorrent(id) => {
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.tx.send(torrent::Command::Resume).ok();
+                    } else {
+                        log::warn!("Cannot resume invalid torrent {}", id);
+                        self.alert_tx
+                            .send(Alert::Error(Error::InvalidTorrentId))?;
+                    }
+                }
+                Command::ListTorrents(tx) => {
+                    tx.send(self.torrents.keys().copied().collect()).ok();
+                }
+                Command::QueryTorrent { id, query } => {
+                    // if the torrent is not found or not running, the query
+                    // is dropped, which the requester is notified of
+                    if let Some(torrent) = self.torrents.get(&id) {
+                        torrent.tx.send(torrent::Command::Query(query)).ok();
+                    } else {
+                        log::warn!("Cannot query invalid torrent {}", id);
+                    }
+                }
                 Command::Shutdown => {
                     self.shutdown().await?;
                     break;
@@ -241,53 +655,104 @@ impl Engine {
         id: TorrentId,
         params: TorrentParams,
     ) -> Result<()> {
-        let conf = params.conf.unwrap_or_else(|| self.conf.torrent.clone());
-        let storage_info = StorageInfo::new(
-            &params.metainfo,
-            self.conf.engine.download_dir.clone(),
-        );
+        let own_pieces = params.mode.own_pieces(params.metainfo.piece_count());
+        // seeds are expected to be complete already, so they are looked up
+        // where completed torrents are kept
+        let conf = params.conf.as_ref().unwrap_or(&self.conf.torrent);
+        let dir = match params.mode {
+            Mode::Seed => &conf.completed_dir,
+            Mode::Download { .. } => &conf.incomplete_dir,
+        };
+        let download_dir = dir
+            .clone()
+            .unwrap_or_else(|| self.conf.engine.download_dir.clone());
+        let state = TorrentState {
+            metainfo: params.metainfo,
+            download_dir,
+            listen_addr: params.listen_addr,
+            seeds: params.mode.seeds(),
+            conf: params.conf,
+            storage: params.storage,
+            progress: TorrentProgress::new(own_pieces),
+        };
+        self.start_torrent(id, state)
+    }
+
+    /// Spawns a new or restored torrent from its state.
+    fn start_torrent(
+        &mut self,
+        id: TorrentId,
+        state: TorrentState,
+    ) -> Result<()> {
+        let conf = state
+            .conf
+            .clone()
+            .unwrap_or_else(|| self.conf.torrent.clone());
+        let storage_info =
+            StorageInfo::new(&state.metainfo, state.download_dir.clone());
+        let allocation = conf.allocation;
+        let mmap_reads = conf.mmap_reads;
 
         // Create trackers from the metainfo URLs
-        let trackers: Vec<_> = params
+        let trackers: Vec<_> = state
             .metainfo
             .trackers
-            .into_iter()
+            .iter()
+            .cloned()
             .map(|url| Tracker::new(
                 url,
-                params.metainfo.info_hash,
+                state.metainfo.info_hash,
                 self.conf.engine.client_id
             ))
             .collect();
 
-        let own_pieces = params.mode.own_pieces(storage_info.piece_count);
-
         // Create and spawn the torrent
         let (mut torrent, torrent_tx) = Torrent::new(torrent::Params {
             id,
             disk_tx: self.disk_tx.clone(),
-            info_hash: params.metainfo.info_hash,
+            write_buf: Arc::clone(&self.write_buf),
+            disk_cache: Arc::clone(&self.disk_cache),
+            hasher: Arc::clone(&self.hasher),
+            info_hash: state.metainfo.info_hash,
             storage_info: storage_info.clone(),
-            own_pieces,
+            progress: state.progress.clone(),
             trackers: trackers.clone(),
             client_id: self.conf.engine.client_id,
-            listen_addr: params.listen_addr.unwrap_or_else(|| {
+            listen_addr: state.listen_addr.unwrap_or_else(|| {
                 // the port 0 tells the kernel to assign a free port from the
                 // dynamic range
                 SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
             }),
             conf,
             alert_tx: self.alert_tx.clone(),
+            engine_tx: self.tx.clone(),
         });
 
         // Allocate torrent on disk
+        let storage = match &state.storage {
+            Some(factory) => factory.create(&storage_info),
+            None => {
+                let storage = FsStorage::new(
+                    storage_info.clone(),
+                    allocation,
+                    Arc::clone(&self.file_pool),
+                );
+                if mmap_reads {
+                    Box::new(storage.with_mmap_reads())
+                } else {
+                    Box::new(storage)
+                }
+            }
+        };
         self.disk_tx.send(disk::Command::NewTorrent {
             id,
             storage_info,
-            piece_hashes: params.metainfo.pieces,
+            storage,
+            piece_hashes: state.metainfo.pieces.clone(),
             torrent_tx: torrent_tx.clone(),
         })?;
 
-        let seeds = params.mode.seeds();
+        let seeds = state.seeds.clone();
         let join_handle =
             task::spawn(async move { torrent.start(&seeds).await });
 
@@ -296,14 +761,16 @@ impl Engine {
         let entry = TorrentEntry {
             tx: torrent_tx,
             join_handle: Some(join_handle),
-            info_hash: params.metainfo.info_hash,
+            info_hash: state.metainfo.info_hash,
             trackers,
+            state,
         };
 
         #[cfg(not(feature = "ratio"))]
         let entry = TorrentEntry {
             tx: torrent_tx,
             join_handle: Some(join_handle),
+            state,
         };
 
         self.torrents.insert(id, entry);
@@ -311,10 +778,427 @@ impl Engine {
         Ok(())
     }
 
+    /// Forwards the configuration change to the torrent, which validates and
+    /// applies it.
+    fn set_torrent_conf(
+        &mut self,
+        id: TorrentId,
+        patch: TorrentConfPatch,
+    ) -> Result<()> {
+        match self.torrents.get_mut(&id) {
+            // the torrent may have already stopped, e.g. after reaching its
+            // seed goal
+            Some(torrent) => {
+                // keep the saved copy of the torrent's own configuration in
+                // sync, which the torrent validates the same way
+                if let Some(conf) = &mut torrent.state.conf
+                    && let Ok(new_conf) = patch.apply(conf)
+                {
+                    *conf = new_conf;
+                }
+                torrent.tx.send(torrent::Command::SetConf(patch)).ok();
+            }
+            None => {
+                log::warn!("Cannot change conf of invalid torrent {}", id);
+                self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the disk task to move the torrent's storage.
+    fn move_storage(
+        &mut self,
+        id: TorrentId,
+        download_dir: PathBuf,
+        conflict: MoveConflict,
+    ) -> Result<()> {
+        let Some(torrent) = self.torrents.get(&id) else {
+            log::warn!("Cannot move storage of invalid torrent {}", id);
+            self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+            return Ok(());
+        };
+        let metainfo = &torrent.state.metainfo;
+        let dir = StorageInfo::torrent_dir(metainfo, download_dir.clone());
+        self.disk_tx.send(disk::Command::MoveStorage {
+            id,
+            download_dir,
+            dir,
+            conflict,
+        })?;
+        Ok(())
+    }
+
+    /// Moves the completed torrent to its completed directory, if it has one,
+    /// or otherwise posts its completion right away.
+    fn handle_torrent_complete(&mut self, id: TorrentId) -> Result<()> {
+        let Some(torrent) = self.torrents.get(&id) else {
+            return Ok(());
+        };
+        let conf = torrent.state.conf.as_ref().unwrap_or(&self.conf.torrent);
+        match &conf.completed_dir {
+            Some(dir) if *dir != torrent.state.download_dir => {
+                log::info!("Moving complete torrent {} to {:?}", id, dir);
+                self.completing.insert(id);
+                let dir = dir.clone();
+                self.move_storage(id, dir, MoveConflict::Fail)
+            }
+            _ => {
+                self.alert_tx.send(Alert::TorrentComplete(id))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes the torrent that stopped after reaching its seeding goal, so
+    /// that it's no longer saved in the session and isn't restored to seed
+    /// again, and posts that the goal was reached.
+    async fn handle_seed_goal_reached(
+        &mut self,
+        id: TorrentId,
+        goal: SeedGoal,
+    ) -> Result<()> {
+        let Some(mut torrent) = self.torrents.remove(&id) else {
+            return Ok(());
+        };
+        // the torrent sends this right before its task ends
+        if let Some(handle) = torrent.join_handle.take()
+            && let Err(e) = handle.await.expect("task error")
+        {
+            log::error!("Torrent error: {}", e);
+        }
+        self.completing.remove(&id);
+        self.renaming.remove(&id);
+        self.disk_tx.send(disk::Command::RemoveTorrent(id))?;
+        self.save_session().await?;
+        self.alert_tx.send(Alert::SeedGoalReached { id, goal })?;
+        Ok(())
+    }
+
+    /// Records the torrent's new download directory, or if the move renamed
+    /// its own directory, its new name, so that it's restored from there, and
+    /// posts the result of the move.
+    ///
+    /// If this was the move of a completed torrent, its completion is posted
+    /// after the result, even if the move failed.
+    fn handle_storage_moved(
+        &mut self,
+        id: TorrentId,
+        download_dir: PathBuf,
+        result: std::io::Result<()>,
+    ) -> Result<()> {
+        match (result, self.renaming.remove(&id)) {
+            (Ok(()), Some(name)) => {
+                log::info!("Torrent {} directory renamed to {:?}", id, name);
+                if let Some(torrent) = self.torrents.get_mut(&id) {
+                    torrent.state.metainfo.name = name.clone();
+                }
+                self.update_storage_info(id)?;
+                self.alert_tx.send(Alert::TorrentRenamed { id, name })?;
+            }
+            (Ok(()), None) => {
+                log::info!("Torrent {} moved to {:?}", id, download_dir);
+                if let Some(torrent) = self.torrents.get_mut(&id) {
+                    torrent.state.download_dir = download_dir.clone();
+                }
+                self.update_storage_info(id)?;
+                self.alert_tx
+                    .send(Alert::StorageMoved { id, download_dir })?;
+            }
+            (Err(error), _) => {
+                log::error!("Error moving torrent {} storage: {}", id, error);
+                self.alert_tx
+                    .send(Alert::Error(Error::MoveStorage { id, error }))?;
+            }
+        }
+        if self.completing.remove(&id) {
+            self.alert_tx.send(Alert::TorrentComplete(id))?;
+        }
+        Ok(())
+    }
+
+    /// Checks the file's new path and asks the disk task to rename it.
+    ///
+    /// The path may not be the same as, or be inside of, the path of another
+    /// of the torrent's files, or the other way around.
+    fn rename_file(
+        &mut self,
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+    ) -> Result<()> {
+        let Some(torrent) = self.torrents.get(&id) else {
+            log::warn!("Cannot rename file of invalid torrent {}", id);
+            self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+            return Ok(());
+        };
+        let files = &torrent.state.metainfo.files;
+        if index >= files.len() {
+            log::warn!(
+                "Cannot rename invalid file {} of torrent {}",
+                index,
+                id
+            );
+            self.alert_tx.send(Alert::Error(Error::InvalidFileIndex))?;
+            return Ok(());
+        }
+        if let Err(MetainfoError::InvalidPath(component)) =
+            metainfo::validate_path(&path)
+        {
+            self.alert_tx
+                .send(Alert::Error(Error::InvalidPath(component)))?;
+            return Ok(());
+        }
+        let conflicts = files.iter().enumerate().any(|(i, file)| {
+            let other = &file.path;
+            i != index && (other.starts_with(&path) || path.starts_with(other))
+        });
+        if conflicts {
+            log::warn!("Torrent {} already has a file at {:?}", id, path);
+            self.alert_tx.send(Alert::Error(Error::RenameFile {
+                id,
+                index,
+                error: std::io::ErrorKind::AlreadyExists.into(),
+            }))?;
+            return Ok(());
+        }
+        self.disk_tx
+            .send(disk::Command::RenameFile { id, index, path })?;
+        Ok(())
+    }
+
+    /// Records the file's new path, so that it's restored from there, and
+    /// posts the result of the rename.
+    ///
+    /// If this renamed the file of a single file torrent, as its new name,
+    /// the torrent's name is recorded and its rename is posted instead.
+    fn handle_file_renamed(
+        &mut self,
+        id: TorrentId,
+        index: FileIndex,
+        path: PathBuf,
+        result: std::io::Result<()>,
+    ) -> Result<()> {
+        // only single file torrents are renamed by renaming their file
+        let is_archive = self
+            .torrents
+            .get(&id)
+            .is_some_and(|torrent| torrent.state.metainfo.is_archive());
+        let name = if is_archive {
+            None
+        } else {
+            self.renaming.remove(&id)
+        };
+        match result {
+            Ok(()) => {
+                log::info!(
+                    "Torrent {} file {} renamed to {:?}",
+                    id,
+                    index,
+                    path
+                );
+                if let Some(torrent) = self.torrents.get_mut(&id) {
+                    let metainfo = &mut torrent.state.metainfo;
+                    metainfo.files[index].path = path.clone();
+                    if let Some(name) = &name {
+                        metainfo.name = name.clone();
+                    }
+                }
+                self.update_storage_info(id)?;
+                match name {
+                    Some(name) => self
+                        .alert_tx
+                        .send(Alert::TorrentRenamed { id, name })?,
+                    None => self
+                        .alert_tx
+                        .send(Alert::FileRenamed { id, index, path })?,
+                }
+            }
+            Err(error) => {
+                log::error!("Error renaming torrent {} file: {}", id, error);
+                self.alert_tx.send(Alert::Error(Error::RenameFile {
+                    id,
+                    index,
+                    error,
+                }))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renames the torrent's own directory by moving its storage, or if it's
+    /// a single file torrent, its file.
+    fn rename_torrent_dir(
+        &mut self,
+        id: TorrentId,
+        name: String,
+    ) -> Result<()> {
+        let Some(torrent) = self.torrents.get(&id) else {
+            log::warn!("Cannot rename invalid torrent {}", id);
+            self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+            return Ok(());
+        };
+        if let Err(MetainfoError::InvalidPath(component)) =
+            metainfo::validate_path_component(&name)
+        {
+            self.alert_tx
+                .send(Alert::Error(Error::InvalidPath(component)))?;
+            return Ok(());
+        }
+        if !torrent.state.metainfo.is_archive() {
+            self.renaming.insert(id, name.clone());
+            self.disk_tx.send(disk::Command::RenameFile {
+                id,
+                index: 0,
+                path: name.into(),
+            })?;
+            return Ok(());
+        }
+        let download_dir = torrent.state.download_dir.clone();
+        let dir = download_dir.join(&name);
+        self.renaming.insert(id, name);
+        self.disk_tx.send(disk::Command::MoveStorage {
+            id,
+            download_dir,
+            dir,
+            conflict: MoveConflict::Fail,
+        })?;
+        Ok(())
+    }
+
+    /// Sends the torrent's storage info, with the current paths of its files,
+    /// to the torrent and the disk task, after its files were moved or
+    /// renamed.
+    fn update_storage_info(&self, id: TorrentId) -> Result<()> {
+        let Some(torrent) = self.torrents.get(&id) else {
+            return Ok(());
+        };
+        let storage_info = StorageInfo::new(
+            &torrent.state.metainfo,
+            torrent.state.download_dir.clone(),
+        );
+        torrent
+            .tx
+            .send(torrent::Command::SetStorageInfo(storage_info.clone()))
+            .ok();
+        self.disk_tx
+            .send(disk::Command::SetStorageInfo { id, storage_info })?;
+        Ok(())
+    }
+
+    /// Applies the change to the engine's configuration and to all torrents
+    /// that use the default torrent configuration.
+    fn set_engine_conf(&mut self, patch: EngineConfPatch) -> Result<()> {
+        let torrent_conf = match patch.torrent.apply(&self.conf.torrent) {
+            Ok(conf) => conf,
+            Err(error) => {
+                log::warn!("Invalid engine conf change: {}", error);
+                self.alert_tx
+                    .send(Alert::Error(Error::InvalidConf { id: None, error }))?;
+                return Ok(());
+            }
+        };
+
+        self.conf.torrent = torrent_conf;
+        if let Some(download_dir) = patch.download_dir {
+            log::info!("Changing download directory to {:?}", download_dir);
+            self.conf.engine.download_dir = download_dir;
+        }
+
+        for torrent in self.torrents.values().filter(|t| t.state.conf.is_none())
+        {
+            torrent
+                .tx
+                .send(torrent::Command::SetConf(patch.torrent.clone()))
+                .ok();
+        }
+
+        self.alert_tx.send(Alert::EngineConfUpdated)?;
+        Ok(())
+    }
+
+    /// Saves the state of all torrents to the session file, if one is
+    /// configured.
+    ///
+    /// Failing to save the session is not fatal: the error is posted as an
+    /// alert and the previously saved session file is left intact.
+    async fn save_session(&mut self) -> Result<()> {
+        let path = match &self.conf.engine.session_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        // update the progress of the torrents that are still running
+        let mut queries = Vec::new();
+        for (id, torrent) in &self.torrents {
+            let (tx, rx) = oneshot::channel();
+            let query = torrent::Command::Query(Query::Progress(tx));
+            if torrent.tx.send(query).is_ok() {
+                queries.push((*id, rx));
+            }
+        }
+        // torrents answer in parallel, and those that are busy for too long,
+        // e.g. announcing to a tracker, are saved with their last known
+        // progress, so that they don't hold up the engine's commands
+        let deadline = time::Instant::now() + PROGRESS_QUERY_TIMEOUT;
+        for (id, rx) in queries {
+            if let Ok(Ok(progress)) = time::timeout_at(deadline, rx).await
+                && let Some(torrent) = self.torrents.get_mut(&id)
+            {
+                torrent.state.progress = progress;
+            }
+        }
+
+        // torrent ids are assigned incrementally, so this preserves the order
+        // in which torrents were added
+        let mut ids: Vec<_> = self.torrents.keys().copied().collect();
+        ids.sort_unstable();
+        let states = ids.iter().map(|id| &self.torrents[id].state);
+
+        log::debug!("Saving session to {:?}", path);
+        let result = match session::encode(states) {
+            Ok(buf) => {
+                task::spawn_blocking(move || session::write(&path, &buf))
+                    .await
+                    .expect("task error")
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::error!("Error saving session: {}", e);
+            self.alert_tx.send(Alert::Error(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the pieces that the running torrents have written to disk, and
+    /// waits until they're durable.
+    async fn flush_torrents(&mut self) {
+        let mut waits = Vec::new();
+        for torrent in self.torrents.values() {
+            let (tx, rx) = oneshot::channel();
+            if torrent.tx.send(torrent::Command::Flush(tx)).is_ok() {
+                waits.push(rx);
+            }
+        }
+        // torrents flush in parallel, and those that stopped in the meantime
+        // are not waited for
+        for rx in waits {
+            rx.await.ok();
+        }
+    }
+
     /// Gracefully shuts down the engine and all its components.
     async fn shutdown(&mut self) -> Result<()> {
         log::info!("Shutting down engine");
 
+        // flush the torrents' downloaded pieces and then save the session
+        // while torrents are still running, so that their latest progress is
+        // included
+        self.flush_torrents().await;
+        self.save_session().await?;
+
         // First get stats from all torrents before shutting them down
         #[cfg(feature = "ratio")]
         let mut stats_map = HashMap::new();
@@ -385,3 +1269,193 @@ impl Mode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::Duration};
+
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::{session::SessionError, FileInfo, BLOCK_LEN};
+
+    /// Tests that a torrent that reached its seeding goal is removed from the
+    /// engine, and isn't restored from the session to seed again.
+    #[tokio::test]
+    async fn should_not_restore_torrent_after_seed_goal() {
+        let dir = Path::new("/tmp/cratetorrent_engine_seed_goal_test");
+        fs::remove_dir_all(dir).ok();
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("seed_goal"), [0; 16]).unwrap();
+        let mut conf = Conf::new(dir);
+        conf.engine.session_path = Some(dir.join("session"));
+        conf.torrent.seed_goals.seed_time = Some(Duration::ZERO);
+        let metainfo = Metainfo {
+            name: "seed_goal".into(),
+            info_hash: [1; 20],
+            pieces: Sha1::digest([0; 16]).to_vec(),
+            piece_len: 16,
+            files: vec![FileInfo {
+                path: "seed_goal".into(),
+                len: 16,
+                torrent_offset: 0,
+                ..Default::default()
+            }],
+            trackers: Vec::new(),
+            raw_info: Vec::new(),
+        };
+
+        let (engine, mut alert_rx) = spawn(conf.clone()).unwrap();
+        let id = engine
+            .create_torrent(TorrentParams {
+                metainfo,
+                conf: None,
+                mode: Mode::Seed,
+                listen_addr: None,
+                storage: None,
+            })
+            .unwrap();
+        let goal = time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(Alert::SeedGoalReached { id: goal_id, goal }) =
+                    alert_rx.recv().await
+                {
+                    assert_eq!(goal_id, id);
+                    return goal;
+                }
+            }
+        })
+        .await
+        .expect("seed goal not reached");
+        assert_eq!(goal, SeedGoal::SeedTime);
+        assert!(engine.list_torrents().await.unwrap().is_empty());
+        engine.shutdown().await.unwrap();
+
+        let (engine, _alert_rx) = spawn(conf).unwrap();
+        assert!(engine.list_torrents().await.unwrap().is_empty());
+        engine.shutdown().await.unwrap();
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    /// Tests that the engine starts without torrents if its session file is
+    /// invalid, e.g. because it was torn by a crash, keeping the file aside.
+    #[tokio::test]
+    async fn should_set_aside_invalid_session() {
+        let dir = Path::new("/tmp/cratetorrent_engine_invalid_session_test");
+        fs::remove_dir_all(dir).ok();
+        fs::create_dir_all(dir).unwrap();
+        let session_path = dir.join("session");
+        fs::write(&session_path, b"d8:torrentsl").unwrap();
+        let mut conf = Conf::new(dir);
+        conf.engine.session_path = Some(session_path.clone());
+
+        let (engine, mut alert_rx) = spawn(conf).unwrap();
+        assert!(matches!(
+            alert_rx.recv().await,
+            Some(Alert::Error(Error::Session(SessionError::Bencode(_))))
+        ));
+        assert!(engine.list_torrents().await.unwrap().is_empty());
+        engine.shutdown().await.unwrap();
+        assert_eq!(
+            fs::read(dir.join("session.invalid")).unwrap(),
+            b"d8:torrentsl"
+        );
+        // the empty session was saved on shutdown
+        assert!(session::load(&session_path).unwrap().is_empty());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    /// Tests that a download completes when the pieces in progress don't fit
+    /// in the disk write buffer's budget, as the blocks of the pieces already
+    /// started are still requested while it's full.
+    #[tokio::test]
+    async fn should_download_with_small_write_buffer() {
+        let dir = Path::new("/tmp/cratetorrent_engine_write_buf_test");
+        fs::remove_dir_all(dir).ok();
+        let seed_dir = dir.join("seed");
+        let download_dir = dir.join("download");
+        fs::create_dir_all(&seed_dir).unwrap();
+        fs::create_dir_all(&download_dir).unwrap();
+
+        // each piece is several times the write buffer's budget
+        let piece_len = 8 * BLOCK_LEN;
+        let data: Vec<u8> =
+            (0..3 * piece_len).map(|b| (b % 251) as u8).collect();
+        fs::write(seed_dir.join("write_buf"), &data).unwrap();
+        let metainfo = Metainfo {
+            name: "write_buf".into(),
+            info_hash: [2; 20],
+            pieces: data
+                .chunks(piece_len as usize)
+                .flat_map(|piece| Sha1::digest(piece).to_vec())
+                .collect(),
+            piece_len,
+            files: vec![FileInfo {
+                path: "write_buf".into(),
+                len: data.len() as u64,
+                torrent_offset: 0,
+                ..Default::default()
+            }],
+            trackers: Vec::new(),
+            raw_info: Vec::new(),
+        };
+        let seed_addr: SocketAddr = "127.0.0.1:48031".parse().unwrap();
+
+        let (seed_engine, _seed_alert_rx) =
+            spawn(Conf::new(&seed_dir)).unwrap();
+        seed_engine
+            .create_torrent(TorrentParams {
+                metainfo: metainfo.clone(),
+                conf: None,
+                mode: Mode::Seed,
+                listen_addr: Some(seed_addr),
+                storage: None,
+            })
+            .unwrap();
+        // wait for the seed to listen for connections
+        time::timeout(Duration::from_secs(10), async {
+            while tokio::net::TcpStream::connect(seed_addr).await.is_err() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("seed not listening");
+
+        let mut conf = Conf::new(&download_dir);
+        conf.engine.write_buf_budget = BLOCK_LEN as u64;
+        let (engine, mut alert_rx) = spawn(conf).unwrap();
+        let id = engine
+            .create_torrent(TorrentParams {
+                metainfo,
+                conf: None,
+                mode: Mode::Download {
+                    seeds: vec![seed_addr],
+                },
+                listen_addr: None,
+                storage: None,
+            })
+            .unwrap();
+        time::timeout(Duration::from_secs(30), async {
+            loop {
+                match alert_rx.recv().await {
+                    Some(Alert::TorrentComplete(complete_id))
+                        if complete_id == id =>
+                    {
+                        return;
+                    }
+                    Some(_) => (),
+                    None => panic!("engine stopped"),
+                }
+            }
+        })
+        .await
+        .expect("download not complete");
+        engine.shutdown().await.unwrap();
+        seed_engine.shutdown().await.unwrap();
+
+        assert_eq!(fs::read(download_dir.join("write_buf")).unwrap(), data);
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -7,7 +7,9 @@ use std::{fmt, net::SocketAddr};
 use crate::TorrentId;
 
 pub use crate::{
-    peer::error::PeerError, torrent::error::TorrentError, tracker::TrackerError,
+    conf::ConfError, disk::error::NewTorrentError, peer::error::PeerError,
+    session::SessionError, torrent::error::TorrentError,
+    tracker::TrackerError,
 };
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
@@ -26,8 +28,34 @@ pub enum Error {
     /// The torrent ID did not correspond to any entry. This is returned when
     /// the user specified a torrent that does not exist.
     InvalidTorrentId,
+    /// The file index did not correspond to any of the torrent's files.
+    InvalidFileIndex,
+    /// A path given to a file or directory of a torrent was rejected, as it
+    /// could lead outside of the torrent's directory or is not a valid file
+    /// name on all platforms. The offending path component is included.
+    InvalidPath(String),
+    /// A configuration change was rejected. If the change was made to
+    /// a specific torrent, its id is included.
+    InvalidConf {
+        id: Option<TorrentId>,
+        error: ConfError,
+    },
     /// Holds global IO related errors.
     Io(IoError),
+    /// The torrent could not be allocated on disk, so it was stopped.
+    NewTorrent { id: TorrentId, error: NewTorrentError },
+    /// The torrent's storage could not be moved. The torrent continues in its
+    /// previous location.
+    MoveStorage { id: TorrentId, error: IoError },
+    /// The torrent's file could not be renamed. The file keeps its previous
+    /// path.
+    RenameFile {
+        id: TorrentId,
+        index: usize,
+        error: IoError,
+    },
+    /// The session state could not be loaded or saved.
+    Session(SessionError),
     /// An error specific to a torrent.
     Torrent { id: TorrentId, error: TorrentError },
     /// An error that occurred while a torrent was announcing to tracker.
@@ -50,7 +78,29 @@ impl fmt::Display for Error {
             Channel => write!(fmt, "channel error"),
             InvalidDownloadPath => write!(fmt, "invalid download path"),
             InvalidTorrentId => write!(fmt, "invalid torrent id"),
+            InvalidFileIndex => write!(fmt, "invalid file index"),
+            InvalidPath(component) => {
+                write!(fmt, "invalid path component {:?}", component)
+            }
+            InvalidConf { id: Some(id), error } => {
+                write!(fmt, "torrent {} invalid conf: {}", id, error)
+            }
+            InvalidConf { id: None, error } => {
+                write!(fmt, "invalid engine conf: {}", error)
+            }
             Io(e) => e.fmt(fmt),
+            NewTorrent { id, error } => {
+                write!(fmt, "torrent {} allocation error: {}", id, error)
+            }
+            MoveStorage { id, error } => {
+                write!(fmt, "torrent {} storage move error: {}", id, error)
+            }
+            RenameFile { id, index, error } => write!(
+                fmt,
+                "torrent {} file {} rename error: {}",
+                id, index, error
+            ),
+            Session(e) => write!(fmt, "session error: {}", e),
             Torrent { id, error } => {
                 write!(fmt, "torrent {} error: {}", id, error)
             }
@@ -70,6 +120,10 @@ impl std::error::Error for Error {
         use Error::*;
         match self {
             Io(e) => Some(e),
+            NewTorrent { error, .. } => Some(error),
+            MoveStorage { error, .. } => Some(error),
+            RenameFile { error, .. } => Some(error),
+            Session(e) => Some(e),
             _ => None,
         }
     }
@@ -83,6 +137,12 @@ impl From<IoError> for Error {
     }
 }
 
+impl From<SessionError> for Error {
+    fn from(e: SessionError) -> Self {
+        Self::Session(e)
+    }
+}
+
 impl<T> From<SendError<T>> for Error {
     fn from(_: SendError<T>) -> Self {
         Self::Channel
@@ -87,6 +87,7 @@
 //!         metainfo,
 //!         // tell the engine to assign a randomly chosen free port
 //!         listen_addr: None,
+//!         storage: None,
 //!         mode: Mode::Download { seeds: Vec::new() },
 //!         conf: None,
 //!     })?;
@@ -129,7 +130,15 @@
 //! seeding--it's indefinite until the user stops it.
 //!
 //! Therefore the application must make sure to provide its own way of stopping
-//! the download.
+//! the download, or set [seeding goals](crate::conf::SeedGoalConf) (share
+//! ratio, seed time or idle time) after which the torrent stops by itself.
+//!
+//! # Restarting the engine
+//!
+//! By default the engine forgets its torrents when it's shut down. To have it
+//! restore them the next time it's spawned, set a [session
+//! file](crate::conf::EngineConf::session_path). See the [`session`] module
+//! for what is saved.
 
 // needed by the `select!` macro reaching the default recursion limit
 #![recursion_limit = "256"]
@@ -139,11 +148,11 @@ extern crate serde_derive;
 
 use std::{
     fmt,
-    ops::Deref,
     sync::atomic::{AtomicU32, Ordering},
-    sync::Arc,
 };
 
+use bytes::Bytes;
+
 
 pub use storage_info::FileInfo;
 
@@ -160,6 +169,8 @@ pub mod metainfo;
 pub mod peer;
 mod piece_picker;
 pub mod prelude;
+pub mod session;
+pub mod storage;
 pub mod storage_info;
 pub mod torrent;
 mod tracker;
@@ -297,12 +308,12 @@ pub(crate) struct Block {
     /// The zero-based byte offset into the piece.
     pub offset: u32,
     /// The actual raw data of the block.
-    pub data: BlockData,
+    pub data: CachedBlock,
 }
 
 impl Block {
     /// Constructs a new block based on the metadata and data.
-    pub fn new(info: BlockInfo, data: impl Into<BlockData>) -> Self {
+    pub fn new(info: BlockInfo, data: impl Into<CachedBlock>) -> Self {
         Self {
             piece_index: info.piece_index,
             offset: info.offset,
@@ -320,59 +331,15 @@ impl Block {
     }
 }
 
-/// Abstracts over the block data type.
+/// Blocks are shared between the peer sessions, the disk task's write buffer
+/// and its cache, so they're reference counted buffers, which are cloned and
+/// sliced without copying the data. Even if a block is evicted from the
+/// cache, a peer still using it still has a valid reference to it.
 ///
-/// A block may be just a normal byte buffer, or it may be a reference into
-/// a cache.
-// after – single derive(Clone)
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) enum BlockData {
-    Owned(Vec<u8>),
-    Cached(CachedBlock),
-}
-
-
-/// Blocks are cached in memory and are shared between the disk task and peer
-/// session tasks. Therefore we use atomic reference counting to make sure that
-/// even if a block is evicted from cache, the peer still using it still has
-/// a valid reference to it.
-pub(crate) type CachedBlock = Arc<Vec<u8>>;
-
-impl BlockData {
-    /// Returns the raw block if it's owned.
-    ///
-    /// # Panics
-    ///
-    /// This method panics if the block is not owned and is in the cache.
-    pub fn into_owned(self) -> Vec<u8> {
-        match self {
-            Self::Owned(b) => b,
-            _ => panic!("cannot move block out of cache"),
-        }
-    }
-}
-
-impl Deref for BlockData {
-    type Target = [u8];
-    fn deref(&self) -> &[u8] {
-        match self {
-            Self::Owned(b) => b.as_ref(),
-            Self::Cached(b) => b.as_ref(),
-        }
-    }
-}
-
-impl From<Vec<u8>> for BlockData {
-    fn from(b: Vec<u8>) -> Self {
-        Self::Owned(b)
-    }
-}
-
-impl From<CachedBlock> for BlockData {
-    fn from(b: CachedBlock) -> Self {
-        Self::Cached(b)
-    }
-}
+/// A block received from a peer refers into the buffer it was read into from
+/// the socket, and a block read from disk refers into a buffer from the disk
+/// cache's pool, or into a file mapped into memory.
+pub(crate) type CachedBlock = Bytes;
 
 #[cfg(test)]
 mod tests {
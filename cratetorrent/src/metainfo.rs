@@ -3,12 +3,14 @@
 
 use std::{
     fmt,
-    path::{Path, PathBuf},
+    ops::Range,
+    path::{Component, Path, PathBuf},
 };
 
 use reqwest::Url;
+use sha1::{Digest, Sha1};
 
-use crate::{FileInfo, Sha1Hash};
+use crate::{storage_info::FileAttrs, FileInfo, Sha1Hash};
 
 pub use serde_bencode::Error as BencodeError;
 
@@ -26,6 +28,11 @@ pub enum MetainfoError {
     InvalidPieces,
     /// The tracker URL is not a valid URL.
     InvalidTrackerUrl,
+    /// The torrent name or a file path contains a component that could be
+    /// used to escape the download directory or is otherwise not a valid file
+    /// name (e.g. `..`, an absolute path, an empty or a reserved name). Holds
+    /// the offending component.
+    InvalidPath(String),
 }
 
 impl From<BencodeError> for MetainfoError {
@@ -48,6 +55,9 @@ impl fmt::Display for MetainfoError {
             InvalidMetainfo => write!(f, "invalid metainfo"),
             InvalidPieces => write!(f, "invalid pieces"),
             InvalidTrackerUrl => write!(f, "invalid tracker URL"),
+            InvalidPath(component) => {
+                write!(f, "invalid path component {:?}", component)
+            }
         }
     }
 }
@@ -82,6 +92,13 @@ pub struct Metainfo {
     /// The tier information is not currently present in this field as
     /// cratetorrent doesn't use it. In the future it may be added.
     pub trackers: Vec<Url>,
+    /// The bencoded `info` dictionary, exactly as it appeared in the metainfo
+    /// file, of which the info hash is the hash.
+    ///
+    /// Unlike the parsed fields, this includes the keys that cratetorrent
+    /// doesn't use, so it can be served to peers or written back to
+    /// a metainfo file without changing the torrent's identity.
+    pub raw_info: Vec<u8>,
 }
 
 impl Metainfo {
@@ -96,31 +113,96 @@ impl Metainfo {
         // verify it afterwards
         let metainfo: raw::Metainfo = serde_bencode::from_bytes(buf)?;
 
+        let mut trackers = Vec::new();
+        if !metainfo.announce_list.is_empty() {
+            let tracker_count = metainfo
+                .announce_list
+                .iter()
+                .map(|t| t.len())
+                .sum::<usize>()
+                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
+            trackers.reserve(tracker_count);
+
+            for tier in metainfo.announce_list.iter() {
+                for tracker in tier.iter() {
+                    let url = Url::parse(tracker)?;
+                    // the tracker may be over UDP, which we don't support (yet)
+                    if url.scheme() == "http" || url.scheme() == "https" {
+                        trackers.push(url);
+                    }
+                }
+            }
+        } else if let Some(tracker) = &metainfo.announce {
+            let url = Url::parse(tracker)?;
+            if url.scheme() == "http" || url.scheme() == "https" {
+                trackers.push(url);
+            }
+        }
+
+        if trackers.is_empty() {
+            log::warn!("No HTTP trackers in metainfo");
+        }
+
+        // the info hash is created from the original encoding of the info
+        // dictionary, as re-encoding the parsed fields would drop the ones we
+        // don't know about
+        let raw_info = buf[find_info_dict(buf)?].to_vec();
+        Self::from_info(metainfo.info, raw_info, trackers)
+    }
+
+    /// Parses the torrent's bencoded info dictionary on its own, e.g. as
+    /// saved in the session, with the trackers kept outside of it.
+    ///
+    /// It's validated in the same way as the info dictionary of
+    /// a metainfo file.
+    pub(crate) fn from_raw_info(
+        raw_info: Vec<u8>,
+        trackers: Vec<Url>,
+    ) -> Result<Self> {
+        let info: raw::Info = serde_bencode::from_bytes(&raw_info)?;
+        Self::from_info(info, raw_info, trackers)
+    }
+
+    /// Verifies the parsed info dictionary, whose original encoding is
+    /// `raw_info`.
+    fn from_info(
+        info: raw::Info,
+        raw_info: Vec<u8>,
+        trackers: Vec<Url>,
+    ) -> Result<Self> {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
         // must be a multiple of 20
-        if metainfo.info.pieces.len() % 20 != 0 {
+        if info.pieces.len() % 20 != 0 {
             return Err(MetainfoError::InvalidPieces);
         }
 
+        // the name is the path of the file or the directory of the archive in
+        // the download directory, so it needs to be a valid file name just
+        // like the components of the files' paths
+        validate_path_component(&info.name)?;
+
         // verify download structure and build up files metadata
         let mut files = Vec::new();
-        if let Some(len) = metainfo.info.len {
-            if metainfo.info.files.is_some() {
+        if let Some(len) = info.len {
+            if info.files.is_some() {
                 log::warn!("Metainfo cannot contain both `length` and `files`");
                 return Err(MetainfoError::InvalidMetainfo);
             }
-            if len == 0 {
-                log::warn!("File length is 0");
-                return Err(MetainfoError::InvalidMetainfo);
-            }
 
             // the path of this file is just the torrent name
-            files.push(FileInfo {
-                path: metainfo.info.name.clone().into(),
+            let mut file = FileInfo {
+                path: info.name.clone().into(),
                 len,
-                torrent_offset: 0,
-            });
-        } else if let Some(raw_files) = &metainfo.info.files {
+                ..Default::default()
+            };
+            parse_file_extensions(
+                &mut file,
+                info.attr.as_deref(),
+                info.symlink_path.as_deref(),
+                info.sha1.as_deref(),
+            )?;
+            files.push(file);
+        } else if let Some(raw_files) = &info.files {
             if raw_files.is_empty() {
                 log::warn!("Metainfo files must not be empty");
                 return Err(MetainfoError::InvalidMetainfo);
@@ -131,37 +213,32 @@ impl Metainfo {
             // and sum up the file offsets in the torrent
             let mut torrent_offset = 0;
             for file in raw_files.iter() {
-                // verify that the file length is non-zero
-                if file.len == 0 {
-                    log::warn!("File {:?} length is 0", file.path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
-
-                // verify that the path is not empty
-                let path: PathBuf = file.path.iter().collect();
-                if path == PathBuf::new() {
+                // verify that the path is not empty and that joining it onto
+                // the download directory can't lead outside of it
+                if file.path.is_empty() {
                     log::warn!("Path in metainfo is empty");
-                    return Err(MetainfoError::InvalidMetainfo);
+                    return Err(MetainfoError::InvalidPath(String::new()));
                 }
-
-                // verify that the path is not absolute
-                if path.is_absolute() {
-                    log::warn!("Path {:?} is absolute", path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
-
-                // verify that the path is not the root
-                if path == Path::new("/") {
-                    log::warn!("Path {:?} is root", path);
-                    return Err(MetainfoError::InvalidMetainfo);
+                for component in file.path.iter() {
+                    validate_path_component(component)?;
                 }
+                let path: PathBuf = file.path.iter().collect();
 
-                // file is now verified, we can collect it
-                files.push(FileInfo {
+                let mut file_info = FileInfo {
                     path,
                     torrent_offset,
                     len: file.len,
-                });
+                    ..Default::default()
+                };
+                parse_file_extensions(
+                    &mut file_info,
+                    file.attr.as_deref(),
+                    file.symlink_path.as_deref(),
+                    file.sha1.as_deref(),
+                )?;
+
+                // file is now verified, we can collect it
+                files.push(file_info);
 
                 // advance offset for next file
                 torrent_offset += file.len;
@@ -171,46 +248,21 @@ impl Metainfo {
             return Err(MetainfoError::InvalidMetainfo);
         }
 
-        let mut trackers = Vec::new();
-        if !metainfo.announce_list.is_empty() {
-            let tracker_count = metainfo
-                .announce_list
-                .iter()
-                .map(|t| t.len())
-                .sum::<usize>()
-                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
-            trackers.reserve(tracker_count);
-
-            for tier in metainfo.announce_list.iter() {
-                for tracker in tier.iter() {
-                    let url = Url::parse(tracker)?;
-                    // the tracker may be over UDP, which we don't support (yet)
-                    if url.scheme() == "http" || url.scheme() == "https" {
-                        trackers.push(url);
-                    }
-                }
-            }
-        } else if let Some(tracker) = &metainfo.announce {
-            let url = Url::parse(tracker)?;
-            if url.scheme() == "http" || url.scheme() == "https" {
-                trackers.push(url);
-            }
-        }
-
-        if trackers.is_empty() {
-            log::warn!("No HTTP trackers in metainfo");
+        // symlinks have no data, but there must be something to download
+        if files.iter().all(|file| file.len == 0) {
+            log::warn!("Torrent has no data");
+            return Err(MetainfoError::InvalidMetainfo);
         }
 
-        // create info hash as a last step
-        let info_hash = metainfo.create_info_hash()?;
-
+        let info_hash = Sha1::digest(&raw_info).into();
         Ok(Self {
-            name: metainfo.info.name,
+            name: info.name,
             info_hash,
-            pieces: metainfo.info.pieces,
-            piece_len: metainfo.info.piece_len,
+            pieces: info.pieces,
+            piece_len: info.piece_len,
             files,
             trackers,
+            raw_info,
         })
     }
 
@@ -233,6 +285,167 @@ impl Metainfo {
     }
 }
 
+/// Validates the optional [BEP 47](http://bittorrent.org/beps/bep_0047.html)
+/// fields of the file and sets them in its info.
+fn parse_file_extensions(
+    file: &mut FileInfo,
+    attr: Option<&str>,
+    symlink_path: Option<&[String]>,
+    sha1: Option<&[u8]>,
+) -> Result<()> {
+    file.attrs = attr.map(FileAttrs::from).unwrap_or_default();
+
+    if file.attrs.symlink {
+        // the target is relative to the torrent's directory, which it must
+        // not lead out of either
+        let target = match symlink_path {
+            Some(target) if !target.is_empty() => target,
+            _ => {
+                log::warn!("Symlink {:?} has no target", file.path);
+                return Err(MetainfoError::InvalidPath(String::new()));
+            }
+        };
+        for component in target.iter() {
+            validate_path_component(component)?;
+        }
+        if file.len != 0 {
+            log::warn!("Symlink {:?} has data", file.path);
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+        file.symlink_path = Some(target.iter().collect());
+    } else if file.len == 0 {
+        log::warn!("File {:?} length is 0", file.path);
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+
+    if let Some(sha1) = sha1 {
+        let sha1 = sha1.try_into().map_err(|_| {
+            log::warn!("File {:?} SHA-1 hash is invalid", file.path);
+            MetainfoError::InvalidMetainfo
+        })?;
+        file.sha1 = Some(sha1);
+    }
+
+    Ok(())
+}
+
+/// Returns the byte range of the `info` value in the bencoded metainfo.
+fn find_info_dict(buf: &[u8]) -> Result<Range<usize>> {
+    if buf.first() != Some(&b'd') {
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+    // walk the top-level dictionary's key-value pairs until the info key
+    let mut pos = 1;
+    while buf.get(pos).is_some_and(|&b| b != b'e') {
+        let key_end = bencode_value_end(buf, pos)?;
+        let value_end = bencode_value_end(buf, key_end)?;
+        if &buf[pos..key_end] == b"4:info" {
+            if buf[key_end] != b'd' {
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+            return Ok(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    log::warn!("No `info` key present in metainfo");
+    Err(MetainfoError::InvalidMetainfo)
+}
+
+/// Returns the end of the bencoded value starting at the position, without
+/// decoding it.
+fn bencode_value_end(buf: &[u8], start: usize) -> Result<usize> {
+    let find = |byte, from: usize| {
+        buf.get(from..)
+            .and_then(|rest| rest.iter().position(|&b| b == byte))
+            .map(|i| from + i)
+            .ok_or(MetainfoError::InvalidMetainfo)
+    };
+    match buf.get(start) {
+        // integer: i<digits>e
+        Some(b'i') => Ok(find(b'e', start + 1)? + 1),
+        // list or dictionary: l<values>e or d<key-value pairs>e
+        Some(b'l' | b'd') => {
+            let mut pos = start + 1;
+            while buf.get(pos) != Some(&b'e') {
+                pos = bencode_value_end(buf, pos)?;
+            }
+            Ok(pos + 1)
+        }
+        // string: <length>:<bytes>
+        Some(b'0'..=b'9') => {
+            let colon = find(b':', start)?;
+            let len: usize = std::str::from_utf8(&buf[start..colon])
+                .ok()
+                .and_then(|len| len.parse().ok())
+                .ok_or(MetainfoError::InvalidMetainfo)?;
+            colon
+                .checked_add(1 + len)
+                .filter(|&end| end <= buf.len())
+                .ok_or(MetainfoError::InvalidMetainfo)
+        }
+        _ => Err(MetainfoError::InvalidMetainfo),
+    }
+}
+
+/// Windows device names, which can't be used as file names (even with an
+/// extension) on that platform.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+    "COM7", "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+    "LPT7", "LPT8", "LPT9",
+];
+
+/// Verifies that the path component from the metainfo is a single, plain file
+/// name.
+///
+/// Torrents are portable so the check is stricter than what the current
+/// platform requires: separators of either platform, NUL bytes and reserved
+/// device names are rejected everywhere.
+pub(crate) fn validate_path_component(component: &str) -> Result<()> {
+    // a plain file name is parsed as a single normal component that is the
+    // same as the input, which rules out empty names, `.`, `..`, roots,
+    // prefixes and embedded separators
+    let mut components = Path::new(component).components();
+    let is_normal = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(name)), None) if name == component
+    );
+    let stem = component.split('.').next().unwrap_or_default();
+    let is_reserved = RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()));
+    if !is_normal || is_reserved || component.contains(['/', '\\', '\0']) {
+        log::warn!("Invalid path component {:?} in metainfo", component);
+        return Err(MetainfoError::InvalidPath(component.to_string()));
+    }
+    Ok(())
+}
+
+/// Verifies that the path, e.g. a new name given to a file of the torrent, is
+/// a relative path of plain file names, which can't lead outside of the
+/// torrent's directory.
+pub(crate) fn validate_path(path: &Path) -> Result<()> {
+    if path.as_os_str().is_empty() {
+        log::warn!("Path is empty");
+        return Err(MetainfoError::InvalidPath(String::new()));
+    }
+    // the components of a path are normalized, so for instance `a//b` is
+    // split into `a` and `b`, but a root or `..` is kept as a component
+    for component in path.components() {
+        let component = component.as_os_str();
+        match component.to_str() {
+            Some(component) => validate_path_component(component)?,
+            None => {
+                log::warn!("Path component {:?} is not UTF-8", component);
+                return Err(MetainfoError::InvalidPath(
+                    component.to_string_lossy().into_owned(),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 impl fmt::Debug for Metainfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metainfo")
@@ -241,6 +454,7 @@ impl fmt::Debug for Metainfo {
             .field("pieces", &"<pieces...>")
             .field("piece_len", &self.piece_len)
             .field("structure", &self.files)
+            .field("raw_info", &"<raw info...>")
             .finish()
     }
 }
@@ -253,10 +467,6 @@ mod raw {
     //! [`Metainfo`], but with semantic requirements encoded in the type
     //! system.
 
-    use sha1::{Digest, Sha1};
-
-    use super::{Result, Sha1Hash};
-
     #[derive(Debug, Deserialize)]
     pub struct Metainfo {
         pub info: Info,
@@ -266,18 +476,9 @@ mod raw {
         pub announce_list: Vec<Vec<String>>,
     }
 
-    impl Metainfo {
-        /// Creates a SHA-1 hash of the encoded `info` field's value.
-        pub fn create_info_hash(&self) -> Result<Sha1Hash> {
-            let info = serde_bencode::to_bytes(&self.info)?;
-            let digest = Sha1::digest(&info);
-            let mut info_hash = [0; 20];
-            info_hash.copy_from_slice(&digest);
-            Ok(info_hash)
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
+    /// Only the fields used by cratetorrent. The info hash is computed from
+    /// the raw encoding of the dictionary, so the rest can be safely ignored.
+    #[derive(Debug, Deserialize)]
     pub struct Info {
         pub name: String,
         #[serde(with = "serde_bytes")]
@@ -287,18 +488,201 @@ mod raw {
         #[serde(rename = "length")]
         pub len: Option<u64>,
         pub files: Option<Vec<File>>,
-        /// This is not currently used but needs to be kept in here so that we
-        /// can encode back a valid info hash for hashing.
-        pub private: Option<u8>,
+        /// The BEP 47 fields of a single file torrent's file.
+        pub attr: Option<String>,
+        #[serde(rename = "symlink path")]
+        pub symlink_path: Option<Vec<String>>,
+        #[serde(default, with = "serde_bytes")]
+        pub sha1: Option<Vec<u8>>,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Deserialize)]
     pub struct File {
         pub path: Vec<String>,
         #[serde(rename = "length")]
         pub len: u64,
+        pub attr: Option<String>,
+        #[serde(rename = "symlink path")]
+        pub symlink_path: Option<Vec<String>>,
+        #[serde(default, with = "serde_bytes")]
+        pub sha1: Option<Vec<u8>>,
     }
 }
 
-// TODO(https://github.com/mandreyel/cratetorrent/issues/8): add metainfo
-// parsing tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a bencode string.
+    fn bstr(s: &str) -> String {
+        format!("{}:{}", s.len(), s)
+    }
+
+    /// Returns an encoded archive metainfo with the name and a file of one
+    /// byte for each path.
+    fn archive(name: &str, paths: &[&[&str]]) -> Vec<u8> {
+        let files: String = paths
+            .iter()
+            .map(|path| {
+                let path: String = path.iter().map(|c| bstr(c)).collect();
+                format!("d6:lengthi1e4:pathl{}ee", path)
+            })
+            .collect();
+        format!(
+            "d4:infod5:filesl{}e4:name{}12:piece lengthi16384e6:pieces{}ee",
+            files,
+            bstr(name),
+            bstr(&"0".repeat(20)),
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_valid_paths() {
+        let buf = archive("torrent", &[&["a", "b.txt"], &["..c"], &["d e"]]);
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        let paths: Vec<_> = metainfo.files.iter().map(|f| &f.path).collect();
+        assert_eq!(
+            paths,
+            [Path::new("a/b.txt"), Path::new("..c"), Path::new("d e")]
+        );
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        let invalid_paths: &[(&[&str], &str)] = &[
+            (&[], ""),
+            (&[""], ""),
+            (&["a", ""], ""),
+            (&["."], "."),
+            (&[".."], ".."),
+            (&["a", "..", "..", "b"], ".."),
+            (&["/etc", "passwd"], "/etc"),
+            (&["/"], "/"),
+            (&["a/../../b"], "a/../../b"),
+            (&["a/"], "a/"),
+            (&["..\\b"], "..\\b"),
+            (&["a\0b"], "a\0b"),
+            (&["con"], "con"),
+            (&["Aux.txt"], "Aux.txt"),
+            (&["a", "LPT1"], "LPT1"),
+        ];
+        for (path, component) in invalid_paths {
+            let buf = archive("torrent", &[path]);
+            match Metainfo::from_bytes(&buf) {
+                Err(MetainfoError::InvalidPath(c)) => assert_eq!(c, *component),
+                res => panic!("path {:?} not rejected: {:?}", path, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_validate_path() {
+        assert!(validate_path(Path::new("a/b.txt")).is_ok());
+        assert!(validate_path(Path::new("..c")).is_ok());
+        let invalid_paths = [
+            ("", ""),
+            ("/etc/passwd", "/"),
+            ("a/../../b", ".."),
+            ("./a", "."),
+            ("a/con", "con"),
+        ];
+        for (path, component) in invalid_paths {
+            match validate_path(Path::new(path)) {
+                Err(MetainfoError::InvalidPath(c)) => assert_eq!(c, component),
+                res => panic!("path {:?} not rejected: {:?}", path, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_info_hash_of_raw_info() {
+        // the info dictionary contains keys that are not parsed, and an
+        // announce after it
+        let info = format!(
+            "d6:lengthi1e4:name4:file12:piece lengthi16384e6:pieces{}\
+            7:privatei1e6:source3:abc8:x-vendord1:ai1eee",
+            bstr(&"0".repeat(20)),
+        );
+        let buf = format!("d4:info{}8:announce15:http://tracker/e", info);
+        let metainfo = Metainfo::from_bytes(buf.as_bytes()).unwrap();
+        assert_eq!(metainfo.raw_info, info.as_bytes());
+        let info_hash: Sha1Hash = Sha1::digest(info.as_bytes()).into();
+        assert_eq!(metainfo.info_hash, info_hash);
+        assert_eq!(metainfo.trackers.len(), 1);
+    }
+
+    #[test]
+    fn test_find_info_dict() {
+        // a string value that contains the key shouldn't be mistaken for it
+        let buf = b"d1:a6:4:info4:infod1:bi2eee";
+        assert_eq!(find_info_dict(buf).unwrap(), 18..26);
+        // truncated or otherwise invalid encodings
+        for buf in [&b"d4:infod1:bi2e"[..], b"d4:info", b"d4:infoi1ee", b"de"] {
+            assert!(matches!(
+                find_info_dict(buf),
+                Err(MetainfoError::InvalidMetainfo)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_file_attrs() {
+        let file = |attr: &str, len: u32, extra: &str| {
+            format!(
+                "d4:attr{}6:lengthi{}e4:pathl1:ae{}e",
+                bstr(attr),
+                len,
+                extra
+            )
+        };
+        let metainfo = |files: &[String]| {
+            format!(
+                "d4:infod5:filesl{}e4:name1:t12:piece lengthi16e6:pieces{}ee",
+                files.concat(),
+                bstr(&"0".repeat(20)),
+            )
+            .into_bytes()
+        };
+
+        let sha1 = format!("4:sha1{}", bstr(&"1".repeat(20)));
+        let buf = metainfo(&[
+            file("x", 10, &sha1),
+            file("p", 6, ""),
+            file("hl", 0, "12:symlink pathl1:b1:ce"),
+        ]);
+        let files = Metainfo::from_bytes(&buf).unwrap().files;
+        assert!(files[0].attrs.executable && !files[0].attrs.padding);
+        assert_eq!(files[0].sha1, Some([b'1'; 20]));
+        assert!(files[1].attrs.padding);
+        assert_eq!(files[1].torrent_offset, 10);
+        assert!(files[2].attrs.hidden && files[2].attrs.symlink);
+        assert_eq!(files[2].symlink_path, Some(PathBuf::from("b/c")));
+        assert_eq!(files[2].attrs.to_string(), "hl");
+
+        // symlinks must have a target inside the torrent and no data, and
+        // the hash must be a SHA-1 hash
+        let invalid_files = [
+            file("l", 0, ""),
+            file("l", 0, "12:symlink pathl2:..1:be"),
+            file("l", 1, "12:symlink pathl1:be"),
+            file("", 0, ""),
+            file("", 1, "4:sha13:abc"),
+        ];
+        for invalid_file in invalid_files {
+            let buf = metainfo(&[file("", 1, ""), invalid_file]);
+            assert!(Metainfo::from_bytes(&buf).is_err());
+        }
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        for name in ["", "..", "/tmp", "a/b", "NUL"] {
+            let buf = archive(name, &[&["a"]]);
+            assert!(matches!(
+                Metainfo::from_bytes(&buf),
+                Err(MetainfoError::InvalidPath(_))
+            ));
+        }
+    }
+}
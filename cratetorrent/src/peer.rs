@@ -15,9 +15,10 @@ use std::{
     time::{Duration, Instant},
 };
 
-use futures::{stream::SplitSink, SinkExt, StreamExt};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
 use tokio::{
-    net::TcpStream,
+    net::{tcp::OwnedWriteHalf, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         RwLock,
@@ -43,6 +44,7 @@ use crate::{
 // These imports are for submodules of peer.rs
 use codec::*;
 use error::*;
+use sink::PeerSink;
 use state::*;
 
 pub use state::{ConnectionState, SessionState};
@@ -54,6 +56,8 @@ pub type Bitfield = BitVec<usize, Msb0>;
 // These are submodules of the peer module (peer.rs)
 mod codec;
 pub mod error;
+mod extension;
+mod sink;
 mod state;
 
 /// The most essential information of a peer session that is sent to torrent
@@ -83,6 +87,9 @@ pub(crate) enum Command {
         /// Tell the session to enter endgame mode.
         in_endgame: bool,
     },
+    /// Notifies this peer session that we no longer have the piece, as its
+    /// data on disk was changed.
+    PieceLoss(PieceIndex),
     /// Eventually shut down the peer session.
     Shutdown,
     #[cfg(feature = "ratio")]
@@ -113,8 +120,8 @@ enum Direction {
 ///
 /// # Important
 ///
-/// For now only the BitTorrent v1 specification is implemented, without any
-/// extensions.
+/// For now only the BitTorrent v1 specification is implemented, and of the
+/// extensions, only [lt_donthave](extension).
 pub(crate) struct PeerSession {
     /// Shared information of the torrent.
     torrent: Arc<TorrentContext>,
@@ -174,6 +181,9 @@ pub(crate) struct PeerSession {
     /// or when the peer cancels it. If a peer sends a request and cancels it
     /// before the disk read is done, the read block is dropped.
     incoming_requests: HashSet<BlockInfo>,
+    /// The pieces we told the peer we no longer have, which are announced
+    /// again once regained, even if the peer has them.
+    lost_pieces: HashSet<PieceIndex>,
 }
 
 /// Information about the peer we're connected to.
@@ -193,6 +203,12 @@ pub(super) struct PeerInfo {
     /// This is equivalent to `self.pieces.count_ones()` and is updated every
     /// time the peer sends us an announcement of a new piece.
     pub piece_count: usize,
+    /// Whether the peer supports the extension protocol, as advertised in its
+    /// handshake.
+    pub supports_extensions: bool,
+    /// The id with which the peer accepts lt_donthave messages, if it
+    /// supports them, as advertised in its extension handshake.
+    pub dont_have_id: Option<u8>,
 }
 
 impl PeerSession {
@@ -202,7 +218,7 @@ impl PeerSession {
         addr: SocketAddr,
     ) -> (Self, Sender) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let piece_count = torrent.storage.piece_count;
+        let piece_count = torrent.storage.borrow().piece_count;
         let log_target = format!("cratetorrent::peer [{}][{}]", torrent.id, addr);
 
         (
@@ -215,6 +231,8 @@ impl PeerSession {
                     pieces: BitVec::repeat(false, piece_count),
                     piece_count: 0,
                     id: Default::default(),
+                    supports_extensions: false,
+                    dont_have_id: None,
                 },
                 ctx: SessionContext {
                     log_target,
@@ -222,6 +240,7 @@ impl PeerSession {
                 },
                 outgoing_requests: HashSet::new(),
                 incoming_requests: HashSet::new(),
+                lost_pieces: HashSet::new(),
             },
             cmd_tx,
         )
@@ -229,7 +248,8 @@ impl PeerSession {
 
     #[cfg(feature = "ratio")]
     fn check_ratio(&self) -> bool {
-        if let Some(max_ratio) = self.torrent.config.max_ratio {
+        let max_ratio = self.torrent.conf.borrow().max_ratio;
+        if let Some(max_ratio) = max_ratio {
             let downloaded = self.ctx.counters.payload.down.total();
             if downloaded > 0 {
                 let uploaded = self.ctx.counters.payload.up.total();
@@ -289,6 +309,13 @@ impl PeerSession {
                 in_endgame
             );
             }
+            Command::PieceLoss(index) => {
+                log::info!(
+                target: &self.ctx.log_target,
+                "Piece {} lost",
+                index
+            );
+            }
             Command::Shutdown => {
                 log::info!(
                 target: &self.ctx.log_target,
@@ -364,6 +391,8 @@ impl PeerSession {
 
             // set the peer's id
             self.peer.id = Some(peer_handshake.peer_id);
+            self.peer.supports_extensions =
+                peer_handshake.supports_extensions();
 
             // if this is an inbound connection, we reply with the handshake
             if direction == Direction::Inbound {
@@ -462,8 +491,15 @@ impl PeerSession {
         self.ctx.connected_time = Some(Instant::now());
 
         // split the sink and stream so that we can pass the sink while holding
-        // a reference to the stream in the loop
-        let (mut sink, mut stream) = socket.split();
+        // a reference to the stream in the loop, keeping the bytes already in
+        // the codec's buffers (the stream is built from its parts, as only
+        // then are the buffered messages decoded before the socket is read)
+        let parts = socket.into_parts();
+        let (read, write) = parts.io.into_split();
+        let mut read_parts = FramedParts::new(read, PeerCodec);
+        read_parts.read_buf = parts.read_buf;
+        let mut stream = Framed::from_parts(read_parts);
+        let mut sink = PeerSink::new(write, parts.write_buf);
 
         {
             let piece_picker_guard = self.torrent.piece_picker.read().await;
@@ -475,19 +511,42 @@ impl PeerSession {
             }
         }
 
+        if self.peer.supports_extensions {
+            log::info!(target: &self.ctx.log_target, "Sending extension handshake");
+            let msg = Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: extension::handshake(),
+            };
+            self.ctx.counters.protocol.up += msg.protocol_len();
+            sink.send(msg).await?;
+        }
+
         let mut tick_timer = time::interval(Duration::from_secs(1));
+        let mut write_buf_full_rx = self.torrent.write_buf.subscribe();
 
         loop {
             tokio::select! {
             now = tick_timer.tick() => {
                 self.tick(&mut sink, now.into_std()).await?;
             }
+            Ok(()) = write_buf_full_rx.changed() => {
+                // once the disk has caught up with writing the buffered
+                // blocks, we may start new pieces
+                if !*write_buf_full_rx.borrow_and_update() {
+                    self.make_requests(&mut sink).await?;
+                }
+            }
             Some(msg) = stream.next() => {
                 let msg = msg?;
 
                 if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
                     if let Message::Bitfield(bitfield) = msg {
                         self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                    } else if let Message::Extended { .. } = msg {
+                        // the extension handshake may be sent before the
+                        // bitfield
+                        self.handle_msg(&mut sink, msg).await?;
+                        continue;
                     } else {
                         self.handle_msg(&mut sink, msg).await?;
                     }
@@ -520,6 +579,9 @@ impl PeerSession {
                         self.ctx.in_endgame = in_endgame;
                         self.handle_piece_completion(&mut sink, index).await?;
                     }
+                    Command::PieceLoss(index) => {
+                        self.handle_piece_loss(&mut sink, index).await?;
+                    }
                     Command::Shutdown => {
                         log::info!(
                             target: &self.ctx.log_target,
@@ -535,7 +597,8 @@ impl PeerSession {
                             let uploaded = session_info.counters.payload.up.total();
                             let ratio = uploaded as f64 / downloaded as f64;
                             
-                            if let Some(max_ratio) = self.torrent.config.max_ratio {
+                            let max_ratio = self.torrent.conf.borrow().max_ratio;
+                            if let Some(max_ratio) = max_ratio {
                                 if ratio >= max_ratio {
                                     log::info!(
                                         target: &self.ctx.log_target,
@@ -564,7 +627,7 @@ impl PeerSession {
     /// target request queue size.
     async fn tick(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         now: Instant,
     ) -> Result<()> {
         // if we haven't become interested in each other for too long,
@@ -644,7 +707,8 @@ impl PeerSession {
     #[cfg(feature = "ratio")]
     async fn handle_ratio_check(&mut self) {
         // Get the current ratio limit from config
-        if let Some(max_ratio) = self.torrent.config.max_ratio {
+        let max_ratio = self.torrent.conf.borrow().max_ratio;
+        if let Some(max_ratio) = max_ratio {
             let downloaded = self.ctx.counters.payload.down.total();
             if downloaded > 0 {
                 let uploaded = self.ctx.counters.payload.up.total();
@@ -665,7 +729,7 @@ impl PeerSession {
     /// Times out the peer if it hasn't sent a request in too long.
     async fn check_request_timeout(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
     ) -> Result<()> {
         if let Some(last_outgoing_request_time) =
             self.ctx.last_outgoing_request_time
@@ -740,7 +804,7 @@ impl PeerSession {
     /// (currently only the bitfield message).
     async fn handle_bitfield_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         mut bitfield: Bitfield,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Handling peer Bitfield message");
@@ -759,7 +823,7 @@ impl PeerSession {
         // According to the spec if the remainder contains any non-zero
         // bits, we need to abort the connection. Not sure if this is too
         // strict, there doesn't seem much harm in it so we skip the check.
-        bitfield.resize(self.torrent.storage.piece_count, false);
+        bitfield.resize(self.torrent.storage.borrow().piece_count, false);
 
         // register peer's pieces with piece picker and determine interest in it
         let is_interested = self
@@ -770,14 +834,14 @@ impl PeerSession {
             .register_peer_pieces(&bitfield);
         self.peer.pieces = bitfield;
         self.peer.piece_count = self.peer.pieces.count_ones();
-        if self.peer.piece_count == self.torrent.storage.piece_count {
+        if self.peer.piece_count == self.torrent.storage.borrow().piece_count {
             log::info!(target: &self.ctx.log_target, "Peer is a seed, interested: {}", is_interested);
         } else {
             log::info!(
                 target: &self.ctx.log_target,
                 "Peer has {}/{} pieces, interested: {}",
                 self.peer.piece_count,
-                self.torrent.storage.piece_count,
+                self.torrent.storage.borrow().piece_count,
                 is_interested
             );
         }
@@ -789,7 +853,7 @@ impl PeerSession {
     /// Handles messages from peer that are expected in the `Connected` state.
     async fn handle_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         msg: Message,
     ) -> Result<()> {
         // record protocol message size
@@ -833,7 +897,7 @@ impl PeerSession {
                 if !self.ctx.state.is_peer_interested {
                     // Check for ghost leech mode when appropriate
                     #[cfg(feature = "ghostleech")]
-                    if self.torrent.config.ghost_leech {
+                    if self.torrent.conf.borrow().ghost_leech {
                         log::info!(target: &self.ctx.log_target, "Peer became interested, but we're in ghost-leech mode");
                         log::info!(target: &self.ctx.log_target, "Keeping peer choked");
                         self.ctx.update_state(|state| {
@@ -879,7 +943,7 @@ impl PeerSession {
                     offset,
                     len: data.len() as u32,
                 };
-                self.handle_block_msg(block_info, data.into_owned()).await?;
+                self.handle_block_msg(block_info, data).await?;
 
                 // we may be able to make more requests now that a block has
                 // arrived
@@ -897,6 +961,9 @@ impl PeerSession {
                 log::info!(target: &self.ctx.log_target, "Peer cancelled block {}", block_info);
                 self.incoming_requests.remove(&block_info);
             }
+            Message::Extended { id, payload } => {
+                self.handle_extended_msg(sink, id, &payload).await?;
+            }
         }
 
         Ok(())
@@ -909,7 +976,7 @@ impl PeerSession {
     /// `Status::best_request_queue_len` or the relevant section in DESIGN.md.
     async fn make_requests(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
     ) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Making requests");
 
@@ -959,6 +1026,16 @@ impl PeerSession {
             if outgoing_request_count >= target_request_queue_len {
                 break;
             }
+            // the disk write buffer only drains once the pieces in progress
+            // are complete and written to disk, so while it's full, only the
+            // blocks of those are requested
+            if self.torrent.write_buf.is_full() {
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Cannot pick new pieces while disk write buffer is full"
+                );
+                break;
+            }
             let to_request_count =
                 target_request_queue_len - outgoing_request_count;
 
@@ -971,7 +1048,7 @@ impl PeerSession {
 
                 let mut download = PieceDownload::new(
                     index,
-                    self.torrent.storage.piece_len(index),
+                    self.torrent.storage.borrow().piece_len(index),
                 );
                 download.pick_blocks(
                     to_request_count,
@@ -1023,7 +1100,7 @@ impl PeerSession {
     async fn handle_block_msg(
         &mut self,
         block_info: BlockInfo,
-        data: Vec<u8>,
+        data: Bytes,
     ) -> Result<()> {
         self.outgoing_requests.remove(&block_info);
 
@@ -1108,9 +1185,17 @@ impl PeerSession {
             return Ok(());
         }
 
+        // the peer may not have learned yet that we lost the piece
+        if !self.torrent.piece_picker.read().await.own_pieces()
+            [block_info.piece_index]
+        {
+            log::warn!(target: &self.ctx.log_target, "Peer requested missing piece");
+            return Ok(());
+        }
+
         // Check for ratio enforcement when appropriate
         #[cfg(feature = "ratio")]
-        if let Some(max_r) = self.torrent.config.max_ratio {
+        if let Some(max_r) = self.torrent.conf.borrow().max_ratio {
             let stats = self.session_info();
             let up = stats.counters.payload.up.total();
             let down = stats.counters.payload.down.total();
@@ -1143,7 +1228,7 @@ impl PeerSession {
     /// request).
     async fn send_block(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         block: Block,
     ) -> Result<()> {
         let info = block.info();
@@ -1157,7 +1242,7 @@ impl PeerSession {
 
         // Check for ghost-leech mode when appropriate
         #[cfg(feature = "ghostleech")]
-        if self.torrent.config.ghost_leech {
+        if self.torrent.conf.borrow().ghost_leech {
             log::info!(
                 target: &self.ctx.log_target,
                 "Ghost-leech mode active, not sending block {}", 
@@ -1168,7 +1253,7 @@ impl PeerSession {
 
         // Check for ratio enforcement when appropriate
         #[cfg(feature = "ratio")]
-        if let Some(max_r) = self.torrent.config.max_ratio {
+        if let Some(max_r) = self.torrent.conf.borrow().max_ratio {
             let stats = self.session_info();
             let up = stats.counters.payload.up.total();
             let down = stats.counters.payload.down.total();
@@ -1202,7 +1287,7 @@ impl PeerSession {
     /// to become interested in peer and start making requests.
     async fn handle_have_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         piece_index: PieceIndex,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Peer has piece {}", piece_index);
@@ -1225,10 +1310,74 @@ impl PeerSession {
         self.update_interest(sink, is_interested).await
     }
 
+    /// Handles a message of the extension protocol: the peer's extension
+    /// handshake, or the announcement that it no longer has a piece.
+    ///
+    /// Messages of extensions we don't support are ignored.
+    async fn handle_extended_msg(
+        &mut self,
+        sink: &mut PeerSink<OwnedWriteHalf>,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<()> {
+        match id {
+            extension::HANDSHAKE_ID => {
+                let dont_have_id = extension::parse_handshake(payload)
+                    .map_err(|_| PeerError::InvalidExtensionMessage)?;
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Peer sent extension handshake, lt_donthave: {:?}",
+                    dont_have_id
+                );
+                self.peer.dont_have_id = dont_have_id;
+            }
+            extension::DONT_HAVE_ID => {
+                let piece_index = extension::parse_dont_have(payload)
+                    .ok_or(PeerError::InvalidExtensionMessage)?;
+                self.handle_dont_have_msg(sink, piece_index).await?;
+            }
+            _ => {
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Ignoring unsupported extension message {}",
+                    id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles the announcement that peer no longer has a piece. This may
+    /// cause us to no longer be interested in peer.
+    async fn handle_dont_have_msg(
+        &mut self,
+        sink: &mut PeerSink<OwnedWriteHalf>,
+        piece_index: PieceIndex,
+    ) -> Result<()> {
+        log::info!(target: &self.ctx.log_target, "Peer no longer has piece {}", piece_index);
+        self.validate_piece_index(piece_index)?;
+
+        if !self.peer.pieces[piece_index] {
+            return Ok(());
+        }
+
+        self.peer.pieces.set(piece_index, false);
+        self.peer.piece_count -= 1;
+
+        let mut piece_picker = self.torrent.piece_picker.write().await;
+        piece_picker.unregister_peer_piece(piece_index);
+        let own_pieces = piece_picker.own_pieces();
+        let is_interested =
+            self.peer.pieces.iter_ones().any(|index| !own_pieces[index]);
+        drop(piece_picker);
+
+        self.update_interest(sink, is_interested).await
+    }
+
     /// Checks whether we have become or stopped being interested in the peer.
     async fn update_interest(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         is_interested: bool,
     ) -> Result<()> {
         if !self.ctx.state.is_interested && is_interested {
@@ -1252,7 +1401,8 @@ impl PeerSession {
     fn validate_block_info(&self, info: &BlockInfo) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Validating {}", info);
         self.validate_piece_index(info.piece_index)?;
-        let piece_len = self.torrent.storage.piece_len(info.piece_index);
+        let piece_len =
+            self.torrent.storage.borrow().piece_len(info.piece_index);
         if info.len > 0 && info.offset + info.len <= piece_len {
             Ok(())
         } else {
@@ -1263,7 +1413,7 @@ impl PeerSession {
 
     /// Validates that the index refers to a valid piece in torrent.
     fn validate_piece_index(&self, index: PieceIndex) -> Result<()> {
-        if index < self.torrent.storage.piece_count {
+        if index < self.torrent.storage.borrow().piece_count {
             Ok(())
         } else {
             log::warn!(
@@ -1282,17 +1432,20 @@ impl PeerSession {
     /// that we need to cancel. If peer doesn't have the piece, we announce it.
     async fn handle_piece_completion(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut PeerSink<OwnedWriteHalf>,
         piece_index: PieceIndex,
     ) -> Result<()> {
-        if !self.peer.pieces[piece_index] {
+        // pieces we told the peer we lost are announced again
+        let was_lost = self.lost_pieces.remove(&piece_index);
+        if !self.peer.pieces[piece_index] || was_lost {
             log::debug!(
                 target: &self.ctx.log_target,
                 "Announcing piece {}",
                 piece_index
             );
             sink.send(Message::Have { piece_index }).await?;
-        } else {
+        }
+        if self.peer.pieces[piece_index] {
             for block in self.outgoing_requests.iter() {
                 if block.piece_index == piece_index {
                     log::info!(
@@ -1306,6 +1459,37 @@ impl PeerSession {
         }
         Ok(())
     }
+
+    /// When the torrent loses a piece because its data on disk was changed,
+    /// peer sessions are notified of it.
+    ///
+    /// If peer supports it, we tell it that we no longer have the piece, and
+    /// if peer has the piece, we may have become interested in it.
+    async fn handle_piece_loss(
+        &mut self,
+        sink: &mut PeerSink<OwnedWriteHalf>,
+        piece_index: PieceIndex,
+    ) -> Result<()> {
+        if let Some(id) = self.peer.dont_have_id {
+            log::debug!(
+                target: &self.ctx.log_target,
+                "Announcing loss of piece {}",
+                piece_index
+            );
+            let msg = Message::Extended {
+                id,
+                payload: extension::dont_have(piece_index),
+            };
+            self.ctx.counters.protocol.up += msg.protocol_len();
+            sink.send(msg).await?;
+            self.lost_pieces.insert(piece_index);
+        }
+
+        if self.peer.pieces[piece_index] {
+            self.update_interest(sink, true).await?;
+        }
+        Ok(())
+    }
 }
 
 /// After this timeout if the peers haven't become intereseted in each other,
@@ -3,10 +3,10 @@ use std::{
     io::{self, Cursor},
 };
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{Bitfield, BlockData, BlockInfo};
+use crate::{Bitfield, BlockInfo};
 
 /// Handshake message exchanged once at connection start.
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -18,10 +18,19 @@ pub(crate) struct Handshake {
 }
 
 impl Handshake {
+    /// Creates our handshake, which advertises support for the extension
+    /// protocol.
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         let mut prot = [0; 19];
         prot.copy_from_slice(PROTOCOL_STRING.as_bytes());
-        Handshake { prot, reserved: [0; 8], info_hash, peer_id }
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_BYTE] |= EXTENSION_BIT;
+        Handshake { prot, reserved, info_hash, peer_id }
+    }
+
+    /// Returns whether the peer supports the extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BYTE] & EXTENSION_BIT != 0
     }
 
     pub const fn len(&self) -> u64 {
@@ -31,6 +40,11 @@ impl Handshake {
 
 pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
+/// The reserved bit of the handshake that signals support for the extension
+/// protocol, the 20th bit from the right.
+const EXTENSION_BYTE: usize = 5;
+const EXTENSION_BIT: u8 = 0x10;
+
 /// Codec for the handshake.
 pub(crate) struct HandshakeCodec;
 
@@ -112,6 +126,7 @@ pub(crate) enum MessageId {
     Request      = 6,
     Block        = 7,
     Cancel       = 8,
+    Extended     = 20,
 }
 
 impl TryFrom<u8> for MessageId {
@@ -129,6 +144,7 @@ impl TryFrom<u8> for MessageId {
             x if x == Request as u8       => Ok(Request),
             x if x == Block as u8         => Ok(Block),
             x if x == Cancel as u8        => Ok(Cancel),
+            x if x == Extended as u8      => Ok(Extended),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "unknown message ID",
@@ -146,6 +162,7 @@ impl MessageId {
             MessageId::Request => base + 3 * 4,
             MessageId::Block   => base + 2 * 4,
             MessageId::Cancel  => base + 3 * 4,
+            MessageId::Extended => base + 1,
             _                  => base,
         }
     }
@@ -165,9 +182,12 @@ pub(crate) enum Message {
     Block {
         piece_index: usize,
         offset: u32,
-        data: BlockData,
+        data: Bytes,
     },
     Cancel(BlockInfo),
+    /// A message of the extension protocol, with the id of the extension
+    /// message, which is 0 for the extension handshake.
+    Extended { id: u8, payload: Bytes },
 }
 
 impl Message {
@@ -185,13 +205,17 @@ impl Message {
             Request(_)     => Some(MessageId::Request),
             Block { .. }   => Some(MessageId::Block),
             Cancel(_)      => Some(MessageId::Cancel),
+            Extended { .. } => Some(MessageId::Extended),
         }
     }
 
     /// Length of the protocol header (length‐prefix + ID + fixed fields).
-    /// KeepAlive counts as 1 (the zero length field).
+    /// KeepAlive counts as 1 (the zero length field). Extension messages
+    /// count in full, as they carry no payload data.
     pub fn protocol_len(&self) -> u64 {
-        if let Some(id) = self.id() {
+        if let Message::Extended { payload, .. } = self {
+            MessageId::Extended.header_len() + payload.len() as u64
+        } else if let Some(id) = self.id() {
             id.header_len()
         } else {
             // KeepAlive: 4-byte prefix (0) is already counted in header_len
@@ -201,6 +225,25 @@ impl Message {
     }
 }
 
+/// Encodes the header of a block message, without the block's data, which
+/// follows it.
+pub(crate) fn encode_block_header(
+    piece_index: usize,
+    offset: u32,
+    data_len: usize,
+    buf: &mut BytesMut,
+) -> io::Result<()> {
+    buf.put_u32(1 + 2 * 4 + data_len as u32);
+    buf.put_u8(MessageId::Block as u8);
+    buf.put_u32(
+        piece_index
+            .try_into()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+    );
+    buf.put_u32(offset);
+    Ok(())
+}
+
 impl Encoder<Message> for PeerCodec {
     type Error = io::Error;
 
@@ -215,11 +258,18 @@ impl Encoder<Message> for PeerCodec {
                 buf.put_u32(0);
             }
             Bitfield(bf) => {
-                let byte_len = bf.len() / 8;
-                buf.put_u32((1 + byte_len) as u32);
+                // the first piece is the highest bit of the first byte, and
+                // the last byte is padded with zeros
+                let bytes: Vec<u8> = bf
+                    .chunks(8)
+                    .map(|bits| {
+                        bits.iter().enumerate().fold(0, |byte, (i, bit)| {
+                            byte | ((*bit as u8) << (7 - i))
+                        })
+                    })
+                    .collect();
+                buf.put_u32((1 + bytes.len()) as u32);
                 buf.put_u8(MessageId::Bitfield as u8);
-                let bytes: Vec<u8> =
-                    bf.as_raw_slice().iter().map(|&w| w as u8).collect();
                 buf.extend_from_slice(&bytes);
             }
             Choke => {
@@ -253,15 +303,9 @@ impl Encoder<Message> for PeerCodec {
                 info.encode(buf)?;
             }
             Block { piece_index, offset, data } => {
-                let data_len = data.len() as u32;
-                buf.put_u32(1 + 2 * 4 + data_len);
-                buf.put_u8(MessageId::Block as u8);
-                buf.put_u32(
-                    piece_index
-                        .try_into()
-                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
-                );
-                buf.put_u32(offset);
+                encode_block_header(piece_index, offset, data.len(), buf)?;
+                // peer sessions don't copy the block into the write buffer,
+                // see `PeerSink`
                 buf.extend_from_slice(&data);
             }
             Cancel(info) => {
@@ -269,6 +313,12 @@ impl Encoder<Message> for PeerCodec {
                 buf.put_u8(MessageId::Cancel as u8);
                 info.encode(buf)?;
             }
+            Extended { id, payload } => {
+                buf.put_u32(1 + 1 + payload.len() as u32);
+                buf.put_u8(MessageId::Extended as u8);
+                buf.put_u8(id);
+                buf.extend_from_slice(&payload);
+            }
         }
         Ok(())
     }
@@ -312,8 +362,10 @@ impl Decoder for PeerCodec {
             MessageId::Bitfield => {
                 let mut raw = vec![0u8; msg_len - 1];
                 buf.copy_to_slice(&mut raw);
-                let elems = raw.into_iter().map(|b| b as usize).collect();
-                Message::Bitfield(Bitfield::from_vec(elems))
+                let bits = raw.into_iter().flat_map(|byte| {
+                    (0..8).rev().map(move |i| byte >> i & 1 == 1)
+                });
+                Message::Bitfield(bits.collect())
             }
             MessageId::Request => {
                 let mut info = BlockInfo { piece_index: 0, offset: 0, len: 0 };
@@ -332,12 +384,13 @@ impl Decoder for PeerCodec {
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
                 let offset = buf.get_u32();
                 let data_len = msg_len - 1 - 8;
-                let mut data = vec![0u8; data_len];
-                buf.copy_to_slice(&mut data);
+                // the block shares the read buffer's memory instead of being
+                // copied out of it
+                let data = buf.split_to(data_len).freeze();
                 Message::Block {
                     piece_index,
                     offset,
-                    data: data.into(),
+                    data,
                 }
             }
             MessageId::Cancel => {
@@ -350,6 +403,17 @@ impl Decoder for PeerCodec {
                 info.len    = buf.get_u32();
                 Message::Cancel(info)
             }
+            MessageId::Extended => {
+                if msg_len < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "extended message without id",
+                    ));
+                }
+                let id = buf.get_u8();
+                let payload = buf.split_to(msg_len - 2).freeze();
+                Message::Extended { id, payload }
+            }
         };
 
         Ok(Some(msg))
@@ -372,3 +436,33 @@ impl BlockInfo {
 
 /// Codec for all peer‐wire messages after the handshake.
 pub(crate) struct PeerCodec;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a bitfield is sent as bytes whose highest bit is the first
+    /// piece, padded with zeros, and is read back with the padding.
+    #[test]
+    fn should_encode_and_decode_bitfield() {
+        let mut bitfield = Bitfield::repeat(false, 11);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        bitfield.set(10, true);
+
+        let mut buf = BytesMut::new();
+        PeerCodec
+            .encode(Message::Bitfield(bitfield.clone()), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 3, 5, 0b1000_0000, 0b0110_0000]);
+
+        let Some(Message::Bitfield(mut decoded)) =
+            PeerCodec.decode(&mut buf).unwrap()
+        else {
+            panic!("bitfield not decoded");
+        };
+        assert_eq!(decoded.len(), 16);
+        decoded.truncate(11);
+        assert_eq!(decoded, bitfield);
+    }
+}
@@ -30,6 +30,8 @@ pub enum PeerError {
     InvalidPieceIndex,
     /// Peer's torrent info hash did not match ours.
     InvalidInfoHash,
+    /// The extension protocol message the peer sent is invalid.
+    InvalidExtensionMessage,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
@@ -49,6 +51,9 @@ impl fmt::Display for PeerError {
             InvalidBlockInfo => write!(fmt, "invalid block info"),
             InvalidPieceIndex => write!(fmt, "invalid piece index"),
             InvalidInfoHash => write!(fmt, "invalid info hash"),
+            InvalidExtensionMessage => {
+                write!(fmt, "invalid extension message")
+            }
             Io(e) => write!(fmt, "{}", e),
         }
     }
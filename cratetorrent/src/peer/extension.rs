@@ -0,0 +1,90 @@
+//! The parts of the [extension protocol](http://bittorrent.org/beps/bep_0010.html)
+//! that the engine supports, which are carried by the extended peer message.
+//!
+//! The only extension supported is
+//! [lt_donthave](http://bittorrent.org/beps/bep_0054.html), with which we tell
+//! peers that we no longer have a piece, e.g. because its data on disk was
+//! changed.
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+
+use crate::PieceIndex;
+
+/// The id of the extension handshake message.
+pub(crate) const HANDSHAKE_ID: u8 = 0;
+
+/// The id with which peers send us lt_donthave messages, as advertised in our
+/// handshake.
+pub(crate) const DONT_HAVE_ID: u8 = 1;
+
+/// The name of the lt_donthave extension in the handshake.
+const DONT_HAVE_NAME: &str = "lt_donthave";
+
+/// The extension handshake, of which only the mapping of the supported
+/// extensions' names to their message ids is used.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Handshake {
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+}
+
+/// Returns the payload of our extension handshake.
+pub(crate) fn handshake() -> Bytes {
+    let mut handshake = Handshake::default();
+    handshake
+        .m
+        .insert(DONT_HAVE_NAME.into(), DONT_HAVE_ID as i64);
+    serde_bencode::to_bytes(&handshake)
+        .expect("cannot encode extension handshake")
+        .into()
+}
+
+/// Parses the peer's extension handshake, returning the id with which it
+/// accepts lt_donthave messages, if it supports them.
+///
+/// Returns an error if the handshake is invalid.
+pub(crate) fn parse_handshake(payload: &[u8]) -> Result<Option<u8>, ()> {
+    let handshake: Handshake =
+        serde_bencode::from_bytes(payload).map_err(|_| ())?;
+    // an id of 0 means that the extension is disabled
+    match handshake.m.get(DONT_HAVE_NAME) {
+        Some(&id) if id > 0 => u8::try_from(id).map(Some).map_err(|_| ()),
+        _ => Ok(None),
+    }
+}
+
+/// Returns the payload of an lt_donthave message for the piece.
+pub(crate) fn dont_have(index: PieceIndex) -> Bytes {
+    Bytes::copy_from_slice(&(index as u32).to_be_bytes())
+}
+
+/// Parses the piece index of an lt_donthave message.
+pub(crate) fn parse_dont_have(payload: &[u8]) -> Option<PieceIndex> {
+    let index: [u8; 4] = payload.try_into().ok()?;
+    Some(u32::from_be_bytes(index) as PieceIndex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake() {
+        assert_eq!(parse_handshake(&handshake()), Ok(Some(DONT_HAVE_ID)));
+        assert_eq!(parse_handshake(b"d1:md11:lt_donthavei7eee"), Ok(Some(7)));
+        // the extension is disabled or not supported
+        assert_eq!(parse_handshake(b"d1:md11:lt_donthavei0eee"), Ok(None));
+        assert_eq!(parse_handshake(b"d1:md6:ut_pexi1eee"), Ok(None));
+        assert_eq!(parse_handshake(b"d1:vi1ee"), Ok(None));
+        assert!(parse_handshake(b"d1:md11:lt_donthavei256eee").is_err());
+        assert!(parse_handshake(b"i1e").is_err());
+    }
+
+    #[test]
+    fn test_dont_have() {
+        assert_eq!(parse_dont_have(&dont_have(0x0102_0304)), Some(0x0102_0304));
+        assert_eq!(parse_dont_have(&[0, 0, 1]), None);
+    }
+}
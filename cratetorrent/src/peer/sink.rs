@@ -0,0 +1,250 @@
+use std::{
+    collections::VecDeque,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::Sink;
+use tokio::io::AsyncWrite;
+use tokio_util::codec::Encoder;
+
+use super::codec::{encode_block_header, Message, PeerCodec};
+
+/// Once this many bytes are waiting to be written, the sink is flushed before
+/// it accepts more messages, like `Framed` does.
+const BACKPRESSURE_BOUNDARY: usize = 8 * 1024;
+
+/// The most buffers passed to a single vectored write.
+const MAX_WRITE_BUF_COUNT: usize = 64;
+
+/// The sink through which messages are sent to the peer.
+///
+/// Messages are encoded into a write buffer with [`PeerCodec`], except for
+/// the data of blocks, which is shared with the disk cache or a memory mapped
+/// file. It's not copied into the write buffer but queued as is, after its
+/// header, and written to the socket along with the rest of the messages
+/// with vectored writes.
+pub(crate) struct PeerSink<W> {
+    io: W,
+    /// The messages encoded since the last block was queued.
+    buf: BytesMut,
+    /// The encoded messages and block data to be written, in order.
+    queue: VecDeque<Bytes>,
+    /// The number of bytes in the queue.
+    queue_len: usize,
+}
+
+impl<W: AsyncWrite + Unpin> PeerSink<W> {
+    /// Creates a sink that writes to `io`, after the bytes already in the
+    /// write buffer.
+    pub fn new(io: W, buf: BytesMut) -> Self {
+        Self {
+            io,
+            buf,
+            queue: VecDeque::new(),
+            queue_len: 0,
+        }
+    }
+
+    /// Queues the encoded messages in the write buffer.
+    fn queue_buf(&mut self) {
+        if !self.buf.is_empty() {
+            let buf = self.buf.split().freeze();
+            self.queue_len += buf.len();
+            self.queue.push_back(buf);
+        }
+    }
+
+    /// Returns the number of bytes waiting to be written.
+    fn pending_len(&self) -> usize {
+        self.queue_len + self.buf.len()
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<Message> for PeerSink<W> {
+    type Error = io::Error;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pending_len() >= BACKPRESSURE_BOUNDARY {
+            self.poll_flush(cx)
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        let this = self.get_mut();
+        match msg {
+            Message::Block {
+                piece_index,
+                offset,
+                data,
+            } => {
+                encode_block_header(
+                    piece_index,
+                    offset,
+                    data.len(),
+                    &mut this.buf,
+                )?;
+                this.queue_buf();
+                this.queue_len += data.len();
+                this.queue.push_back(data);
+                Ok(())
+            }
+            msg => PeerCodec.encode(msg, &mut this.buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_buf();
+        while !this.queue.is_empty() {
+            let bufs: Vec<_> = this
+                .queue
+                .iter()
+                .take(MAX_WRITE_BUF_COUNT)
+                .map(|buf| IoSlice::new(buf))
+                .collect();
+            let mut n = match Pin::new(&mut this.io)
+                .poll_write_vectored(cx, &bufs)
+            {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            this.queue_len -= n;
+            // drop the buffers written in full, and skip the written part of
+            // the last one
+            while let Some(buf) = this.queue.front_mut() {
+                if n < buf.len() {
+                    buf.advance(n);
+                    break;
+                }
+                n -= buf.len();
+                this.queue.pop_front();
+            }
+        }
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => (),
+            res => return res,
+        }
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+
+    use super::*;
+    use crate::BlockInfo;
+
+    /// A socket that accepts at most a few bytes per write.
+    struct SlowWriter {
+        data: Vec<u8>,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(7);
+            self.get_mut().data.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let mut n = 0;
+            for buf in bufs {
+                let len = buf.len().min(7 - n);
+                this.data.extend_from_slice(&buf[..len]);
+                n += len;
+                if n == 7 {
+                    break;
+                }
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Tests that the messages written through the sink, including blocks
+    /// whose data is queued as is, are encoded like with the codec.
+    #[tokio::test]
+    async fn should_write_messages_in_order() {
+        let msgs = vec![
+            Message::Unchoke,
+            Message::Block {
+                piece_index: 1,
+                offset: 0,
+                data: Bytes::from(vec![1; 20]),
+            },
+            Message::Block {
+                piece_index: 1,
+                offset: 20,
+                data: Bytes::from(vec![2; 3]),
+            },
+            Message::Request(BlockInfo {
+                piece_index: 2,
+                offset: 0,
+                len: 16,
+            }),
+            Message::KeepAlive,
+        ];
+        let mut expected = BytesMut::new();
+        for msg in msgs.iter().cloned() {
+            PeerCodec.encode(msg, &mut expected).unwrap();
+        }
+
+        let mut sink =
+            PeerSink::new(SlowWriter { data: Vec::new() }, BytesMut::new());
+        for msg in msgs {
+            sink.feed(msg).await.unwrap();
+        }
+        sink.flush().await.unwrap();
+        assert_eq!(sink.io.data, expected);
+        assert_eq!(sink.pending_len(), 0);
+    }
+}
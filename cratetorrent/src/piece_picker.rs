@@ -195,6 +195,46 @@ impl PiecePicker {
         piece.is_pending = false;
     }
 
+    /// Decrements the availability of a piece.
+    ///
+    /// This should be called when a peer tells us it no longer has a piece it
+    /// had announced.
+    pub fn unregister_peer_piece(&mut self, index: PieceIndex) {
+        log::trace!("Unregistering no longer available piece {}", index);
+        let piece = &mut self.pieces[index];
+        piece.frequency = piece.frequency.saturating_sub(1);
+    }
+
+    /// Tells the piece picker that we no longer have the piece, e.g. because
+    /// its data on disk was changed.
+    ///
+    /// The piece is left pending, as if it had been picked, while it's checked
+    /// whether its data is still intact: if it is, it's
+    /// [received](Self::received_piece) again, and otherwise it's
+    /// [released](Self::release_piece) so that it's downloaded again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we don't have the piece.
+    pub fn lost_piece(&mut self, index: PieceIndex) {
+        log::trace!("Registering lost piece {}", index);
+        assert!(self.own_pieces[index], "Piece {} was not received", index);
+
+        self.own_pieces.set(index, false);
+        self.missing_count += 1;
+        self.pieces[index].is_pending = true;
+    }
+
+    /// Makes the pending piece available to be picked again.
+    pub fn release_piece(&mut self, index: PieceIndex) {
+        log::trace!("Releasing piece {}", index);
+        let piece = &mut self.pieces[index];
+        if piece.is_pending && !self.own_pieces[index] {
+            piece.is_pending = false;
+            self.free_count += 1;
+        }
+    }
+
     /// Access to piece metadata for diagnostic and statistics purposes
     pub fn pieces(&self) -> &[Piece] {
         &self.pieces
@@ -329,6 +369,37 @@ mod tests {
         assert!(piece_picker.all_pieces_picked());
     }
 
+    /// Tests that a lost piece is not picked while it's being checked, and
+    /// that it's either received again or picked once it's released.
+    #[test]
+    fn should_lose_pieces() {
+        let piece_count = 4;
+        let mut piece_picker =
+            PiecePicker::new(BitVec::repeat(true, piece_count));
+        piece_picker.register_peer_pieces(&BitVec::repeat(true, piece_count));
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+
+        piece_picker.lost_piece(1);
+        piece_picker.lost_piece(2);
+        assert!(!piece_picker.own_pieces[1]);
+        assert_eq!(piece_picker.missing_piece_count(), 2);
+        assert!(piece_picker.all_pieces_picked());
+        assert_eq!(piece_picker.pick_piece(), None);
+
+        // the first piece turned out to be intact
+        piece_picker.received_piece(1);
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+        assert!(piece_picker.all_pieces_picked());
+
+        // while the second has to be downloaded again
+        piece_picker.release_piece(2);
+        assert!(!piece_picker.all_pieces_picked());
+        assert_eq!(piece_picker.pick_piece(), Some(2));
+        piece_picker.received_piece(2);
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+        assert!(piece_picker.all_pieces_picked());
+    }
+
     /// Tests that the piece picker correctly determines whether we are
     /// interested in a variety of piece sets.
     #[test]
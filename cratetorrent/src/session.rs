@@ -0,0 +1,676 @@
+//! Persistent session state, which allows the engine to pick up where it left
+//! off after a restart.
+//!
+//! If [`session_path`](crate::conf::EngineConf::session_path) is set, the
+//! engine saves the torrents it manages to that file periodically and on
+//! shutdown. For each torrent this includes what is needed to restart it:
+//! its metainfo, download directory, its own configuration (if it was given
+//! one), the pieces it has, the modification times of its complete files and
+//! its cumulative transfer statistics. When the
+//! engine is spawned with the same path again, these torrents are restored
+//! and the caller doesn't have to add them again.
+//!
+//! The file is bencoded, and torrents are saved in the order they were added.
+//!
+//! Only the parts of a torrent's configuration that are available in all
+//! builds are saved; feature specific options are restored with their default
+//! values. Neither are custom [storage backends](crate::storage): restored
+//! torrents are stored in the file system.
+
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Url;
+
+use crate::{
+    Bitfield,
+    conf::{
+        Allocation, FlushPolicy, SeedGoalConf, TorrentAlertConf, TorrentConf,
+    },
+    metainfo::{self, BencodeError, Metainfo, MetainfoError},
+    storage::StorageFactory,
+};
+
+pub(crate) type Result<T> = crate::error::Result<T, SessionError>;
+
+/// The reasons the session state could not be loaded or saved.
+#[derive(Debug)]
+pub enum SessionError {
+    /// The session file could not be read or written.
+    Io(io::Error),
+    /// The session file is not correctly bencoded.
+    Bencode(BencodeError),
+    /// The session file is correctly encoded but its contents are not valid.
+    InvalidState,
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<BencodeError> for SessionError {
+    fn from(e: BencodeError) -> Self {
+        Self::Bencode(e)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SessionError::*;
+        match self {
+            Io(e) => e.fmt(f),
+            Bencode(e) => e.fmt(f),
+            InvalidState => write!(f, "invalid session state"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Bencode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Everything needed to recreate a torrent.
+#[derive(Clone, Debug)]
+pub(crate) struct TorrentState {
+    pub metainfo: Metainfo,
+    /// The directory in which the torrent's files are stored.
+    pub download_dir: PathBuf,
+    /// The address the torrent was asked to listen on, if any.
+    pub listen_addr: Option<SocketAddr>,
+    /// The seeds the torrent was started with.
+    pub seeds: Vec<SocketAddr>,
+    /// The torrent's own configuration, or none if it uses the engine's
+    /// default torrent configuration.
+    pub conf: Option<TorrentConf>,
+    /// The torrent's custom storage backend, if any. This can't be saved, so
+    /// it's always none for restored torrents.
+    pub storage: Option<Arc<dyn StorageFactory>>,
+    pub progress: TorrentProgress,
+}
+
+/// The parts of a torrent's state that change while it's running.
+#[derive(Clone, Debug)]
+pub(crate) struct TorrentProgress {
+    /// The pieces the torrent has downloaded and verified.
+    pub own_pieces: Bitfield,
+    /// The total number of payload bytes downloaded.
+    pub downloaded: u64,
+    /// The total number of payload bytes uploaded.
+    pub uploaded: u64,
+    /// The total time the torrent has been running.
+    pub run_duration: Duration,
+    /// The total time the torrent has been seeding.
+    pub seed_duration: Duration,
+    /// The modification times of the torrent's complete files, against which
+    /// the files are checked for changes made while the torrent was not
+    /// running. Either empty or indexed the same way as the torrent's files.
+    pub file_mtimes: Vec<Option<SystemTime>>,
+}
+
+impl TorrentProgress {
+    /// Creates the progress of a new torrent, which has no transfer history.
+    pub fn new(own_pieces: Bitfield) -> Self {
+        Self {
+            own_pieces,
+            downloaded: 0,
+            uploaded: 0,
+            run_duration: Duration::default(),
+            seed_duration: Duration::default(),
+            file_mtimes: Vec::new(),
+        }
+    }
+}
+
+/// Reads and decodes the session file at the given path.
+///
+/// If the file doesn't exist, there is nothing to restore, so an empty list
+/// is returned.
+pub(crate) fn load(path: &Path) -> Result<Vec<TorrentState>> {
+    match fs::read(path) {
+        Ok(buf) => decode(&buf),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Moves the session file that could not be loaded aside, to the same path
+/// with an `.invalid` extension, so that it's not overwritten by the next
+/// save and its torrents may still be recovered.
+pub(crate) fn set_aside(path: &Path) {
+    let mut invalid_path = path.as_os_str().to_owned();
+    invalid_path.push(".invalid");
+    if let Err(e) = fs::rename(path, &invalid_path) {
+        log::warn!("Cannot move invalid session file {:?}: {}", path, e);
+    }
+}
+
+/// Writes the encoded session state to the given path.
+///
+/// The state is first written to a temporary file which then replaces the
+/// previous session file, so that a crash during the write doesn't corrupt
+/// the last saved state. The file is synced before it replaces the previous
+/// one, and the directory after, as otherwise after a power loss the session
+/// file may be empty or only partially written.
+pub(crate) fn write(path: &Path, buf: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)?;
+    // the rename is only durable once the directory is synced
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Bencodes the state of the given torrents.
+pub(crate) fn encode<'a>(
+    torrents: impl Iterator<Item = &'a TorrentState>,
+) -> Result<Vec<u8>> {
+    let session = raw::Session {
+        torrents: torrents.map(raw::Torrent::from).collect(),
+    };
+    Ok(serde_bencode::to_bytes(&session)?)
+}
+
+/// Decodes and validates the bencoded state of torrents.
+pub(crate) fn decode(buf: &[u8]) -> Result<Vec<TorrentState>> {
+    let session: raw::Session = serde_bencode::from_bytes(buf)?;
+    session
+        .torrents
+        .into_iter()
+        .map(TorrentState::try_from)
+        .collect()
+}
+
+impl From<&TorrentState> for raw::Torrent {
+    fn from(state: &TorrentState) -> Self {
+        let metainfo = &state.metainfo;
+        let progress = &state.progress;
+
+        // pack the piece bits, most significant bit first, as in the
+        // bitfield message
+        let mut own_pieces = vec![0; progress.own_pieces.len().div_ceil(8)];
+        for piece in progress.own_pieces.iter_ones() {
+            own_pieces[piece / 8] |= 0x80 >> (piece % 8);
+        }
+
+        Self {
+            name: metainfo.name.clone(),
+            info_hash: metainfo.info_hash.to_vec(),
+            files: metainfo
+                .files
+                .iter()
+                .map(|f| raw::File {
+                    path: f.path.clone(),
+                })
+                .collect(),
+            trackers: metainfo.trackers.iter().map(Url::to_string).collect(),
+            raw_info: metainfo.raw_info.clone(),
+            download_dir: state.download_dir.clone(),
+            listen_addr: state.listen_addr.map(|a| a.to_string()),
+            seeds: state.seeds.iter().map(SocketAddr::to_string).collect(),
+            conf: state.conf.as_ref().map(raw::Conf::from),
+            own_pieces,
+            downloaded: progress.downloaded,
+            uploaded: progress.uploaded,
+            run_duration: progress.run_duration.as_secs(),
+            seed_duration: progress.seed_duration.as_secs(),
+            file_mtimes: Some(
+                progress
+                    .file_mtimes
+                    .iter()
+                    .map(|mtime| {
+                        mtime
+                            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                            .map_or(0, |d| d.as_nanos() as u64)
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl TryFrom<raw::Torrent> for TorrentState {
+    type Error = SessionError;
+
+    fn try_from(torrent: raw::Torrent) -> Result<Self> {
+        let trackers = torrent
+            .trackers
+            .iter()
+            .map(|url| Url::parse(url))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| SessionError::InvalidState)?;
+
+        // the metainfo is parsed and validated again from its info
+        // dictionary instead of trusting the saved fields, so that an edited
+        // session file can't place the files outside of the download
+        // directory
+        let invalid = |e: MetainfoError| {
+            log::warn!("Invalid metainfo in session: {}", e);
+            SessionError::InvalidState
+        };
+        let mut metainfo = Metainfo::from_raw_info(torrent.raw_info, trackers)
+            .map_err(invalid)?;
+        if metainfo.info_hash[..] != torrent.info_hash[..]
+            || metainfo.files.len() != torrent.files.len()
+        {
+            return Err(SessionError::InvalidState);
+        }
+        // the torrent's directory and files may have been renamed since it
+        // was added, which is applied again, just as checked as the renames
+        if torrent.name != metainfo.name {
+            metainfo::validate_path_component(&torrent.name).map_err(invalid)?;
+            metainfo.name = torrent.name;
+        }
+        for (file, saved) in metainfo.files.iter_mut().zip(torrent.files) {
+            if saved.path != file.path {
+                metainfo::validate_path(&saved.path).map_err(invalid)?;
+                file.path = saved.path;
+            }
+        }
+
+        let piece_count = metainfo.piece_count();
+        if torrent.own_pieces.len() != piece_count.div_ceil(8) {
+            return Err(SessionError::InvalidState);
+        }
+        let own_pieces = (0..piece_count)
+            .map(|piece| torrent.own_pieces[piece / 8] & (0x80 >> (piece % 8)))
+            .map(|bit| bit != 0)
+            .collect();
+
+        let listen_addr = torrent
+            .listen_addr
+            .map(|addr| addr.parse())
+            .transpose()
+            .map_err(|_| SessionError::InvalidState)?;
+        let seeds = torrent
+            .seeds
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| SessionError::InvalidState)?;
+        let conf = torrent.conf.map(TorrentConf::try_from).transpose()?;
+        let file_mtimes = torrent.file_mtimes.unwrap_or_default();
+        if !file_mtimes.is_empty() && file_mtimes.len() != metainfo.files.len()
+        {
+            return Err(SessionError::InvalidState);
+        }
+        let file_mtimes = file_mtimes
+            .into_iter()
+            .map(|nanos| {
+                (nanos > 0).then(|| UNIX_EPOCH + Duration::from_nanos(nanos))
+            })
+            .collect();
+
+        Ok(Self {
+            metainfo,
+            download_dir: torrent.download_dir,
+            listen_addr,
+            seeds,
+            conf,
+            storage: None,
+            progress: TorrentProgress {
+                own_pieces,
+                downloaded: torrent.downloaded,
+                uploaded: torrent.uploaded,
+                run_duration: Duration::from_secs(torrent.run_duration),
+                seed_duration: Duration::from_secs(torrent.seed_duration),
+                file_mtimes,
+            },
+        })
+    }
+}
+
+impl From<&TorrentConf> for raw::Conf {
+    fn from(conf: &TorrentConf) -> Self {
+        Self {
+            min_requested_peer_count: conf.min_requested_peer_count,
+            max_connected_peer_count: conf.max_connected_peer_count,
+            announce_interval: conf.announce_interval.as_secs(),
+            tracker_error_threshold: conf.tracker_error_threshold,
+            completed_pieces_alerts: conf.alerts.completed_pieces.into(),
+            peers_alerts: conf.alerts.peers.into(),
+            files_alerts: conf.alerts.files.into(),
+            // bencode has no floats, and the shortest representation of
+            // a float is parsed back into the same value
+            seed_ratio: conf.seed_goals.ratio.map(|r| r.to_string()),
+            seed_time: conf.seed_goals.seed_time.map(|d| d.as_secs()),
+            idle_time: conf.seed_goals.idle_time.map(|d| d.as_secs()),
+            allocation: match conf.allocation {
+                Allocation::None => 0,
+                Allocation::Sparse => 1,
+                Allocation::Full => 2,
+            },
+            mmap_reads: Some(conf.mmap_reads.into()),
+            incomplete_dir: conf.incomplete_dir.clone(),
+            completed_dir: conf.completed_dir.clone(),
+            flush_policy: Some(match conf.flush_policy {
+                FlushPolicy::Never => 0,
+                FlushPolicy::PerPiece => 1,
+                FlushPolicy::Periodic(_) => 2,
+                FlushPolicy::OnCompletion => 3,
+            }),
+            flush_interval: match conf.flush_policy {
+                FlushPolicy::Periodic(interval) => Some(interval.as_secs()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl TryFrom<raw::Conf> for TorrentConf {
+    type Error = SessionError;
+
+    fn try_from(raw: raw::Conf) -> Result<Self> {
+        let ratio = raw
+            .seed_ratio
+            .map(|r| r.parse())
+            .transpose()
+            .map_err(|_| SessionError::InvalidState)?;
+        let allocation = match raw.allocation {
+            0 => Allocation::None,
+            1 => Allocation::Sparse,
+            2 => Allocation::Full,
+            _ => return Err(SessionError::InvalidState),
+        };
+        // sessions saved before the policy was introduced get the default
+        let flush_policy = match (raw.flush_policy, raw.flush_interval) {
+            (None, _) => FlushPolicy::default(),
+            (Some(0), _) => FlushPolicy::Never,
+            (Some(1), _) => FlushPolicy::PerPiece,
+            (Some(2), Some(secs)) => {
+                FlushPolicy::Periodic(Duration::from_secs(secs))
+            }
+            (Some(3), _) => FlushPolicy::OnCompletion,
+            _ => return Err(SessionError::InvalidState),
+        };
+        // all fields are set, so that new options can't be left out of the
+        // session by accident
+        let conf = TorrentConf {
+            min_requested_peer_count: raw.min_requested_peer_count,
+            max_connected_peer_count: raw.max_connected_peer_count,
+            announce_interval: Duration::from_secs(raw.announce_interval),
+            tracker_error_threshold: raw.tracker_error_threshold,
+            // the feature specific options are not saved, and are restored
+            // with their defaults
+            #[cfg(feature = "spoofing")]
+            spoof_client: None,
+            #[cfg(feature = "peer_inject")]
+            extra_peers: Vec::new(),
+            #[cfg(feature = "ghostleech")]
+            ghost_leech: false,
+            #[cfg(feature = "ratio")]
+            max_ratio: None,
+            #[cfg(feature = "upload_multiplier")]
+            upload_multiplier: None,
+            alerts: TorrentAlertConf {
+                completed_pieces: raw.completed_pieces_alerts != 0,
+                peers: raw.peers_alerts != 0,
+                files: raw.files_alerts != 0,
+            },
+            seed_goals: SeedGoalConf {
+                ratio,
+                seed_time: raw.seed_time.map(Duration::from_secs),
+                idle_time: raw.idle_time.map(Duration::from_secs),
+            },
+            allocation,
+            // sessions saved before the option was introduced don't map files
+            mmap_reads: raw.mmap_reads.is_some_and(|m| m != 0),
+            flush_policy,
+            incomplete_dir: raw.incomplete_dir,
+            completed_dir: raw.completed_dir,
+        };
+        conf.validate().map_err(|_| SessionError::InvalidState)?;
+        Ok(conf)
+    }
+}
+
+mod raw {
+    //! The types that are directly (de)serialized. As with the metainfo, the
+    //! validity of the decoded state is not ensured at this level.
+
+    use std::path::PathBuf;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Session {
+        pub torrents: Vec<Torrent>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Torrent {
+        pub name: String,
+        #[serde(with = "serde_bytes")]
+        pub info_hash: Vec<u8>,
+        /// The files, which are otherwise parsed from the raw info
+        /// dictionary, only to restore their paths after a rename.
+        pub files: Vec<File>,
+        pub trackers: Vec<String>,
+        #[serde(with = "serde_bytes")]
+        pub raw_info: Vec<u8>,
+        pub download_dir: PathBuf,
+        pub listen_addr: Option<String>,
+        pub seeds: Vec<String>,
+        pub conf: Option<Conf>,
+        #[serde(with = "serde_bytes")]
+        pub own_pieces: Vec<u8>,
+        pub downloaded: u64,
+        pub uploaded: u64,
+        /// In seconds.
+        pub run_duration: u64,
+        /// In seconds.
+        pub seed_duration: u64,
+        /// In nanoseconds since the Unix epoch, or 0 if unknown.
+        pub file_mtimes: Option<Vec<u64>>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct File {
+        pub path: PathBuf,
+    }
+
+    /// Durations are stored in seconds, and flags as 0 or 1, as bencode
+    /// doesn't have booleans.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Conf {
+        pub min_requested_peer_count: usize,
+        pub max_connected_peer_count: usize,
+        pub announce_interval: u64,
+        pub tracker_error_threshold: usize,
+        pub completed_pieces_alerts: u8,
+        pub peers_alerts: u8,
+        pub files_alerts: u8,
+        pub seed_ratio: Option<String>,
+        pub seed_time: Option<u64>,
+        pub idle_time: Option<u64>,
+        /// 0 for none, 1 for sparse and 2 for full allocation.
+        pub allocation: u8,
+        pub mmap_reads: Option<u8>,
+        pub incomplete_dir: Option<PathBuf>,
+        pub completed_dir: Option<PathBuf>,
+        /// 0 for never, 1 for per piece, 2 for periodic (with the interval)
+        /// and 3 for on completion.
+        pub flush_policy: Option<u8>,
+        pub flush_interval: Option<u64>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the metainfo of an archive of 11 pieces, the second file of
+    /// which has attributes and a hash.
+    fn archive() -> Metainfo {
+        let mut buf = b"d8:announce21:http://tracker.test/a4:infod5:filesl\
+            d6:lengthi100e4:pathl1:aee\
+            d4:attr2:xh6:lengthi70e4:pathl1:b1:ce4:sha120:"
+            .to_vec();
+        buf.extend_from_slice(&[3; 20]);
+        buf.extend_from_slice(
+            b"ee4:name7:archive12:piece lengthi16e6:pieces220:",
+        );
+        buf.extend_from_slice(&[1; 220]);
+        buf.extend_from_slice(b"ee");
+        Metainfo::from_bytes(&buf).unwrap()
+    }
+
+    /// Returns the state of a torrent without progress.
+    fn state(metainfo: Metainfo) -> TorrentState {
+        let piece_count = metainfo.piece_count();
+        TorrentState {
+            metainfo,
+            download_dir: "/tmp".into(),
+            listen_addr: None,
+            seeds: Vec::new(),
+            conf: None,
+            storage: None,
+            progress: TorrentProgress::new(Bitfield::repeat(true, piece_count)),
+        }
+    }
+
+    /// Encodes the session of the single torrent.
+    fn encode_raw(torrent: raw::Torrent) -> Vec<u8> {
+        serde_bencode::to_bytes(&raw::Session {
+            torrents: vec![torrent],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let piece_count = 11;
+        let mut own_pieces = Bitfield::repeat(false, piece_count);
+        own_pieces.set(0, true);
+        own_pieces.set(7, true);
+        own_pieces.set(10, true);
+        // the torrent's directory and one of its files were renamed
+        let mut metainfo = archive();
+        metainfo.name = "renamed".into();
+        metainfo.files[1].path = "d/c".into();
+        let state = TorrentState {
+            metainfo,
+            download_dir: "/tmp/downloads".into(),
+            listen_addr: Some("127.0.0.1:6881".parse().unwrap()),
+            seeds: vec!["10.0.0.1:50051".parse().unwrap()],
+            conf: Some(TorrentConf {
+                max_connected_peer_count: 20,
+                seed_goals: SeedGoalConf {
+                    ratio: Some(1.1),
+                    seed_time: Some(Duration::from_secs(3600)),
+                    idle_time: None,
+                },
+                allocation: Allocation::Full,
+                mmap_reads: true,
+                completed_dir: Some("/tmp/complete".into()),
+                flush_policy: FlushPolicy::Periodic(Duration::from_secs(5)),
+                ..Default::default()
+            }),
+            storage: None,
+            progress: TorrentProgress {
+                own_pieces: own_pieces.clone(),
+                downloaded: 120,
+                uploaded: 300,
+                run_duration: Duration::from_secs(42),
+                seed_duration: Duration::from_secs(3),
+                file_mtimes: vec![
+                    Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123)),
+                    None,
+                ],
+            },
+        };
+
+        let buf = encode(std::iter::once(&state)).unwrap();
+        let decoded = decode(&buf).unwrap();
+        assert_eq!(decoded.len(), 1);
+        let decoded = &decoded[0];
+
+        assert_eq!(decoded.metainfo.name, state.metainfo.name);
+        assert_eq!(decoded.metainfo.info_hash, state.metainfo.info_hash);
+        assert_eq!(decoded.metainfo.pieces, state.metainfo.pieces);
+        for (decoded, file) in
+            decoded.metainfo.files.iter().zip(&state.metainfo.files)
+        {
+            assert_eq!(decoded.path, file.path);
+            assert_eq!(decoded.len, file.len);
+            assert_eq!(decoded.torrent_offset, file.torrent_offset);
+            assert_eq!(decoded.attrs, file.attrs);
+            assert_eq!(decoded.symlink_path, file.symlink_path);
+            assert_eq!(decoded.sha1, file.sha1);
+        }
+        assert_eq!(decoded.metainfo.trackers, state.metainfo.trackers);
+        assert_eq!(decoded.metainfo.raw_info, state.metainfo.raw_info);
+        assert_eq!(decoded.download_dir, state.download_dir);
+        assert_eq!(decoded.listen_addr, state.listen_addr);
+        assert_eq!(decoded.seeds, state.seeds);
+        let conf = decoded.conf.as_ref().unwrap();
+        assert_eq!(conf.max_connected_peer_count, 20);
+        assert_eq!(conf.seed_goals.ratio, Some(1.1));
+        assert_eq!(conf.seed_goals.seed_time, Some(Duration::from_secs(3600)));
+        assert_eq!(conf.seed_goals.idle_time, None);
+        assert_eq!(conf.allocation, Allocation::Full);
+        assert!(conf.mmap_reads);
+        assert_eq!(conf.incomplete_dir, None);
+        assert_eq!(conf.completed_dir, Some("/tmp/complete".into()));
+        assert_eq!(
+            conf.flush_policy,
+            FlushPolicy::Periodic(Duration::from_secs(5))
+        );
+        assert_eq!(decoded.progress.own_pieces, own_pieces);
+        assert_eq!(decoded.progress.downloaded, 120);
+        assert_eq!(decoded.progress.uploaded, 300);
+        assert_eq!(decoded.progress.run_duration, Duration::from_secs(42));
+        assert_eq!(decoded.progress.seed_duration, Duration::from_secs(3));
+        assert_eq!(decoded.progress.file_mtimes, state.progress.file_mtimes);
+    }
+
+    #[test]
+    fn test_decode_invalid_state() {
+        let state = state(archive());
+        let invalid_edits: [fn(&mut raw::Torrent); 6] = [
+            // the piece bits don't match the number of pieces
+            |torrent| torrent.own_pieces.push(0),
+            // the info dictionary doesn't match the info hash
+            |torrent| torrent.info_hash[0] ^= 1,
+            |torrent| torrent.raw_info.truncate(10),
+            |torrent| {
+                torrent.files.pop();
+            },
+            // the name or a path leads outside of the download directory
+            |torrent| torrent.name = "..".into(),
+            |torrent| torrent.files[0].path = "../../etc/passwd".into(),
+        ];
+        for edit in invalid_edits {
+            let mut torrent = raw::Torrent::from(&state);
+            edit(&mut torrent);
+            assert!(matches!(
+                decode(&encode_raw(torrent)),
+                Err(SessionError::InvalidState)
+            ));
+        }
+
+        assert!(matches!(
+            decode(b"not bencode"),
+            Err(SessionError::Bencode(_))
+        ));
+    }
+}
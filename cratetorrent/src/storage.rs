@@ -0,0 +1,811 @@
+//! The backends in which torrents store their data.
+//!
+//! The disk task doesn't access files directly, but through the [`Storage`]
+//! trait. This works with ranges of bytes in the torrent, viewing all its files
+//! as a single contiguous byte array, so implementations are free to lay out
+//! the data as they see fit.
+//!
+//! By default torrents are stored in the file system, by [`FsStorage`], which
+//! keeps the handles of the files it's using in the engine-wide [`FilePool`].
+//! A different backend may be set per torrent via
+//! [`TorrentParams::storage`](crate::engine::TorrentParams::storage), such as
+//! [`MemoryStorage`], which is mostly useful for tests.
+
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+
+use crate::{error::NewTorrentError, storage_info::StorageInfo};
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use {
+    crate::{disk::io::file::TorrentFile, storage_info::FileSlice},
+    std::sync::Arc,
+};
+
+pub use file_pool::FilePool;
+pub use fs::FsStorage;
+pub use memory::MemoryStorage;
+
+mod file_pool;
+mod fs;
+mod memory;
+mod mmap;
+mod range_lock;
+
+/// The storage of a single torrent's data.
+///
+/// Offsets are relative to the start of the torrent. The disk task performs
+/// IO on a thread pool, so implementations may block, and reads and writes
+/// of different pieces may be issued concurrently.
+pub trait Storage: Send + Sync {
+    /// Prepares the storage for reads and writes.
+    ///
+    /// This is called once, when the torrent is allocated on disk. For
+    /// example, the file system storage checks that there is enough free space
+    /// for the torrent, creates its directories and opens its files here. If
+    /// this fails, the torrent is stopped.
+    fn open(&mut self) -> Result<(), NewTorrentError>;
+
+    /// Writes the buffers, one after the other, starting at the offset.
+    fn write(&self, offset: u64, bufs: &[&[u8]]) -> io::Result<()>;
+
+    /// Fills the buffers, one after the other, with the data starting at the
+    /// offset.
+    ///
+    /// If some of the data was never written, an error of kind
+    /// [`io::ErrorKind::UnexpectedEof`] is returned.
+    fn read(&self, offset: u64, bufs: &mut [&mut [u8]]) -> io::Result<()>;
+
+    /// Flushes the data written to the range of bytes to durable storage, so
+    /// that it survives a crash or power loss.
+    ///
+    /// Storages whose data doesn't outlive the process have nothing to flush,
+    /// which is the default.
+    fn sync(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves the torrent's data to the new directory.
+    ///
+    /// The directory has the same meaning as
+    /// [`StorageInfo::download_dir`]: for archives, it includes the torrent's
+    /// own directory. After this, reads and writes use the new location.
+    ///
+    /// The progress function should be called with the number of bytes moved
+    /// so far, as the move proceeds. If the move fails, the data should be
+    /// left (or put back) in its original location where possible.
+    fn move_to(
+        &mut self,
+        dir: &Path,
+        conflict: MoveConflict,
+        progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()>;
+
+    /// Deletes the torrent's data.
+    fn delete(&mut self) -> io::Result<()>;
+
+    /// Renames the file at the index, whose path becomes the given one,
+    /// relative to [`StorageInfo::download_dir`]. After this, reads and
+    /// writes of the file use the new path.
+    ///
+    /// Storages that don't keep the torrent's data in files have nothing to
+    /// rename, which is the default.
+    fn rename_file(&mut self, _index: usize, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the stored range of bytes without copying it, as buffers that
+    /// refer to the data in place, e.g. in memory mapped files, one after the
+    /// other.
+    ///
+    /// This is used to serve the blocks peers request. Storages that can't do
+    /// this return `None`, which is the default, in which case the blocks'
+    /// pieces are read into the disk cache with [`Self::read`] instead.
+    ///
+    /// If some of the data was never written, an error of kind
+    /// [`io::ErrorKind::UnexpectedEof`] may be returned.
+    fn read_mapped(
+        &self,
+        _offset: u64,
+        _len: u64,
+    ) -> io::Result<Option<Vec<Bytes>>> {
+        Ok(None)
+    }
+
+    /// Drops what the storage keeps of the files' data or handles, such as
+    /// their memory mappings, as the files may have been changed or replaced
+    /// by another program. This is done before the files' pieces are
+    /// rechecked, so that they are read from the files as they are now.
+    ///
+    /// Storages that keep nothing of the files have nothing to drop, which is
+    /// the default.
+    fn invalidate_files(&self, _files: &[usize]) {}
+
+    /// Returns the current state of each of the torrent's files, in order.
+    ///
+    /// This is used to notice when the data of a torrent was changed or
+    /// removed by another program, so that the pieces it had in the affected
+    /// files are checked again. Storages that don't keep the torrent's data
+    /// in files return `None`, which is the default.
+    fn file_states(&self) -> io::Result<Option<Vec<FileState>>> {
+        Ok(None)
+    }
+
+    /// Returns the parts of the storage's files that hold the range of the
+    /// torrent's bytes, in order, so that the disk task can read and write
+    /// them through io_uring instead of the storage's blocking methods.
+    ///
+    /// Like the storage's own reads and writes, the regions hold their parts
+    /// of the files for reading, or writing if `is_write` is set, until
+    /// they're dropped. This must not block: if a part is held by another
+    /// access, an error of kind [`io::ErrorKind::WouldBlock`] is returned,
+    /// and the disk task tries again later.
+    ///
+    /// Storages that don't keep the torrent's data in files return `None`,
+    /// which is the default.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn file_regions(
+        &self,
+        _offset: u64,
+        _len: u64,
+        _is_write: bool,
+    ) -> io::Result<Option<Vec<FileRegion<'_>>>> {
+        Ok(None)
+    }
+}
+
+/// The state of one of the storage's files on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileState {
+    /// The length of the file, which is 0 if it doesn't exist.
+    pub len: u64,
+    /// When the file was last modified, or `None` if it doesn't exist or the
+    /// platform doesn't report it.
+    pub mtime: Option<SystemTime>,
+}
+
+/// A part of one of the storage's files, which the disk task accesses
+/// directly through io_uring.
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+pub struct FileRegion<'a> {
+    /// The path of the file, which is reported along with its errors.
+    pub(crate) path: PathBuf,
+    /// The open file, or `None` if the file has no data on disk, e.g. because
+    /// it's padding, in which case the region reads as zeros and writes to it
+    /// are discarded.
+    pub(crate) file: Option<Arc<TorrentFile>>,
+    /// The part of the file.
+    pub(crate) slice: FileSlice,
+    /// Holds the part of the file while it's accessed, if the file is on
+    /// disk.
+    _guard: Option<range_lock::RangeGuard<'a>>,
+}
+
+/// What to do if some of the torrent's files already exist where its storage
+/// is being moved to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MoveConflict {
+    /// Fail the move with an error of kind
+    /// [`io::ErrorKind::AlreadyExists`], without moving anything.
+    #[default]
+    Fail,
+    /// Replace the existing files with the torrent's.
+    Overwrite,
+    /// Keep the existing files and use them as the torrent's data, deleting
+    /// the torrent's own copies. This is useful if the data has already been
+    /// copied there. The existing files are not checked, so they must have
+    /// the same contents.
+    KeepExisting,
+}
+
+/// An IO error of one of the torrent's files.
+///
+/// Storages that keep the torrent's data in files may return this as the
+/// inner error of the [`io::Error`]s of their reads and writes, with the same
+/// error kind, so that the file is reported along with the error.
+#[derive(Debug)]
+pub struct FileError {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The error of the file's IO.
+    pub error: io::Error,
+}
+
+impl FileError {
+    /// Wraps the error of the file in an [`io::Error`] of the same kind.
+    pub fn wrap(path: PathBuf, error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), Self { path, error })
+    }
+
+    /// Returns the path of the file and its error if the error was
+    /// [wrapped](Self::wrap), or no path and the error itself otherwise.
+    pub(crate) fn unwrap(error: io::Error) -> (Option<PathBuf>, io::Error) {
+        let is_file_error = error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<FileError>());
+        if !is_file_error {
+            return (None, error);
+        }
+        let inner = error.into_inner().expect("no inner error");
+        let e = inner.downcast::<FileError>().expect("not a file error");
+        (Some(e.path), e.error)
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{:?}: {}", self.path, self.error)
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl fmt::Debug for dyn Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Storage")
+    }
+}
+
+/// Creates the storage of a torrent when it's added to the engine.
+///
+/// This is implemented for closures that take the torrent's [`StorageInfo`]
+/// and return the boxed storage.
+pub trait StorageFactory: Send + Sync {
+    fn create(&self, info: &StorageInfo) -> Box<dyn Storage>;
+}
+
+impl<F> StorageFactory for F
+where
+    F: Fn(&StorageInfo) -> Box<dyn Storage> + Send + Sync,
+{
+    fn create(&self, info: &StorageInfo) -> Box<dyn Storage> {
+        self(info)
+    }
+}
+
+impl fmt::Debug for dyn StorageFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageFactory")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{conf::Allocation, FileInfo};
+
+    /// Tests that data spanning several files is written and read back, and
+    /// that reading data that was never written fails.
+    #[test]
+    fn test_read_write() {
+        let info = StorageInfo {
+            piece_count: 2,
+            piece_len: 8,
+            last_piece_len: 4,
+            download_len: 12,
+            download_dir: PathBuf::from("/tmp/storage_read_write_test"),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    len: 5,
+                    torrent_offset: 0,
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/c"),
+                    len: 7,
+                    torrent_offset: 5,
+                    ..Default::default()
+                },
+            ],
+        };
+        std::fs::remove_dir_all(&info.download_dir).ok();
+
+        let mut fs_storage = fs_storage(info.clone(), Allocation::None);
+        let mut mem_storage = MemoryStorage::new(&info);
+        let storages: [&mut dyn Storage; 2] =
+            [&mut fs_storage, &mut mem_storage];
+        for storage in storages {
+            storage.open().unwrap();
+
+            let mut buf = [0; 4];
+            let err = storage.read(8, &mut [&mut buf]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+            // the first piece spans both files
+            storage.write(0, &[&[1, 2, 3], &[4, 5, 6, 7, 8]]).unwrap();
+            storage.write(8, &[&[9, 10, 11, 12]]).unwrap();
+
+            let (mut a, mut b) = ([0; 6], [0; 6]);
+            storage.read(0, &mut [&mut a, &mut b]).unwrap();
+            assert_eq!(a, [1, 2, 3, 4, 5, 6]);
+            assert_eq!(b, [7, 8, 9, 10, 11, 12]);
+        }
+
+        fs_storage.delete().unwrap();
+        assert!(!info.download_dir.join("a").exists());
+        assert!(!info.download_dir.join("b").exists());
+    }
+
+    /// Tests that a mapped file that is truncated or replaced is not read from
+    /// its stale mapping, and that it's mapped again afterwards.
+    #[test]
+    fn test_fs_mmap_changed_file() {
+        let info = single_file_info("/tmp/storage_mmap_test", 32);
+        std::fs::remove_dir_all(&info.download_dir).ok();
+        let mut storage =
+            fs_storage(info.clone(), Allocation::None).with_mmap_reads();
+        storage.open().unwrap();
+        storage.write(0, &[&[1; 32]]).unwrap();
+        let read = |storage: &FsStorage| {
+            storage.read_mapped(16, 16).map(|bufs| bufs.unwrap().concat())
+        };
+        assert_eq!(read(&storage).unwrap(), [1; 16]);
+
+        // the truncated file would crash the process if it was read from
+        let path = info.download_dir.join("file");
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(8)
+            .unwrap();
+        assert!(read(&storage).is_err());
+        let err = read(&storage).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        storage.write(0, &[&[1; 32]]).unwrap();
+        assert_eq!(read(&storage).unwrap(), [1; 16]);
+
+        // the file is replaced, while the old one is still open
+        let tmp_path = info.download_dir.join("tmp");
+        std::fs::write(&tmp_path, [2; 32]).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+        assert!(read(&storage).is_err());
+        assert_eq!(read(&storage).unwrap(), [2; 16]);
+
+        storage.delete().unwrap();
+    }
+
+    /// Tests that a file replaced by another program is read from its new data
+    /// once it's invalidated, both with and without mapping it.
+    #[test]
+    fn test_fs_invalidate_files() {
+        let info = single_file_info("/tmp/storage_invalidate_test", 32);
+        std::fs::remove_dir_all(&info.download_dir).ok();
+        let mut storage =
+            fs_storage(info.clone(), Allocation::None).with_mmap_reads();
+        storage.open().unwrap();
+        storage.write(0, &[&[1; 32]]).unwrap();
+        let mapped = storage.read_mapped(0, 16).unwrap().unwrap();
+        assert_eq!(mapped.concat(), [1; 16]);
+
+        let path = info.download_dir.join("file");
+        let tmp_path = info.download_dir.join("tmp");
+        std::fs::write(&tmp_path, [2; 32]).unwrap();
+        std::fs::rename(&tmp_path, &path).unwrap();
+        storage.invalidate_files(&[0]);
+
+        let mut buf = [0; 16];
+        storage.read(0, &mut [&mut buf]).unwrap();
+        assert_eq!(buf, [2; 16]);
+        let mapped = storage.read_mapped(0, 16).unwrap().unwrap();
+        assert_eq!(mapped.concat(), [2; 16]);
+
+        storage.delete().unwrap();
+    }
+
+    /// Tests that the file regions hold their parts of the files like the
+    /// storage's reads and writes, without blocking.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    #[test]
+    fn test_fs_file_regions_lock() {
+        let info = single_file_info("/tmp/storage_file_regions_test", 32);
+        std::fs::remove_dir_all(&info.download_dir).ok();
+        let mut storage = fs_storage(info, Allocation::None);
+        storage.open().unwrap();
+
+        let regions = storage.file_regions(0, 16, true).unwrap().unwrap();
+        assert_eq!(regions.len(), 1);
+        // overlapping accesses would block, while disjoint ones don't
+        let err = storage.file_regions(8, 16, false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(storage.file_regions(16, 16, true).is_ok());
+        drop(regions);
+
+        let regions = storage.file_regions(0, 16, false).unwrap().unwrap();
+        assert!(storage.file_regions(8, 16, false).is_ok());
+        let err = storage.file_regions(8, 16, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(regions);
+        assert!(storage.file_regions(8, 16, true).is_ok());
+
+        storage.delete().unwrap();
+    }
+
+    /// Tests moving an archive's files with each conflict policy, when one of
+    /// its files already exists in the destination.
+    #[test]
+    fn test_fs_move_conflict() {
+        let info = StorageInfo {
+            piece_count: 1,
+            piece_len: 6,
+            last_piece_len: 6,
+            download_len: 6,
+            download_dir: PathBuf::from("/tmp/storage_move_test/src/archive"),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    len: 3,
+                    torrent_offset: 0,
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/c"),
+                    len: 3,
+                    torrent_offset: 3,
+                    ..Default::default()
+                },
+            ],
+        };
+        let dst_dir = Path::new("/tmp/storage_move_test/dst/archive");
+
+        for conflict in [
+            MoveConflict::Fail,
+            MoveConflict::Overwrite,
+            MoveConflict::KeepExisting,
+        ] {
+            std::fs::remove_dir_all("/tmp/storage_move_test").ok();
+            std::fs::create_dir_all(dst_dir).unwrap();
+            std::fs::write(dst_dir.join("a"), [7, 7, 7]).unwrap();
+
+            let mut storage = fs_storage(info.clone(), Allocation::None);
+            storage.open().unwrap();
+            storage.write(0, &[&[1, 2, 3, 4, 5, 6]]).unwrap();
+
+            let mut progress = Vec::new();
+            let res = storage
+                .move_to(dst_dir, conflict, &mut |moved| progress.push(moved));
+
+            let mut buf = [0; 6];
+            storage.read(0, &mut [&mut buf]).unwrap();
+            match conflict {
+                MoveConflict::Fail => {
+                    assert_eq!(
+                        res.unwrap_err().kind(),
+                        io::ErrorKind::AlreadyExists
+                    );
+                    assert!(progress.is_empty());
+                    assert!(info.download_dir.join("b/c").is_file());
+                    assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+                }
+                MoveConflict::Overwrite => {
+                    res.unwrap();
+                    assert_eq!(progress, [3, 6]);
+                    assert!(!info.download_dir.exists());
+                    assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+                }
+                MoveConflict::KeepExisting => {
+                    res.unwrap();
+                    assert_eq!(progress, [3, 6]);
+                    assert!(!info.download_dir.exists());
+                    assert_eq!(buf, [7, 7, 7, 4, 5, 6]);
+                }
+            }
+        }
+        std::fs::remove_dir_all("/tmp/storage_move_test").ok();
+    }
+
+    /// Tests that an archive is moved as a whole, and that its copy on another
+    /// file system only appears in the destination once it's complete.
+    #[test]
+    fn test_fs_move_root() {
+        let info = StorageInfo {
+            piece_count: 1,
+            piece_len: 6,
+            last_piece_len: 6,
+            download_len: 6,
+            download_dir: PathBuf::from("/tmp/storage_move_root_test/src/a"),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    len: 3,
+                    torrent_offset: 0,
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/c"),
+                    len: 3,
+                    torrent_offset: 3,
+                    ..Default::default()
+                },
+            ],
+        };
+        let dst_dir = Path::new("/tmp/storage_move_root_test/dst/a");
+        std::fs::remove_dir_all("/tmp/storage_move_root_test").ok();
+
+        let mut storage = fs_storage(info.clone(), Allocation::None);
+        storage.open().unwrap();
+        storage.write(0, &[&[1, 2, 3, 4, 5, 6]]).unwrap();
+        let mut progress = Vec::new();
+        storage
+            .move_to(dst_dir, MoveConflict::Fail, &mut |moved| {
+                assert!(dst_dir.join("b/c").is_file());
+                progress.push(moved);
+            })
+            .unwrap();
+        assert_eq!(progress, [6]);
+        assert!(!info.download_dir.exists());
+        let mut buf = [0; 6];
+        storage.read(0, &mut [&mut buf]).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+
+        // the copy is made under a temporary name
+        let copy_dir = Path::new("/tmp/storage_move_root_test/copy/a");
+        let tmp_dir = Path::new("/tmp/storage_move_root_test/copy/a.tmp");
+        std::fs::create_dir_all(copy_dir.parent().unwrap()).unwrap();
+        let mut progress = Vec::new();
+        fs::copy_root(dst_dir, copy_dir, &mut |copied| {
+            assert!(!copy_dir.exists());
+            assert!(tmp_dir.is_dir());
+            progress.push(copied);
+        })
+        .unwrap();
+        assert_eq!(progress.last(), Some(&6));
+        assert!(!tmp_dir.exists());
+        assert_eq!(std::fs::read(copy_dir.join("a")).unwrap(), [1, 2, 3]);
+        assert_eq!(std::fs::read(copy_dir.join("b/c")).unwrap(), [4, 5, 6]);
+        assert!(dst_dir.join("b/c").is_file());
+
+        std::fs::remove_dir_all("/tmp/storage_move_root_test").ok();
+    }
+
+    /// Tests renaming an archive's file into a new subdirectory, and that it's
+    /// not renamed over an existing file.
+    #[test]
+    fn test_fs_rename_file() {
+        let dir = Path::new("/tmp/storage_rename_test");
+        let info = StorageInfo {
+            piece_count: 1,
+            piece_len: 6,
+            last_piece_len: 6,
+            download_len: 6,
+            download_dir: dir.join("archive"),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    len: 3,
+                    torrent_offset: 0,
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/c"),
+                    len: 3,
+                    torrent_offset: 3,
+                    ..Default::default()
+                },
+            ],
+        };
+        std::fs::remove_dir_all(dir).ok();
+
+        let mut storage = fs_storage(info.clone(), Allocation::None);
+        storage.open().unwrap();
+        storage.write(0, &[&[1, 2, 3, 4, 5, 6]]).unwrap();
+
+        let res = storage.rename_file(1, Path::new("a"));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        storage.rename_file(1, Path::new("d/e/f")).unwrap();
+        assert!(!info.download_dir.join("b").exists());
+        assert!(info.download_dir.join("d/e/f").is_file());
+        let mut buf = [0; 6];
+        storage.read(0, &mut [&mut buf]).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6]);
+        storage.write(3, &[&[7, 8, 9]]).unwrap();
+        assert_eq!(
+            std::fs::read(info.download_dir.join("d/e/f")).unwrap(),
+            [7, 8, 9]
+        );
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    /// Tests that padding files are never stored but read as zeros, that
+    /// executables get the exec bit and that symlinks are created, and kept
+    /// valid when the storage is moved.
+    #[test]
+    fn test_file_attrs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let info = StorageInfo {
+            piece_count: 2,
+            piece_len: 8,
+            last_piece_len: 4,
+            download_len: 12,
+            download_dir: PathBuf::from("/tmp/storage_attrs_test/src"),
+            files: vec![
+                FileInfo {
+                    path: PathBuf::from("a"),
+                    len: 3,
+                    torrent_offset: 0,
+                    attrs: "x".into(),
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/link"),
+                    len: 0,
+                    torrent_offset: 3,
+                    attrs: "l".into(),
+                    symlink_path: Some(PathBuf::from("a")),
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from(".pad/5"),
+                    len: 5,
+                    torrent_offset: 3,
+                    attrs: "p".into(),
+                    ..Default::default()
+                },
+                FileInfo {
+                    path: PathBuf::from("b/c"),
+                    len: 4,
+                    torrent_offset: 8,
+                    ..Default::default()
+                },
+            ],
+        };
+        std::fs::remove_dir_all("/tmp/storage_attrs_test").ok();
+
+        let mut fs_storage = fs_storage(info.clone(), Allocation::None);
+        let mut mem_storage = MemoryStorage::new(&info);
+        let storages: [&mut dyn Storage; 2] =
+            [&mut fs_storage, &mut mem_storage];
+        for storage in storages {
+            storage.open().unwrap();
+
+            // the padding is present before anything is written
+            let mut buf = [1; 5];
+            storage.read(3, &mut [&mut buf]).unwrap();
+            assert_eq!(buf, [0; 5]);
+
+            storage.write(0, &[&[1, 2, 3, 0, 0], &[0, 0, 0]]).unwrap();
+            storage.write(8, &[&[9, 10, 11, 12]]).unwrap();
+            let mut buf = [1; 12];
+            storage.read(0, &mut [&mut buf]).unwrap();
+            assert_eq!(buf, [1, 2, 3, 0, 0, 0, 0, 0, 9, 10, 11, 12]);
+        }
+
+        let dir = &info.download_dir;
+        assert!(!dir.join(".pad").exists());
+        let mode = std::fs::metadata(dir.join("a")).unwrap().permissions();
+        assert_ne!(mode.mode() & 0o111, 0);
+        assert_eq!(
+            std::fs::read_link(dir.join("b/link")).unwrap(),
+            Path::new("../a")
+        );
+        assert_eq!(std::fs::read(dir.join("b/link")).unwrap(), [1, 2, 3]);
+
+        let dst_dir = Path::new("/tmp/storage_attrs_test/dst");
+        fs_storage
+            .move_to(dst_dir, MoveConflict::Fail, &mut |_| {})
+            .unwrap();
+        assert!(!dir.exists());
+        assert_eq!(std::fs::read(dst_dir.join("b/link")).unwrap(), [1, 2, 3]);
+
+        fs_storage.delete().unwrap();
+        assert!(!dst_dir.exists());
+        std::fs::remove_dir_all("/tmp/storage_attrs_test").ok();
+    }
+
+    /// Tests that storages sharing a file pool keep at most its limit of files
+    /// open, and close their files when dropped.
+    #[test]
+    fn test_fs_file_pool() {
+        let pool = Arc::new(FilePool::new(2));
+        let mut storages: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let dir = format!("/tmp/storage_file_pool_test/{}", name);
+                let mut info = single_file_info(&dir, 12);
+                info.files = (0..3)
+                    .map(|i| FileInfo {
+                        path: PathBuf::from(i.to_string()),
+                        len: 4,
+                        torrent_offset: i * 4,
+                        ..Default::default()
+                    })
+                    .collect();
+                FsStorage::new(info, Allocation::None, Arc::clone(&pool))
+            })
+            .collect();
+        std::fs::remove_dir_all("/tmp/storage_file_pool_test").ok();
+
+        for storage in storages.iter_mut() {
+            storage.open().unwrap();
+            assert_eq!(pool.open_count(), 0);
+        }
+        let data: Vec<u8> = (0..12).collect();
+        for storage in storages.iter() {
+            storage.write(0, &[&data]).unwrap();
+            assert_eq!(pool.open_count(), 2);
+        }
+        for storage in storages.iter() {
+            let mut buf = [0; 12];
+            storage.read(0, &mut [&mut buf]).unwrap();
+            assert_eq!(buf[..], data[..]);
+        }
+
+        // the last read files are those of the second storage
+        storages.remove(1);
+        assert_eq!(pool.open_count(), 0);
+        std::fs::remove_dir_all("/tmp/storage_file_pool_test").ok();
+    }
+
+    /// Tests that the file system storage allocates files according to the
+    /// allocation mode.
+    #[test]
+    fn test_fs_allocation() {
+        let len = 100_000;
+        for (allocation, expected_len) in [
+            (Allocation::None, 0),
+            (Allocation::Sparse, len),
+            (Allocation::Full, len),
+        ] {
+            let info = single_file_info("/tmp/storage_allocation_test", len);
+            let path = info.download_dir.join(&info.files[0].path);
+            std::fs::remove_file(&path).ok();
+
+            let mut storage = fs_storage(info, allocation);
+            storage.open().unwrap();
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!(metadata.len(), expected_len, "{:?}", allocation);
+            if allocation == Allocation::Full {
+                use std::os::unix::fs::MetadataExt;
+                assert!(metadata.blocks() * 512 >= len);
+            }
+            storage.delete().unwrap();
+        }
+    }
+
+    /// Tests that opening the file system storage fails if the torrent doesn't
+    /// fit on disk, without creating anything.
+    #[test]
+    fn test_fs_insufficient_space() {
+        let info = single_file_info("/tmp/storage_no_space_test", 1 << 60);
+        let mut storage = fs_storage(info.clone(), Allocation::None);
+        assert!(matches!(
+            storage.open(),
+            Err(NewTorrentError::InsufficientSpace { required, .. })
+                if required == 1 << 60
+        ));
+        assert!(!info.download_dir.exists());
+    }
+
+    fn fs_storage(info: StorageInfo, allocation: Allocation) -> FsStorage {
+        FsStorage::new(info, allocation, Arc::new(FilePool::new(16)))
+    }
+
+    fn single_file_info(download_dir: &str, len: u64) -> StorageInfo {
+        StorageInfo {
+            piece_count: len.div_ceil(16) as usize,
+            piece_len: 16,
+            last_piece_len: (len - (len.div_ceil(16) - 1) * 16) as u32,
+            download_len: len,
+            download_dir: PathBuf::from(download_dir),
+            files: vec![FileInfo {
+                path: PathBuf::from("file"),
+                len,
+                torrent_offset: 0,
+                ..Default::default()
+            }],
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    io,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lru::LruCache;
+
+use crate::{disk::io::file::TorrentFile, FileIndex};
+
+/// Identifies a file of a storage in the pool.
+type Key = (u64, FileIndex);
+
+/// The open file handles of the file system storages of all torrents.
+///
+/// Files are opened when they are first read or written, and are kept open
+/// for subsequent access, but only up to a limit: when it's reached, the least
+/// recently used file is closed. This keeps torrents with many files, or many
+/// torrents, from running out of file descriptors.
+///
+/// A file that is closed while it's being read or written is only closed once
+/// that's done, so the limit may be briefly exceeded.
+pub struct FilePool {
+    files: Mutex<LruCache<Key, Arc<TorrentFile>>>,
+    /// The id of the next storage that uses the pool.
+    next_storage_id: AtomicU64,
+}
+
+impl FilePool {
+    /// Creates a pool that keeps at most `max_open_files` files open (but at
+    /// least one).
+    pub fn new(max_open_files: usize) -> Self {
+        let cap =
+            NonZeroUsize::new(max_open_files).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            files: Mutex::new(LruCache::new(cap)),
+            next_storage_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of currently open files.
+    pub fn open_count(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    /// Returns a new id with which a storage can identify its files.
+    pub(crate) fn register(&self) -> u64 {
+        self.next_storage_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the handle of the storage's file, opening it with `open` if
+    /// it's not open, which may close the least recently used file.
+    pub(crate) fn get(
+        &self,
+        storage_id: u64,
+        index: FileIndex,
+        open: impl FnOnce() -> io::Result<TorrentFile>,
+    ) -> io::Result<Arc<TorrentFile>> {
+        let key = (storage_id, index);
+        if let Some(file) = self.files.lock().unwrap().get(&key) {
+            return Ok(Arc::clone(file));
+        }
+
+        // the file is opened without holding the lock so that other files can
+        // be accessed in the meantime
+        log::trace!("Opening file {} of storage {}", index, storage_id);
+        let file = Arc::new(open()?);
+        // if the file was opened by another thread in the meantime, that
+        // handle is used and this one is closed
+        let mut files = self.files.lock().unwrap();
+        Ok(Arc::clone(files.get_or_insert(key, || file)))
+    }
+
+    /// Closes all open files of the storage.
+    pub(crate) fn close_all(&self, storage_id: u64) {
+        let mut files = self.files.lock().unwrap();
+        let keys: Vec<_> = files
+            .iter()
+            .map(|(key, _)| *key)
+            .filter(|(id, _)| *id == storage_id)
+            .collect();
+        for key in keys {
+            files.pop(&key);
+        }
+    }
+}
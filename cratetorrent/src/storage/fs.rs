@@ -0,0 +1,876 @@
+use std::{
+    fs, io, iter,
+    ops::Range,
+    os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use nix::sys::statvfs::statvfs;
+
+use crate::{
+    conf::Allocation,
+    disk::io::file::TorrentFile,
+    error::NewTorrentError,
+    iovecs::{self, IoVec, IoVecs},
+    storage_info::{FileInfo, StorageInfo},
+};
+
+use super::{
+    mmap, range_lock::RangeLock, FileError, FilePool, FileState, MoveConflict,
+    Storage,
+};
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+use super::FileRegion;
+
+/// The default storage, which saves the torrent's files in its download
+/// directory.
+pub struct FsStorage {
+    /// The layout of the torrent's files.
+    ///
+    /// The download directory is updated when the storage is moved.
+    info: StorageInfo,
+    /// How the files are allocated when they are created.
+    allocation: Allocation,
+    /// The pool from which the handles of the files are taken, opening them
+    /// as they are needed.
+    pool: Arc<FilePool>,
+    /// The id of the storage in the pool.
+    id: u64,
+    /// A byte-range lock for each file in torrent.
+    ///
+    /// Each writer thread gets exclusive access to the part of the file it
+    /// writes, so that pieces in different parts of the same file are written
+    /// in parallel. Multiple readers may read from the same part of a file,
+    /// but not while there is a pending write to it.
+    locks: Vec<RangeLock>,
+    /// Whether reads of entire blocks are served from memory mapped files.
+    mmap_reads: bool,
+    /// The memory mappings of the files, which are `None` until a file is
+    /// first read from when reads are memory mapped.
+    maps: Mutex<Vec<Option<FileMap>>>,
+}
+
+/// The memory mapping of one of the torrent's files.
+#[derive(Clone)]
+struct FileMap {
+    buf: Bytes,
+    /// The state of the file when it was mapped.
+    state: FileState,
+    /// The inode of the mapped file, which differs from that of the file at
+    /// its path once it's replaced.
+    ino: u64,
+}
+
+impl FsStorage {
+    /// Creates the storage for the torrent's files, whose handles are kept in
+    /// the pool. Nothing is created on disk until the storage is opened.
+    pub fn new(
+        info: StorageInfo,
+        allocation: Allocation,
+        pool: Arc<FilePool>,
+    ) -> Self {
+        let id = pool.register();
+        let locks = info.files.iter().map(|_| RangeLock::default()).collect();
+        let maps = Mutex::new(vec![None; info.files.len()]);
+        Self {
+            info,
+            allocation,
+            pool,
+            id,
+            locks,
+            mmap_reads: false,
+            maps,
+        }
+    }
+
+    /// Serves the blocks that peers request straight from the files mapped
+    /// into memory, see [`Storage::read_mapped`].
+    ///
+    /// This is meant for seeding: the kernel's page cache takes the place of
+    /// the disk cache, which saves memory and syscalls. Reading the missing
+    /// part of a mapping crashes the process, so each file is checked before
+    /// it's read from, and its mapping is dropped once it changed. This only
+    /// leaves a small window in which another program may truncate it.
+    pub fn with_mmap_reads(mut self) -> Self {
+        self.mmap_reads = true;
+        self
+    }
+
+    /// Returns the memory mapping of the file, which covers at least the
+    /// first `len` bytes of it.
+    ///
+    /// The file is mapped when it's first read from. Its state is checked on
+    /// each read, and if it was changed (e.g. truncated or replaced) since it
+    /// was mapped, the mapping is dropped and an error is returned, so that
+    /// the file is checked again. The mapping is also dropped if the read
+    /// fails for any other reason.
+    fn map(&self, index: usize, len: u64) -> io::Result<Bytes> {
+        let file = &self.info.files[index];
+        let path = self.info.download_dir.join(&file.path);
+        let mut maps = self.maps.lock().unwrap();
+        let map = maps[index].take();
+
+        let metadata =
+            fs::metadata(&path).map_err(|e| FileError::wrap(path.clone(), e))?;
+        let state = file_state(&metadata);
+        if let Some(map) = map {
+            if map.state != state || map.ino != metadata.ino() {
+                log::warn!("File {:?} changed while mapped", path);
+                // the file may have been replaced, so it's reopened
+                self.pool.close_all(self.id);
+                return Err(FileError::wrap(path, changed_error()));
+            }
+            if map.buf.len() as u64 >= len {
+                let buf = map.buf.clone();
+                maps[index] = Some(map);
+                return Ok(buf);
+            }
+        }
+
+        let handle = self.file(index)?;
+        let file_len = state.len.min(handle.info.len);
+        // like with reads, the data must have been written
+        if file_len < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let ino = handle.handle.metadata()?.ino();
+        // the open file may have been replaced since it was opened
+        if ino != metadata.ino() {
+            self.pool.close_all(self.id);
+            return Err(FileError::wrap(path, changed_error()));
+        }
+        log::debug!("Mapping file {} ({} bytes)", index, file_len);
+        let buf = mmap::map(&handle.handle, file_len)
+            .map_err(|e| FileError::wrap(path, e))?;
+        maps[index] = Some(FileMap {
+            buf: buf.clone(),
+            state,
+            ino,
+        });
+        Ok(buf)
+    }
+
+    /// Moves the torrent to the directory by renaming its root, i.e. its only
+    /// file, or the directory of an archive, so that it appears there all at
+    /// once. Across file systems, the root is [copied](copy_root) instead.
+    ///
+    /// Returns false if the root is missing, or if it already exists in the
+    /// destination, in which case the files are moved one by one.
+    fn move_root(
+        &self,
+        dir: &Path,
+        progress: &mut dyn FnMut(u64),
+    ) -> io::Result<bool> {
+        let (src, dst) = if self.info.is_archive() {
+            (self.info.download_dir.clone(), dir.to_path_buf())
+        } else {
+            let file = &self.info.files[0];
+            // a link is recreated when the file is reopened
+            if file.symlink_path.is_some() {
+                return Ok(false);
+            }
+            (
+                self.info.download_dir.join(&file.path),
+                dir.join(&file.path),
+            )
+        };
+        if fs::symlink_metadata(&src).is_err()
+            || fs::symlink_metadata(&dst).is_ok()
+        {
+            return Ok(false);
+        }
+
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let len = self.info.download_len;
+        match fs::rename(&src, &dst) {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                // the root may hold other files than the torrent's
+                copy_root(&src, &dst, &mut |copied| progress(copied.min(len)))?;
+                if let Err(e) = remove_all(&src) {
+                    log::warn!("Failed to remove moved {:?}: {}", src, e);
+                }
+            }
+            res => res?,
+        }
+        progress(len);
+        Ok(true)
+    }
+
+    /// Unmaps the files, once the mappings still in use are dropped.
+    fn unmap_all(&mut self) {
+        for map in self.maps.get_mut().unwrap().iter_mut() {
+            *map = None;
+        }
+    }
+
+    /// Returns an error if the file system of the download directory doesn't
+    /// have enough free space for the parts of the torrent's files that are
+    /// not yet on disk.
+    fn check_free_space(&self) -> Result<(), NewTorrentError> {
+        let download_dir = &self.info.download_dir;
+        let required: u64 = self
+            .info
+            .files
+            .iter()
+            .filter(|file| !file.attrs.padding)
+            .map(|file| {
+                // files that already exist (e.g. because the torrent is
+                // restarted) only need space for their missing parts, and
+                // since they may be sparse, their allocated size is what
+                // counts, not their length
+                let allocated = fs::metadata(download_dir.join(&file.path))
+                    .map(|metadata| metadata.blocks() * 512)
+                    .unwrap_or(0);
+                file.len.saturating_sub(allocated)
+            })
+            .sum();
+
+        // the download directory may not exist yet, in which case it will be
+        // created on the file system of its closest existing ancestor
+        let dir = download_dir
+            .ancestors()
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| Path::new("."));
+        let stat = statvfs(dir).map_err(io::Error::from)?;
+        let available = stat.blocks_available() * stat.fragment_size();
+        log::debug!(
+            "Torrent requires {} b, {} b available in {:?}",
+            required,
+            available,
+            dir
+        );
+
+        if required > available {
+            log::warn!("Not enough space for torrent in {:?}", download_dir);
+            return Err(NewTorrentError::InsufficientSpace {
+                required,
+                available,
+            });
+        }
+        Ok(())
+    }
+
+    /// Creates the missing directories and files of the torrent, allocating
+    /// the files, which are then closed until they are needed.
+    ///
+    /// Padding files are skipped, and symlinks are created if they don't
+    /// exist.
+    fn create_files(&self) -> io::Result<()> {
+        let download_dir = &self.info.download_dir;
+        if !download_dir.is_dir() {
+            log::warn!(
+                "Creating missing download directory {:?}",
+                download_dir
+            );
+            fs::create_dir_all(download_dir)?;
+            log::info!("Download directory {:?} created", download_dir);
+        }
+
+        debug_assert_ne!(self.info.files.len(), 0, "torrent must have files");
+        log::debug!("Opening torrent files: {:?}", self.info.files);
+
+        for file in self.info.files.iter() {
+            // padding files are all zeros so they are never written to disk
+            if file.attrs.padding {
+                continue;
+            }
+
+            // get the parent of the file path: if there is one (i.e. this is
+            // not a file in the torrent root), and doesn't exist, create it
+            let path = download_dir.join(&file.path);
+            if let Some(subdir) = path.parent()
+                && !subdir.exists()
+            {
+                log::info!("Creating torrent subdir {:?}", subdir);
+                fs::create_dir_all(subdir).inspect_err(|_| {
+                    log::error!("Failed to create subdir {:?}", subdir);
+                })?;
+            }
+
+            if let Some(target) = &file.symlink_path {
+                // an existing link is assumed to be ours from a previous run
+                if fs::symlink_metadata(&path).is_err() {
+                    let target = symlink_target(&file.path, target);
+                    log::debug!("Creating symlink {:?} -> {:?}", path, target);
+                    symlink(&target, &path)?;
+                }
+                continue;
+            }
+
+            let torrent_file =
+                TorrentFile::new(download_dir, file.clone(), self.allocation)?;
+            if file.attrs.executable {
+                // give execute permission to whoever may read the file
+                let mut perms = torrent_file.handle.metadata()?.permissions();
+                perms.set_mode(perms.mode() | (perms.mode() & 0o444) >> 2);
+                torrent_file.handle.set_permissions(perms)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the handle of the file from the pool, opening it if needed.
+    fn file(&self, index: usize) -> io::Result<Arc<TorrentFile>> {
+        self.pool.get(self.id, index, || {
+            // the file was already created and allocated when the storage
+            // was opened
+            TorrentFile::new(
+                &self.info.download_dir,
+                self.info.files[index].clone(),
+                Allocation::None,
+            )
+        })
+    }
+
+    /// Returns the indices of the files that overlap with the range of bytes.
+    fn files_intersecting(&self, offset: u64, len: u64) -> Range<usize> {
+        self.info.files_intersecting_bytes(offset..offset + len)
+    }
+
+    /// Returns whether the file has data of its own on disk that needs to be
+    /// moved along with the torrent.
+    fn is_on_disk(file: &FileInfo) -> bool {
+        !file.attrs.padding && file.symlink_path.is_none()
+    }
+
+    /// Removes the now empty subdirectories of the torrent in the directory,
+    /// and if the torrent is an archive, the directory itself.
+    ///
+    /// Directories that still have other files in them are left alone.
+    fn remove_dirs(&self, dir: &Path) {
+        for file in self.info.files.iter() {
+            // the ancestors are visited deepest first, skipping the file
+            // itself and the empty path
+            for subdir in file.path.ancestors().skip(1) {
+                if !subdir.as_os_str().is_empty() {
+                    fs::remove_dir(dir.join(subdir)).ok();
+                }
+            }
+        }
+        if self.info.is_archive() {
+            fs::remove_dir(dir).ok();
+        }
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&mut self) -> Result<(), NewTorrentError> {
+        self.check_free_space()?;
+        self.create_files()?;
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum();
+        // convert the buffers to IO slices that the underlying systemcall can
+        // deal with
+        let mut iovecs: Vec<_> =
+            bufs.iter().map(|buf| IoVec::from_slice(buf)).collect();
+        // the actual slice of buffers being worked on
+        let mut bufs = iovecs.as_mut_slice();
+
+        // the offset at which we need to write in torrent, which is updated
+        // with each write
+        let mut torrent_offset = offset;
+        let mut remaining_len = len;
+        for index in self.files_intersecting(offset, len) {
+            let file_slice =
+                self.info.files[index].get_slice(torrent_offset, remaining_len);
+            // empty files have nothing to write to
+            if file_slice.len == 0 {
+                continue;
+            }
+
+            if Self::is_on_disk(&self.info.files[index]) {
+                // writes take exclusive access to the part of the file so
+                // that concurrent reads don't see partially written data
+                let range =
+                    file_slice.offset..file_slice.offset + file_slice.len;
+                let _lock = self.locks[index].write(range);
+                // `TorrentFile::write` only writes at most `file_slice.len`
+                // bytes of `bufs` to disk and returns the portion that wasn't
+                // written, which is the write buffer of the next file
+                bufs = self
+                    .file(index)
+                    .and_then(|file| file.write(file_slice, bufs))
+                    .map_err(|e| {
+                        let file = &self.info.files[index];
+                        let path = self.info.download_dir.join(&file.path);
+                        FileError::wrap(path, e)
+                    })?;
+            } else {
+                // the padding's zeros are discarded
+                bufs =
+                    IoVecs::bounded(bufs, file_slice.len as usize).into_tail();
+            }
+
+            torrent_offset += file_slice.len;
+            remaining_len -= file_slice.len;
+        }
+
+        // we should have used up all write buffers (i.e. written all bytes to
+        // disk)
+        debug_assert!(bufs.is_empty());
+
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, bufs: &mut [&mut [u8]]) -> io::Result<()> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum();
+        let mut iovecs: Vec<_> = bufs
+            .iter_mut()
+            .map(|buf| IoVec::from_mut_slice(buf))
+            .collect();
+        let mut bufs = iovecs.as_mut_slice();
+
+        // the offset at which we need to read from torrent, which is updated
+        // with each read
+        let mut torrent_offset = offset;
+        let mut remaining_len = len;
+        for index in self.files_intersecting(offset, len) {
+            let file_slice =
+                self.info.files[index].get_slice(torrent_offset, remaining_len);
+            if file_slice.len == 0 {
+                continue;
+            }
+
+            if Self::is_on_disk(&self.info.files[index]) {
+                let range =
+                    file_slice.offset..file_slice.offset + file_slice.len;
+                let _lock = self.locks[index].read(range);
+                bufs = self.file(index)?.read(file_slice, bufs)?;
+            } else {
+                // padding is always present and all zeros
+                let mut zeros = file_slice.len as usize;
+                for buf in bufs.iter_mut() {
+                    let buf = buf.as_mut_slice();
+                    let n = zeros.min(buf.len());
+                    buf[..n].fill(0);
+                    zeros -= n;
+                    if zeros == 0 {
+                        break;
+                    }
+                }
+                bufs = iovecs::advance(bufs, file_slice.len as usize);
+            }
+
+            torrent_offset += file_slice.len;
+            remaining_len -= file_slice.len;
+        }
+
+        // we should have read in the whole range
+        debug_assert_eq!(remaining_len, 0);
+
+        Ok(())
+    }
+
+    fn move_to(
+        &mut self,
+        dir: &Path,
+        conflict: MoveConflict,
+        progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()> {
+        if dir == self.info.download_dir {
+            progress(self.info.download_len);
+            return Ok(());
+        }
+        log::info!(
+            "Moving torrent storage from {:?} to {:?} ({:?})",
+            self.info.download_dir,
+            dir,
+            conflict
+        );
+
+        // the files that already exist in the destination
+        let existing: Vec<bool> = self
+            .info
+            .files
+            .iter()
+            .map(|file| Self::is_on_disk(file) && dir.join(&file.path).exists())
+            .collect();
+        if conflict == MoveConflict::Fail && existing.contains(&true) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "torrent files already exist in destination",
+            ));
+        }
+
+        // the files are closed, to be reopened at their new location
+        self.pool.close_all(self.id);
+        self.unmap_all();
+
+        // unless the torrent is merged with files already in the destination,
+        // it's moved as a whole, so that it appears there only once complete
+        if !existing.contains(&true) {
+            match self.move_root(dir, progress) {
+                Ok(true) => {
+                    self.info.download_dir = dir.into();
+                    self.create_files()?;
+                    return Ok(());
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    log::error!("Failed to move torrent to {:?}: {}", dir, e);
+                    if let Err(e) = self.create_files() {
+                        log::error!("Failed to reopen torrent files: {}", e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut moved: Vec<&FileInfo> =
+            Vec::with_capacity(self.info.files.len());
+        let mut moved_len = 0;
+        for (file, &exists) in self.info.files.iter().zip(existing.iter()) {
+            // the torrent's own copies of files that are kept are only removed
+            // once everything else is moved, so that they can be used if the
+            // move fails, and symlinks are recreated when the files are
+            // reopened
+            if Self::is_on_disk(file)
+                && !(exists && conflict == MoveConflict::KeepExisting)
+            {
+                let src = self.info.download_dir.join(&file.path);
+                let dst = dir.join(&file.path);
+                let res = match dst.parent() {
+                    Some(subdir) => fs::create_dir_all(subdir),
+                    None => Ok(()),
+                }
+                .and_then(|_| move_file(&src, &dst));
+                if let Err(e) = res {
+                    log::error!("Failed to move {:?} to {:?}: {}", src, dst, e);
+                    // move back the files moved so far so that the torrent can
+                    // continue in its old location
+                    for file in moved {
+                        let src = dir.join(&file.path);
+                        let dst = self.info.download_dir.join(&file.path);
+                        move_file(&src, &dst).ok();
+                    }
+                    self.remove_dirs(dir);
+                    // the move's error is what the caller needs to know about
+                    if let Err(e) = self.create_files() {
+                        log::error!("Failed to reopen torrent files: {}", e);
+                    }
+                    return Err(e);
+                }
+                moved.push(file);
+            }
+            moved_len += file.len;
+            progress(moved_len);
+        }
+
+        for (file, &exists) in self.info.files.iter().zip(existing.iter()) {
+            if (exists && conflict == MoveConflict::KeepExisting)
+                || file.symlink_path.is_some()
+            {
+                let path = self.info.download_dir.join(&file.path);
+                fs::remove_file(&path).ok();
+            }
+        }
+
+        let old_dir =
+            std::mem::replace(&mut self.info.download_dir, dir.into());
+        self.remove_dirs(&old_dir);
+        self.create_files()?;
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        log::info!("Deleting torrent files in {:?}", self.info.download_dir);
+        self.pool.close_all(self.id);
+        self.unmap_all();
+        for file in self.info.files.iter() {
+            let path = self.info.download_dir.join(&file.path);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        self.remove_dirs(&self.info.download_dir);
+        Ok(())
+    }
+
+    fn rename_file(&mut self, index: usize, path: &Path) -> io::Result<()> {
+        let file = &self.info.files[index];
+        if file.path == path {
+            return Ok(());
+        }
+        let src = self.info.download_dir.join(&file.path);
+        let dst = self.info.download_dir.join(path);
+        log::info!("Renaming torrent file {:?} to {:?}", src, dst);
+        if fs::symlink_metadata(&dst).is_ok() {
+            return Err(FileError::wrap(
+                dst,
+                io::ErrorKind::AlreadyExists.into(),
+            ));
+        }
+
+        // the file is closed, to be reopened at its new location
+        let (is_on_disk, is_symlink) =
+            (Self::is_on_disk(file), file.symlink_path.is_some());
+        self.pool.close_all(self.id);
+        self.unmap_all();
+        if is_on_disk && src.exists() {
+            if let Some(subdir) = dst.parent() {
+                fs::create_dir_all(subdir)
+                    .map_err(|e| FileError::wrap(subdir.into(), e))?;
+            }
+            move_file(&src, &dst).map_err(|e| FileError::wrap(src, e))?;
+        } else if is_symlink {
+            // the link's target is relative to its own location, so it's
+            // recreated at the new path when the files are reopened
+            fs::remove_file(&src).ok();
+        }
+
+        let old_path = std::mem::replace(
+            &mut self.info.files[index].path,
+            path.to_path_buf(),
+        );
+        // the subdirectories of the old path left empty are removed
+        for subdir in old_path.ancestors().skip(1) {
+            if !subdir.as_os_str().is_empty() {
+                fs::remove_dir(self.info.download_dir.join(subdir)).ok();
+            }
+        }
+        self.create_files()
+    }
+
+    fn sync(&self, offset: u64, len: u64) -> io::Result<()> {
+        for index in self.files_intersecting(offset, len) {
+            let file = &self.info.files[index];
+            if !Self::is_on_disk(file) || file.len == 0 {
+                continue;
+            }
+            // syncing through any handle of the file flushes all its data,
+            // so the file may have been closed and reopened since the write
+            self.file(index)
+                .and_then(|f| f.handle.sync_data())
+                .map_err(|e| {
+                    let path = self.info.download_dir.join(&file.path);
+                    FileError::wrap(path, e)
+                })?;
+        }
+        Ok(())
+    }
+
+    fn invalidate_files(&self, files: &[usize]) {
+        let mut maps = self.maps.lock().unwrap();
+        for &index in files {
+            maps[index] = None;
+        }
+        // the files are reopened in case they were replaced
+        self.pool.close_all(self.id);
+    }
+
+    fn file_states(&self) -> io::Result<Option<Vec<FileState>>> {
+        let states = self
+            .info
+            .files
+            .iter()
+            .map(|file| {
+                // padding is never stored, and symlinks have no data of their
+                // own, so they're always intact
+                if !Self::is_on_disk(file) {
+                    return Ok(FileState {
+                        len: file.len,
+                        mtime: None,
+                    });
+                }
+                let path = self.info.download_dir.join(&file.path);
+                match fs::metadata(&path) {
+                    Ok(metadata) => Ok(file_state(&metadata)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        Ok(FileState::default())
+                    }
+                    Err(e) => Err(FileError::wrap(path, e)),
+                }
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Some(states))
+    }
+
+    fn read_mapped(
+        &self,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Option<Vec<Bytes>>> {
+        if !self.mmap_reads {
+            return Ok(None);
+        }
+
+        let mut bufs = Vec::new();
+        let mut torrent_offset = offset;
+        let mut remaining_len = len;
+        for index in self.files_intersecting(offset, len) {
+            let file = &self.info.files[index];
+            let file_slice = file.get_slice(torrent_offset, remaining_len);
+            if file_slice.len == 0 {
+                continue;
+            }
+
+            let start = file_slice.offset as usize;
+            let end = start + file_slice.len as usize;
+            if Self::is_on_disk(file) {
+                let map = self.map(index, end as u64)?;
+                bufs.push(map.slice(start..end));
+            } else {
+                // padding is always present and all zeros
+                bufs.push(Bytes::from(vec![0; file_slice.len as usize]));
+            }
+
+            torrent_offset += file_slice.len;
+            remaining_len -= file_slice.len;
+        }
+        Ok(Some(bufs))
+    }
+
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn file_regions(
+        &self,
+        offset: u64,
+        len: u64,
+        is_write: bool,
+    ) -> io::Result<Option<Vec<FileRegion<'_>>>> {
+        let mut regions = Vec::new();
+        let mut torrent_offset = offset;
+        let mut remaining_len = len;
+        for index in self.files_intersecting(offset, len) {
+            let info = &self.info.files[index];
+            let slice = info.get_slice(torrent_offset, remaining_len);
+            if slice.len == 0 {
+                continue;
+            }
+            let (file, guard) = if Self::is_on_disk(info) {
+                let range = slice.offset..slice.offset + slice.len;
+                let lock = &self.locks[index];
+                let guard = if is_write {
+                    lock.try_write(range)
+                } else {
+                    lock.try_read(range)
+                };
+                let guard = guard.ok_or(io::ErrorKind::WouldBlock)?;
+                (Some(self.file(index)?), Some(guard))
+            } else {
+                (None, None)
+            };
+            regions.push(FileRegion {
+                path: self.info.download_dir.join(&info.path),
+                file,
+                slice,
+                _guard: guard,
+            });
+            torrent_offset += slice.len;
+            remaining_len -= slice.len;
+        }
+        Ok(Some(regions))
+    }
+}
+
+impl Drop for FsStorage {
+    fn drop(&mut self) {
+        self.pool.close_all(self.id);
+    }
+}
+
+/// Returns the target of the symlink at the path, relative to the symlink's
+/// directory, so that it remains valid when the torrent is moved.
+///
+/// Both paths are relative to the torrent's directory.
+fn symlink_target(path: &Path, target: &Path) -> PathBuf {
+    let depth = path.components().count().saturating_sub(1);
+    iter::repeat_n(Path::new(".."), depth)
+        .collect::<PathBuf>()
+        .join(target)
+}
+
+/// Moves the file by renaming it, or if that's not possible because the
+/// destination is on another file system, by copying and then removing it.
+fn move_file(src: &Path, dst: &Path) -> io::Result<()> {
+    match fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            log::debug!("Copying {:?} to {:?} across file systems", src, dst);
+            fs::copy(src, dst)?;
+            fs::remove_file(src)
+        }
+        res => res,
+    }
+}
+
+/// Copies the file or directory to the destination on another file system,
+/// reporting the number of bytes copied so far to the progress function.
+///
+/// It's copied under a temporary name next to the destination, and only
+/// renamed into place once all of it is copied and synced, so that the
+/// destination never holds an incomplete copy. The source is left as is.
+pub(super) fn copy_root(
+    src: &Path,
+    dst: &Path,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
+    let mut tmp = dst.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    log::debug!("Copying {:?} to {:?} across file systems", src, tmp);
+    // the leftover of an interrupted copy is started over
+    remove_all(&tmp).ok();
+    let res = copy_all(src, &tmp, &mut 0, progress)
+        .and_then(|_| fs::rename(&tmp, dst));
+    if res.is_err() {
+        remove_all(&tmp).ok();
+    }
+    res
+}
+
+/// Copies the file or directory, along with everything in it, adding the
+/// length of the copied files to `copied`.
+fn copy_all(
+    src: &Path,
+    dst: &Path,
+    copied: &mut u64,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.is_dir() {
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let dst = dst.join(entry.file_name());
+            copy_all(&entry.path(), &dst, copied, progress)?;
+        }
+    } else if metadata.is_symlink() {
+        symlink(fs::read_link(src)?, dst)?;
+    } else {
+        *copied += fs::copy(src, dst)?;
+        fs::File::open(dst)?.sync_all()?;
+        progress(*copied);
+    }
+    Ok(())
+}
+
+/// Removes the file or directory, along with everything in it.
+fn remove_all(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Returns the state of the file with the metadata.
+fn file_state(metadata: &fs::Metadata) -> FileState {
+    FileState {
+        len: metadata.len(),
+        mtime: metadata.modified().ok(),
+    }
+}
+
+/// Returns the error of a read from a mapped file that was changed since it
+/// was mapped.
+fn changed_error() -> io::Error {
+    io::Error::other("file changed while mapped")
+}
@@ -0,0 +1,127 @@
+use std::{io, ops::Range, path::Path, sync::RwLock};
+
+use crate::{error::NewTorrentError, storage_info::StorageInfo, Bitfield};
+
+use super::{MoveConflict, Storage};
+
+/// A storage that keeps the torrent's data in memory, which is mostly useful
+/// for tests.
+///
+/// The whole torrent is allocated when the storage is opened, so this is only
+/// suitable for small torrents. The data is lost when the storage is dropped.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    /// The length of the torrent.
+    len: u64,
+    /// The byte ranges of the padding files, which are always present.
+    padding: Vec<Range<usize>>,
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The torrent's data, as a single contiguous byte array.
+    data: Vec<u8>,
+    /// Which bytes of the data have been written, so that reading data that
+    /// was never written fails like it does with files.
+    written: Bitfield,
+}
+
+impl MemoryStorage {
+    /// Creates the storage for the torrent. Nothing is allocated until the
+    /// storage is opened.
+    pub fn new(info: &StorageInfo) -> Self {
+        Self {
+            len: info.download_len,
+            padding: info
+                .files
+                .iter()
+                .filter(|file| file.attrs.padding)
+                .map(|file| {
+                    let range = file.byte_range();
+                    range.start as usize..range.end as usize
+                })
+                .collect(),
+            inner: RwLock::default(),
+        }
+    }
+
+    /// Marks all data as missing, except for the padding.
+    fn reset_written(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        inner.written.fill(false);
+        for range in self.padding.iter() {
+            inner.written[range.clone()].fill(true);
+        }
+    }
+
+    /// Returns the torrent's range of bytes starting at the offset, or an
+    /// error if it's out of the torrent's bounds.
+    fn range(&self, offset: u64, len: usize) -> io::Result<(usize, usize)> {
+        let end = offset + len as u64;
+        if end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range exceeds torrent length",
+            ));
+        }
+        Ok((offset as usize, end as usize))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&mut self) -> Result<(), NewTorrentError> {
+        let inner = self.inner.get_mut().unwrap();
+        inner.data = vec![0; self.len as usize];
+        inner.written = Bitfield::repeat(false, self.len as usize);
+        self.reset_written();
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, bufs: &[&[u8]]) -> io::Result<()> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let (start, end) = self.range(offset, len)?;
+        let mut inner = self.inner.write().unwrap();
+        let mut pos = start;
+        for buf in bufs {
+            inner.data[pos..pos + buf.len()].copy_from_slice(buf);
+            pos += buf.len();
+        }
+        inner.written[start..end].fill(true);
+        Ok(())
+    }
+
+    fn read(&self, offset: u64, bufs: &mut [&mut [u8]]) -> io::Result<()> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        let (start, end) = self.range(offset, len)?;
+        let inner = self.inner.read().unwrap();
+        if !inner.written[start..end].all() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut pos = start;
+        for buf in bufs {
+            let buf_len = buf.len();
+            buf.copy_from_slice(&inner.data[pos..pos + buf_len]);
+            pos += buf_len;
+        }
+        Ok(())
+    }
+
+    fn move_to(
+        &mut self,
+        _dir: &Path,
+        _conflict: MoveConflict,
+        progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()> {
+        // the data is not stored in a directory, so there is nothing to move
+        progress(self.len);
+        Ok(())
+    }
+
+    fn delete(&mut self) -> io::Result<()> {
+        // start over as if nothing had been written
+        self.inner.get_mut().unwrap().data.fill(0);
+        self.reset_written();
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use std::{ffi::c_void, fs::File, io, num::NonZeroUsize, ptr::NonNull};
+
+use bytes::Bytes;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+
+/// A read-only, shared memory mapping of the start of a file.
+///
+/// The mapped pages are backed by the kernel's page cache, so reading from
+/// the mapping doesn't copy the data into memory of our own. If the file is
+/// truncated while it's mapped, reading the pages past its end raises
+/// `SIGBUS`, so the file's length must be checked before reading from it.
+struct Mapping {
+    ptr: NonNull<c_void>,
+    len: usize,
+}
+
+// The mapping is read-only, so it may be read from any thread.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl AsRef<[u8]> for Mapping {
+    fn as_ref(&self) -> &[u8] {
+        // SAFETY: the pages are mapped until the mapping is dropped. The
+        // mapping is shared, so the bytes still change under the slice if
+        // another program writes to the file, and reading them raises
+        // `SIGBUS` once it's truncated. The slices are thus only read for
+        // pieces that were already verified, right after the file's length
+        // and state are checked in `FsStorage::map`.
+        unsafe {
+            std::slice::from_raw_parts(self.ptr.as_ptr() as *const u8, self.len)
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the pointer and length are those of the mapping
+        if let Err(e) = unsafe { munmap(self.ptr, self.len) } {
+            log::error!("Cannot unmap file: {}", e);
+        }
+    }
+}
+
+/// Maps the first `len` bytes of the file into memory, and returns them as a
+/// buffer that keeps the file mapped as long as it or any of its slices are
+/// alive.
+pub(super) fn map(file: &File, len: u64) -> io::Result<Bytes> {
+    let Some(len) = NonZeroUsize::new(len as usize) else {
+        return Ok(Bytes::new());
+    };
+    // SAFETY: a new mapping is created, which doesn't alias any memory
+    let ptr = unsafe {
+        mmap(
+            None,
+            len,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_SHARED,
+            file,
+            0,
+        )
+    }?;
+    Ok(Bytes::from_owner(Mapping {
+        ptr,
+        len: len.get(),
+    }))
+}
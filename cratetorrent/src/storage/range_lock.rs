@@ -0,0 +1,161 @@
+use std::{
+    ops::Range,
+    sync::{Condvar, Mutex},
+};
+
+/// A reader-writer lock over the byte ranges of a file.
+///
+/// Reads and writes of disjoint ranges don't wait for each other, so pieces
+/// can be written to and read from the same file in parallel, since
+/// `pwritev`/`preadv` take the offset at which they access the file. Reads of
+/// overlapping ranges don't wait for each other either, but a write waits
+/// for any access to a range overlapping with its own, so that reads never
+/// see partially written data.
+///
+/// Pieces are rarely accessed concurrently in overlapping ranges, so the held
+/// ranges are kept in a list.
+///
+/// The io_uring operations of the disk task must not block its runtime, so
+/// they only try to take their ranges, and retry if they're held.
+#[derive(Default)]
+pub(super) struct RangeLock {
+    held: Mutex<Vec<Held>>,
+    /// Signaled when a range is released.
+    released: Condvar,
+}
+
+struct Held {
+    range: Range<u64>,
+    is_write: bool,
+}
+
+impl RangeLock {
+    /// Blocks until the range can be read, and returns a guard that holds it
+    /// until it's dropped.
+    pub fn read(&self, range: Range<u64>) -> RangeGuard<'_> {
+        self.lock(range, false)
+    }
+
+    /// Blocks until the range can be written, and returns a guard that holds
+    /// it until it's dropped.
+    pub fn write(&self, range: Range<u64>) -> RangeGuard<'_> {
+        self.lock(range, true)
+    }
+
+    /// Returns a guard that holds the range for reading, or `None` if it
+    /// would have to wait for a write.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    pub fn try_read(&self, range: Range<u64>) -> Option<RangeGuard<'_>> {
+        self.try_lock(range, false)
+    }
+
+    /// Returns a guard that holds the range for writing, or `None` if it
+    /// would have to wait for other accesses.
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    pub fn try_write(&self, range: Range<u64>) -> Option<RangeGuard<'_>> {
+        self.try_lock(range, true)
+    }
+
+    fn lock(&self, range: Range<u64>, is_write: bool) -> RangeGuard<'_> {
+        let mut held = self.held.lock().unwrap();
+        while is_held(&held, &range, is_write) {
+            held = self.released.wait(held).unwrap();
+        }
+        self.hold(&mut held, range, is_write)
+    }
+
+    #[cfg(all(feature = "io_uring", target_os = "linux"))]
+    fn try_lock(
+        &self,
+        range: Range<u64>,
+        is_write: bool,
+    ) -> Option<RangeGuard<'_>> {
+        let mut held = self.held.lock().unwrap();
+        if is_held(&held, &range, is_write) {
+            return None;
+        }
+        Some(self.hold(&mut held, range, is_write))
+    }
+
+    fn hold(
+        &self,
+        held: &mut Vec<Held>,
+        range: Range<u64>,
+        is_write: bool,
+    ) -> RangeGuard<'_> {
+        held.push(Held {
+            range: range.clone(),
+            is_write,
+        });
+        RangeGuard {
+            lock: self,
+            range,
+            is_write,
+        }
+    }
+}
+
+/// Returns whether an access to the range has to wait for the held ranges.
+fn is_held(held: &[Held], range: &Range<u64>, is_write: bool) -> bool {
+    held.iter().any(|other| {
+        (is_write || other.is_write)
+            && other.range.start < range.end
+            && range.start < other.range.end
+    })
+}
+
+/// Holds a range of a [`RangeLock`], releasing it when dropped.
+pub(super) struct RangeGuard<'a> {
+    lock: &'a RangeLock,
+    range: Range<u64>,
+    is_write: bool,
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.lock.held.lock().unwrap();
+        // the same range may be held by several readers, any of which may be
+        // removed
+        if let Some(pos) = held.iter().position(|other| {
+            other.range == self.range && other.is_write == self.is_write
+        }) {
+            held.swap_remove(pos);
+        }
+        drop(held);
+        self.lock.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use super::*;
+
+    /// Tests that accesses to disjoint byte ranges of a file don't wait for
+    /// each other, while an access overlapping a write waits for it.
+    #[test]
+    fn test_range_lock() {
+        let lock = RangeLock::default();
+        let write = lock.write(0..10);
+        let read = lock.read(10..20);
+        thread::scope(|scope| {
+            // disjoint writes and overlapping reads are not held back
+            scope.spawn(|| drop(lock.write(20..30))).join().unwrap();
+            scope.spawn(|| drop(lock.read(15..25))).join().unwrap();
+
+            let (tx, rx) = mpsc::channel();
+            let lock = &lock;
+            scope.spawn(move || {
+                let _write = lock.write(5..15);
+                tx.send(()).unwrap();
+            });
+            let timeout = Duration::from_millis(50);
+            assert!(rx.recv_timeout(timeout).is_err());
+            drop(write);
+            assert!(rx.recv_timeout(timeout).is_err());
+            drop(read);
+            rx.recv().unwrap();
+        });
+    }
+}
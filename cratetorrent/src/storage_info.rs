@@ -1,9 +1,9 @@
-use std::{ops::Range, path::PathBuf};
+use std::{fmt, ops::Range, path::PathBuf};
 
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use crate::{metainfo::Metainfo, FileIndex, PieceIndex, Sha1Hash};
 
 /// Information about a torrent's file.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct FileInfo {
     /// The file's relative path from the download directory.
     pub path: PathBuf,
@@ -13,6 +13,13 @@ pub struct FileInfo {
     /// torrent are viewed as a single contiguous byte array. This is always
     /// 0 for a single file torrent.
     pub torrent_offset: u64,
+    /// The file's attributes.
+    pub attrs: FileAttrs,
+    /// If the file is a symbolic link, the path of its target relative to the
+    /// torrent's directory.
+    pub symlink_path: Option<PathBuf>,
+    /// The SHA-1 hash of the whole file, if the metainfo has it.
+    pub sha1: Option<Sha1Hash>,
 }
 
 impl FileInfo {
@@ -40,7 +47,8 @@ impl FileInfo {
     /// # Panics
     ///
     /// This will panic if `torrent_offset` is smaller than the file's offset in
-    /// torrent, or if it's past the last byte in file.
+    /// torrent, or if it's past the last byte in file. Empty files return an
+    /// empty slice at their offset.
     pub fn get_slice(&self, torrent_offset: u64, len: u64) -> FileSlice {
         assert!(
             torrent_offset >= self.torrent_offset,
@@ -49,7 +57,8 @@ impl FileInfo {
 
         let torrent_end_offset = self.torrent_end_offset();
         assert!(
-            torrent_offset < torrent_end_offset,
+            torrent_offset < torrent_end_offset
+                || (self.len == 0 && torrent_offset == self.torrent_offset),
             "torrent offset must be smaller than file end offset",
         );
 
@@ -60,6 +69,51 @@ impl FileInfo {
     }
 }
 
+/// The attributes of a file, as defined by
+/// [BEP 47](http://bittorrent.org/beps/bep_0047.html).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileAttrs {
+    /// The file is only there to align the next file to a piece boundary. Its
+    /// data is all zeros, which is never stored, so it's always present.
+    pub padding: bool,
+    /// The file is made executable.
+    pub executable: bool,
+    /// The file should be hidden. This has no effect on Unix, where files are
+    /// hidden by their names.
+    pub hidden: bool,
+    /// The file is a symbolic link to [`FileInfo::symlink_path`], and has no
+    /// data of its own.
+    pub symlink: bool,
+}
+
+impl From<&str> for FileAttrs {
+    /// Parses the `attr` string of the metainfo, ignoring unknown flags.
+    fn from(attr: &str) -> Self {
+        Self {
+            padding: attr.contains('p'),
+            executable: attr.contains('x'),
+            hidden: attr.contains('h'),
+            symlink: attr.contains('l'),
+        }
+    }
+}
+
+impl fmt::Display for FileAttrs {
+    /// Formats the attributes as the `attr` string of the metainfo.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (self.padding, 'p'),
+            (self.executable, 'x'),
+            (self.hidden, 'h'),
+            (self.symlink, 'l'),
+        ];
+        for (_, flag) in flags.iter().filter(|(is_set, _)| *is_set) {
+            write!(f, "{}", flag)?;
+        }
+        Ok(())
+    }
+}
+
 /// Represents the location of a range of bytes within a file.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FileSlice {
@@ -96,7 +150,8 @@ pub struct StorageInfo {
     /// E.g. downloading files into ~/Downloads/<torrent> instead of just
     /// ~/Downloads.
     pub download_dir: PathBuf,
-    /// All files in torrent.
+    /// All files in torrent, including the padding files, whose data is not
+    /// stored.
     pub files: Vec<FileInfo>,
 }
 
@@ -110,12 +165,7 @@ impl StorageInfo {
             download_len - piece_len as u64 * (piece_count - 1) as u64;
         let last_piece_len = last_piece_len as u32;
 
-        // if this is an archive, download files into torrent's own dir
-        let download_dir = if metainfo.is_archive() {
-            download_dir.join(&metainfo.name)
-        } else {
-            download_dir
-        };
+        let download_dir = Self::torrent_dir(metainfo, download_dir);
 
         Self {
             piece_count,
@@ -127,6 +177,23 @@ impl StorageInfo {
         }
     }
 
+    /// Returns the directory in which the torrent's files are stored, if it's
+    /// downloaded into the given download directory.
+    pub fn torrent_dir(metainfo: &Metainfo, download_dir: PathBuf) -> PathBuf {
+        // if this is an archive, download files into torrent's own dir
+        if metainfo.is_archive() {
+            download_dir.join(&metainfo.name)
+        } else {
+            download_dir
+        }
+    }
+
+    /// Returns true if the torrent is an archive, whose files are stored in
+    /// its own directory.
+    pub fn is_archive(&self) -> bool {
+        self.files.len() > 1
+    }
+
     /// Returns the zero-based indices of the files of torrent that intersect
     /// with the piece.
     ///
@@ -206,6 +273,43 @@ impl StorageInfo {
         }
     }
 
+    /// Returns the slices of the files that the piece overlaps with, along
+    /// with the index of each file.
+    ///
+    /// The first and last slices may cover only part of their files, and the
+    /// lengths of the slices always add up to the piece's length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the piece index is invalid.
+    pub fn piece_file_slices(
+        &self,
+        index: PieceIndex,
+    ) -> impl Iterator<Item = (FileIndex, FileSlice)> + '_ {
+        let mut torrent_offset = self.torrent_piece_offset(index);
+        let mut remaining_piece_len = self.piece_len(index) as u64;
+        self.files_intersecting_piece(index).map(move |file_index| {
+            let slice = self.files[file_index]
+                .get_slice(torrent_offset, remaining_piece_len);
+            torrent_offset += slice.len;
+            remaining_piece_len -= slice.len;
+            (file_index, slice)
+        })
+    }
+
+    /// Returns the pieces that overlap with the given non-empty,
+    /// left-inclusive range of bytes.
+    pub fn pieces_intersecting_bytes(
+        &self,
+        byte_range: Range<u64>,
+    ) -> Range<PieceIndex> {
+        debug_assert!(byte_range.start < byte_range.end);
+        let piece_len = self.piece_len as u64;
+        let first = byte_range.start / piece_len;
+        let last = (byte_range.end - 1) / piece_len;
+        first as usize..last as usize + 1
+    }
+
     /// Returns the piece's absolute offset in the torrent.
     pub fn torrent_piece_offset(&self, index: PieceIndex) -> u64 {
         index as u64 * self.piece_len as u64
@@ -240,6 +344,7 @@ mod tests {
             path: PathBuf::from("/tmp/does/not/exist"),
             len: 500,
             torrent_offset: 200,
+            ..Default::default()
         };
 
         assert_eq!(
@@ -281,6 +386,7 @@ mod tests {
             path: PathBuf::from("/tmp/does/not/exist"),
             len: 500,
             torrent_offset: 200,
+            ..Default::default()
         };
         // we can't query a file slace for a byte range starting before the file
         file.get_slice(100, 400);
@@ -296,6 +402,7 @@ mod tests {
             path: PathBuf::from("/tmp/does/not/exist"),
             len: 500,
             torrent_offset: 200,
+            ..Default::default()
         };
         // we can't query a file slace for a byte range starting before the file
         file.get_slice(200 + 500, 400);
@@ -313,6 +420,7 @@ mod tests {
             path: PathBuf::from("/bogus"),
             torrent_offset: 0,
             len: download_len,
+            ..Default::default()
         }];
         let info = StorageInfo {
             piece_count,
@@ -343,36 +451,43 @@ mod tests {
                 path: PathBuf::from("/0"),
                 torrent_offset: 0,
                 len: 9,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/1"),
                 torrent_offset: 9,
                 len: 11,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/2"),
                 torrent_offset: 20,
                 len: 7,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/3"),
                 torrent_offset: 27,
                 len: 9,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/4"),
                 torrent_offset: 36,
                 len: 12,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/5"),
                 torrent_offset: 48,
                 len: 16,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/6"),
                 torrent_offset: 64,
                 len: 8,
+                ..Default::default()
             },
         ];
         let download_len: u64 = files.iter().map(|f| f.len).sum();
@@ -414,6 +529,84 @@ mod tests {
         assert_eq!(info.files_intersecting_piece(4), 6..7);
     }
 
+    #[test]
+    fn test_piece_file_slices() {
+        // same layout as in `test_files_intersecting_pieces`
+        let files = [
+            (0, 9),
+            (9, 11),
+            (20, 7),
+            (27, 9),
+            (36, 12),
+            (48, 16),
+            (64, 8),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, &(torrent_offset, len))| FileInfo {
+            path: PathBuf::from(format!("/{}", i)),
+            torrent_offset,
+            len,
+            ..Default::default()
+        })
+        .collect();
+        let info = StorageInfo {
+            piece_count: 5,
+            piece_len: 16,
+            last_piece_len: 8,
+            download_len: 72,
+            download_dir: PathBuf::from("/"),
+            files,
+        };
+
+        let slices = |index| info.piece_file_slices(index).collect::<Vec<_>>();
+        assert_eq!(
+            slices(0),
+            vec![
+                (0, FileSlice { offset: 0, len: 9 }),
+                (1, FileSlice { offset: 0, len: 7 }),
+            ]
+        );
+        assert_eq!(
+            slices(1),
+            vec![
+                (1, FileSlice { offset: 7, len: 4 }),
+                (2, FileSlice { offset: 0, len: 7 }),
+                (3, FileSlice { offset: 0, len: 5 }),
+            ]
+        );
+        assert_eq!(
+            slices(2),
+            vec![
+                (3, FileSlice { offset: 5, len: 4 }),
+                (4, FileSlice { offset: 0, len: 12 }),
+            ]
+        );
+        assert_eq!(slices(3), vec![(5, FileSlice { offset: 0, len: 16 })]);
+        assert_eq!(slices(4), vec![(6, FileSlice { offset: 0, len: 8 })]);
+
+        // the slices of all pieces must add up to the length of each file
+        let mut file_lens = vec![0; info.files.len()];
+        for piece in 0..info.piece_count {
+            for (file, slice) in info.piece_file_slices(piece) {
+                file_lens[file] += slice.len;
+            }
+        }
+        for (file, len) in info.files.iter().zip(file_lens) {
+            assert_eq!(file.len, len);
+        }
+
+        // the pieces of a file are those its slices are in
+        let file_pieces = |index: usize| {
+            info.pieces_intersecting_bytes(info.files[index].byte_range())
+        };
+        assert_eq!(file_pieces(0), 0..1);
+        assert_eq!(file_pieces(1), 0..2);
+        assert_eq!(file_pieces(3), 1..3);
+        assert_eq!(file_pieces(5), 3..4);
+        assert_eq!(file_pieces(6), 4..5);
+    }
+
     #[test]
     fn test_files_intersecting_bytes() {
         let download_len = 12341234;
@@ -421,6 +614,7 @@ mod tests {
             path: PathBuf::from("/bogus"),
             torrent_offset: 0,
             len: download_len,
+            ..Default::default()
         }];
         let info = StorageInfo {
             // arbitrary piece info (not used in this test)
@@ -441,21 +635,25 @@ mod tests {
                 path: PathBuf::from("/bogus0"),
                 torrent_offset: 0,
                 len: 4,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/bogus1"),
                 torrent_offset: 4,
                 len: 9,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/bogus2"),
                 torrent_offset: 13,
                 len: 3,
+                ..Default::default()
             },
             FileInfo {
                 path: PathBuf::from("/bogus3"),
                 torrent_offset: 16,
                 len: 10,
+                ..Default::default()
             },
         ];
         let download_len = files.iter().map(|f| f.len).sum();
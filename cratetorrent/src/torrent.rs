@@ -1,8 +1,8 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     net::SocketAddr,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::sync::oneshot;
 
@@ -10,29 +10,38 @@ use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        watch, RwLock,
     },
     task, time,
 };
 
 use crate::{
     alert::{Alert, AlertSender},
-    conf::TorrentConf,
-    counter::ThruputCounters,
+    conf::{FlushPolicy, SeedGoal, TorrentConf, TorrentConfPatch},
+    counter::{Counter, ThruputCounters},
     disk::{
         self,
+        cache::DiskCache,
         error::{ReadError, WriteError},
+        hasher::HasherPool,
+        WriteBuffer,
     },
     download::PieceDownload,
+    engine,
     error::Error,
     peer::{self, ConnectionState, PeerSession, SessionState, SessionTick},
     piece_picker::PiecePicker,
+    session::TorrentProgress,
+    storage::FileState,
     storage_info::StorageInfo,
     tracker::{Announce, Event, Tracker},
     Bitfield, BlockInfo, PeerId, PieceIndex, Sha1Hash, TorrentId,
 };
 use error::*;
-use stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use stats::{
+    DiskStats, FileStats, PeerSessionStats, Peers, PieceStats, ThruputStats,
+    TorrentStats,
+};
 
 pub mod error;
 pub mod stats;
@@ -51,16 +60,39 @@ pub(crate) enum Command {
     /// Sent when some blocks were written to disk or an error ocurred while
     /// writing.
     PieceCompletion(Result<PieceCompletion, WriteError>),
+    /// Sent when the pieces written since the last flush were flushed to
+    /// disk, after the torrent asked for it, or when flushing them failed.
+    Flushed {
+        pieces: Vec<PieceIndex>,
+        result: Result<(), WriteError>,
+    },
     /// There was an error reading a block.
     ReadError {
         block_info: BlockInfo,
         error: ReadError,
     },
+    /// The current state of the torrent's files, sent by the disk task when
+    /// the torrent asked for it or after reading from them failed.
+    FileStates(Vec<FileState>),
+    /// Sent when a piece whose data may have changed on disk was rechecked.
+    Rechecked(PieceCompletion),
     /// A message sent only once, after the peer has been connected.
     PeerConnected { addr: SocketAddr, id: PeerId },
     /// Peer sessions periodically send this message when they have a state
     /// change.
     PeerState { addr: SocketAddr, info: SessionTick },
+    /// Changes the torrent's configuration at runtime.
+    SetConf(TorrentConfPatch),
+    /// Sent by the engine when the torrent's files were moved or renamed,
+    /// with the new paths of the files.
+    SetStorageInfo(StorageInfo),
+    /// A request for some of the torrent's state, answered on demand.
+    Query(Query),
+    /// Clears the storage error that paused the torrent and resumes it.
+    Resume,
+    /// Flushes the pieces written so far to disk, and answers once they're
+    /// durable (or failed to flush), e.g. before the session is saved.
+    Flush(oneshot::Sender<()>),
     /// Gracefully shut down the torrent.
     ///
     /// This command tells all active peer sessions of torrent to do the same,
@@ -71,6 +103,22 @@ pub(crate) enum Command {
     GetStats { resp: oneshot::Sender<TorrentStats> },
 }
 
+/// The on demand queries of a torrent's state. Each carries the channel on
+/// which the answer is sent.
+#[derive(Debug)]
+pub(crate) enum Query {
+    /// The torrent's current statistics.
+    Status(oneshot::Sender<TorrentStats>),
+    /// The full list of connected peers.
+    Peers(oneshot::Sender<Vec<PeerSessionStats>>),
+    /// The download progress of each file in torrent.
+    Files(oneshot::Sender<Vec<FileStats>>),
+    /// The pieces we have.
+    PieceMap(oneshot::Sender<Bitfield>),
+    /// The state that is saved in the engine's session file.
+    Progress(oneshot::Sender<TorrentProgress>),
+}
+
 /// The type returned on completing a piece.
 #[derive(Debug)]
 pub(crate) struct PieceCompletion {
@@ -120,26 +168,42 @@ pub(crate) struct TorrentContext {
     /// The handle to the disk IO task, used to issue commands on it. A copy of
     /// this handle is passed down to each peer session.
     pub disk_tx: disk::Sender,
+    /// The engine-wide disk write buffer usage. Peer sessions don't start new
+    /// pieces while it's full.
+    pub write_buf: Arc<WriteBuffer>,
+    /// The engine-wide disk cache, whose statistics the torrent reports.
+    pub disk_cache: Arc<DiskCache>,
+    /// The engine-wide hasher pool, whose statistics the torrent reports.
+    pub hasher: Arc<HasherPool>,
     /// Info about the torrent's storage (piece length, download length, etc).
-    pub storage: StorageInfo,
-
-    /// Torrent configuration for access by peer sessions
-    #[cfg(any(feature = "ghostleech", feature = "ratio"))]
-    pub config: TorrentConf,
+    ///
+    /// Like the configuration, it's shared through a channel, as the paths of
+    /// the files change when they're moved or renamed.
+    pub storage: watch::Sender<StorageInfo>,
+
+    /// The configuration of this particular torrent, which may be changed at
+    /// runtime. Peer sessions read it from here, so that they see changes
+    /// right away.
+    pub conf: watch::Sender<TorrentConf>,
 }
 
 /// Parameters for the torrent constructor.
 pub(crate) struct Params {
     pub id: TorrentId,
     pub disk_tx: disk::Sender,
+    pub write_buf: Arc<WriteBuffer>,
+    pub disk_cache: Arc<DiskCache>,
+    pub hasher: Arc<HasherPool>,
     pub info_hash: Sha1Hash,
     pub storage_info: StorageInfo,
-    pub own_pieces: Bitfield,
+    /// The pieces we have and the transfer history of previous runs.
+    pub progress: TorrentProgress,
     pub trackers: Vec<Tracker>,
     pub client_id: PeerId,
     pub listen_addr: SocketAddr,
     pub conf: TorrentConf,
     pub alert_tx: AlertSender,
+    pub engine_tx: engine::Sender,
 }
 
 /// Represents a torrent upload or download.
@@ -161,6 +225,10 @@ pub(crate) struct Torrent {
     /// The channel has to be wrapped in a `stream::Fuse` so that we can
     /// `select!` on it in the torrent event loop.
     cmd_rx: Receiver,
+    /// The channel on which the torrent notifies the engine of its
+    /// completion and of reaching its seeding goals, which posts the alerts
+    /// of them.
+    engine_tx: engine::Sender,
     /// The trackers we can announce to.
     trackers: Vec<TrackerEntry>,
 
@@ -175,6 +243,11 @@ pub(crate) struct Torrent {
     /// relied upon due to the fact that it is possible to pause a torrent, in
     /// which case we don't want to record the run time.
     run_duration: Duration,
+    /// The total time the torrent has been running as a seed.
+    seed_duration: Duration,
+    /// The time the torrent has been seeding without uploading any payload.
+    /// This is reset each round in which some payload was uploaded.
+    seed_idle_duration: Duration,
 
     /// In the last part of the download the torrent is in what's called the
     /// endgame. This is the stage when all pieces have been picked but not all
@@ -189,9 +262,6 @@ pub(crate) struct Torrent {
     /// Measures various transfer statistics.
     counters: ThruputCounters,
 
-    /// The configuration of this particular torrent.
-    conf: TorrentConf,
-
     /// If `TorrentAlertConf::latest_completed_pieces` alert type is set, each
     /// round the torrent collects the pieces that were downloaded, sends them
     /// to peer as an alert, and resets the list.
@@ -199,6 +269,39 @@ pub(crate) struct Torrent {
     /// This is set to some if the configuration is enabled, and set to none if
     /// disabled.
     completed_pieces: Option<Vec<PieceIndex>>,
+
+    /// The number of bytes we have of each file, counted in verified pieces.
+    /// Indexed the same way as the torrent's files.
+    file_progress: Vec<u64>,
+    /// The modification time of each complete file when its data was last
+    /// known to be intact, against which the files are checked for changes
+    /// made by other programs. Indexed the same way as the torrent's files,
+    /// and none for the files that are not complete.
+    file_mtimes: Vec<Option<SystemTime>>,
+
+    /// Whether the torrent is paused because writing its data failed, until
+    /// the user resumes it. No peers are connected while it's paused.
+    is_errored: bool,
+
+    /// The pieces that are known to be on disk: the pieces the torrent was
+    /// started with, and the downloaded pieces once they were flushed to disk
+    /// according to the [flush policy](FlushPolicy).
+    ///
+    /// Only these pieces are saved in the session, and the torrent is only
+    /// complete once all pieces are durable.
+    durable_pieces: Bitfield,
+    /// The number of downloaded pieces that have not been flushed yet.
+    unflushed_piece_count: usize,
+    /// The number of flushes requested from the disk task that have not been
+    /// answered yet.
+    pending_flush_count: usize,
+    /// When a flush was last requested, for the periodic flush policy.
+    last_flush_time: Instant,
+    /// Those waiting for the pending flushes to finish.
+    flush_waiters: Vec<oneshot::Sender<()>>,
+    /// Whether all pieces were downloaded, but the torrent's completion is
+    /// only reported once they're all durable.
+    is_completion_pending: bool,
 }
 
 impl Torrent {
@@ -212,19 +315,44 @@ impl Torrent {
         let Params {
             id,
             disk_tx,
+            write_buf,
+            disk_cache,
+            hasher,
             info_hash,
             storage_info,
-            own_pieces,
+            progress,
             trackers,
             client_id,
             listen_addr,
             conf,
             alert_tx,
+            engine_tx,
         } = params;
 
+        let TorrentProgress {
+            own_pieces,
+            downloaded,
+            uploaded,
+            run_duration,
+            seed_duration,
+            mut file_mtimes,
+        } = progress;
+
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let mut file_progress = vec![0; storage_info.files.len()];
+        for piece in own_pieces.iter_ones() {
+            for (index, slice) in storage_info.piece_file_slices(piece) {
+                file_progress[index] += slice.len;
+            }
+        }
+        file_mtimes.resize(storage_info.files.len(), None);
+        let durable_pieces = own_pieces.clone();
         let piece_picker = PiecePicker::new(own_pieces);
         let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+        // the transfer totals continue from the previous runs
+        let mut counters = ThruputCounters::default();
+        counters.payload.down = Counter::with_total(downloaded);
+        counters.payload.up = Counter::with_total(uploaded);
         let completed_pieces = if conf.alerts.completed_pieces {
             Some(Vec::new())
         } else {
@@ -240,9 +368,11 @@ impl Torrent {
             client_id,
             alert_tx,
             disk_tx,
+            write_buf,
+            disk_cache,
+            hasher,
             storage: storage_info,
-            #[cfg(any(feature = "ghostleech", feature = "ratio"))]
-            config: conf.clone(),
+            conf,
         };
 
         (
@@ -251,14 +381,25 @@ impl Torrent {
                 available_peers: Vec::new(),
                 ctx: Arc::new(ctx_builder.build()),
                 start_time: None,
-                run_duration: Duration::default(),
+                run_duration,
+                seed_duration,
+                seed_idle_duration: Duration::default(),
                 cmd_rx,
+                engine_tx,
                 trackers,
                 in_endgame: false,
-                counters: Default::default(),
+                counters,
                 listen_addr,
-                conf,
                 completed_pieces,
+                file_progress,
+                file_mtimes,
+                is_errored: false,
+                durable_pieces,
+                unflushed_piece_count: 0,
+                pending_flush_count: 0,
+                last_flush_time: Instant::now(),
+                flush_waiters: Vec::new(),
+                is_completion_pending: false,
             },
             cmd_tx,
         )
@@ -273,14 +414,21 @@ impl Torrent {
         // record the torrent starttime
         self.start_time = Some(Instant::now());
 
+        // the files may have been changed while the torrent was not running
+        self.ctx.disk_tx.send(disk::Command::CheckFiles(self.ctx.id))?;
+
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+        if is_seed {
+            self.ctx.disk_tx.send(disk::Command::SetSeed {
+                id: self.ctx.id,
+                is_seed,
+            })?;
+        }
+
         // if the torrent is a seed, don't send the started event, just an
         // empty announce
-        let tracker_event =
-            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
-                None
-            } else {
-                Some(Event::Started)
-            };
+        let tracker_event = if is_seed { None } else { Some(Event::Started) };
         if let Err(e) = self
             .announce_to_trackers(Instant::now(), tracker_event)
             .await
@@ -313,17 +461,21 @@ impl Torrent {
     /// Get current torrent stats for ratio checking
     #[cfg(feature = "ratio")]
     fn get_stats(&self) -> TorrentStats {
+        let piece_count = self.ctx.storage.borrow().piece_count;
         TorrentStats {
             start_time: self.start_time,
             run_duration: self.run_duration,
+            seed_duration: self.seed_duration,
             pieces: PieceStats {
-                total: self.ctx.storage.piece_count,
-                complete: self.ctx.storage.piece_count - self.ctx.piece_picker.blocking_read().missing_piece_count(),
+                total: piece_count,
+                complete: piece_count - self.ctx.piece_picker.blocking_read().missing_piece_count(),
                 pending: self.ctx.downloads.blocking_read().len(),
                 latest_completed: None,
             },
             thruput: ThruputStats::from(&self.counters),
             peers: Peers::Count(self.peers.len()),
+            files: None,
+            disk: Default::default(),
         }
     }
 
@@ -344,6 +496,19 @@ impl Torrent {
             tokio::select! {
                 tick_time = tick_timer.tick() => {
                     self.tick(&mut last_tick_time, tick_time.into_std()).await?;
+                    if let Some(goal) = self.reached_seed_goal().await {
+                        log::info!("Reached seed goal {:?}, stopping", goal);
+                        self.shutdown().await?;
+                        // the engine removes the torrent so that it's not
+                        // restored, and notifies the user
+                        self.engine_tx
+                            .send(engine::Command::SeedGoalReached {
+                                id: self.ctx.id,
+                                goal,
+                            })
+                            .ok();
+                        break;
+                    }
                 }
                 peer_conn_result = listener.accept() => {
                     let (socket, addr) = match peer_conn_result {
@@ -354,6 +519,10 @@ impl Torrent {
                         }
                     };
                     log::info!("New connection {:?}", addr);
+                    if self.is_errored {
+                        log::debug!("Torrent paused, rejecting {:?}", addr);
+                        continue;
+                    }
 
                     // start inbound session
                     let (session, tx) = PeerSession::new(
@@ -377,6 +546,24 @@ impl Torrent {
                         Command::PeerState { addr, info } => {
                             self.handle_peer_state_change(addr, info);
                         }
+                        Command::SetConf(patch) => {
+                            self.set_conf(patch)?;
+                        }
+                        Command::SetStorageInfo(info) => {
+                            self.ctx.storage.send_replace(info);
+                        }
+                        Command::Query(query) => {
+                            self.answer_query(query).await;
+                        }
+                        Command::Resume => {
+                            self.resume()?;
+                        }
+                        Command::Flush(tx) => {
+                            self.flush_all(tx)?;
+                        }
+                        Command::Flushed { pieces, result } => {
+                            self.handle_flush_result(pieces, result).await?;
+                        }
                         Command::PieceCompletion(write_result) => {
                             log::debug!("Disk write result {:?}", write_result);
                             match write_result {
@@ -384,10 +571,7 @@ impl Torrent {
                                     self.handle_piece_completion(piece).await?;
                                 }
                                 Err(e) => {
-                                    log::error!(
-                                        "Failed to write piece to disk: {}",
-                                        e
-                                    );
+                                    self.handle_write_error(e)?;
                                 }
                             }
                         }
@@ -397,11 +581,19 @@ impl Torrent {
                                 block_info,
                                 error
                             );
-                            // TODO: For now we just log for simplicity's sake, but in the
-                            // future we'll need error recovery mechanisms here.
-                            // For instance, it may be that the torrent file got moved while
-                            // the torrent was still seeding. In this case we'd need to stop
-                            // torrent and send an alert to the API consumer.
+                            // the disk task checks the files after other
+                            // errors, but a piece that is corrupt on disk is
+                            // only dropped here
+                            if let ReadError::HashMismatch = error {
+                                self.lose_corrupt_piece(block_info.piece_index)
+                                    .await?;
+                            }
+                        }
+                        Command::FileStates(states) => {
+                            self.check_files(states).await?;
+                        }
+                        Command::Rechecked(piece) => {
+                            self.handle_recheck_result(piece).await?;
                         }
                         Command::Shutdown => {
                             self.shutdown().await?;
@@ -440,10 +632,31 @@ impl Torrent {
         self.run_duration += elapsed_since_last_tick;
         *last_tick_time = Some(now);
 
+        // the seed time and idle counters only run while we're seeding
+        if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
+            self.seed_duration += elapsed_since_last_tick;
+            if self.counters.payload.up.round() > 0 {
+                self.seed_idle_duration = Duration::default();
+            } else {
+                self.seed_idle_duration += elapsed_since_last_tick;
+            }
+        }
+
         // check if we can connect some peers
         // NOTE: do this before announcing as we don't want to block new
         // connections with the potentially long running announce requests
-        self.connect_peers();
+        if !self.is_errored {
+            self.connect_peers();
+        }
+
+        // flush the pieces written since the last flush, if it's time
+        let flush_policy = self.ctx.conf.borrow().flush_policy;
+        if let FlushPolicy::Periodic(interval) = flush_policy
+            && self.unflushed_piece_count > 0
+            && now.saturating_duration_since(self.last_flush_time) >= interval
+        {
+            self.flush()?;
+        }
 
         // check if we need to announce to some trackers
         let event = None;
@@ -499,10 +712,129 @@ impl Torrent {
         Ok(())
     }
 
+    /// Validates and applies the configuration change, alerting the user of
+    /// the result.
+    ///
+    /// Most options are read on demand and thus take effect on their next
+    /// use. If the maximum number of connected peers was lowered, the excess
+    /// peers with the lowest transfer rates are disconnected right away.
+    fn set_conf(&mut self, patch: TorrentConfPatch) -> Result<()> {
+        let conf = match patch.apply(&self.ctx.conf.borrow()) {
+            Ok(conf) => conf,
+            Err(error) => {
+                log::warn!("Invalid conf change: {}", error);
+                self.ctx.alert_tx.send(Alert::Error(Error::InvalidConf {
+                    id: Some(self.ctx.id),
+                    error,
+                }))?;
+                return Ok(());
+            }
+        };
+        log::info!("Changing conf: {:?}", patch);
+
+        // only start collecting completed pieces if it was just turned on, so
+        // as not to lose the pieces collected since the last tick
+        if !conf.alerts.completed_pieces {
+            self.completed_pieces = None;
+        } else if self.completed_pieces.is_none() {
+            self.completed_pieces = Some(Vec::new());
+        }
+
+        // peers that are being disconnected no longer have a command channel
+        let mut connected: Vec<_> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| peer.tx.is_some())
+            .collect();
+        if connected.len() > conf.max_connected_peer_count {
+            let excess = connected.len() - conf.max_connected_peer_count;
+            log::info!("Disconnecting {} excess peer(s)", excess);
+            connected.sort_by_key(|(_, peer)| {
+                peer.thruput.payload.down.rate + peer.thruput.payload.up.rate
+            });
+            for (addr, peer) in connected.into_iter().take(excess) {
+                log::debug!("Disconnecting peer {}", addr);
+                if let Some(tx) = peer.tx.take() {
+                    tx.send(peer::Command::Shutdown).ok();
+                }
+            }
+        }
+
+        // peer sessions see the new conf from the next time they read it
+        self.ctx.conf.send_replace(conf);
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentConfUpdated(self.ctx.id))?;
+        Ok(())
+    }
+
+    /// Pauses the torrent after its data could not be written, alerting the
+    /// user of the error.
+    ///
+    /// The peers are disconnected so that no more pieces are downloaded until
+    /// the user resumes the torrent, after which they are reconnected.
+    fn handle_write_error(&mut self, error: WriteError) -> Result<()> {
+        log::error!("Failed to write piece to disk: {}", error);
+        // the error of each piece whose write failed is reported, but the
+        // torrent is paused (and the user alerted) only once
+        if self.is_errored {
+            return Ok(());
+        }
+        log::warn!("Pausing torrent");
+        self.is_errored = true;
+
+        for (addr, peer) in self.peers.iter_mut() {
+            if let Some(tx) = peer.tx.take() {
+                tx.send(peer::Command::Shutdown).ok();
+                self.available_peers.push(*addr);
+            }
+        }
+
+        let WriteError::Io { path, error } = error;
+        self.ctx.alert_tx.send(Alert::StorageError {
+            id: self.ctx.id,
+            path,
+            error,
+        })?;
+        Ok(())
+    }
+
+    /// Resumes the torrent paused due to a storage error by retrying the
+    /// failed writes. The peers are reconnected on the next tick.
+    fn resume(&mut self) -> Result<()> {
+        if !self.is_errored {
+            log::debug!("Torrent is not paused, nothing to resume");
+            return Ok(());
+        }
+        log::info!("Resuming torrent");
+        self.is_errored = false;
+        self.ctx
+            .disk_tx
+            .send(disk::Command::ResumeWrites(self.ctx.id))?;
+        Ok(())
+    }
+
+    /// Returns the first of the torrent's seeding goals that has been reached,
+    /// if the torrent is a seed.
+    async fn reached_seed_goal(&self) -> Option<SeedGoal> {
+        if self.ctx.piece_picker.read().await.missing_piece_count() > 0 {
+            return None;
+        }
+        let ratio = ThruputStats::from(&self.counters)
+            .share_ratio(self.ctx.storage.borrow().download_len);
+        self.ctx.conf.borrow().seed_goals.reached(
+            ratio,
+            self.seed_duration,
+            self.seed_idle_duration,
+        )
+    }
+
     /// Attempts to connect available peers, if we have any.
     fn connect_peers(&mut self) {
         let connect_count = self
+            .ctx
             .conf
+            .borrow()
             .max_connected_peer_count
             .saturating_sub(self.peers.len())
             .min(self.available_peers.len());
@@ -530,11 +862,23 @@ impl Torrent {
         // calculate transfer statistics in advance
         let uploaded = self.counters.payload.up.total();
         let downloaded = self.counters.payload.down.total();
-        let left = self.ctx.storage.download_len - downloaded;
+        // the totals carry over from earlier runs and include pieces that were
+        // downloaded again, so what's left is counted from the missing pieces
+        let left = self
+            .ctx
+            .piece_picker
+            .read()
+            .await
+            .own_pieces()
+            .iter_zeros()
+            .map(|index| self.ctx.storage.borrow().piece_len(index) as u64)
+            .sum();
+        // the conf can't be borrowed across the announces
+        let conf = self.ctx.conf.borrow().clone();
 
         // skip trackers that errored too often
         // TODO: introduce a retry timeout
-        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        let tracker_error_threshold = conf.tracker_error_threshold;
         for tracker in self
             .trackers
             .iter_mut()
@@ -545,26 +889,26 @@ impl Torrent {
             // torrent.
             let peer_count = self.peers.len() + self.available_peers.len();
             let needed_peer_count = if peer_count
-                >= self.conf.min_requested_peer_count
+                >= conf.min_requested_peer_count
                 || event == Some(Event::Stopped)
             {
                 None
             } else {
-                debug_assert!(self.conf.max_connected_peer_count >= peer_count);
-                let needed = self.conf.max_connected_peer_count - peer_count;
+                debug_assert!(conf.max_connected_peer_count >= peer_count);
+                let needed = conf.max_connected_peer_count - peer_count;
                 // Download at least this numbe of peers, even if we don't need
                 // as many. This is because later we may be able to connect to
                 // more peers and in that case we don't want to wait till the
                 // next tracker request.
-                Some(self.conf.min_requested_peer_count.max(needed))
+                Some(conf.min_requested_peer_count.max(needed))
             };
 
             // we can override the normal annoucne interval if we need peers or
             // if we have an event to announce
             if event.is_some()
                 || (needed_peer_count > Some(0)
-                && tracker.can_announce(now, self.conf.announce_interval))
-                || tracker.should_announce(now, self.conf.announce_interval)
+                && tracker.can_announce(now, conf.announce_interval))
+                || tracker.should_announce(now, conf.announce_interval)
             {
                 let params = Announce {
                     tracker_id: tracker.id.clone(),
@@ -580,10 +924,10 @@ impl Torrent {
 
                     // Add conditional fields based on feature flags
                     #[cfg(feature = "spoofing")]
-                    spoof_client: self.conf.spoof_client.clone(),
+                    spoof_client: conf.spoof_client.clone(),
 
                     #[cfg(feature = "peer_inject")]
-                    extra_peers: self.conf.extra_peers.clone(),
+                    extra_peers: conf.extra_peers.clone(),
 
                     #[cfg(feature = "upload_multiplier")]
                     show_as_seeder: false,
@@ -676,41 +1020,115 @@ impl Torrent {
         Ok(())
     }
 
-    /// Returns high-level statistics about the torrent for sending to the user.
+    /// Returns high-level statistics about the torrent for sending to the user
+    /// with each tick.
+    ///
+    /// Unlike [`Self::status`], this includes the optional information
+    /// enabled in the torrent's alert configuration.
     async fn build_stats(&mut self) -> TorrentStats {
+        let mut stats = self.status().await;
+        stats.pieces.latest_completed =
+            self.completed_pieces.as_mut().map(std::mem::take);
+        let alerts = self.ctx.conf.borrow().alerts.clone();
+        if alerts.peers {
+            stats.peers = Peers::Full(self.peer_stats());
+        }
+        if alerts.files {
+            stats.files = Some(self.file_progress.clone());
+        }
+        stats
+    }
+
+    /// Returns a snapshot of the torrent's statistics with only the number of
+    /// connected peers.
+    async fn status(&self) -> TorrentStats {
         let missing_piece_count =
             self.ctx.piece_picker.read().await.missing_piece_count();
-        let piece_count = self.ctx.storage.piece_count;
-        let completed_pieces =
-            self.completed_pieces.as_mut().map(std::mem::take);
-        let peers = if self.conf.alerts.peers {
-            let peers = self
-                .peers
-                .iter()
-                .map(|(addr, entry)| stats::PeerSessionStats {
-                    addr: *addr,
-                    id: entry.id,
-                    state: entry.state,
-                    piece_count: entry.piece_count,
-                    thruput: entry.thruput,
-                })
-                .collect();
-            Peers::Full(peers)
-        } else {
-            Peers::Count(self.peers.len())
-        };
+        let piece_count = self.ctx.storage.borrow().piece_count;
 
         TorrentStats {
             start_time: self.start_time,
             run_duration: self.run_duration,
+            seed_duration: self.seed_duration,
             pieces: PieceStats {
                 total: piece_count,
                 complete: piece_count - missing_piece_count,
                 pending: self.ctx.downloads.read().await.len(),
-                latest_completed: completed_pieces,
+                latest_completed: None,
             },
             thruput: ThruputStats::from(&self.counters),
-            peers,
+            peers: Peers::Count(self.peers.len()),
+            files: None,
+            disk: DiskStats {
+                write_buf_len: self.ctx.write_buf.len(),
+                write_buf_budget: self.ctx.write_buf.budget(),
+                cache: self.ctx.disk_cache.stats(),
+                hasher: self.ctx.hasher.stats(),
+            },
+        }
+    }
+
+    /// Returns the statistics of each connected peer.
+    fn peer_stats(&self) -> Vec<PeerSessionStats> {
+        self.peers
+            .iter()
+            .map(|(addr, entry)| PeerSessionStats {
+                addr: *addr,
+                id: entry.id,
+                state: entry.state,
+                piece_count: entry.piece_count,
+                thruput: entry.thruput,
+            })
+            .collect()
+    }
+
+    /// Returns the download progress of each file.
+    fn file_stats(&self) -> Vec<FileStats> {
+        self.ctx
+            .storage
+            .borrow()
+            .files
+            .iter()
+            .zip(&self.file_progress)
+            .map(|(info, complete)| FileStats {
+                info: info.clone(),
+                complete: *complete,
+            })
+            .collect()
+    }
+
+    /// Sends the answer to the query on its channel.
+    async fn answer_query(&self, query: Query) {
+        log::debug!("Answering query {:?}", query);
+        // the requester may have stopped waiting for the answer, which is not
+        // an error
+        match query {
+            Query::Status(tx) => {
+                tx.send(self.status().await).ok();
+            }
+            Query::Peers(tx) => {
+                tx.send(self.peer_stats()).ok();
+            }
+            Query::Files(tx) => {
+                tx.send(self.file_stats()).ok();
+            }
+            Query::PieceMap(tx) => {
+                let pieces =
+                    self.ctx.piece_picker.read().await.own_pieces().clone();
+                tx.send(pieces).ok();
+            }
+            Query::Progress(tx) => {
+                // pieces that are not on disk yet may be lost in a crash
+                tx.send(TorrentProgress {
+                    own_pieces: self.durable_pieces.clone(),
+                    downloaded: self.counters.payload.down.total(),
+                    uploaded: self.counters.payload.up.total(),
+                    run_duration: self.run_duration,
+                    seed_duration: self.seed_duration,
+                    file_mtimes: self.file_mtimes.clone(),
+                })
+                .ok();
+            }
         }
     }
 
@@ -789,22 +1207,28 @@ impl Torrent {
                 latest_completed_pieces.push(piece.index);
             }
 
-            // tell all sessions that we got a new piece so that they can send
-            // a "have(piece)" message to their peers or cancel potential
-            // duplicate requests for the same piece
-            for peer in self.peers.values() {
-                if let Some(tx) = &peer.tx {
-                    // this may be after the peer session had already stopped
-                    // but before the torrent tick ran and got a chance to reap
-                    // the dead session
-                    tx.send(peer::Command::PieceCompletion {
-                        index: piece.index,
-                        in_endgame: self.in_endgame,
-                    })
-                        .ok();
+            // the piece may only be claimed in the session or as part of
+            // a complete torrent once it's on disk
+            let flush_policy = self.ctx.conf.borrow().flush_policy;
+            match flush_policy {
+                FlushPolicy::Never => {
+                    self.durable_pieces.set(piece.index, true);
+                }
+                FlushPolicy::PerPiece => {
+                    self.unflushed_piece_count += 1;
+                    self.flush()?;
+                }
+                FlushPolicy::Periodic(_) | FlushPolicy::OnCompletion => {
+                    self.unflushed_piece_count += 1;
+                    if missing_piece_count == 0 {
+                        self.flush()?;
+                    }
                 }
             }
 
+            self.add_file_progress(piece.index)?;
+            self.announce_piece(piece.index);
+
             // if the torrent is fully downloaded, stop the download loop
             if missing_piece_count == 0 {
                 log::info!(
@@ -813,19 +1237,8 @@ impl Torrent {
                     self.counters.payload.down.peak(),
                     self.counters.waste.total(),
                 );
-
-                // notify user of torrent completion
-                self.ctx
-                    .alert_tx
-                    .send(Alert::TorrentComplete(self.ctx.id))
-                    .ok();
-
-                // tell trackers we've finished
-                self.announce_to_trackers(
-                    Instant::now(),
-                    Some(Event::Completed),
-                )
-                    .await?;
+                self.is_completion_pending = true;
+                self.complete_if_durable().await?;
             }
         } else {
             // TODO(https://github.com/mandreyel/cratetorrent/issues/61):
@@ -842,6 +1255,297 @@ impl Torrent {
         Ok(())
     }
 
+    /// Counts the piece towards the progress of the files it overlaps with.
+    ///
+    /// The state of the files it completes is checked, so that their
+    /// modification times are recorded.
+    fn add_file_progress(&mut self, piece_index: PieceIndex) -> Result<()> {
+        // A piece may only partially overlap with the first and last files
+        // it intersects, so only the overlapping part is counted. A file is
+        // complete once all pieces overlapping with it are counted.
+        let mut has_completed_file = false;
+        let storage = self.ctx.storage.borrow();
+        for (index, slice) in storage.piece_file_slices(piece_index) {
+            self.file_progress[index] += slice.len;
+            let file = &storage.files[index];
+            debug_assert!(self.file_progress[index] <= file.len);
+            if self.file_progress[index] == file.len {
+                log::info!("Downloaded file {:?}", file.path);
+                has_completed_file = true;
+                self.ctx
+                    .alert_tx
+                    .send(Alert::FileCompleted {
+                        id: self.ctx.id,
+                        index,
+                    })
+                    .ok();
+            }
+        }
+        if has_completed_file {
+            self.ctx.disk_tx.send(disk::Command::CheckFiles(self.ctx.id))?;
+        }
+        Ok(())
+    }
+
+    /// Tells all sessions that we got a new piece so that they can send
+    /// a "have(piece)" message to their peers or cancel potential duplicate
+    /// requests for the same piece.
+    fn announce_piece(&self, piece_index: PieceIndex) {
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                // this may be after the peer session had already stopped but
+                // before the torrent tick ran and got a chance to reap the
+                // dead session
+                tx.send(peer::Command::PieceCompletion {
+                    index: piece_index,
+                    in_endgame: self.in_endgame,
+                })
+                .ok();
+            }
+        }
+    }
+
+    /// Checks the state of the torrent's files for data that was changed or
+    /// removed by another program, and drops the pieces we have in the
+    /// changed parts of the files until they're rechecked.
+    ///
+    /// A file that is shorter than it should be loses the pieces past its
+    /// end, and a complete file whose modification time changed loses all of
+    /// its pieces.
+    async fn check_files(&mut self, states: Vec<FileState>) -> Result<()> {
+        let picker = self.ctx.piece_picker.read().await;
+        let own_pieces = picker.own_pieces();
+        let mut changed_files = Vec::new();
+        let mut lost_pieces = BTreeSet::new();
+        // the storage info can't be held across awaits
+        let storage = self.ctx.storage.borrow().clone();
+        let files = storage.files.iter().zip(&states).enumerate();
+        for (index, (file, state)) in files {
+            if file.len == 0 {
+                continue;
+            }
+            let is_complete = self.file_progress[index] == file.len;
+            let mtime = &mut self.file_mtimes[index];
+            let intact_len = if state.len < file.len {
+                state.len
+            } else if is_complete && mtime.is_some() && *mtime != state.mtime {
+                0
+            } else {
+                if is_complete {
+                    *mtime = state.mtime;
+                }
+                continue;
+            };
+
+            let start = file.torrent_offset + intact_len;
+            let end = file.torrent_offset + file.len;
+            let pieces: Vec<_> = storage
+                .pieces_intersecting_bytes(start..end)
+                .filter(|&piece| own_pieces[piece])
+                .collect();
+            if !pieces.is_empty() {
+                log::warn!(
+                    "File {:?} was changed, rechecking {} piece(s)",
+                    file.path,
+                    pieces.len()
+                );
+                changed_files.push(index);
+                lost_pieces.extend(pieces);
+            }
+        }
+        drop(picker);
+
+        if lost_pieces.is_empty() {
+            return Ok(());
+        }
+        self.lose_pieces(changed_files, lost_pieces.into_iter().collect())
+            .await
+    }
+
+    /// Drops the piece that was found to be corrupt on disk, if we still have
+    /// it, until it's rechecked.
+    async fn lose_corrupt_piece(&mut self, index: PieceIndex) -> Result<()> {
+        if !self.ctx.piece_picker.read().await.own_pieces()[index] {
+            return Ok(());
+        }
+        let files = self
+            .ctx
+            .storage
+            .borrow()
+            .piece_file_slices(index)
+            .map(|(file, _)| file)
+            .collect();
+        self.lose_pieces(files, vec![index]).await
+    }
+
+    /// Drops the pieces whose data on disk may have been changed or removed
+    /// from the pieces we have, tells the peers that we no longer have them,
+    /// and asks the disk task to recheck them.
+    ///
+    /// The pieces that turn out to be intact are regained once rechecked, and
+    /// the rest are downloaded again.
+    async fn lose_pieces(
+        &mut self,
+        files: Vec<usize>,
+        pieces: Vec<PieceIndex>,
+    ) -> Result<()> {
+        let mut picker = self.ctx.piece_picker.write().await;
+        for &index in pieces.iter() {
+            picker.lost_piece(index);
+            self.durable_pieces.set(index, false);
+            let storage = self.ctx.storage.borrow();
+            for (file, slice) in storage.piece_file_slices(index) {
+                self.file_progress[file] -= slice.len;
+                self.file_mtimes[file] = None;
+            }
+        }
+        drop(picker);
+        // the torrent is only complete again once the pieces are regained
+        self.is_completion_pending = false;
+        self.ctx.disk_tx.send(disk::Command::SetSeed {
+            id: self.ctx.id,
+            is_seed: false,
+        })?;
+
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                for &index in pieces.iter() {
+                    tx.send(peer::Command::PieceLoss(index)).ok();
+                }
+            }
+        }
+
+        self.ctx
+            .alert_tx
+            .send(Alert::StorageChanged {
+                id: self.ctx.id,
+                files,
+                pieces: pieces.clone(),
+            })
+            .ok();
+        self.ctx.disk_tx.send(disk::Command::Recheck {
+            id: self.ctx.id,
+            pieces,
+        })?;
+        Ok(())
+    }
+
+    /// Regains the rechecked piece if its data is intact, or otherwise lets it
+    /// be downloaded again.
+    async fn handle_recheck_result(
+        &mut self,
+        piece: PieceCompletion,
+    ) -> Result<()> {
+        let mut picker = self.ctx.piece_picker.write().await;
+        if !piece.is_valid {
+            log::warn!("Rechecked piece {} is not intact", piece.index);
+            picker.release_piece(piece.index);
+            return Ok(());
+        }
+        if picker.own_pieces()[piece.index] {
+            return Ok(());
+        }
+
+        log::info!("Rechecked piece {} is intact", piece.index);
+        picker.received_piece(piece.index);
+        let missing_piece_count = picker.missing_piece_count();
+        drop(picker);
+
+        // the piece was read back from disk, so it's already durable
+        self.durable_pieces.set(piece.index, true);
+        self.add_file_progress(piece.index)?;
+        self.announce_piece(piece.index);
+
+        if missing_piece_count == 0 {
+            self.is_completion_pending = true;
+            self.complete_if_durable().await?;
+        }
+        Ok(())
+    }
+
+    /// Reports the torrent's completion once all its pieces are durable, if
+    /// it has downloaded all pieces.
+    async fn complete_if_durable(&mut self) -> Result<()> {
+        if !self.is_completion_pending || !self.durable_pieces.all() {
+            return Ok(());
+        }
+        self.is_completion_pending = false;
+        self.ctx.disk_tx.send(disk::Command::SetSeed {
+            id: self.ctx.id,
+            is_seed: true,
+        })?;
+
+        // the engine notifies the user of the torrent's completion, after
+        // moving it to its completed directory if it has one
+        self.engine_tx
+            .send(engine::Command::TorrentComplete(self.ctx.id))
+            .ok();
+
+        // tell trackers we've finished
+        self.announce_to_trackers(Instant::now(), Some(Event::Completed))
+            .await
+    }
+
+    /// Asks the disk task to flush the pieces written since the last flush.
+    fn flush(&mut self) -> Result<()> {
+        log::debug!("Flushing {} piece(s)", self.unflushed_piece_count);
+        self.ctx.disk_tx.send(disk::Command::Flush(self.ctx.id))?;
+        self.pending_flush_count += 1;
+        self.last_flush_time = Instant::now();
+        Ok(())
+    }
+
+    /// Flushes all pieces written so far, and answers on the channel once
+    /// they are durable.
+    fn flush_all(&mut self, tx: oneshot::Sender<()>) -> Result<()> {
+        if self.unflushed_piece_count > 0 {
+            self.flush()?;
+        }
+        if self.pending_flush_count == 0 {
+            tx.send(()).ok();
+        } else {
+            self.flush_waiters.push(tx);
+        }
+        Ok(())
+    }
+
+    /// Marks the flushed pieces as durable, completing the torrent if they
+    /// were its last pieces, or pauses the torrent if the flush failed.
+    ///
+    /// The pieces that failed to flush are never marked as durable, so they
+    /// are not saved in the session and are downloaded again after a restart.
+    async fn handle_flush_result(
+        &mut self,
+        pieces: Vec<PieceIndex>,
+        result: Result<(), WriteError>,
+    ) -> Result<()> {
+        self.pending_flush_count = self.pending_flush_count.saturating_sub(1);
+        if self.pending_flush_count == 0 {
+            for tx in self.flush_waiters.drain(..) {
+                tx.send(()).ok();
+            }
+        }
+
+        self.unflushed_piece_count =
+            self.unflushed_piece_count.saturating_sub(pieces.len());
+        match result {
+            Ok(()) => {
+                log::debug!("Flushed {} piece(s)", pieces.len());
+                // pieces lost since they were written are only durable again
+                // once they're rechecked
+                let picker = self.ctx.piece_picker.read().await;
+                for piece in pieces {
+                    if picker.own_pieces()[piece] {
+                        self.durable_pieces.set(piece, true);
+                    }
+                }
+                drop(picker);
+                self.complete_if_durable().await
+            }
+            Err(e) => self.handle_write_error(e),
+        }
+    }
+
     /// Shuts down torrent and all peer sessions, and also announces torrent's
     /// exit to tracker.
     async fn shutdown(&mut self) -> Result<()> {
@@ -882,9 +1586,11 @@ struct TorrentContextBuilder {
     client_id: PeerId,
     alert_tx: AlertSender,
     disk_tx: disk::Sender,
+    write_buf: Arc<WriteBuffer>,
+    disk_cache: Arc<DiskCache>,
+    hasher: Arc<HasherPool>,
     storage: StorageInfo,
-    #[cfg(any(feature = "ghostleech", feature = "ratio"))]
-    config: TorrentConf,
+    conf: TorrentConf,
 }
 
 impl TorrentContextBuilder {
@@ -898,9 +1604,11 @@ impl TorrentContextBuilder {
             client_id: self.client_id,
             alert_tx: self.alert_tx,
             disk_tx: self.disk_tx,
-            storage: self.storage,
-            #[cfg(any(feature = "ghostleech", feature = "ratio"))]
-            config: self.config,
+            write_buf: self.write_buf,
+            disk_cache: self.disk_cache,
+            hasher: self.hasher,
+            storage: watch::Sender::new(self.storage),
+            conf: watch::Sender::new(self.conf),
         }
     }
 }
@@ -5,7 +5,7 @@ use std::{
 
 use crate::{
     counter::{ChannelCounter, Counter, ThruputCounters},
-    PeerId, PieceIndex,
+    FileInfo, PeerId, PieceIndex,
 };
 
 pub use crate::peer::{ConnectionState, SessionState};
@@ -19,6 +19,9 @@ pub struct TorrentStats {
     /// How long the torrent has been running.
     pub run_duration: Duration,
 
+    /// How long the torrent has been running as a seed.
+    pub seed_duration: Duration,
+
     /// Aggregate statistics about a torrent's pieces.
     pub pieces: PieceStats,
 
@@ -33,6 +36,87 @@ pub struct TorrentStats {
 
     /// Various thruput statistics of the torrent.
     pub thruput: ThruputStats,
+
+    /// The number of bytes downloaded and verified of each file, in the order
+    /// of the torrent's files.
+    ///
+    /// By default this information is not sent, as it has some overhead. It
+    /// needs to be turned on in the torrent's [configuration]
+    /// (crate::conf::TorrentAlertConf::files).
+    pub files: Option<Vec<u64>>,
+
+    /// Statistics of the engine's disk IO, which is shared by all torrents.
+    pub disk: DiskStats,
+}
+
+/// Statistics of the engine's disk IO.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    /// The number of downloaded bytes held in memory until they are written to
+    /// disk, across all torrents.
+    pub write_buf_len: u64,
+    /// The write buffer's [budget](crate::conf::EngineConf::write_buf_budget).
+    /// While it's exceeded, no new blocks are requested.
+    pub write_buf_budget: u64,
+    /// Statistics of the cache of pieces in memory.
+    pub cache: CacheStats,
+    /// Statistics of the threads that hash pieces.
+    pub hasher: HasherStats,
+}
+
+/// The priority of the hashing jobs of the engine's hasher threads, from
+/// highest to lowest. Jobs of a higher priority are always run first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HashPriority {
+    /// Verifying the pieces being downloaded.
+    Download,
+    /// Verifying the pieces read from disk before they are uploaded.
+    Seed,
+    /// Rechecking the data on disk.
+    Recheck,
+}
+
+impl HashPriority {
+    /// The number of priorities.
+    pub(crate) const COUNT: usize = 3;
+}
+
+/// Statistics of the engine's hasher threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HasherStats {
+    /// The number of hasher threads, as
+    /// [configured](crate::conf::EngineConf::hasher_thread_count).
+    pub thread_count: usize,
+    /// The number of jobs waiting for a thread, indexed by their priority.
+    /// See [`Self::queue_len`].
+    pub queue_lens: [usize; HashPriority::COUNT],
+    /// The total number of bytes hashed.
+    pub hashed_len: u64,
+    /// The number of bytes hashed per second of the time the threads spent
+    /// hashing.
+    pub hash_rate: u64,
+}
+
+impl HasherStats {
+    /// Returns the number of jobs of the priority waiting for a thread.
+    pub fn queue_len(&self, priority: HashPriority) -> usize {
+        self.queue_lens[priority as usize]
+    }
+}
+
+/// Statistics of the engine's disk cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of bytes cached.
+    pub len: u64,
+    /// The cache's [budget](crate::conf::EngineConf::disk_cache_budget).
+    pub budget: u64,
+    /// The number of block reads served from the cache.
+    pub hit_count: u64,
+    /// The number of block reads that had to go to disk.
+    pub miss_count: u64,
+    /// The number of pieces evicted from the cache to make room for others.
+    pub eviction_count: u64,
 }
 
 /// Statistics of a torrent's pieces.
@@ -95,6 +179,22 @@ impl Default for Peers {
     }
 }
 
+/// The download progress of a file in a torrent.
+#[derive(Clone, Debug)]
+pub struct FileStats {
+    /// The file's metadata.
+    pub info: FileInfo,
+    /// The number of bytes of the file we have, counted in whole pieces.
+    pub complete: u64,
+}
+
+impl FileStats {
+    /// Returns whether we have all bytes of the file.
+    pub fn is_complete(&self) -> bool {
+        self.complete == self.info.len
+    }
+}
+
 /// Aggregate statistics of a peer session.
 #[derive(Clone, Debug)]
 pub struct PeerSessionStats {
@@ -122,6 +222,22 @@ pub struct ThruputStats {
     pub waste: u64,
 }
 
+impl ThruputStats {
+    /// Returns the share ratio: the uploaded payload divided by the downloaded
+    /// payload.
+    ///
+    /// If less than the torrent's total length was downloaded (e.g. the torrent
+    /// was started as a seed), the torrent's length is used instead.
+    pub fn share_ratio(&self, torrent_len: u64) -> f64 {
+        let downloaded = self.payload.down.total.max(torrent_len);
+        if downloaded == 0 {
+            0.0
+        } else {
+            self.payload.up.total as f64 / downloaded as f64
+        }
+    }
+}
+
 impl From<&ThruputCounters> for ThruputStats {
     fn from(c: &ThruputCounters) -> Self {
         Self {
@@ -66,6 +66,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         listen_addr: args.listen,
         mode: args.mode,
         conf: None,
+        storage: None,
     })?;
 
     // listen to alerts from the engine